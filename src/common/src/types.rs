@@ -13,4 +13,18 @@ pub struct Metadata {
     pub fee: Nat,
     pub feeTo: Principal,
     pub isTestToken: Option<bool>,
+    pub minTransferAmount: Option<Nat>,
+    pub maxSupply: Option<Nat>,
+}
+
+/// A transaction signed by the key pair of a self-authenticating principal. The token canister
+/// accepts the approvals signed with Ed25519 keys in this form with its `permit` method.
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct SignedTx {
+    /// DER-encoded public key of the signer.
+    pub publickey: Vec<u8>,
+    /// Signature of the `serialized_tx` bytes.
+    pub signature: Vec<u8>,
+    /// CBOR-serialized transaction payload.
+    pub serialized_tx: Vec<u8>,
 }
@@ -1,30 +1,98 @@
-use crate::canister::dip20_transactions::{approve, burn, mint, transfer, transfer_from};
+use crate::canister::dip20_transactions::{
+    approve, burn, burn_from, decrease_allowance, increase_allowance, mint, transfer,
+    transfer_from, transfer_from_with_allowance, transfer_with_memo,
+};
+use crate::canister::is20_archive::{archive_info, archive_transactions, set_archive, ArchiveInfo};
 use crate::canister::is20_auction::{
-    auction_info, bid_cycles, bidding_info, run_auction, AuctionError, BiddingInfo,
+    auction_count, auction_info, auction_stats, auctions, auto_auction_log, bid_cycles,
+    bidding_info, cancel_bid, claim_auction_reward, claim_bid_refund, fee_pool, my_bid,
+    run_auction, set_fee_ratio_bounds, AuctionError, AuctionStats, AutoAuctionRun, BiddingInfo,
+};
+use crate::canister::is20_backup::{
+    backup_ledger, backup_progress, restore_ledger, BACKUP_CHUNK_LEN, MAX_BACKUP_CHUNKS,
+};
+use crate::canister::is20_claim::{claim_mint, set_claim_root};
+use crate::canister::is20_emission::{emission_info, set_emission, EmissionInfo};
+use crate::canister::is20_events::{
+    approve_subscriber, delivery_queue_stats, list_subscribers, remove_subscriber, subscribe,
+    unsubscribe, DeliveryQueueStats,
+};
+use crate::canister::is20_notify::{
+    approve_and_notify, burn_and_notify, flush_owner_notifications, mint_and_notify,
+    notification_status, notify, pending_owner_notifications, set_allowance_notifications,
+    transfer_and_notify, unnotified_transactions,
+};
+use crate::canister::is20_permit::{permit, permit_nonce};
+use crate::canister::is20_transactions::{
+    add_minter, add_non_circulating_account, admin_revoke_approvals, approve_expected,
+    approve_once, approve_unlimited, batch_approve, batch_mint, faucet, owner_transfer_from,
+    remove_minter, remove_non_circulating_account, renounce_minting, revoke_all_approvals,
+    set_faucet_limit, set_mint_limit, set_mint_limit_owner_exempt, test_burn,
+    transfer_from_include_fee, transfer_include_fee,
+};
+use crate::canister::is20_vesting::{mint_vested, vesting_info, VestingInfo};
+use crate::canister::is20_votes::delegate;
+use crate::certified::tip_tree;
+use crate::export::export_chunk;
+use crate::ledger::to_id;
+use crate::state::{CanisterState, PendingOwnerNotification, Subscriber};
+use crate::types::{
+    Allowance, ApprovalDetails, ApprovalsPage, AuctionController, AuctionInfo, AuctionPayoutMode,
+    BackupChunk, BackupProgress, BlocksPage, CertifiedTip, ExportChunk, FeePaymentsPage,
+    LedgerCheckResult, LedgerStats, LedgerTip, NotificationStatus, Operation, OperationCounts,
+    StatsData, Timestamp, TokenInfo, TransactionOrder, TransactionStatusFilter, TransactionsPage,
+    TransactionsQueryResult, TransferFromReceipt, TxError, TxFilter, TxReceipt, TxRecord,
+    UnnotifiedPage, UserStats, MAX_AUCTION_INCENTIVE_RATE, MAX_FEE_BURN_RATE,
 };
-use crate::canister::is20_notify::{notify, transfer_and_notify};
-use crate::canister::is20_transactions::transfer_include_fee;
-use crate::state::CanisterState;
-use crate::types::{AuctionInfo, StatsData, Timestamp, TokenInfo, TxError, TxReceipt, TxRecord};
 use candid::Nat;
-use common::types::Metadata;
+use common::types::{Metadata, SignedTx};
 use ic_canister::{init, query, update, Canister};
 use ic_cdk::export::candid::Principal;
 use num_traits::ToPrimitive;
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
 use std::rc::Rc;
 
-mod dip20_transactions;
+pub mod dip20_transactions;
+mod heartbeat;
 mod inspect;
+pub mod is20_archive;
 pub mod is20_auction;
+mod is20_backup;
+mod is20_claim;
+pub mod is20_emission;
+pub mod is20_events;
 pub mod is20_notify;
+mod is20_permit;
 mod is20_transactions;
+pub mod is20_vesting;
+mod is20_votes;
 
 // 1 day in nanoseconds.
-const DEFAULT_AUCTION_PERIOD: Timestamp = 24 * 60 * 60 * 1_000_000;
+const DEFAULT_AUCTION_PERIOD: Timestamp = 24 * 60 * 60 * 1_000_000_000;
 
+/// Minimum period between the auctions the owner can set with `setAuctionPeriod`, in seconds.
+pub const MIN_AUCTION_PERIOD_SEC: u64 = 60 * 60;
+
+/// Default maximum number of the transactions returned by one query.
 const MAX_TRANSACTION_QUERY_LEN: usize = 1000;
 
+/// Upper bound of the maximum query limit the owner can set with `setMaxQueryLimit`.
+const MAX_TRANSACTION_QUERY_HARD_CAP: u64 = 10_000;
+
+/// Maximum number of the transactions checked by one filtered transaction query.
+const MAX_TRANSACTION_SCAN_LEN: usize = 10_000;
+
+const MAX_ALLOWANCES_QUERY_LEN: usize = 1000;
+
+/// Maximum size of the data returned by `exportTransactions` and `getBlocksSince`, to fit into
+/// the reply message.
+const MAX_EXPORT_CHUNK_BYTES: u64 = 1_900_000;
+
+/// Maximum number of the records checked by one `verifyLedger` call. Every record is hashed, so the
+/// limit keeps the call well within the query instruction limit.
+const MAX_LEDGER_CHECK_LEN: usize = 5_000;
+
 #[derive(Clone, Canister)]
 pub struct TokenCanister {
     #[id]
@@ -38,6 +106,10 @@ pub struct TokenCanister {
 impl TokenCanister {
     #[init]
     fn init(&self, metadata: Metadata) {
+        if matches!(&metadata.maxSupply, Some(max_supply) if metadata.totalSupply > *max_supply) {
+            ic_kit::ic::trap("Initial total supply exceeds the maximum supply");
+        }
+
         self.state
             .borrow_mut()
             .balances
@@ -47,9 +119,12 @@ impl TokenCanister {
             metadata.owner,
             metadata.owner,
             metadata.totalSupply.clone(),
+            None,
         );
         self.state.borrow_mut().stats = metadata.into();
-        self.state.borrow_mut().bidding_state.auction_period = DEFAULT_AUCTION_PERIOD;
+        let bidding_state = &mut self.state.borrow_mut().bidding_state;
+        bidding_state.auction_period = DEFAULT_AUCTION_PERIOD;
+        bidding_state.auction_period_in_ns = true;
     }
 
     #[query]
@@ -62,10 +137,12 @@ impl TokenCanister {
         TokenInfo {
             metadata: self.state.borrow().get_metadata(),
             feeTo: fee_to,
-            historySize: self.state.borrow().ledger.len(),
+            historySize: Nat::from(self.state.borrow().ledger.len()),
             deployTime: deploy_time,
             holderNumber: self.state.borrow().balances.0.len(),
             cycles: ic_kit::ic::balance(),
+            totalBurned: self.state.borrow().stats.total_burned.clone(),
+            mintingDisabled: self.state.borrow().stats.minting_disabled,
         }
     }
 
@@ -79,9 +156,116 @@ impl TokenCanister {
         self.state.borrow().allowance_size()
     }
 
+    /// Returns allowances of all users as `(owner, spender, value)` entries in range
+    /// `[start, start + limit)`. The entries are ordered by owner and spender principals.
+    ///
+    /// The `limit` is clamped to `MAX_ALLOWANCES_QUERY_LEN`.
+    #[query]
+    fn getAllowances(&self, start: usize, limit: usize) -> Vec<(Principal, Principal, Nat)> {
+        self.state
+            .borrow()
+            .allowances_page(start, limit.min(MAX_ALLOWANCES_QUERY_LEN))
+    }
+
+    /// Returns not expired allowances of all users with all their parameters in range
+    /// `[offset, offset + limit)`, and the total number of such allowances. The entries are
+    /// ordered by owner and spender principals.
+    ///
+    /// Every page is consistent at the time of the call, but allowances given, revoked or expired
+    /// between the calls shift the following entries. If the `total` changes between the pages,
+    /// the export should be restarted to get a consistent snapshot.
+    ///
+    /// The `limit` is clamped to `MAX_ALLOWANCES_QUERY_LEN`.
+    #[query]
+    fn exportApprovals(&self, offset: usize, limit: usize) -> ApprovalsPage {
+        self.state
+            .borrow()
+            .export_approvals(offset, limit.min(MAX_ALLOWANCES_QUERY_LEN))
+    }
+
+    /// Returns the sum of all not expired allowances given by the `owner`.
+    #[query]
+    fn getApprovedTotal(&self, owner: Principal) -> Nat {
+        self.state.borrow().approved_total(owner)
+    }
+
+    /// Returns not expired allowances given to the `spender` as `(owner, value)` entries in range
+    /// `[start, start + limit)`. The entries are ordered by the owner principal.
+    ///
+    /// The `limit` is clamped to `MAX_ALLOWANCES_QUERY_LEN`.
+    #[query]
+    fn approvalsForSpender(
+        &self,
+        spender: Principal,
+        start: usize,
+        limit: usize,
+    ) -> Vec<(Principal, Nat)> {
+        self.state
+            .borrow()
+            .spender_approvals(spender, start, limit.min(MAX_ALLOWANCES_QUERY_LEN))
+    }
+
+    /// Removes up to `max_entries` allowances that are expired or have zero value, and returns the
+    /// number of removed entries. The method can be called repeatedly until it returns zero.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn purgeAllowances(&self, max_entries: u64) -> u64 {
+        check_caller(self.owner()).unwrap();
+        self.state
+            .borrow_mut()
+            .purge_allowances(max_entries as usize) as u64
+    }
+
+    /// Returns the list of not expired allowances given by the user `who`, ordered by the spender
+    /// principal.
+    ///
+    /// The number of returned entries is limited by `MAX_ALLOWANCES_QUERY_LEN`. Use
+    /// `getUserApprovalsPage` to get all the allowances of the user.
+    #[query]
+    fn getUserApprovals(&self, who: Principal) -> Vec<(Principal, Allowance)> {
+        self.getUserApprovalsPage(who, 0, MAX_ALLOWANCES_QUERY_LEN)
+    }
+
+    /// Returns not expired allowances given by the user `who` in range `[start, start + limit)`,
+    /// ordered by the spender principal.
+    ///
+    /// The `limit` is clamped to `MAX_ALLOWANCES_QUERY_LEN`.
+    #[query]
+    fn getUserApprovalsPage(
+        &self,
+        who: Principal,
+        start: usize,
+        limit: usize,
+    ) -> Vec<(Principal, Allowance)> {
+        self.state
+            .borrow()
+            .user_approvals(who, start, limit.min(MAX_ALLOWANCES_QUERY_LEN))
+    }
+
+    /// Returns the list of not expired allowances given by the user `who` together with their usage
+    /// statistics, ordered by the spender principal.
+    ///
+    /// The number of returned entries is limited by `MAX_ALLOWANCES_QUERY_LEN`.
+    #[query]
+    fn getUserApprovalsDetailed(&self, who: Principal) -> Vec<ApprovalDetails> {
+        self.getUserApprovals(who)
+            .into_iter()
+            .map(|(spender, allowance)| ApprovalDetails {
+                spender,
+                value: allowance.value,
+                expires_at: allowance.expires_at,
+                created_at: allowance.created_at,
+                times_used: allowance.times_used,
+                last_used: allowance.last_used,
+            })
+            .collect()
+    }
+
+    /// Returns the number of not expired allowances given by the user `who`.
     #[query]
-    fn getUserApprovals(&self, who: Principal) -> Vec<(Principal, Nat)> {
-        self.state.borrow().user_approvals(who)
+    fn getUserApprovalsCount(&self, who: Principal) -> usize {
+        self.state.borrow().user_approvals_count(who)
     }
 
     #[query]
@@ -92,9 +276,11 @@ impl TokenCanister {
     #[update]
     fn toggleTest(&self) -> bool {
         check_caller(self.owner()).unwrap();
-        let stats = &mut self.state.borrow_mut().stats;
-        stats.is_test_token = !stats.is_test_token;
-        stats.is_test_token
+        let mut state = self.state.borrow_mut();
+        let is_test_token = !state.stats.is_test_token;
+        state.stats.is_test_token = is_test_token;
+        record_config_change(&mut state, "is_test_token", !is_test_token, is_test_token);
+        is_test_token
     }
 
     #[query]
@@ -127,259 +313,1724 @@ impl TokenCanister {
         self.state.borrow().balances.balance_of(&holder)
     }
 
+    /// Returns the balance of the `holder` right after the `tx_id` transaction. The balance is
+    /// replayed from the ledger records starting from the last balance checkpoint, see
+    /// `setBalanceCheckpointSpacing`.
+    ///
+    /// Returns `TransactionDoesNotExist` error if the transaction was not added yet or was removed
+    /// from the history. The transactions made before the upgrade that added the balance index
+    /// are indexed on the following heartbeats, and the balances at the transactions removed from
+    /// the history before that upgrade are not known.
     #[query]
-    fn allowance(&self, owner: Principal, spender: Principal) -> Nat {
-        self.state.borrow().allowance(owner, spender)
+    fn balanceOfAt(&self, holder: Principal, tx_id: Nat) -> Result<Nat, TxError> {
+        self.state.borrow().ledger.balance_at(holder, to_id(&tx_id))
     }
 
-    #[query]
-    fn getMetadata(&self) -> Metadata {
-        self.state.borrow().get_metadata()
+    /// Sets the number of the transactions between two balance checkpoints. A `balanceOfAt` call
+    /// replays up to `spacing` transactions, while every checkpoint saves the balances changed
+    /// since the previous one. The `spacing` must be greater than zero, otherwise
+    /// `InvalidCheckpointSpacing` error is returned.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setBalanceCheckpointSpacing(&self, spacing: u64) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        if spacing == 0 {
+            return Err(TxError::InvalidCheckpointSpacing);
+        }
+
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let old_spacing = state.ledger.checkpoint_spacing();
+        state
+            .ledger
+            .set_checkpoint_spacing(spacing, &state.balances.0);
+        record_config_change(state, "balance_checkpoint_spacing", old_spacing, spacing);
+        Ok(())
     }
 
+    /// Returns the number of the transactions between two balance checkpoints.
     #[query]
-    fn historySize(&self) -> Nat {
-        self.state.borrow().ledger.len()
+    fn getBalanceCheckpointSpacing(&self) -> u64 {
+        self.state.borrow().ledger.checkpoint_spacing()
+    }
+
+    /// Delegates the votes of the caller to the `to` principal. The whole balance of the caller,
+    /// including the future balance changes, is counted in the vote weight of the delegate. The
+    /// votes delegated to the caller are not delegated further. Delegating to the caller itself
+    /// restores the default self-delegation. The delegation is recorded in the ledger as
+    /// `Operation::Delegate`.
+    #[update]
+    fn delegate(&self, to: Principal) -> TxReceipt {
+        delegate(self, to)
     }
 
+    /// Returns the principal voting with the balance of `who`. Principals that never delegated
+    /// their votes vote for themselves.
     #[query]
-    fn getTransaction(&self, id: Nat) -> TxRecord {
-        self.state
-            .borrow()
-            .ledger
-            .get(&id)
-            .unwrap_or_else(|| ic_kit::ic::trap(&format!("Transaction {} does not exist", id)))
+    fn getDelegate(&self, who: Principal) -> Principal {
+        self.state.borrow().ledger.delegate_of(who)
     }
 
+    /// Returns the current vote weight of `who`: the sum of the balances delegated to it.
     #[query]
-    fn getTransactions(&self, start: Nat, limit: Nat) -> Vec<TxRecord> {
-        if limit > MAX_TRANSACTION_QUERY_LEN {
-            ic_kit::ic::trap(&format!(
-                "Limit must be less then {}",
-                MAX_TRANSACTION_QUERY_LEN
-            ));
-        }
+    fn getVotes(&self, who: Principal) -> Nat {
+        self.state.borrow().ledger.votes(who)
+    }
 
-        self.state
-            .borrow()
-            .ledger
-            .get_range(&start, &limit)
-            .to_vec()
+    /// Returns the vote weight of `who` right after the `tx_id` transaction. Returns
+    /// `TransactionDoesNotExist` error if the transaction was not added yet.
+    #[query]
+    fn getPastVotes(&self, who: Principal, tx_id: Nat) -> Result<Nat, TxError> {
+        self.state.borrow().ledger.past_votes(who, to_id(&tx_id))
     }
 
-    #[update]
-    fn setName(&self, name: String) {
-        check_caller(self.owner()).unwrap();
-        self.state.borrow_mut().stats.name = name;
+    #[query]
+    fn allowance(&self, owner: Principal, spender: Principal) -> Nat {
+        self.state.borrow().allowance(owner, spender)
     }
 
-    #[update]
-    fn setLogo(&self, logo: String) {
-        check_caller(self.owner()).unwrap();
-        self.state.borrow_mut().stats.logo = logo;
+    #[query]
+    fn getMetadata(&self) -> Metadata {
+        self.state.borrow().get_metadata()
     }
 
-    #[update]
-    fn setFee(&self, fee: Nat) {
-        check_caller(self.owner()).unwrap();
-        self.state.borrow_mut().stats.fee = fee;
+    /// Returns the total supply without the balances of the non-circulating accounts and the tokens
+    /// that are locked by vesting schedules.
+    #[query]
+    fn circulatingSupply(&self) -> Nat {
+        self.state.borrow().circulating_supply()
     }
 
-    #[update]
-    fn setFeeTo(&self, fee_to: Principal) {
-        check_caller(self.owner()).unwrap();
-        self.state.borrow_mut().stats.fee_to = fee_to;
+    /// Returns the total amount of tokens minted over the lifetime of the token, including the
+    /// initial supply.
+    #[query]
+    fn totalMinted(&self) -> Nat {
+        self.state.borrow().stats.total_minted()
     }
 
-    #[update]
-    fn setOwner(&self, owner: Principal) {
-        check_caller(self.owner()).unwrap();
-        self.state.borrow_mut().stats.owner = owner;
+    /// Returns the total amount of tokens burned over the lifetime of the token, including the
+    /// burned shares of the fees.
+    #[query]
+    fn totalBurned(&self) -> Nat {
+        self.state.borrow().stats.total_burned.clone()
     }
 
     #[query]
-    fn owner(&self) -> Principal {
-        self.state.borrow().stats.owner
+    fn historySize(&self) -> Nat {
+        Nat::from(self.state.borrow().ledger.len())
     }
 
-    /// Returns an array of transaction records in range [start, start + limit) related to user `who`.
-    /// Unlike `getTransactions` function, the range [start, start + limit) for `getUserTransactions`
-    /// is not the global range of all transactions. The range [start, start + limit) here pertains to
-    /// the transactions of user who. Implementations are allowed to return less TxRecords than
-    /// requested to fend off DoS attacks.
+    /// Returns the id and the hash of the last transaction, so that the clients can verify the
+    /// hash chain of the transactions they got from the ledger.
     #[query]
-    fn getUserTransactions(&self, who: Principal, start: Nat, limit: Nat) -> Vec<TxRecord> {
-        let mut transactions = vec![];
-
-        let limit_usize = limit.0.to_usize().unwrap_or(usize::MAX);
-        if limit_usize > MAX_TRANSACTION_QUERY_LEN {
-            ic_kit::ic::trap(&format!(
-                "Limit must be less then {}",
-                MAX_TRANSACTION_QUERY_LEN
-            ));
+    fn getLedgerTip(&self) -> LedgerTip {
+        let ledger = &self.state.borrow().ledger;
+        LedgerTip {
+            last_id: Nat::from(ledger.last_id()),
+            last_hash: ledger.tip_hash(),
         }
+    }
 
-        for tx in self.state.borrow().ledger.get_range(&start, &limit) {
-            if tx.from == who || tx.to == who || tx.caller == Some(who) {
-                transactions.push(tx.clone());
-            }
+    /// Same as `getLedgerTip`, but also returns the certificate of the canister's certified data
+    /// and the hash tree (witness) connecting the tip to the certified data. See the `certified`
+    /// module for the layout of the tree.
+    #[query]
+    fn getCertifiedTip(&self) -> CertifiedTip {
+        let ledger = &self.state.borrow().ledger;
+        let last_id = Nat::from(ledger.last_id());
+        let last_hash = ledger.tip_hash();
+        let witness = tip_tree(&last_id, &last_hash).to_cbor();
+        CertifiedTip {
+            last_id,
+            last_hash,
+            certificate: ic_kit::ic::data_certificate(),
+            witness,
         }
+    }
 
-        transactions
+    #[query]
+    fn getTransaction(&self, id: Nat) -> TxRecord {
+        self.getTransactionSafe(id.clone())
+            .unwrap_or_else(|err| match err {
+                TxError::TransactionArchived { canister_id } => ic_kit::ic::trap(&format!(
+                    "Transaction {} is archived in canister {}",
+                    id, canister_id
+                )),
+                _ => ic_kit::ic::trap(&format!("Transaction {} does not exist", id)),
+            })
     }
 
-    /// Returns total number of transactions related to the user `who`.
+    /// Same as `getTransaction`, but returns an error instead of trapping if the transaction is
+    /// not in the ledger: `TransactionArchived` with the archive canister holding it if the
+    /// transaction was archived, and `TransactionDoesNotExist` otherwise.
     #[query]
-    fn getUserTransactionAmount(&self, who: Principal) -> Nat {
-        let mut amount = Nat::from(0);
-        for tx in self.state.borrow().ledger.iter() {
-            if tx.from == who || tx.to == who || tx.caller == Some(who) {
-                amount += tx.amount.clone();
-            }
+    fn getTransactionSafe(&self, id: Nat) -> Result<TxRecord, TxError> {
+        let state = self.state.borrow();
+        if let Some(tx) = state.ledger.get(to_id(&id)) {
+            return Ok(tx.clone());
         }
 
-        amount
+        match state.archive.find(&id) {
+            Some(range) => Err(TxError::TransactionArchived {
+                canister_id: range.canister_id,
+            }),
+            None => Err(TxError::TransactionDoesNotExist),
+        }
     }
 
-    #[update]
-    fn transfer(&self, to: Principal, value: Nat, fee_limit: Option<Nat>) -> TxReceipt {
-        transfer(self, to, value, fee_limit)
-    }
+    /// Returns the transactions in the range [start, start + limit). The records kept in the
+    /// ledger are returned directly, and the parts of the range moved to the archive canisters are
+    /// returned as pointers to the archives. The `limit` is clamped to
+    /// the maximum query limit.
+    #[query]
+    fn queryTransactions(&self, start: Nat, limit: Nat) -> TransactionsQueryResult {
+        let limit = Nat::from(query_len(self, &limit));
+        let end = start.clone() + limit;
 
-    #[update]
-    fn transferFrom(&self, from: Principal, to: Principal, value: Nat) -> TxReceipt {
-        transfer_from(self, from, to, value)
+        let state = self.state.borrow();
+        let local_start = start.clone().max(Nat::from(state.ledger.first_id()));
+        let records = if local_start < end {
+            let local_limit = (end.clone() - local_start.clone())
+                .0
+                .to_usize()
+                .unwrap_or(usize::MAX);
+            state
+                .ledger
+                .get_range(to_id(&local_start), local_limit)
+                .to_vec()
+        } else {
+            vec![]
+        };
+
+        TransactionsQueryResult {
+            records,
+            archived: state.archive.ranges_in(&start, &end),
+        }
     }
 
-    /// Transfers `value` amount to the `to` principal, applying American style fee. This means, that
-    /// the recipient will receive `value - fee`, and the sender account will be reduced exactly by `value`.
-    ///
-    /// Note, that the `value` cannot be less than the `fee` amount. If the value given is too small,
-    /// transaction will fail with `TxError::AmountTooSmall` error.
-    #[update]
-    fn transferIncludeFee(&self, to: Principal, value: Nat) -> TxReceipt {
-        transfer_include_fee(self, to, value)
+    /// Returns the transaction records with the given ids in the same order, with `None` for the
+    /// ids of the transactions that don't exist. Only the first ids up to the maximum query limit
+    /// are looked up, the rest are ignored.
+    #[query]
+    fn getTransactionsByIds(&self, ids: Vec<Nat>) -> Vec<Option<TxRecord>> {
+        let max_len = max_query_len(self);
+        let state = self.state.borrow();
+        ids.iter()
+            .take(max_len)
+            .map(|id| state.ledger.get(to_id(id)).cloned())
+            .collect()
     }
 
-    #[update]
-    fn approve(&self, spender: Principal, value: Nat) -> TxReceipt {
-        approve(self, spender, value)
+    /// Returns the transactions in the range [start, start + limit). The `limit` is clamped to the
+    /// maximum query limit.
+    #[query]
+    fn getTransactions(&self, start: Nat, limit: Nat) -> Vec<TxRecord> {
+        let limit = query_len(self, &limit);
+        self.state
+            .borrow()
+            .ledger
+            .get_range(to_id(&start), limit)
+            .to_vec()
     }
 
-    #[update]
-    fn mint(&self, to: Principal, amount: Nat) -> TxReceipt {
-        if !self.isTestToken() {
-            check_caller(self.owner())?;
+    /// Same as `getTransactions`, but returns `QueryLimitExceeded` error instead of clamping the
+    /// `limit` if it is greater than the maximum query limit.
+    #[query]
+    fn getTransactionsSafe(&self, start: Nat, limit: Nat) -> Result<Vec<TxRecord>, TxError> {
+        let max_len = max_query_len(self);
+        if limit > max_len {
+            return Err(TxError::QueryLimitExceeded {
+                max: max_len as u64,
+            });
         }
 
-        mint(self, to, amount)
+        Ok(self
+            .state
+            .borrow()
+            .ledger
+            .get_range(to_id(&start), query_len(self, &limit))
+            .to_vec())
     }
 
-    #[update]
-    fn burn(&self, amount: Nat) -> TxReceipt {
-        burn(self, amount)
+    /// Returns the transactions starting from the `start` id serialized with CBOR into a chunk of at
+    /// most `max_bytes` bytes (clamped to `MAX_EXPORT_CHUNK_BYTES`), and the id to continue the
+    /// export from. See the `export` module for the format of the chunk.
+    #[query]
+    fn exportTransactions(&self, start: Nat, max_bytes: u64) -> ExportChunk {
+        let max_bytes = max_bytes.min(MAX_EXPORT_CHUNK_BYTES) as usize;
+        export_chunk(&self.state.borrow().ledger, to_id(&start), max_bytes)
     }
 
-    /********************** AUCTION ***********************/
-
-    /// Bid cycles for the next cycle auction.
-    ///
-    /// This method must be called with the cycles provided in the call. The amount of cycles cannot be
-    /// less than 1_000_000. The provided cycles are accepted by the canister, and the user bid is
-    /// saved for the next auction.
-    #[update]
-    fn bidCycles(&self, bidder: Principal) -> Result<u64, AuctionError> {
-        bid_cycles(self, bidder)
+    /// Returns the transactions starting from the `since` id until either `max_count` records are
+    /// taken or their size would exceed `max_bytes`, whichever comes first. At least one record is
+    /// returned if there are records left. The `max_count` is clamped to the maximum query limit,
+    /// and the `max_bytes` to `MAX_EXPORT_CHUNK_BYTES`.
+    #[query]
+    fn getBlocksSince(&self, since: Nat, max_count: Nat, max_bytes: u64) -> BlocksPage {
+        let max_count = query_len(self, &max_count);
+        let max_bytes = max_bytes.min(MAX_EXPORT_CHUNK_BYTES) as usize;
+        self.state
+            .borrow()
+            .ledger
+            .get_blocks(to_id(&since), max_count, max_bytes)
     }
 
-    /// Current information about bids and auction.
+    /// Checks the consistency of up to `limit` transaction records starting from the `from` id:
+    /// the ids of the records, the hash chain, the order of the timestamps and the fee
+    /// breakdowns. Returns the first inconsistency found, or the amounts minted and burned in the
+    /// checked records and the id to continue the check from. The `limit` is clamped to
+    /// `MAX_LEDGER_CHECK_LEN`.
     #[query]
-    fn biddingInfo(&self) -> BiddingInfo {
-        bidding_info(self)
+    fn verifyLedger(&self, from: Nat, limit: Nat) -> LedgerCheckResult {
+        let limit = limit
+            .0
+            .to_usize()
+            .unwrap_or(usize::MAX)
+            .min(MAX_LEDGER_CHECK_LEN);
+        self.state.borrow().ledger.verify(to_id(&from), limit)
     }
 
-    /// Starts the cycle auction.
-    ///
-    /// This method can be called only once in a [BiddingState.auction_period]. If the time elapsed
-    /// since the last auction is less than the set period, [AuctionError::TooEarly] will be returned.
-    ///
-    /// The auction will distribute the accumulated fees in proportion to the user cycle bids, and
-    /// then will update the fee ratio until the next auction.
-    #[update]
-    fn runAuction(&self) -> Result<AuctionInfo, AuctionError> {
-        run_auction(self)
+    /// Returns the number of the stored transaction records, their time span and the memory taken
+    /// by the ledger. The heap size is an estimate computed from the sizes of the records and the
+    /// ledger indexes.
+    #[query]
+    fn getLedgerStats(&self) -> LedgerStats {
+        LedgerStats {
+            stable_bytes: stable_memory_size(),
+            ..self.state.borrow().ledger.stats()
+        }
     }
 
-    /// Returns the information about a previously held auction.
+    /// Returns the number of the successful transactions of every operation over the lifetime of
+    /// the token, sorted by the operation names. Operations without transactions are not included.
+    /// After an upgrade from a version without the counters, the older transactions are counted in
+    /// batches on the following heartbeats, and the counts are not `complete` until then.
     #[query]
-    fn auctionInfo(&self, id: usize) -> Result<AuctionInfo, AuctionError> {
-        auction_info(self, id)
+    fn getOperationCounts(&self) -> OperationCounts {
+        self.state.borrow().ledger.operation_counts()
     }
 
-    /// Returns the minimum cycles set for the canister.
+    /// Returns up to `limit` transaction records starting from the `cursor` transaction id, and the
+    /// cursor to request the next page with. If the `cursor` is not given, the records are
+    /// returned from the oldest one for the ascending order (default), and from the newest one for
+    /// the descending order. The `limit` is clamped to the maximum query limit.
     ///
-    /// This value affects the fee ratio set by the auctions. The more cycles available in the canister
-    /// the less proportion of the fees will be transferred to the auction participants. If the amount
-    /// of cycles in the canister drops below this value, all the fees will be used for cycle auction.
+    /// If the `operation` or the `status` is given, only the matching transactions are returned. No more
+    /// than `MAX_TRANSACTION_SCAN_LEN` transactions are checked in one call, so a page can contain
+    /// less than `limit` records even if there are more matching transactions. The query should
+    /// be repeated with the returned cursor until it is `None`.
     #[query]
-    fn getMinCycles(&self) -> u64 {
-        self.state.borrow().stats.min_cycles
+    fn getTransactionsPage(
+        &self,
+        cursor: Option<Nat>,
+        limit: Nat,
+        order: Option<TransactionOrder>,
+        operation: Option<Operation>,
+        status: Option<TransactionStatusFilter>,
+    ) -> TransactionsPage {
+        let limit = query_len(self, &limit);
+        self.state.borrow().ledger.get_page(
+            cursor.as_ref().map(to_id),
+            limit,
+            order.unwrap_or(TransactionOrder::Ascending),
+            operation,
+            status,
+            MAX_TRANSACTION_SCAN_LEN,
+        )
     }
 
-    /// Sets the minimum cycles for the canister. For more information about this value, read [get_min_cycles].
+    /// Returns up to `limit` transactions matching all the criteria of the `filter` in ascending
+    /// order, starting from the `cursor` transaction id. The `limit` is clamped to the maximum
+    /// query limit.
     ///
-    /// Only the owner is allowed to call this method.
-    #[update]
-    fn setMinCycles(&self, min_cycles: u64) -> Result<(), TxError> {
-        check_caller(self.owner())?;
-        self.state.borrow_mut().stats.min_cycles = min_cycles;
-        Ok(())
+    /// The transactions are taken from the ledger indexes if the filter has a `caller`, `from` or
+    /// `to` principal. No more than `MAX_TRANSACTION_SCAN_LEN` transactions are checked in one
+    /// call, so the query should be repeated with the returned cursor until it is `None`.
+    ///
+    /// Only the transactions kept by the token canister are checked, the archived ones are not
+    /// included.
+    #[query]
+    fn queryTransactionsByFilter(
+        &self,
+        filter: TxFilter,
+        cursor: Option<Nat>,
+        limit: Nat,
+    ) -> TransactionsPage {
+        let limit = query_len(self, &limit);
+        self.state.borrow().ledger.query(
+            &filter,
+            cursor.as_ref().map(to_id),
+            limit,
+            TransactionOrder::Ascending,
+            MAX_TRANSACTION_SCAN_LEN,
+        )
     }
 
-    /// Sets the minimum time between two consecutive auctions, in seconds.
+    /// Returns up to `limit` transactions with the amounts in the range [min, max] in ascending
+    /// order, starting from the `start_cursor` transaction id. If `max` is not given, the range has
+    /// no upper bound. The `limit` is clamped to the maximum query limit.
     ///
-    /// Only the owner is allowed to call this method.
-    #[update]
-    fn setAuctionPeriod(&self, period_sec: u64) -> Result<(), TxError> {
-        check_caller(self.owner())?;
-        // IC timestamp is in nanoseconds, thus multiplying
-        self.state.borrow_mut().bidding_state.auction_period = period_sec * 1_000_000;
-        Ok(())
+    /// No more than `MAX_TRANSACTION_SCAN_LEN` transactions are checked in one call, so the query
+    /// should be repeated with the returned cursor until it is `None`.
+    #[query]
+    fn getTransactionsByAmount(
+        &self,
+        min: Nat,
+        max: Option<Nat>,
+        start_cursor: Option<Nat>,
+        limit: Nat,
+    ) -> TransactionsPage {
+        let limit = query_len(self, &limit);
+        self.state.borrow().ledger.get_by_amount(
+            &min,
+            max.as_ref(),
+            start_cursor.as_ref().map(to_id),
+            limit,
+            MAX_TRANSACTION_SCAN_LEN,
+        )
     }
 
-    /*********************** NOTIFY **********************/
+    /// Returns the id of the first transaction with the timestamp not less than `timestamp`, or
+    /// `None` if all the transactions stored by the token are older.
+    #[query]
+    fn getTransactionIndexByTime(&self, timestamp: Timestamp) -> Option<Nat> {
+        self.state
+            .borrow()
+            .ledger
+            .first_index_at_or_after(timestamp)
+            .map(Nat::from)
+    }
 
-    /// Notifies the transaction receiver about a previously performed transaction.
-    ///
-    /// This method guarantees that a notification for the same transaction id can be sent only once.
-    /// It allows to use this method to reliably inform the transaction receiver without danger of
-    /// duplicate transaction attack.
-    ///
-    /// In case the notification call fails, an [TxError::NotificationFailed] error is returned and
-    /// the transaction will still be marked as not notified.
-    ///
-    /// If a notification request is made for a transaction that was already notified, a
-    /// [TxError::AlreadyNotified] error is returned.
-    #[update]
-    async fn notify(&self, transaction_id: Nat) -> TxReceipt {
-        notify(self, transaction_id).await
+    /// Returns up to `limit` transaction records with the timestamps in the range [from, to),
+    /// starting from the `start_cursor` transaction id if it is given, and the cursor to request
+    /// the next page with. The `limit` is clamped to the maximum query limit.
+    #[query]
+    fn getTransactionsByTime(
+        &self,
+        from: Timestamp,
+        to: Timestamp,
+        start_cursor: Option<Nat>,
+        limit: Nat,
+    ) -> TransactionsPage {
+        let limit = query_len(self, &limit);
+        self.state
+            .borrow()
+            .ledger
+            .get_by_time(from, to, start_cursor.as_ref().map(to_id), limit)
     }
 
-    /// Convenience method to make a transaction and notify the receiver with just one call.
-    ///
-    /// If the notification fails for any reason, the transaction is still completed, but it will be
-    /// marked as not notified, so a [notify] call can be done later to re-request the notification of
-    /// this transaction.
-    #[update]
-    async fn transferAndNotify(
+    /// Returns up to `limit` transactions with exactly the given `memo`, starting from the
+    /// `start_cursor` transaction id if it is given, and the cursor to request the next page with.
+    /// The `limit` is clamped to the maximum query limit.
+    #[query]
+    fn findTransactionsByMemo(
         &self,
-        to: Principal,
-        amount: Nat,
-        fee_limit: Option<Nat>,
-    ) -> TxReceipt {
-        transfer_and_notify(self, to, amount, fee_limit).await
+        memo: Vec<u8>,
+        start_cursor: Option<Nat>,
+        limit: Nat,
+    ) -> TransactionsPage {
+        let limit = query_len(self, &limit);
+        self.state.borrow().find_by_memo(&memo, start_cursor, limit)
     }
-}
+
+    /// Returns up to `limit` payments of the fee shares to the `fee_to` principals, starting from
+    /// the payment of the `start_cursor` transaction if it is given, and the cursor to request the
+    /// next page with. Every payment is attributed to the `fee_to` principal at the time of the
+    /// transaction. The `limit` is clamped to the maximum query limit.
+    #[query]
+    fn getFeeTransactions(&self, start_cursor: Option<Nat>, limit: Nat) -> FeePaymentsPage {
+        let limit = query_len(self, &limit);
+        self.state
+            .borrow()
+            .ledger
+            .get_fee_payments(start_cursor.as_ref().map(to_id), limit)
+    }
+
+    /// Returns the sum of all the fee shares credited to the `fee_to` principals.
+    #[query]
+    fn totalFeesToRecipient(&self) -> Nat {
+        self.state.borrow().ledger.total_fee_payments()
+    }
+
+    /// Returns up to `limit` transactions made by the `caller`, including the `transferFrom`
+    /// calls of a spender, starting from the `start_cursor` transaction id if it is given, and the
+    /// cursor to request the next page with. The `limit` is clamped to the maximum query limit.
+    #[query]
+    fn getCallerTransactions(
+        &self,
+        caller: Principal,
+        start_cursor: Option<Nat>,
+        limit: Nat,
+    ) -> TransactionsPage {
+        let limit = query_len(self, &limit);
+        self.state
+            .borrow()
+            .ledger
+            .get_caller_page(caller, start_cursor.as_ref().map(to_id), limit)
+    }
+
+    #[update]
+    fn setName(&self, name: String) {
+        check_caller(self.owner()).unwrap();
+        let mut state = self.state.borrow_mut();
+        let old_name = std::mem::replace(&mut state.stats.name, name.clone());
+        record_config_change(
+            &mut state,
+            "name",
+            text_digest(&old_name),
+            text_digest(&name),
+        );
+    }
+
+    #[update]
+    fn setLogo(&self, logo: String) {
+        check_caller(self.owner()).unwrap();
+        let mut state = self.state.borrow_mut();
+        let old_logo = std::mem::replace(&mut state.stats.logo, logo.clone());
+        record_config_change(
+            &mut state,
+            "logo",
+            text_digest(&old_logo),
+            text_digest(&logo),
+        );
+    }
+
+    #[update]
+    fn setFee(&self, fee: Nat) {
+        check_caller(self.owner()).unwrap();
+        let mut state = self.state.borrow_mut();
+        let old_fee = std::mem::replace(&mut state.stats.fee, fee.clone());
+        record_config_change(&mut state, "fee", old_fee, fee);
+    }
+
+    /// Sets the share of every fee that is burned, in basis points. The rest of the fee is sent to
+    /// `fee_to` and the cycle auction as usual. The rate cannot be greater than 10000, which means
+    /// burning the whole fee.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setFeeBurnRate(&self, percent_bp: u64) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        if percent_bp > MAX_FEE_BURN_RATE {
+            return Err(TxError::InvalidFeeBurnRate);
+        }
+
+        let mut state = self.state.borrow_mut();
+        let old_rate = std::mem::replace(&mut state.stats.fee_burn_rate, percent_bp);
+        record_config_change(&mut state, "fee_burn_rate", old_rate, percent_bp);
+        Ok(())
+    }
+
+    /// Returns the share of every fee that is burned, in basis points.
+    #[query]
+    fn getFeeBurnRate(&self) -> u64 {
+        self.state.borrow().stats.fee_burn_rate
+    }
+
+    /// Enables or disables the spender whitelist. When the whitelist is enabled, allowances can be
+    /// given only to the spenders added with `addApprovedSpender`, and existing allowances of other
+    /// spenders cannot be used.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setSpenderWhitelistEnabled(&self, enabled: bool) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        let mut state = self.state.borrow_mut();
+        let old_enabled = std::mem::replace(&mut state.spender_whitelist.enabled, enabled);
+        record_config_change(
+            &mut state,
+            "spender_whitelist_enabled",
+            old_enabled,
+            enabled,
+        );
+        Ok(())
+    }
+
+    /// Adds the `spender` to the spender whitelist.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn addApprovedSpender(&self, spender: Principal) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        self.state
+            .borrow_mut()
+            .spender_whitelist
+            .spenders
+            .insert(spender);
+        Ok(())
+    }
+
+    /// Removes the `spender` from the spender whitelist.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn removeApprovedSpender(&self, spender: Principal) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        self.state
+            .borrow_mut()
+            .spender_whitelist
+            .spenders
+            .remove(&spender);
+        Ok(())
+    }
+
+    /// Returns the list of spenders in the spender whitelist.
+    #[query]
+    fn getApprovedSpenders(&self) -> Vec<Principal> {
+        self.state
+            .borrow()
+            .spender_whitelist
+            .spenders
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// Sets the minimum amount of tokens that can be transferred with `transfer`, `transferFrom`
+    /// and `transferIncludeFee` methods. Transfers of smaller amounts fail with
+    /// `TxError::AmountTooSmall` error.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setMinTransferAmount(&self, amount: Nat) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        let mut state = self.state.borrow_mut();
+        let old_amount = std::mem::replace(&mut state.stats.min_transfer_amount, amount.clone());
+        record_config_change(&mut state, "min_transfer_amount", old_amount, amount);
+        Ok(())
+    }
+
+    /// Enables or disables charging the fee on `approve`, `increaseAllowance`, `decreaseAllowance`
+    /// and other methods changing allowances. Transfers are charged the fee regardless of this
+    /// setting. Charging fees on approvals is enabled by default.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setApproveFeeEnabled(&self, enabled: bool) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        let mut state = self.state.borrow_mut();
+        let old_enabled = std::mem::replace(&mut state.stats.approve_fee_enabled, enabled);
+        record_config_change(&mut state, "approve_fee_enabled", old_enabled, enabled);
+        Ok(())
+    }
+
+    /// Enables or disables recording the rejected transfers in the ledger. When enabled, the
+    /// transfers that are rejected by the canister are recorded with the `Failed` status and the
+    /// error. Such records don't change any balances and are not counted in the users'
+    /// transaction totals. Disabled by default.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setRecordFailedTransactions(&self, enabled: bool) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        let mut state = self.state.borrow_mut();
+        let old_enabled = std::mem::replace(&mut state.stats.record_failed_transactions, enabled);
+        record_config_change(
+            &mut state,
+            "record_failed_transactions",
+            old_enabled,
+            enabled,
+        );
+        Ok(())
+    }
+
+    /// Sets the maximum number of the transactions returned by one transaction query. The limits
+    /// given to the queries are clamped to this value. The `limit` must be between 1 and
+    /// `MAX_TRANSACTION_QUERY_HARD_CAP`, otherwise `InvalidQueryLimit` error is returned.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setMaxQueryLimit(&self, limit: u64) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        if limit == 0 || limit > MAX_TRANSACTION_QUERY_HARD_CAP {
+            return Err(TxError::InvalidQueryLimit {
+                max: MAX_TRANSACTION_QUERY_HARD_CAP,
+            });
+        }
+
+        let old_limit = max_query_len(self);
+        let mut state = self.state.borrow_mut();
+        state.stats.max_query_limit = Some(limit);
+        record_config_change(&mut state, "max_query_limit", old_limit, limit);
+        Ok(())
+    }
+
+    /// Returns the maximum number of the transactions returned by one transaction query.
+    #[query]
+    fn getMaxQueryLimit(&self) -> u64 {
+        max_query_len(self) as u64
+    }
+
+    #[update]
+    fn setFeeTo(&self, fee_to: Principal) {
+        check_caller(self.owner()).unwrap();
+        let mut state = self.state.borrow_mut();
+        let old_fee_to = std::mem::replace(&mut state.stats.fee_to, fee_to);
+        record_config_change(&mut state, "fee_to", old_fee_to, fee_to);
+    }
+
+    #[update]
+    fn setOwner(&self, owner: Principal) {
+        check_caller(self.owner()).unwrap();
+        let mut state = self.state.borrow_mut();
+        let old_owner = std::mem::replace(&mut state.stats.owner, owner);
+        record_config_change(&mut state, "owner", old_owner, owner);
+    }
+
+    #[query]
+    fn owner(&self) -> Principal {
+        self.state.borrow().stats.owner
+    }
+
+    /// Returns an array of transaction records in range [start, start + limit) related to user `who`.
+    /// Unlike `getTransactions` function, the range [start, start + limit) for `getUserTransactions`
+    /// is not the global range of all transactions. The range [start, start + limit) here pertains to
+    /// the transactions of user who. Implementations are allowed to return less TxRecords than
+    /// requested to fend off DoS attacks: the `limit` is clamped to the maximum query limit.
+    #[query]
+    fn getUserTransactions(&self, who: Principal, start: Nat, limit: Nat) -> Vec<TxRecord> {
+        let limit = query_len(self, &limit);
+        let start = start.0.to_usize().unwrap_or(usize::MAX);
+        self.state
+            .borrow()
+            .ledger
+            .get_user_transactions(who, start, limit)
+    }
+
+    /// Returns up to `limit` transaction records of the user `who` starting from the `start` index
+    /// of the user's transactions (as in `getUserTransactions`), together with the number of the
+    /// user's transactions and the start of the next page. The `limit` is clamped to
+    /// the maximum query limit. The `order` and the `operation` filter work the same way as in
+    /// `getTransactionsPage`: with the descending order the `start` counts from the user's newest
+    /// transaction.
+    #[query]
+    fn getUserTransactionsPage(
+        &self,
+        who: Principal,
+        start: Nat,
+        limit: Nat,
+        order: Option<TransactionOrder>,
+        operation: Option<Operation>,
+    ) -> TransactionsPage {
+        let limit = query_len(self, &limit);
+        let start = start.0.to_usize().unwrap_or(usize::MAX);
+        self.state.borrow().ledger.get_user_page(
+            who,
+            start,
+            limit,
+            order.unwrap_or(TransactionOrder::Ascending),
+            operation,
+            MAX_TRANSACTION_SCAN_LEN,
+        )
+    }
+
+    /// Returns the sum of the amounts of the transactions related to the user `who`. Use
+    /// `getUserTransactionCount` to get the number of the transactions.
+    #[query]
+    fn getUserTransactionAmount(&self, who: Principal) -> Nat {
+        self.state.borrow().ledger.user_totals(who).1
+    }
+
+    /// Returns the number of the transactions related to the user `who`: the transactions where
+    /// `who` is the sender, the receiver or the caller.
+    #[query]
+    fn getUserTransactionCount(&self, who: Principal) -> Nat {
+        Nat::from(self.state.borrow().ledger.user_totals(who).0)
+    }
+
+    /// Returns the aggregated transactions of the user `who`: the amounts sent, received and paid
+    /// as fees, the number of the transactions and the ids of the first and the last of them.
+    /// After an upgrade from a version without the stats, the older transactions are added to the
+    /// stats in batches on the following heartbeats.
+    #[query]
+    fn getUserStats(&self, who: Principal) -> UserStats {
+        self.state.borrow().ledger.user_stats(who)
+    }
+
+    #[update]
+    fn transfer(&self, to: Principal, value: Nat, fee_limit: Option<Nat>) -> TxReceipt {
+        transfer(self, to, value, fee_limit)
+    }
+
+    /// Same as `transfer`, but also stores the `memo` (up to `MAX_MEMO_LEN` bytes) in the
+    /// transaction record. The transactions can be found by the memo with `findTransactionsByMemo`.
+    #[update]
+    fn transferWithMemo(
+        &self,
+        to: Principal,
+        value: Nat,
+        fee_limit: Option<Nat>,
+        memo: Vec<u8>,
+    ) -> TxReceipt {
+        transfer_with_memo(self, to, value, fee_limit, Some(memo))
+    }
+
+    #[update]
+    fn transferFrom(&self, from: Principal, to: Principal, value: Nat) -> TxReceipt {
+        transfer_from(self, from, to, value)
+    }
+
+    /// Same as `transferFrom`, but also returns the allowance left for the caller after the transfer.
+    #[update]
+    fn transferFromV2(
+        &self,
+        from: Principal,
+        to: Principal,
+        value: Nat,
+    ) -> Result<TransferFromReceipt, TxError> {
+        transfer_from_with_allowance(self, from, to, value)
+    }
+
+    /// Transfers `value` amount to the `to` principal, applying American style fee. This means, that
+    /// the recipient will receive `value - fee`, and the sender account will be reduced exactly by `value`.
+    ///
+    /// Note, that the `value` cannot be less than the `fee` amount. If the value given is too small,
+    /// transaction will fail with `TxError::AmountTooSmall` error.
+    #[update]
+    fn transferIncludeFee(&self, to: Principal, value: Nat) -> TxReceipt {
+        transfer_include_fee(self, to, value)
+    }
+
+    /// Same as `transferFrom`, but applies American style fee: the `from` account and the caller's
+    /// allowance are reduced exactly by `value`, and the recipient receives `value - fee`.
+    ///
+    /// If the `value` is not greater than the `fee`, transaction fails with
+    /// `TxError::AmountTooSmall` error.
+    #[update]
+    fn transferFromIncludeFee(&self, from: Principal, to: Principal, value: Nat) -> TxReceipt {
+        transfer_from_include_fee(self, from, to, value)
+    }
+
+    /// Moves `value` amount of tokens from `from` to `to` without requiring an allowance. The
+    /// operation is recorded in the ledger as `Operation::Clawback`.
+    ///
+    /// Only the owner of a test token is allowed to call this method. If the token is not a test
+    /// token, `TxError::Unauthorized` error is returned.
+    #[update]
+    fn ownerTransferFrom(&self, from: Principal, to: Principal, value: Nat) -> TxReceipt {
+        owner_transfer_from(self, from, to, value)
+    }
+
+    /// Burns `amount` of tokens from the `from` account without requiring an allowance. The
+    /// operation is recorded in the ledger as `Operation::Burn` with the owner as the caller.
+    ///
+    /// Only the owner of a test token is allowed to call this method. If the token is not a test
+    /// token, `TxError::Unauthorized` error is returned.
+    #[update]
+    fn testBurn(&self, from: Principal, amount: Nat) -> TxReceipt {
+        test_burn(self, from, amount)
+    }
+
+    /// Allows the `spender` to transfer up to `value` amount of tokens from the caller's account.
+    ///
+    /// If `expires_at` is set, the allowance is treated as zero after this IC time.
+    ///
+    /// If `spending_limit` is set to `(limit_per_period, period_sec)`, the spender cannot transfer
+    /// more than `limit_per_period` (including the transfer fees) in every `period_sec` seconds.
+    /// Transfers over the limit fail with `TxError::SpendingLimitExceeded` error.
+    #[update]
+    fn approve(
+        &self,
+        spender: Principal,
+        value: Nat,
+        expires_at: Option<Timestamp>,
+        spending_limit: Option<(Nat, u64)>,
+    ) -> TxReceipt {
+        approve(self, spender, value, expires_at, spending_limit)
+    }
+
+    /// Allows the `spender` to make a single `transferFrom` call of up to `value` amount. The
+    /// allowance is revoked after the first successful transfer, even if less than `value` amount
+    /// was transferred.
+    #[update]
+    fn approveOnce(&self, spender: Principal, value: Nat) -> TxReceipt {
+        approve_once(self, spender, value)
+    }
+
+    /// Allows the `spender` to transfer any amount of tokens from the caller account. The allowance
+    /// is not decreased by `transferFrom` calls, and the `allowance` method reports
+    /// `UNLIMITED_ALLOWANCE` (`2^128 - 1`) value for it. To revoke the allowance, approve zero
+    /// amount to the `spender`.
+    #[update]
+    fn approveUnlimited(&self, spender: Principal) -> TxReceipt {
+        approve_unlimited(self, spender)
+    }
+
+    /// Sets the allowance of the `spender` to `new_value`, if the current allowance is equal to
+    /// `expected_current`. Otherwise `TxError::AllowanceChanged` error is returned.
+    ///
+    /// This method prevents the spender from using both the old and the new allowance by
+    /// front-running the allowance change.
+    #[update]
+    fn approveExpected(
+        &self,
+        spender: Principal,
+        expected_current: Nat,
+        new_value: Nat,
+    ) -> TxReceipt {
+        approve_expected(self, spender, expected_current, new_value)
+    }
+
+    /// Sets allowances for multiple spenders in one call, returning the transaction ids of the
+    /// approvals. The `approve` fee is charged for every entry.
+    ///
+    /// If the caller cannot pay all the fees or any of the entries is invalid, no allowance is
+    /// changed.
+    #[update]
+    fn batchApprove(&self, approvals: Vec<(Principal, Nat)>) -> Result<Vec<Nat>, TxError> {
+        batch_approve(self, approvals)
+    }
+
+    /// Sets an allowance on behalf of the owner, who signed the approval offline.
+    ///
+    /// The `serialized_tx` of the `signed` transaction must contain CBOR-encoded tuple
+    /// `(owner, spender, value, expires_at, nonce)`, signed by the Ed25519 key of the `owner`
+    /// self-authenticating principal. The `nonce` must be equal to the value returned by
+    /// `getPermitNonce` for the owner, so every permit can be applied only once. The approval fee is
+    /// charged from the `owner` account.
+    #[update]
+    fn permit(&self, signed: SignedTx) -> TxReceipt {
+        permit(self, signed)
+    }
+
+    /// Returns the nonce the next permit of the `owner` must be signed with.
+    #[query]
+    fn getPermitNonce(&self, owner: Principal) -> u64 {
+        permit_nonce(self, owner)
+    }
+
+    /// Removes all allowances given by the caller and returns the number of removed allowances.
+    ///
+    /// The approval fee is charged only once regardless of the number of allowances. An `Approve`
+    /// transaction with zero amount is recorded for every revoked allowance.
+    #[update]
+    fn revokeAllApprovals(&self) -> Result<u64, TxError> {
+        revoke_all_approvals(self)
+    }
+
+    /// Removes all allowances given by the `owner` and returns the number of removed allowances.
+    /// An `AdminRevoke` transaction is recorded for every revoked allowance.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn adminRevokeApprovals(&self, owner: Principal) -> Result<u64, TxError> {
+        admin_revoke_approvals(self, owner)
+    }
+
+    /// Increases the allowance of the `spender` by `delta` amount. Unlike `approve`, this method
+    /// changes the allowance relative to its current value, so it cannot be front-run by the spender.
+    #[update]
+    fn increaseAllowance(&self, spender: Principal, delta: Nat) -> TxReceipt {
+        increase_allowance(self, spender, delta)
+    }
+
+    /// Decreases the allowance of the `spender` by `delta` amount. If `delta` is larger than the
+    /// current allowance, the allowance is set to zero.
+    #[update]
+    fn decreaseAllowance(&self, spender: Principal, delta: Nat) -> TxReceipt {
+        decrease_allowance(self, spender, delta)
+    }
+
+    /// Mints `amount` of new tokens to the `to` principal. Only the owner or the principals added
+    /// with `addMinter` are allowed to mint. Other users can get test tokens with `faucet`.
+    ///
+    /// The optional `reason` is stored in the transaction record. It can be at most
+    /// `MAX_MINT_REASON_LEN` bytes long.
+    #[update]
+    fn mint(&self, to: Principal, amount: Nat, reason: Option<String>) -> TxReceipt {
+        check_minter(self)?;
+        mint(self, to, amount, reason)
+    }
+
+    /// Mints tokens to multiple recipients in one call. The call is atomic: if it fails, no tokens
+    /// are minted. Only the principals allowed to call `mint` can call this method.
+    ///
+    /// The optional `reason` is stored in the records of all the mints.
+    #[update]
+    fn batchMint(
+        &self,
+        mints: Vec<(Principal, Nat)>,
+        reason: Option<String>,
+    ) -> Result<Vec<Nat>, TxError> {
+        check_minter(self)?;
+        batch_mint(self, mints, reason)
+    }
+
+    /// Mints `amount` of tokens to the `to` principal with a vesting schedule. The tokens are
+    /// locked for `cliff_sec` seconds, and then unlocked linearly until `duration_sec` seconds
+    /// since the mint. Locked tokens are included in the balance but cannot be transferred.
+    ///
+    /// Only the principals allowed to call `mint` can call this method.
+    #[update]
+    fn mintVested(
+        &self,
+        to: Principal,
+        amount: Nat,
+        cliff_sec: u64,
+        duration_sec: u64,
+    ) -> TxReceipt {
+        check_minter(self)?;
+        mint_vested(self, to, amount, cliff_sec, duration_sec)
+    }
+
+    /// Returns the amounts of locked and unlocked tokens of the `holder`, and their vesting
+    /// schedules.
+    #[query]
+    fn getVestingInfo(&self, holder: Principal) -> VestingInfo {
+        vesting_info(self, holder)
+    }
+
+    /// Sets the root hash of the merkle tree of the allocation list. The principals in the list can
+    /// claim their allocations with `claimMint`.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setClaimRoot(&self, root: Vec<u8>) -> Result<(), TxError> {
+        set_claim_root(self, root)
+    }
+
+    /// Mints `amount` of tokens to the caller, if the `proof` shows that the caller is allowed to
+    /// claim this amount by the allocation list set with `setClaimRoot`. Every principal can claim
+    /// only once.
+    #[update]
+    fn claimMint(&self, amount: Nat, proof: Vec<Vec<u8>>) -> TxReceipt {
+        claim_mint(self, amount, proof)
+    }
+
+    /// Allows the `minter` to mint new tokens.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn addMinter(&self, minter: Principal) -> Result<(), TxError> {
+        add_minter(self, minter)
+    }
+
+    /// Removes the `minter` from the list of principals allowed to mint new tokens.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn removeMinter(&self, minter: Principal) -> Result<(), TxError> {
+        remove_minter(self, minter)
+    }
+
+    /// Returns the list of principals allowed to mint new tokens in addition to the owner.
+    #[query]
+    fn getMinters(&self) -> Vec<Principal> {
+        let mut minters: Vec<_> = self.state.borrow().minters.iter().copied().collect();
+        minters.sort();
+        minters
+    }
+
+    /// Mints `amount` of test tokens to the caller. Every principal can receive at most the amount
+    /// set with `setFaucetLimit` in 24 hours.
+    ///
+    /// This method can only be called for test tokens.
+    #[update]
+    fn faucet(&self, amount: Nat) -> TxReceipt {
+        faucet(self, amount)
+    }
+
+    /// Sets the amount of test tokens every principal can receive with `faucet` in 24 hours.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setFaucetLimit(&self, amount: Nat) -> Result<(), TxError> {
+        set_faucet_limit(self, amount)
+    }
+
+    /// Permanently disables minting of new tokens. After this call all the ways of minting tokens
+    /// fail with `TxError::MintingDisabled`, and there is no way to enable minting again.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn renounceMinting(&self) -> Result<(), TxError> {
+        renounce_minting(self)
+    }
+
+    /// Limits the total amount of tokens that can be minted in any `window_sec` seconds to
+    /// `max_amount`. The limit is shared by all the minters and applies to all the ways of minting
+    /// tokens. Zero `window_sec` removes the limit.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setMintLimit(&self, max_amount: Nat, window_sec: u64) -> Result<(), TxError> {
+        set_mint_limit(self, max_amount, window_sec)
+    }
+
+    /// Sets if the mints made by the owner are exempt from the mint limit.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setMintLimitOwnerExempt(&self, exempt: bool) -> Result<(), TxError> {
+        set_mint_limit_owner_exempt(self, exempt)
+    }
+
+    /// Excludes the balance of the `account` from the circulating supply.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn addNonCirculatingAccount(&self, account: Principal) -> Result<(), TxError> {
+        add_non_circulating_account(self, account)
+    }
+
+    /// Includes the balance of the `account` into the circulating supply again.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn removeNonCirculatingAccount(&self, account: Principal) -> Result<(), TxError> {
+        remove_non_circulating_account(self, account)
+    }
+
+    /// Returns the list of accounts whose balances are not included in the circulating supply.
+    #[query]
+    fn getNonCirculatingAccounts(&self) -> Vec<Principal> {
+        let mut accounts: Vec<_> = self
+            .state
+            .borrow()
+            .non_circulating
+            .iter()
+            .copied()
+            .collect();
+        accounts.sort();
+        accounts
+    }
+
+    #[update]
+    fn burn(&self, amount: Nat) -> TxReceipt {
+        burn(self, amount)
+    }
+
+    /// Burns `amount` of tokens from the `from` account using the allowance given to the caller.
+    /// The allowance is decreased by `amount`, no fee is charged.
+    #[update]
+    fn burnFrom(&self, from: Principal, amount: Nat) -> TxReceipt {
+        burn_from(self, from, amount)
+    }
+
+    /********************** AUCTION ***********************/
+
+    /// Bid cycles for the next cycle auction.
+    ///
+    /// This method must be called with the cycles provided in the call. The amount of cycles cannot be
+    /// less than the minimum bid set with `setMinBid`. The provided cycles are accepted by the
+    /// canister and added to the bid of the `bidder`, or of the caller if the `bidder` is not
+    /// given, for the next auction. Bids on behalf of other principals are listed in
+    /// `biddingInfo`, and can't be made on behalf of the anonymous principal.
+    ///
+    /// If the share of the `bidder` in the next auction is less than `min_expected_tokens`, the
+    /// bid is skipped by the auction and its cycles are sent back to the `bidder`.
+    #[update]
+    fn bidCycles(
+        &self,
+        bidder: Option<Principal>,
+        min_expected_tokens: Option<Nat>,
+    ) -> Result<u64, AuctionError> {
+        bid_cycles(self, bidder, min_expected_tokens)
+    }
+
+    /// Cancels the caller's bid for the next cycle auction and sends the cycles back to the caller
+    /// canister with the `wallet_receive` call. Returns the amount of the refunded cycles. The
+    /// cycles bid on behalf of the caller by other principals are not refunded and stay in the bid.
+    ///
+    /// If the caller has no pending bid of its own cycles, or the auction the bid was made for was
+    /// already held, [AuctionError::NoBid] is returned. If the refund call fails, the bid is kept.
+    #[update]
+    async fn cancelBid(&self) -> Result<u64, AuctionError> {
+        cancel_bid(self.state.clone(), ic_kit::ic::caller()).await
+    }
+
+    /// Sends the cycles of the skipped bids paid by the caller that could not be refunded after the
+    /// auction back to the caller canister with the `wallet_receive` call. Returns the amount of the
+    /// sent cycles.
+    ///
+    /// If the caller has nothing to claim, [AuctionError::NoRefundCredit] is returned. If the
+    /// call fails, the cycles stay claimable.
+    #[update]
+    async fn claimBidRefund(&self) -> Result<u64, AuctionError> {
+        claim_bid_refund(self.state.clone(), ic_kit::ic::caller()).await
+    }
+
+    /// Current information about bids and auction.
+    #[query]
+    fn biddingInfo(&self) -> BiddingInfo {
+        bidding_info(self)
+    }
+
+    /// Returns the total amount of cycles the caller bid for the next cycle auction. Repeated bids
+    /// are added to the caller's bid.
+    #[query]
+    fn myBid(&self) -> u64 {
+        my_bid(self)
+    }
+
+    /// Returns the amount of the accumulated fees the auction would distribute to the bidders if
+    /// it was run now.
+    #[query]
+    fn getFeePool(&self) -> Nat {
+        fee_pool(self)
+    }
+
+    /// Starts the cycle auction.
+    ///
+    /// This method can be called only once in a [BiddingState.auction_period]. If the time elapsed
+    /// since the last auction is less than the set period, [AuctionError::TooEarly] will be returned.
+    ///
+    /// The auction will distribute the accumulated fees in proportion to the user cycle bids, and
+    /// then will update the fee ratio until the next auction. The caller is paid the incentive set
+    /// with `setAuctionIncentive` before the fees are distributed. If the auction is restricted to
+    /// the owner with `setAuctionController`, other callers get [AuctionError::Unauthorized].
+    #[update]
+    fn runAuction(&self) -> Result<AuctionInfo, AuctionError> {
+        run_auction(self)
+    }
+
+    /// Returns the lifetime statistics of the held auctions.
+    #[query]
+    fn getAuctionStats(&self) -> AuctionStats {
+        auction_stats(self)
+    }
+
+    /// Returns the information about a previously held auction.
+    #[query]
+    fn auctionInfo(&self, id: usize) -> Result<AuctionInfo, AuctionError> {
+        auction_info(self, id)
+    }
+
+    /// Returns up to `limit` previously held auctions starting from the `start` auction id, oldest
+    /// first. The `limit` is clamped to `MAX_AUCTIONS_QUERY_LEN`.
+    #[query]
+    fn getAuctions(&self, start: usize, limit: usize) -> Vec<AuctionInfo> {
+        auctions(self, start, limit)
+    }
+
+    /// Number of the held auctions.
+    #[query]
+    fn getAuctionCount(&self) -> usize {
+        auction_count(self)
+    }
+
+    /// Returns the minimum cycles set for the canister.
+    ///
+    /// This value affects the fee ratio set by the auctions. The more cycles available in the canister
+    /// the less proportion of the fees will be transferred to the auction participants. If the amount
+    /// of cycles in the canister drops below this value, all the fees will be used for cycle auction.
+    #[query]
+    fn getMinCycles(&self) -> u64 {
+        self.state.borrow().stats.min_cycles
+    }
+
+    /// Sets the minimum cycles for the canister. For more information about this value, read [get_min_cycles].
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setMinCycles(&self, min_cycles: u64) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        let mut state = self.state.borrow_mut();
+        let old_min_cycles = std::mem::replace(&mut state.stats.min_cycles, min_cycles);
+        record_config_change(&mut state, "min_cycles", old_min_cycles, min_cycles);
+        Ok(())
+    }
+
+    /// Enables or disables the automatic auctions. When enabled, the auction is run on the canister
+    /// heartbeat every `auction_period`, and the results are added to the automatic auction log.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setAutoAuction(&self, enabled: bool) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        let mut state = self.state.borrow_mut();
+        let old_enabled = std::mem::replace(&mut state.bidding_state.auto_auction, enabled);
+        record_config_change(&mut state, "auto_auction", old_enabled, enabled);
+        Ok(())
+    }
+
+    /// Sets how the auctions pay the distributed tokens to the bidders. In the `Claim` mode the
+    /// auctions only record the rewards of the bidders, and the bidders claim them with
+    /// `claimAuctionReward`. The rewards of the auctions held before the change are not affected.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setAuctionPayoutMode(&self, mode: AuctionPayoutMode) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        let mut state = self.state.borrow_mut();
+        let old_mode = std::mem::replace(&mut state.bidding_state.payout_mode, mode);
+        record_config_change(
+            &mut state,
+            "auction_payout_mode",
+            format!("{:?}", old_mode),
+            format!("{:?}", mode),
+        );
+        Ok(())
+    }
+
+    /// Sets who is allowed to run the auctions with `runAuction`. The automatic auctions run on the
+    /// heartbeat are not affected.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setAuctionController(&self, controller: AuctionController) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        let mut state = self.state.borrow_mut();
+        let old_controller = std::mem::replace(&mut state.bidding_state.controller, controller);
+        record_config_change(
+            &mut state,
+            "auction_controller",
+            format!("{:?}", old_controller),
+            format!("{:?}", controller),
+        );
+        Ok(())
+    }
+
+    /// Sets the share of the auction pool paid to the caller of `runAuction`, in basis points.
+    /// The rate can't be larger than [MAX_AUCTION_INCENTIVE_RATE].
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setAuctionIncentive(&self, rate_bp: u64) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        if rate_bp > MAX_AUCTION_INCENTIVE_RATE {
+            return Err(TxError::InvalidAuctionIncentive);
+        }
+
+        let mut state = self.state.borrow_mut();
+        let old_rate = std::mem::replace(&mut state.bidding_state.incentive_rate, rate_bp);
+        record_config_change(&mut state, "auction_incentive_rate", old_rate, rate_bp);
+        Ok(())
+    }
+
+    /// Transfers the caller's reward of the auction `auction_id` held in the `Claim` payout mode
+    /// to the caller. Returns the id of the `Auction` transaction paying the reward.
+    ///
+    /// If the caller has no reward in the auction, [TxError::NoAuctionReward] is returned. If the
+    /// reward was already claimed, [TxError::AlreadyClaimed] is returned.
+    #[update]
+    fn claimAuctionReward(&self, auction_id: usize) -> TxReceipt {
+        claim_auction_reward(self, auction_id)
+    }
+
+    /// Returns the results of the last automatic auctions, oldest first.
+    #[query]
+    fn getAutoAuctionLog(&self) -> Vec<AutoAuctionRun> {
+        auto_auction_log(self)
+    }
+
+    /// Enables or disables the auction whitelist. When the whitelist is enabled, only the bidders
+    /// added with `addAuctionBidder` can bid cycles. The bids placed before are kept.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setAuctionWhitelistEnabled(&self, enabled: bool) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        let mut state = self.state.borrow_mut();
+        let old_enabled = std::mem::replace(&mut state.auction_whitelist.enabled, enabled);
+        record_config_change(
+            &mut state,
+            "auction_whitelist_enabled",
+            old_enabled,
+            enabled,
+        );
+        Ok(())
+    }
+
+    /// Adds the `bidder` to the auction whitelist.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn addAuctionBidder(&self, bidder: Principal) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        self.state
+            .borrow_mut()
+            .auction_whitelist
+            .bidders
+            .insert(bidder);
+        Ok(())
+    }
+
+    /// Removes the `bidder` from the auction whitelist. The pending bids of the bidder are kept.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn removeAuctionBidder(&self, bidder: Principal) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        self.state
+            .borrow_mut()
+            .auction_whitelist
+            .bidders
+            .remove(&bidder);
+        Ok(())
+    }
+
+    /// Returns the list of bidders in the auction whitelist.
+    #[query]
+    fn getAuctionBidders(&self) -> Vec<Principal> {
+        self.state
+            .borrow()
+            .auction_whitelist
+            .bidders
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// Sets the bounds the fee ratio computed by the auctions is clamped into. The bounds must
+    /// satisfy `0 <= min <= max <= 1`. The current fee ratio is clamped right away.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setFeeRatioBounds(&self, min: f64, max: f64) -> Result<(), TxError> {
+        set_fee_ratio_bounds(self, min, max)
+    }
+
+    /// Sets the minimum amount of cycles accepted by one `bidCycles` call. The bids placed before
+    /// the change are kept.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setMinBid(&self, amount: u64) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        let mut state = self.state.borrow_mut();
+        let old_amount = state.bidding_state.min_bid();
+        state.bidding_state.min_bid = Some(amount);
+        record_config_change(&mut state, "min_bid", old_amount, amount);
+        Ok(())
+    }
+
+    /// Sets the minimum time between two consecutive auctions, in seconds. The period can't be
+    /// shorter than [MIN_AUCTION_PERIOD_SEC].
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setAuctionPeriod(&self, period_sec: u64) -> Result<(), TxError> {
+        check_caller(self.owner())?;
+        if period_sec < MIN_AUCTION_PERIOD_SEC {
+            return Err(TxError::AuctionPeriodTooShort {
+                min: MIN_AUCTION_PERIOD_SEC,
+            });
+        }
+
+        // IC timestamp is in nanoseconds, thus multiplying
+        let period = period_sec.saturating_mul(1_000_000_000);
+        let mut state = self.state.borrow_mut();
+        let old_period = std::mem::replace(&mut state.bidding_state.auction_period, period);
+        record_config_change(&mut state, "auction_period", old_period, period);
+        Ok(())
+    }
+
+    /********************* EMISSION *********************/
+
+    /// Sets the schedule of minting `amount_per_period` tokens to the `to` principal every
+    /// `period_sec` seconds. The first emission is made one period after this call. The emission
+    /// stops when the maximum supply of the token is reached.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setEmission(
+        &self,
+        to: Principal,
+        amount_per_period: Nat,
+        period_sec: u64,
+        enabled: bool,
+    ) -> Result<(), TxError> {
+        set_emission(self, to, amount_per_period, period_sec, enabled)
+    }
+
+    /// Returns the current emission schedule, or `None` if the emission was never set.
+    #[query]
+    fn getEmissionInfo(&self) -> Option<EmissionInfo> {
+        emission_info(self)
+    }
+
+    /*********************** ARCHIVE **********************/
+
+    /// Configures moving the old transactions to the `canister_id` archive canister. When the
+    /// number of the transactions kept in the ledger exceeds the `threshold`, the oldest
+    /// transactions are moved to the archive in batches of up to `batch_size` transactions on the
+    /// canister heartbeat. Zero `threshold` disables automatic archiving.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn setArchive(
+        &self,
+        canister_id: Principal,
+        threshold: u64,
+        batch_size: u64,
+    ) -> Result<(), TxError> {
+        set_archive(self, canister_id, threshold, batch_size)
+    }
+
+    /// Moves a batch of the oldest transactions to the archive canister, regardless of the
+    /// threshold. Returns the number of the archived transactions.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    async fn archiveTransactions(&self) -> Result<u64, TxError> {
+        check_caller(self.owner())?;
+        archive_transactions(self.state.clone()).await
+    }
+
+    #[query]
+    fn getArchiveInfo(&self) -> ArchiveInfo {
+        archive_info(self)
+    }
+
+    /*********************** BACKUP **********************/
+
+    /// Sends the ledger records starting from the `start` record to the `target` canister with the
+    /// `receive_backup(BackupChunk)` call. Up to `MAX_BACKUP_CHUNKS` chunks are sent by one call,
+    /// and the method is called again with the same arguments until the returned progress is
+    /// `completed`. A backup interrupted by a failed call is resumed from the first chunk not
+    /// confirmed by the target.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    async fn backupLedger(&self, target: Principal, start: Nat) -> Result<BackupProgress, TxError> {
+        backup_ledger(
+            self.state.clone(),
+            target,
+            start,
+            BACKUP_CHUNK_LEN,
+            MAX_BACKUP_CHUNKS,
+        )
+        .await
+    }
+
+    /// Restores a chunk of a ledger backup. The chunks are restored in order, starting from the
+    /// first record, into a token with no records other than the initial mint. The restored
+    /// ledger replaces the balances and the supply of the token.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update]
+    fn restoreLedger(&self, chunk: BackupChunk) -> Result<BackupProgress, TxError> {
+        restore_ledger(self, chunk)
+    }
+
+    #[query]
+    fn getBackupProgress(&self) -> BackupProgress {
+        backup_progress(self)
+    }
+
+    /*********************** EVENTS **********************/
+
+    /// Subscribes the `callback_canister` to the new transactions of the token. Every new
+    /// transaction is sent to the callback canister with the `on_is20_event(TxRecord)` call after
+    /// the owner approved the subscription. The number of the subscribers is limited.
+    #[update]
+    fn subscribe(&self, callback_canister: Principal) -> Result<(), TxError> {
+        subscribe(self, callback_canister)
+    }
+
+    /// Removes the subscription of the caller.
+    #[update]
+    fn unsubscribe(&self) -> Result<(), TxError> {
+        unsubscribe(self)
+    }
+
+    /// Approves the subscription of the `subscriber`. Only the owner is allowed to call this
+    /// method.
+    #[update]
+    fn approveSubscriber(&self, subscriber: Principal) -> Result<(), TxError> {
+        approve_subscriber(self, subscriber)
+    }
+
+    /// Removes the subscription of the `subscriber`. Only the owner is allowed to call this
+    /// method.
+    #[update]
+    fn removeSubscriber(&self, subscriber: Principal) -> Result<(), TxError> {
+        remove_subscriber(self, subscriber)
+    }
+
+    /// Returns the subscribers with their callback canisters and delivery counters. Only the owner
+    /// is allowed to call this method.
+    #[query]
+    fn listSubscribers(&self) -> Result<Vec<(Principal, Subscriber)>, TxError> {
+        list_subscribers(self)
+    }
+
+    /// Returns the number of the events waiting to be sent again to the subscribers, the time of
+    /// the next attempt and the number of the dropped events.
+    #[query]
+    fn getDeliveryQueueStats(&self) -> DeliveryQueueStats {
+        delivery_queue_stats(self)
+    }
+
+    /*********************** NOTIFY **********************/
+
+    /// Notifies the transaction receiver about a previously performed transaction.
+    ///
+    /// This method guarantees that a notification for the same transaction id can be sent only once.
+    /// It allows to use this method to reliably inform the transaction receiver without danger of
+    /// duplicate transaction attack.
+    ///
+    /// In case the notification call fails, an [TxError::NotificationFailed] error is returned and
+    /// the transaction will still be marked as not notified.
+    ///
+    /// If a notification request is made for a transaction that was already notified, a
+    /// [TxError::AlreadyNotified] error is returned.
+    #[update]
+    async fn notify(&self, transaction_id: Nat) -> TxReceipt {
+        notify(self, transaction_id).await
+    }
+
+    /// Returns the notification state of the transaction: `Pending` if the receiver can be
+    /// notified with `notify`, `Failed` with the number of the failed attempts and the last error
+    /// if the notification can be retried after a failure, and `NotPending` if the receiver was
+    /// notified or the transaction doesn't need a notification.
+    #[query]
+    fn getNotificationStatus(&self, transaction_id: Nat) -> Result<NotificationStatus, TxError> {
+        notification_status(self, transaction_id)
+    }
+
+    /// Returns up to `limit` ids of the transactions waiting for a notification, in ascending
+    /// order starting from the `start_cursor` id, and the cursor to request the next page with.
+    /// If the `receiver` is given, only the transactions to the `receiver` are returned. The
+    /// `limit` is clamped to the maximum query limit.
+    #[query]
+    fn getUnnotifiedTransactions(
+        &self,
+        receiver: Option<Principal>,
+        start_cursor: Option<Nat>,
+        limit: Nat,
+    ) -> UnnotifiedPage {
+        let limit = query_len(self, &limit);
+        unnotified_transactions(
+            self,
+            receiver,
+            start_cursor,
+            limit,
+            MAX_TRANSACTION_SCAN_LEN,
+        )
+    }
+
+    /// Convenience method to make a transaction and notify the receiver with just one call.
+    ///
+    /// If the notification fails for any reason, the transaction is still completed, but it will be
+    /// marked as not notified, so a [notify] call can be done later to re-request the notification of
+    /// this transaction.
+    #[update]
+    async fn transferAndNotify(
+        &self,
+        to: Principal,
+        amount: Nat,
+        fee_limit: Option<Nat>,
+    ) -> TxReceipt {
+        transfer_and_notify(self, to, amount, fee_limit).await
+    }
+
+    /// Convenience method to approve the `spender` and notify it about the allowance with just one
+    /// call. The spender is notified with `approval_notification` call.
+    ///
+    /// If the notification fails for any reason, the approval still stands, but it will be marked as
+    /// not notified, so a [notify] call can be done later to re-request the notification.
+    #[update]
+    async fn approveAndNotify(&self, spender: Principal, value: Nat) -> TxReceipt {
+        approve_and_notify(self, spender, value).await
+    }
+
+    /// Convenience method to mint tokens to the `to` principal and notify it about the transaction
+    /// with just one call. Only the principals allowed to call `mint` can call this method.
+    ///
+    /// If the notification fails for any reason, the tokens are still minted, but the transaction
+    /// will be marked as not notified, so a [notify] call can be done later to re-request the
+    /// notification.
+    #[update]
+    async fn mintAndNotify(&self, to: Principal, amount: Nat) -> TxReceipt {
+        mint_and_notify(self, to, amount).await
+    }
+
+    /// Burns `amount` of the caller's tokens and notifies the `bridge` canister about the burn with
+    /// `burn_notification` call, passing the `payload` along.
+    ///
+    /// If the notification fails for any reason, the tokens are still burned, but the transaction
+    /// will be marked as not notified, so a [notify] call can be done later to re-request the
+    /// notification.
+    #[update]
+    async fn burnAndNotify(&self, amount: Nat, bridge: Principal, payload: Vec<u8>) -> TxReceipt {
+        burn_and_notify(self, amount, bridge, payload).await
+    }
+
+    /// Enables or disables notifications about the caller's allowances being used. When enabled,
+    /// every successful `transferFrom` from the caller account queues an `allowance_notification`
+    /// call to the caller. Only canisters can receive the notifications.
+    #[update]
+    fn setAllowanceNotifications(&self, enabled: bool) {
+        set_allowance_notifications(self, enabled)
+    }
+
+    /// Tries to deliver the pending allowance notifications and returns the number of notifications
+    /// delivered. Failed notifications are retried on the next call, up to 3 attempts. Failures
+    /// never affect the transfers themselves.
+    #[update]
+    async fn flushOwnerNotifications(&self) -> u64 {
+        flush_owner_notifications(self).await
+    }
+
+    /// Returns the allowance notifications that were not delivered yet.
+    #[query]
+    fn getPendingOwnerNotifications(&self) -> Vec<PendingOwnerNotification> {
+        pending_owner_notifications(self)
+    }
+}
+
+/// Records the change of the `setting` made by the caller in the ledger.
+fn record_config_change(
+    state: &mut CanisterState,
+    setting: &str,
+    old_value: impl ToString,
+    new_value: impl ToString,
+) {
+    state.ledger.configure(
+        ic_kit::ic::caller(),
+        setting,
+        old_value.to_string(),
+        new_value.to_string(),
+    );
+}
+
+/// Returns the sha256 hash of the value of a free-form setting, such as the logo, rendered as
+/// `sha256:<hex>`. The hash is recorded in the ledger instead of the value, which can be many
+/// kilobytes long.
+fn text_digest(text: &str) -> String {
+    format!("sha256:{}", to_hex(&Sha256::digest(text.as_bytes())))
+}
+
+/// Renders the `bytes` as a lowercase hex string.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Returns the maximum number of the transactions returned by one transaction query.
+fn max_query_len(canister: &TokenCanister) -> usize {
+    canister
+        .state
+        .borrow()
+        .stats
+        .max_query_limit
+        .map_or(MAX_TRANSACTION_QUERY_LEN, |limit| limit as usize)
+}
+
+/// Returns the `limit` of a transaction query clamped to the maximum query limit.
+fn query_len(canister: &TokenCanister, limit: &Nat) -> usize {
+    limit
+        .0
+        .to_usize()
+        .unwrap_or(usize::MAX)
+        .min(max_query_len(canister))
+}
+
+/// Returns the size of the stable memory of the canister in bytes.
+fn stable_memory_size() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        const WASM_PAGE_SIZE: u64 = 64 * 1024;
+        ic_cdk::api::stable::stable_size() as u64 * WASM_PAGE_SIZE
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
 
 fn check_caller(owner: Principal) -> Result<(), TxError> {
     if ic_kit::ic::caller() == owner {
@@ -392,6 +2043,17 @@ fn check_caller(owner: Principal) -> Result<(), TxError> {
     }
 }
 
+/// Checks if the caller is allowed to mint new tokens. Other users can get test tokens with
+/// `faucet`.
+fn check_minter(canister: &TokenCanister) -> Result<(), TxError> {
+    let state = canister.state.borrow();
+    if state.minters.contains(&ic_kit::ic::caller()) {
+        Ok(())
+    } else {
+        check_caller(state.stats.owner)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -404,6 +2066,175 @@ mod test {
         canister.__post_upgrade_inst();
     }
 
+    #[test]
+    fn test_upgrade_from_first_version() {
+        use candid::{CandidType, Deserialize, Int};
+        use ic_kit::mock_principals::{alice, bob, john};
+        use ic_kit::MockContext;
+        use ic_storage::stable::{write, Versioned};
+        use std::collections::{HashMap, HashSet};
+
+        // State written by the first version of the canister, field for field.
+        #[derive(CandidType, Deserialize)]
+        struct BiddingState {
+            fee_ratio: f64,
+            last_auction: u64,
+            auction_period: u64,
+            cycles_since_auction: u64,
+            bids: HashMap<Principal, u64>,
+        }
+
+        #[derive(CandidType, Deserialize)]
+        struct Balances(HashMap<Principal, Nat>);
+
+        #[derive(CandidType, Deserialize)]
+        struct AuctionInfo {
+            auction_id: usize,
+            auction_time: u64,
+            tokens_distributed: Nat,
+            cycles_collected: u64,
+            fee_ratio: f64,
+            first_transaction_id: Nat,
+            last_transaction_id: Nat,
+        }
+
+        #[derive(CandidType, Deserialize)]
+        struct AuctionHistory(Vec<AuctionInfo>);
+
+        #[derive(CandidType, Deserialize)]
+        struct StatsData {
+            logo: String,
+            name: String,
+            symbol: String,
+            decimals: u8,
+            total_supply: Nat,
+            owner: Principal,
+            fee: Nat,
+            fee_to: Principal,
+            deploy_time: u64,
+            min_cycles: u64,
+            is_test_token: bool,
+        }
+
+        #[derive(CandidType, Deserialize)]
+        enum TransactionStatus {
+            Succeeded,
+            Failed,
+        }
+
+        #[derive(CandidType, Deserialize)]
+        enum Operation {
+            Approve,
+            Mint,
+            Transfer,
+            TransferFrom,
+            Burn,
+            Auction,
+        }
+
+        #[derive(CandidType, Deserialize)]
+        struct TxRecord {
+            caller: Option<Principal>,
+            index: Nat,
+            from: Principal,
+            to: Principal,
+            amount: Nat,
+            fee: Nat,
+            timestamp: Int,
+            status: TransactionStatus,
+            operation: Operation,
+        }
+
+        #[derive(CandidType, Deserialize)]
+        struct Ledger {
+            history: Vec<TxRecord>,
+            vec_offset: Nat,
+        }
+
+        #[derive(CandidType, Deserialize)]
+        struct FirstState {
+            bidding_state: BiddingState,
+            balances: Balances,
+            auction_history: AuctionHistory,
+            stats: StatsData,
+            allowances: HashMap<Principal, HashMap<Principal, Nat>>,
+            ledger: Ledger,
+            notifications: HashSet<Nat>,
+        }
+
+        impl Versioned for FirstState {
+            type Previous = ();
+
+            fn upgrade((): ()) -> Self {
+                unreachable!()
+            }
+        }
+
+        MockContext::new().with_caller(alice()).inject();
+        let allowances = HashMap::from([(
+            alice(),
+            HashMap::from([(bob(), Nat::from(100)), (john(), Nat::from(50))]),
+        )]);
+        write(&FirstState {
+            bidding_state: BiddingState {
+                fee_ratio: 0.0,
+                last_auction: 0,
+                auction_period: 86_400_000_000,
+                cycles_since_auction: 0,
+                bids: HashMap::new(),
+            },
+            balances: Balances(HashMap::from([(alice(), Nat::from(1000))])),
+            auction_history: AuctionHistory(vec![]),
+            stats: StatsData {
+                logo: "".to_string(),
+                name: "Token".to_string(),
+                symbol: "TKN".to_string(),
+                decimals: 8,
+                total_supply: Nat::from(1000),
+                owner: alice(),
+                fee: Nat::from(0),
+                fee_to: alice(),
+                deploy_time: 0,
+                min_cycles: 0,
+                is_test_token: false,
+            },
+            allowances,
+            ledger: Ledger {
+                history: vec![TxRecord {
+                    caller: Some(alice()),
+                    index: Nat::from(0),
+                    from: alice(),
+                    to: alice(),
+                    amount: Nat::from(1000),
+                    fee: Nat::from(0),
+                    timestamp: Int::from(0),
+                    status: TransactionStatus::Succeeded,
+                    operation: Operation::Mint,
+                }],
+                vec_offset: Nat::from(0),
+            },
+            notifications: HashSet::from([Nat::from(0)]),
+        })
+        .unwrap();
+
+        let canister = TokenCanister::init_instance();
+        canister.__post_upgrade_inst();
+
+        assert_eq!(canister.getMetadata().name, "Token");
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+        assert_eq!(canister.historySize(), Nat::from(1));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(100));
+        assert_eq!(canister.allowance(alice(), john()), Nat::from(50));
+        assert_eq!(canister.getApprovedTotal(alice()), Nat::from(150));
+        assert!(canister.state.borrow().stats.approve_fee_enabled);
+
+        MockContext::new().with_caller(bob()).inject();
+        assert!(canister
+            .transferFrom(alice(), bob(), Nat::from(100))
+            .is_ok());
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+    }
+
     #[test]
     fn test_upgrade_from_current() {
         // Set a value on the state...
@@ -425,4 +2256,478 @@ mod test {
         let state = canister.state.borrow();
         assert_eq!(state.bidding_state.fee_ratio, 12345.0);
     }
+
+    #[test]
+    fn test_upgrade_keeps_memo_index() {
+        let canister = TokenCanister::init_instance();
+        canister
+            .state
+            .borrow_mut()
+            .memo_index
+            .insert(b"memo".to_vec(), vec![Nat::from(1), Nat::from(2)]);
+        canister.__pre_upgrade_inst();
+
+        canister.state.borrow_mut().memo_index.clear();
+        canister.__post_upgrade_inst();
+        assert_eq!(
+            canister.state.borrow().memo_index.get(&b"memo".to_vec()),
+            Some(&vec![Nat::from(1), Nat::from(2)])
+        );
+    }
+
+    fn test_canister() -> TokenCanister {
+        ic_kit::MockContext::new()
+            .with_caller(ic_kit::mock_principals::alice())
+            .inject();
+
+        let canister = TokenCanister::init_instance();
+        canister.init(Metadata {
+            logo: "".to_string(),
+            name: "".to_string(),
+            symbol: "".to_string(),
+            decimals: 8,
+            totalSupply: Nat::from(1000),
+            owner: ic_kit::mock_principals::alice(),
+            fee: Nat::from(0),
+            feeTo: ic_kit::mock_principals::alice(),
+            isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
+        });
+
+        canister
+    }
+
+    #[test]
+    fn admin_setters_record_config_changes() {
+        use crate::types::ConfigChange;
+        use ic_kit::mock_principals::{alice, bob};
+
+        type Setter = Box<dyn Fn(&TokenCanister)>;
+        let setters: Vec<(&str, &str, &str, Setter)> = vec![
+            ("fee", "0", "10", Box::new(|c| c.setFee(Nat::from(10)))),
+            (
+                "fee_burn_rate",
+                "0",
+                "100",
+                Box::new(|c| c.setFeeBurnRate(100).unwrap()),
+            ),
+            (
+                "spender_whitelist_enabled",
+                "false",
+                "true",
+                Box::new(|c| c.setSpenderWhitelistEnabled(true).unwrap()),
+            ),
+            (
+                "min_transfer_amount",
+                "0",
+                "5",
+                Box::new(|c| c.setMinTransferAmount(Nat::from(5)).unwrap()),
+            ),
+            (
+                "approve_fee_enabled",
+                "true",
+                "false",
+                Box::new(|c| c.setApproveFeeEnabled(false).unwrap()),
+            ),
+            (
+                "record_failed_transactions",
+                "false",
+                "true",
+                Box::new(|c| c.setRecordFailedTransactions(true).unwrap()),
+            ),
+            (
+                "max_query_limit",
+                "1000",
+                "50",
+                Box::new(|c| c.setMaxQueryLimit(50).unwrap()),
+            ),
+            (
+                "min_cycles",
+                "10000000000000",
+                "100",
+                Box::new(|c| c.setMinCycles(100).unwrap()),
+            ),
+            (
+                "auction_period",
+                "86400000000000",
+                "7200000000000",
+                Box::new(|c| c.setAuctionPeriod(7200).unwrap()),
+            ),
+            (
+                "balance_checkpoint_spacing",
+                "1000",
+                "10",
+                Box::new(|c| c.setBalanceCheckpointSpacing(10).unwrap()),
+            ),
+            (
+                "is_test_token",
+                "false",
+                "true",
+                Box::new(|c| {
+                    c.toggleTest();
+                }),
+            ),
+            (
+                "claim_root",
+                "none",
+                "abcd",
+                Box::new(|c| c.setClaimRoot(vec![0xab, 0xcd]).unwrap()),
+            ),
+            (
+                "faucet_limit",
+                "0",
+                "100",
+                Box::new(|c| c.setFaucetLimit(Nat::from(100)).unwrap()),
+            ),
+            (
+                "minting_disabled",
+                "false",
+                "true",
+                Box::new(|c| c.renounceMinting().unwrap()),
+            ),
+            (
+                "mint_limit",
+                "[0, 0]",
+                "[300, 60000000000]",
+                Box::new(|c| c.setMintLimit(Nat::from(300), 60).unwrap()),
+            ),
+            (
+                "mint_limit_owner_exempt",
+                "false",
+                "true",
+                Box::new(|c| c.setMintLimitOwnerExempt(true).unwrap()),
+            ),
+        ];
+
+        for (setting, old_value, new_value, set) in setters {
+            let canister = test_canister();
+            let id = canister.historySize();
+            set(&canister);
+            assert_eq!(canister.historySize(), id.clone() + 1, "{}", setting);
+
+            let tx = canister.getTransaction(id);
+            assert_eq!(tx.operation, Operation::Configure);
+            assert_eq!(tx.caller, Some(alice()));
+            assert_eq!(tx.amount, Nat::from(0));
+            assert_eq!(
+                tx.config,
+                Some(ConfigChange {
+                    setting: setting.to_string(),
+                    old_value: old_value.to_string(),
+                    new_value: new_value.to_string(),
+                })
+            );
+        }
+
+        let canister = test_canister();
+        canister.setFeeTo(bob());
+        canister.setOwner(bob());
+        let txs = canister.getTransactions(Nat::from(1), Nat::from(10));
+        assert_eq!(txs.len(), 2);
+        let changes: Vec<_> = txs.into_iter().map(|tx| tx.config.unwrap()).collect();
+        assert_eq!(changes[0].setting, "fee_to");
+        assert_eq!(changes[0].old_value, alice().to_string());
+        assert_eq!(changes[0].new_value, bob().to_string());
+        assert_eq!(changes[1].setting, "owner");
+        assert_eq!(changes[1].new_value, bob().to_string());
+
+        let canister = test_canister();
+        canister
+            .setEmission(bob(), Nat::from(100), 60, true)
+            .unwrap();
+        canister.setArchive(bob(), 10, 5).unwrap();
+        let txs = canister.getTransactions(Nat::from(1), Nat::from(10));
+        assert_eq!(txs.len(), 2);
+        let changes: Vec<_> = txs.into_iter().map(|tx| tx.config.unwrap()).collect();
+        assert_eq!(changes[0].setting, "emission");
+        assert_eq!(changes[0].old_value, "none");
+        assert_eq!(
+            changes[0].new_value,
+            format!("[{}, 100, 60000000000, true]", bob())
+        );
+        assert_eq!(changes[1].setting, "archive");
+        assert_eq!(changes[1].old_value, "none");
+        assert_eq!(changes[1].new_value, format!("[{}, 10, 5]", bob()));
+    }
+
+    #[test]
+    fn free_form_settings_recorded_as_digests() {
+        let canister = test_canister();
+        let logo = format!("data:image/png;base64,{}", "A".repeat(10_000));
+        canister.setName("Token".into());
+        canister.setLogo(logo.clone());
+
+        let txs = canister.getTransactions(Nat::from(1), Nat::from(10));
+        let changes: Vec<_> = txs.into_iter().map(|tx| tx.config.unwrap()).collect();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].setting, "name");
+        assert_eq!(changes[0].old_value, text_digest(""));
+        assert_eq!(changes[0].new_value, text_digest("Token"));
+        assert_eq!(changes[1].setting, "logo");
+        assert_eq!(changes[1].old_value, text_digest(""));
+        assert_eq!(changes[1].new_value, text_digest(&logo));
+        assert_eq!(changes[1].new_value.len(), "sha256:".len() + 64);
+        assert_eq!(
+            text_digest(""),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn config_changes_not_aggregated() {
+        use ic_kit::mock_principals::alice;
+
+        let canister = test_canister();
+        let stats = canister.getUserStats(alice());
+        canister.setFee(Nat::from(10));
+        canister.setMinCycles(100).unwrap();
+
+        assert_eq!(canister.getUserStats(alice()), stats);
+        assert_eq!(canister.getUserTransactionCount(alice()), Nat::from(1));
+        assert_eq!(canister.getUserTransactionAmount(alice()), Nat::from(1000));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+
+        let page = canister.getTransactionsPage(
+            None,
+            Nat::from(10),
+            None,
+            Some(Operation::Configure),
+            None,
+        );
+        assert_eq!(page.records.len(), 2);
+        assert_eq!(page.records[0].config.as_ref().unwrap().setting, "fee");
+    }
+
+    #[test]
+    fn test_upgrade_keeps_user_stats() {
+        use ic_kit::mock_principals::{alice, bob};
+
+        ic_kit::MockContext::new().inject();
+        let canister = TokenCanister::init_instance();
+        canister
+            .state
+            .borrow_mut()
+            .ledger
+            .transfer(alice(), bob(), Nat::from(100), Nat::from(1));
+        let stats = canister.getUserStats(alice());
+        assert_eq!(stats.sent, Nat::from(100));
+        canister.__pre_upgrade_inst();
+
+        canister.state.borrow_mut().ledger = Default::default();
+        canister.__post_upgrade_inst();
+        assert_eq!(canister.getUserStats(alice()), stats);
+        assert_eq!(canister.getUserStats(bob()).received, Nat::from(100));
+    }
+
+    #[test]
+    fn verify_ledger_after_upgrade() {
+        use ic_kit::mock_principals::bob;
+
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.setFeeBurnRate(5_000).unwrap();
+        for _ in 0..10 {
+            canister.transfer(bob(), Nat::from(10), None).unwrap();
+        }
+        canister.burn(Nat::from(100)).unwrap();
+
+        canister.__pre_upgrade_inst();
+        canister.state.borrow_mut().ledger = Default::default();
+        canister.__post_upgrade_inst();
+
+        let mut from = Nat::from(0);
+        let mut minted_total = Nat::from(0);
+        let mut burned_total = Nat::from(0);
+        loop {
+            match canister.verifyLedger(from, Nat::from(4)) {
+                LedgerCheckResult::Consistent {
+                    checked,
+                    minted,
+                    burned,
+                    next,
+                } => {
+                    assert!(checked <= 4);
+                    minted_total += minted;
+                    burned_total += burned;
+                    match next {
+                        Some(next) => from = next,
+                        None => break,
+                    }
+                }
+                result => panic!("inconsistent ledger: {:?}", result),
+            }
+        }
+
+        assert_eq!(minted_total - burned_total, canister.totalSupply());
+    }
+
+    #[test]
+    fn test_upgrade_keeps_max_supply() {
+        let canister = TokenCanister::init_instance();
+        canister.state.borrow_mut().stats.max_supply = Some(Nat::from(1000));
+        canister.__pre_upgrade_inst();
+
+        canister.state.borrow_mut().stats.max_supply = None;
+        canister.__post_upgrade_inst();
+        assert_eq!(canister.getMetadata().maxSupply, Some(Nat::from(1000)));
+    }
+
+    #[test]
+    fn test_upgrade_keeps_total_burned() {
+        let canister = TokenCanister::init_instance();
+        canister.state.borrow_mut().stats.total_burned = Nat::from(100);
+        canister.state.borrow_mut().stats.total_minted = Some(Nat::from(200));
+        canister.__pre_upgrade_inst();
+
+        canister.state.borrow_mut().stats.total_burned = Nat::from(0);
+        canister.state.borrow_mut().stats.total_minted = None;
+        canister.__post_upgrade_inst();
+        assert_eq!(canister.totalBurned(), Nat::from(100));
+        assert_eq!(canister.totalMinted(), Nat::from(200));
+    }
+
+    #[test]
+    fn test_upgrade_keeps_minting_disabled() {
+        let canister = TokenCanister::init_instance();
+        canister.state.borrow_mut().stats.minting_disabled = true;
+        canister.__pre_upgrade_inst();
+
+        canister.state.borrow_mut().stats.minting_disabled = false;
+        canister.__post_upgrade_inst();
+        assert!(canister.getTokenInfo().mintingDisabled);
+    }
+
+    #[test]
+    fn test_upgrade_without_max_supply() {
+        use candid::{CandidType, Deserialize};
+        use ic_kit::mock_principals::alice;
+        use ic_kit::MockContext;
+
+        // Stats written by the first version of the canister, before the `min_transfer_amount`,
+        // `max_supply` and the later fields were added.
+        #[derive(CandidType, Deserialize)]
+        struct PreviousStatsData {
+            logo: String,
+            name: String,
+            symbol: String,
+            decimals: u8,
+            total_supply: Nat,
+            owner: Principal,
+            fee: Nat,
+            fee_to: Principal,
+            deploy_time: u64,
+            min_cycles: u64,
+            is_test_token: bool,
+        }
+
+        MockContext::new().with_caller(alice()).inject();
+        let encoded = candid::Encode!(&PreviousStatsData {
+            logo: "".to_string(),
+            name: "".to_string(),
+            symbol: "".to_string(),
+            decimals: 8,
+            total_supply: Nat::from(1000),
+            owner: alice(),
+            fee: Nat::from(0),
+            fee_to: alice(),
+            deploy_time: 0,
+            min_cycles: 0,
+            is_test_token: false,
+        })
+        .unwrap();
+        let stats = candid::Decode!(&encoded, StatsData).unwrap();
+        assert_eq!(stats.min_transfer_amount, Nat::from(0));
+        assert!(stats.approve_fee_enabled);
+        assert_eq!(stats.max_supply, None);
+        assert_eq!(stats.fee_burn_rate, 0);
+        assert_eq!(stats.total_burned, Nat::from(0));
+        assert_eq!(stats.total_minted(), Nat::from(1000));
+        assert!(!stats.minting_disabled);
+
+        let canister = TokenCanister::init_instance();
+        canister.state.borrow_mut().stats = stats;
+        canister.__pre_upgrade_inst();
+        canister.__post_upgrade_inst();
+
+        assert!(canister.mint(alice(), Nat::from(u64::MAX), None).is_ok());
+        assert_eq!(canister.getMetadata().maxSupply, None);
+        assert_eq!(
+            canister.totalMinted(),
+            Nat::from(1000) + Nat::from(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn balance_of_at_matches_balances() {
+        use crate::canister::is20_auction::auction_principal;
+        use ic_kit::mock_principals::{alice, bob, john, xtc};
+        use ic_kit::MockContext;
+
+        let holders = [alice(), bob(), john(), xtc(), auction_principal()];
+        let balances = |canister: &TokenCanister| -> Vec<Nat> {
+            holders
+                .iter()
+                .map(|holder| canister.balanceOf(*holder))
+                .collect()
+        };
+
+        let canister = test_canister();
+        let mut snapshots = vec![(Nat::from(0), balances(&canister))];
+        canister.setFee(Nat::from(10));
+        canister.setFeeTo(john());
+        canister.setFeeBurnRate(2_000).unwrap();
+        canister.state.borrow_mut().bidding_state.fee_ratio = 0.5;
+        canister.setBalanceCheckpointSpacing(3).unwrap();
+        assert_eq!(
+            canister.setBalanceCheckpointSpacing(0),
+            Err(TxError::InvalidCheckpointSpacing)
+        );
+        assert_eq!(canister.getBalanceCheckpointSpacing(), 3);
+
+        let mut snapshot = |canister: &TokenCanister| {
+            snapshots.push((canister.historySize() - Nat::from(1), balances(canister)));
+        };
+
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        snapshot(&canister);
+        canister
+            .approve(john(), Nat::from(200), None, None)
+            .unwrap();
+        snapshot(&canister);
+
+        MockContext::new().with_caller(john()).inject();
+        canister
+            .transferFrom(alice(), xtc(), Nat::from(50))
+            .unwrap();
+        snapshot(&canister);
+        canister
+            .transferFromIncludeFee(alice(), bob(), Nat::from(60))
+            .unwrap();
+        snapshot(&canister);
+
+        MockContext::new().with_caller(bob()).inject();
+        canister.transfer(xtc(), Nat::from(20), None).unwrap();
+        snapshot(&canister);
+        canister.burn(Nat::from(5)).unwrap();
+        snapshot(&canister);
+
+        MockContext::new().with_caller(alice()).inject();
+        canister.mint(bob(), Nat::from(30), None).unwrap();
+        snapshot(&canister);
+        canister.setFeeTo(xtc());
+        canister.transfer(bob(), Nat::from(40), None).unwrap();
+        snapshot(&canister);
+
+        for (id, expected) in snapshots {
+            for (holder, balance) in holders.iter().zip(expected) {
+                assert_eq!(canister.balanceOfAt(*holder, id.clone()), Ok(balance));
+            }
+        }
+
+        assert_eq!(
+            canister.balanceOfAt(bob(), canister.historySize()),
+            Err(TxError::TransactionDoesNotExist)
+        );
+    }
 }
@@ -1,10 +1,16 @@
 use super::TokenCanister;
 use crate::canister::is20_auction::auction_principal;
-use crate::state::{Balances, CanisterState};
-use crate::types::{TxError, TxReceipt};
+use crate::ledger::TxFee;
+use crate::state::{Balances, CanisterState, PendingOwnerNotification};
+use crate::types::{
+    Allowance, FeeBreakdown, Operation, SpendingLimit, StatsData, Timestamp, TransferFromReceipt,
+    TxError, TxReceipt, MAX_FEE_BURN_RATE,
+};
 use candid::Nat;
 use ic_cdk::export::Principal;
-use std::collections::HashMap;
+
+/// Maximum length of a transfer memo in bytes.
+pub const MAX_MEMO_LEN: usize = 32;
 
 pub fn transfer(
     canister: &TokenCanister,
@@ -12,29 +18,66 @@ pub fn transfer(
     value: Nat,
     fee_limit: Option<Nat>,
 ) -> TxReceipt {
+    transfer_with_memo(canister, to, value, fee_limit, None)
+}
+
+/// Same as `transfer`, but also stores the `memo` in the transaction record and indexes the
+/// transaction by it.
+pub fn transfer_with_memo(
+    canister: &TokenCanister,
+    to: Principal,
+    value: Nat,
+    fee_limit: Option<Nat>,
+    memo: Option<Vec<u8>>,
+) -> TxReceipt {
+    let from = ic_kit::ic::caller();
+    let result = _transfer_with_memo(canister, to, value.clone(), fee_limit, memo);
+    _record_failure(canister, &result, Operation::Transfer, from, to, value);
+    result
+}
+
+fn _transfer_with_memo(
+    canister: &TokenCanister,
+    to: Principal,
+    value: Nat,
+    fee_limit: Option<Nat>,
+    memo: Option<Vec<u8>>,
+) -> TxReceipt {
+    if matches!(&memo, Some(memo) if memo.len() > MAX_MEMO_LEN) {
+        return Err(TxError::MemoTooLong {
+            max: MAX_MEMO_LEN as u64,
+        });
+    }
+
     let from = ic_kit::ic::caller();
-    let (fee, fee_to) = canister.state.borrow().stats.fee_info();
+    let fee = canister.state.borrow().stats.fee.clone();
     if let Some(fee_limit) = fee_limit {
         if fee > fee_limit {
             return Err(TxError::FeeExceededLimit);
         }
     }
 
-    let fee_ratio = canister.state.borrow().bidding_state.fee_ratio;
+    if value < canister.state.borrow().stats.min_transfer_amount {
+        return Err(TxError::AmountTooSmall);
+    }
 
-    {
-        let balances = &mut canister.state.borrow_mut().balances;
+    let mut state = canister.state.borrow_mut();
+    if state.balances.balance_of(&from) < value.clone() + fee.clone() {
+        return Err(TxError::InsufficientBalance);
+    }
 
-        if balances.balance_of(&from) < value.clone() + fee.clone() {
-            return Err(TxError::InsufficientBalance);
-        }
+    _check_unlocked(&state, from, &(value.clone() + fee.clone()))?;
 
-        _charge_fee(balances, from, fee_to, fee.clone(), fee_ratio);
-        _transfer(balances, from, to, value.clone());
+    let fee = _charge_fee(&mut state, from, fee)?;
+    _transfer(&mut state.balances, from, to, value.clone())?;
+
+    let id = state
+        .ledger
+        .transfer_with_memo(from, to, value, fee, memo.clone());
+    if let Some(memo) = memo {
+        state.memo_index.entry(memo).or_default().push(id.clone());
     }
 
-    let mut state = canister.state.borrow_mut();
-    let id = state.ledger.transfer(from, to, value, fee);
     state.notifications.insert(id.clone());
     Ok(id)
 }
@@ -45,183 +88,565 @@ pub fn transfer_from(
     to: Principal,
     value: Nat,
 ) -> TxReceipt {
+    transfer_from_with_allowance(canister, from, to, value).map(|receipt| receipt.tx_id)
+}
+
+/// Same as `transfer_from`, but also returns the remaining allowance of the caller after the
+/// transfer.
+pub fn transfer_from_with_allowance(
+    canister: &TokenCanister,
+    from: Principal,
+    to: Principal,
+    value: Nat,
+) -> Result<TransferFromReceipt, TxError> {
+    _transfer_from(canister, from, to, value, false)
+}
+
+/// Moves tokens from `from` to `to` using the allowance given to the caller. If `fee_included` is
+/// set, the `from` account and the allowance are reduced exactly by `value`, and the recipient
+/// receives `value - fee`. Otherwise the recipient receives `value`, and the fee is charged on top
+/// of it.
+pub(crate) fn _transfer_from(
+    canister: &TokenCanister,
+    from: Principal,
+    to: Principal,
+    value: Nat,
+    fee_included: bool,
+) -> Result<TransferFromReceipt, TxError> {
+    let result = _try_transfer_from(canister, from, to, value.clone(), fee_included);
+    let operation = if fee_included {
+        Operation::TransferFromIncludeFee
+    } else {
+        Operation::TransferFrom
+    };
+    _record_failure(canister, &result, operation, from, to, value);
+    result
+}
+
+fn _try_transfer_from(
+    canister: &TokenCanister,
+    from: Principal,
+    to: Principal,
+    value: Nat,
+    fee_included: bool,
+) -> Result<TransferFromReceipt, TxError> {
     let owner = ic_kit::ic::caller();
     let mut state = canister.state.borrow_mut();
-    let from_allowance = state.allowance(from, owner);
+    let mut allowance = _current_allowance(&state, from, owner);
     let CanisterState {
-        ref mut balances,
-        ref bidding_state,
+        ref balances,
         ref stats,
+        ref spender_whitelist,
         ..
-    } = &mut *state;
+    } = &*state;
 
-    let (fee, fee_to) = stats.fee_info();
-    let fee_ratio = bidding_state.fee_ratio;
+    let fee = stats.fee.clone();
 
-    let value_with_fee = value.clone() + fee.clone();
-    if from_allowance < value_with_fee {
-        return Err(TxError::InsufficientAllowance);
+    if !spender_whitelist.is_allowed(&owner) {
+        return Err(TxError::SpenderNotAllowed);
+    }
+
+    // Without an allowance the caller gets a zero default, so a zero transfer would pass the
+    // allowance check and be recorded on behalf of any account.
+    if value == 0u32 || value < stats.min_transfer_amount || (fee_included && value <= fee) {
+        return Err(TxError::AmountTooSmall);
     }
 
+    let (value_with_fee, received) = if fee_included {
+        (value.clone(), value.clone() - fee.clone())
+    } else {
+        (value.clone() + fee.clone(), value.clone())
+    };
+
+    // The allowance is a copy, so it is not changed if the transfer fails.
+    _use_allowance(&mut allowance, &value_with_fee)?;
+
     let from_balance = balances.balance_of(&from);
     if from_balance < value_with_fee {
         return Err(TxError::InsufficientBalance);
     }
 
-    _charge_fee(balances, from, fee_to, fee.clone(), fee_ratio);
-    _transfer(balances, from, to, value.clone());
-
-    let allowances = &mut state.allowances;
-    match allowances.get(&from) {
-        Some(inner) => {
-            let result = inner.get(&owner).unwrap().clone();
-            let mut temp = inner.clone();
-            if result.clone() - value_with_fee.clone() != 0 {
-                temp.insert(owner, result - value_with_fee);
-                allowances.insert(from, temp);
-            } else {
-                temp.remove(&owner);
-                if temp.is_empty() {
-                    allowances.remove(&from);
-                } else {
-                    allowances.insert(from, temp);
-                }
-            }
-        }
-        None => panic!(),
+    _check_unlocked(&state, from, &value_with_fee)?;
+
+    let fee = _charge_fee(&mut state, from, fee)?;
+    _transfer(&mut state.balances, from, to, received)?;
+
+    let remaining_allowance = allowance.value.clone();
+    _set_allowance(&mut state, from, owner, allowance);
+
+    let tx_id = if fee_included {
+        state
+            .ledger
+            .transfer_from_include_fee(owner, from, to, value.clone(), fee)
+    } else {
+        state
+            .ledger
+            .transfer_from(owner, from, to, value.clone(), fee)
+    };
+
+    // Only canisters can receive the notification calls.
+    if is_canister(&from) {
+        state.owner_notifications.push(PendingOwnerNotification {
+            owner: from,
+            tx_id: tx_id.clone(),
+            spender: owner,
+            amount: value,
+            attempts: 0,
+        });
     }
 
-    let id = state.ledger.transfer_from(owner, from, to, value, fee);
-    Ok(id)
+    Ok(TransferFromReceipt {
+        tx_id,
+        remaining_allowance,
+    })
 }
 
-pub fn approve(canister: &TokenCanister, spender: Principal, value: Nat) -> TxReceipt {
-    let owner = ic_kit::ic::caller();
+/// Records the `operation` from `from` to `to` of `amount` tokens in the ledger as failed if the
+/// `result` is an error and the owner enabled recording the failed transactions. The record keeps
+/// the error and has no effect on the balances.
+pub(crate) fn _record_failure<T>(
+    canister: &TokenCanister,
+    result: &Result<T, TxError>,
+    operation: Operation,
+    from: Principal,
+    to: Principal,
+    amount: Nat,
+) {
+    let error = match result {
+        Err(error) => error.clone(),
+        Ok(_) => return,
+    };
+
     let mut state = canister.state.borrow_mut();
+    if state.stats.record_failed_transactions {
+        let caller = ic_kit::ic::caller();
+        state
+            .ledger
+            .failed(operation, caller, from, to, amount, error);
+    }
+}
 
-    let CanisterState {
-        ref mut bidding_state,
-        ref mut balances,
-        ref stats,
-        ..
-    } = &mut *state;
+/// Checks that the `allowance` is enough to use `amount` of tokens, and deducts the amount from it.
+fn _use_allowance(allowance: &mut Allowance, amount: &Nat) -> Result<(), TxError> {
+    if !allowance.unlimited && allowance.value < *amount {
+        return Err(TxError::InsufficientAllowance);
+    }
 
-    let (fee, fee_to) = stats.fee_info();
-    let fee_ratio = bidding_state.fee_ratio;
-    if balances.balance_of(&owner) < fee {
-        return Err(TxError::InsufficientBalance);
+    if let Some(limit) = &mut allowance.spending_limit {
+        if limit.remaining < *amount {
+            return Err(TxError::SpendingLimitExceeded {
+                resets_at: limit.resets_at,
+            });
+        }
+
+        limit.remaining -= amount.clone();
     }
 
-    _charge_fee(balances, owner, fee_to, fee.clone(), fee_ratio);
-    let v = value.clone() + fee.clone();
+    allowance.times_used += 1;
+    allowance.last_used = Some(ic_kit::ic::time());
 
-    match state.allowances.get(&owner) {
-        Some(inner) => {
-            let mut temp = inner.clone();
-            if v != 0 {
-                temp.insert(spender, v);
-                state.allowances.insert(owner, temp);
-            } else {
-                temp.remove(&spender);
-                if temp.is_empty() {
-                    state.allowances.remove(&owner);
-                } else {
-                    state.allowances.insert(owner, temp);
-                }
-            }
-        }
-        None if v != 0 => {
-            let mut inner = HashMap::new();
-            inner.insert(spender, v);
-            state.allowances.insert(owner, inner);
-        }
-        None => {}
+    // One-time allowances are revoked after the first use regardless of the used amount.
+    if allowance.one_time {
+        allowance.value = Nat::from(0);
+    } else if !allowance.unlimited {
+        allowance.value -= amount.clone();
     }
 
+    Ok(())
+}
+
+/// Canister ids are opaque principals, that end with `0x01` byte.
+pub(crate) fn is_canister(principal: &Principal) -> bool {
+    principal.as_slice().last() == Some(&0x01)
+}
+
+pub fn approve(
+    canister: &TokenCanister,
+    spender: Principal,
+    value: Nat,
+    expires_at: Option<Timestamp>,
+    spending_limit: Option<(Nat, u64)>,
+) -> TxReceipt {
+    let spending_limit = match spending_limit {
+        Some((_, 0)) => return Err(TxError::InvalidSpendingLimit),
+        // IC timestamp is in nanoseconds, thus multiplying
+        Some((limit_per_period, period_sec)) => Some(SpendingLimit::new(
+            limit_per_period,
+            period_sec.saturating_mul(1_000_000_000),
+        )),
+        None => None,
+    };
+
+    let owner = ic_kit::ic::caller();
+    let mut state = canister.state.borrow_mut();
+    _approve(
+        &mut state,
+        owner,
+        spender,
+        Allowance {
+            value,
+            expires_at,
+            spending_limit,
+            ..Default::default()
+        },
+    )
+}
+
+/// Sets the allowance of the `spender` on behalf of the `owner`, charging the approval fee from the
+/// owner account. The transfer fee amount is added to the `allowance.value`, so the spender can pay
+/// the `transferFrom` fee.
+pub fn _approve(
+    state: &mut CanisterState,
+    owner: Principal,
+    spender: Principal,
+    allowance: Allowance,
+) -> TxReceipt {
+    _check_spender(state, owner, spender)?;
+
+    let fee = _charge_approve_fee(state, owner)?;
+    let value = allowance.value.clone();
+    _set_allowance(
+        state,
+        owner,
+        spender,
+        Allowance {
+            value: _allowance_with_fee(&state.stats, value.clone()),
+            ..allowance
+        },
+    );
+
     let id = state.ledger.approve(owner, spender, value, fee);
     Ok(id)
 }
 
-pub fn mint(canister: &TokenCanister, to: Principal, amount: Nat) -> TxReceipt {
-    let caller = ic_kit::ic::caller();
-    {
-        let balances = &mut canister.state.borrow_mut().balances;
-        let to_balance = balances.balance_of(&to);
-        balances.0.insert(to, to_balance + amount.clone());
+/// Returns the amount to be stored as an allowance for the approved `value`, so the spender could
+/// pay the `transferFrom` fee. Zero approvals stay zero, so that the entry is removed.
+pub fn _allowance_with_fee(stats: &StatsData, value: Nat) -> Nat {
+    if value == 0 {
+        value
+    } else {
+        value + stats.fee.clone()
+    }
+}
+
+/// Returns the approved value of the stored `allowance`, without the headroom for the
+/// `transferFrom` fee added by `_allowance_with_fee`.
+fn _allowance_without_fee(stats: &StatsData, allowance: &Allowance) -> Nat {
+    if allowance.unlimited {
+        allowance.value.clone()
+    } else if allowance.value > stats.fee {
+        allowance.value.clone() - stats.fee.clone()
+    } else {
+        Nat::from(0)
     }
+}
 
+pub fn increase_allowance(canister: &TokenCanister, spender: Principal, delta: Nat) -> TxReceipt {
+    let owner = ic_kit::ic::caller();
     let mut state = canister.state.borrow_mut();
-    state.stats.total_supply += amount.clone();
-    let id = state.ledger.mint(caller, to, amount);
+    _check_spender(&state, owner, spender)?;
+
+    let fee = _charge_approve_fee(&mut state, owner)?;
+    let mut allowance = _current_allowance(&state, owner, spender);
+    let mut value = _allowance_without_fee(&state.stats, &allowance);
+    if !allowance.unlimited {
+        value += delta;
+        allowance.value = _allowance_with_fee(&state.stats, value.clone());
+    }
+    _set_allowance(&mut state, owner, spender, allowance);
 
+    let id = state.ledger.approve(owner, spender, value, fee);
     Ok(id)
 }
 
-pub fn burn(canister: &TokenCanister, amount: Nat) -> TxReceipt {
+pub fn decrease_allowance(canister: &TokenCanister, spender: Principal, delta: Nat) -> TxReceipt {
+    let owner = ic_kit::ic::caller();
+    let mut state = canister.state.borrow_mut();
+
+    let fee = _charge_approve_fee(&mut state, owner)?;
+    let mut allowance = _current_allowance(&state, owner, spender);
+    let value = _allowance_without_fee(&state.stats, &allowance);
+    let value = if value > delta {
+        value - delta
+    } else {
+        Nat::from(0)
+    };
+    // Decreasing an unlimited allowance turns it into a regular one.
+    allowance.unlimited = false;
+    allowance.value = _allowance_with_fee(&state.stats, value.clone());
+    _set_allowance(&mut state, owner, spender, allowance);
+
+    let id = state.ledger.approve(owner, spender, value, fee);
+    Ok(id)
+}
+
+/// Maximum length of the mint reason in bytes.
+pub const MAX_MINT_REASON_LEN: usize = 64;
+
+pub fn mint(
+    canister: &TokenCanister,
+    to: Principal,
+    amount: Nat,
+    reason: Option<String>,
+) -> TxReceipt {
+    _check_mint_reason(&reason)?;
     let caller = ic_kit::ic::caller();
-    {
-        let mut state = canister.state.borrow_mut();
-        let caller_balance = state.balances.balance_of(&caller);
-        if caller_balance < amount {
-            return Err(TxError::InsufficientBalance);
-        }
+    _mint(&mut canister.state.borrow_mut(), caller, to, amount, reason)
+}
 
-        state
-            .balances
-            .0
-            .insert(caller, caller_balance - amount.clone());
+pub(crate) fn _check_mint_reason(reason: &Option<String>) -> Result<(), TxError> {
+    match reason {
+        Some(reason) if reason.len() > MAX_MINT_REASON_LEN => Err(TxError::ReasonTooLong {
+            max: MAX_MINT_REASON_LEN as u64,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Adds `amount` of new tokens to the `to` account and records the mint in the ledger. Fails
+/// without changing the state if the maximum supply or the mint limit would be exceeded.
+pub(crate) fn _mint(
+    state: &mut CanisterState,
+    caller: Principal,
+    to: Principal,
+    amount: Nat,
+    reason: Option<String>,
+) -> TxReceipt {
+    state.check_mint_limit(caller, &amount)?;
+    state.increase_supply(&amount)?;
+    state.record_mint(caller, amount.clone());
+    state.balances.credit(to, amount.clone())?;
+    Ok(state.ledger.mint(caller, to, amount, reason))
+}
+
+/// Burns `amount` of the caller's tokens. No fee is charged for burning.
+///
+/// Fails with `TxError::AmountTooSmall` if the amount is zero, with
+/// `TxError::InsufficientBalance` if the caller doesn't have enough tokens, and with
+/// `TxError::InsufficientUnlockedBalance` if the burn needs the tokens locked by vesting.
+pub fn burn(canister: &TokenCanister, amount: Nat) -> TxReceipt {
+    if amount == 0u32 {
+        return Err(TxError::AmountTooSmall);
     }
 
+    let caller = ic_kit::ic::caller();
     let mut state = canister.state.borrow_mut();
-    state.stats.total_supply -= amount.clone();
+    _check_unlocked(&state, caller, &amount)?;
+    _burn(&mut state, caller, amount.clone())?;
 
     let id = state.ledger.burn(caller, amount);
     Ok(id)
 }
 
-pub fn _transfer(balances: &mut Balances, from: Principal, to: Principal, value: Nat) {
-    let from_balance = balances.balance_of(&from);
-    let from_balance_new = from_balance - value.clone();
-    if from_balance_new != 0 {
-        balances.0.insert(from, from_balance_new);
-    } else {
-        balances.0.remove(&from);
+/// Burns `amount` of tokens from the `from` account using the allowance given to the caller.
+///
+/// Fails with `TxError::AmountTooSmall` if the amount is zero, the same as `burn`.
+pub fn burn_from(canister: &TokenCanister, from: Principal, amount: Nat) -> TxReceipt {
+    if amount == 0u32 {
+        return Err(TxError::AmountTooSmall);
     }
-    let to_balance = balances.balance_of(&to);
-    let to_balance_new = to_balance + value;
-    if to_balance_new != 0 {
-        balances.0.insert(to, to_balance_new);
+
+    let caller = ic_kit::ic::caller();
+    let mut state = canister.state.borrow_mut();
+    if !state.spender_whitelist.is_allowed(&caller) {
+        return Err(TxError::SpenderNotAllowed);
     }
+
+    let mut allowance = _current_allowance(&state, from, caller);
+    _use_allowance(&mut allowance, &amount)?;
+
+    _check_unlocked(&state, from, &amount)?;
+    _burn(&mut state, from, amount.clone())?;
+    _set_allowance(&mut state, from, caller, allowance);
+
+    let id = state.ledger.burn_from(caller, from, amount);
+    Ok(id)
+}
+
+/// Removes `amount` of tokens from the `from` account and from the total supply. The holder entry
+/// is removed if the balance becomes zero. Fails without changing the state if the balance is
+/// less than `amount`.
+pub(crate) fn _burn(
+    state: &mut CanisterState,
+    from: Principal,
+    amount: Nat,
+) -> Result<(), TxError> {
+    state.balances.debit(from, amount.clone())?;
+    // The balance is a part of the total supply, so this cannot fail after a successful debit.
+    state.decrease_supply(&amount)
 }
 
-pub fn _charge_fee(
+/// Moves `value` tokens from `from` to `to`. Fails without changing the balances if the `from`
+/// balance is less than `value`.
+pub fn _transfer(
     balances: &mut Balances,
-    user: Principal,
-    fee_to: Principal,
-    fee: Nat,
-    fee_ratio: f64,
+    from: Principal,
+    to: Principal,
+    value: Nat,
+) -> Result<(), TxError> {
+    balances.debit(from, value.clone())?;
+    balances.credit(to, value)
+}
+
+/// Checks that the `amount` can be spent from the part of the `holder` balance that is not locked
+/// by the vesting schedules.
+pub(crate) fn _check_unlocked(
+    state: &CanisterState,
+    holder: Principal,
+    amount: &Nat,
+) -> Result<(), TxError> {
+    if state.unlocked_balance(&holder) < *amount {
+        return Err(TxError::InsufficientUnlockedBalance);
+    }
+
+    Ok(())
+}
+
+/// Checks if the `owner` can give an allowance to the `spender`.
+pub fn _check_spender(
+    state: &CanisterState,
+    owner: Principal,
+    spender: Principal,
+) -> Result<(), TxError> {
+    if spender == owner {
+        return Err(TxError::SelfApproval);
+    }
+
+    if spender == Principal::anonymous() {
+        return Err(TxError::AnonymousPrincipal);
+    }
+
+    if !state.spender_whitelist.is_allowed(&spender) {
+        return Err(TxError::SpenderNotAllowed);
+    }
+
+    Ok(())
+}
+
+pub fn _charge_approve_fee(state: &mut CanisterState, owner: Principal) -> Result<TxFee, TxError> {
+    let fee = state.stats.approve_fee();
+    _charge_fee(state, owner, fee)
+}
+
+/// Returns the current not expired allowance, or an empty allowance if there is none.
+fn _current_allowance(state: &CanisterState, owner: Principal, spender: Principal) -> Allowance {
+    let mut allowance = state
+        .active_allowance(owner, spender)
+        .cloned()
+        .unwrap_or_default();
+    allowance.refresh();
+    allowance
+}
+
+/// Stores the allowance, or removes it if `allowance.value` is zero. The reverse index of the
+/// spenders and the approved total of the owner are updated accordingly.
+pub fn _set_allowance(
+    state: &mut CanisterState,
+    owner: Principal,
+    spender: Principal,
+    mut allowance: Allowance,
 ) {
-    if fee > 0u32 {
-        const INT_CONVERSION_K: u64 = 1_000_000_000_000;
-        let auction_fee_amount =
-            fee.clone() * (fee_ratio * INT_CONVERSION_K as f64) as u64 / INT_CONVERSION_K;
-        let owner_fee_amount = fee - auction_fee_amount.clone();
-        _transfer(balances, user, fee_to, owner_fee_amount);
-        _transfer(balances, user, auction_principal(), auction_fee_amount);
+    let CanisterState {
+        ref mut allowances,
+        ref mut spender_index,
+        ref mut approved_totals,
+        ..
+    } = state;
+    let inner = allowances.entry(owner).or_default();
+    let total = approved_totals.entry(owner).or_default();
+
+    // Expired allowances of the owner are purged on every update, so they don't stay in the
+    // state forever.
+    inner.retain(|&expired_spender, allowance| {
+        let is_expired = allowance.is_expired();
+        if is_expired {
+            spender_index.remove(expired_spender, owner);
+            total.subtract(&allowance.value);
+        }
+
+        !is_expired
+    });
+
+    if let Some(previous) = inner.remove(&spender) {
+        total.subtract(&previous.value);
+    }
+
+    if allowance.value != 0 {
+        if allowance.created_at == 0 {
+            allowance.created_at = ic_kit::ic::time();
+        }
+
+        total.total += allowance.value.clone();
+        inner.insert(spender, allowance);
+        spender_index.insert(spender, owner);
+    } else {
+        spender_index.remove(spender, owner);
+    }
+
+    total.update_next_expiry(inner);
+
+    if inner.is_empty() {
+        allowances.remove(&owner);
+        approved_totals.remove(&owner);
     }
 }
 
+/// Charges the `fee` from the `user` account. The share of the fee set with `setFeeBurnRate` is
+/// burned, and the rest is split between the `fee_to` principal and the cycle auction.
+///
+/// The whole fee is debited at once, so if the user cannot pay it, the state is not changed.
+/// Returns the split of the fee and its recipient to be stored in the ledger.
+pub fn _charge_fee(state: &mut CanisterState, user: Principal, fee: Nat) -> Result<TxFee, TxError> {
+    if fee == 0u32 {
+        return Ok(FeeBreakdown::default().into());
+    }
+
+    state.balances.debit(user, fee.clone())?;
+
+    let burned_amount = fee.clone() * state.stats.fee_burn_rate / MAX_FEE_BURN_RATE;
+    if burned_amount > 0u32 {
+        state.decrease_supply(&burned_amount)?;
+        state.ledger.burn(user, burned_amount.clone());
+    }
+
+    const INT_CONVERSION_K: u64 = 1_000_000_000_000;
+    let fee = fee - burned_amount.clone();
+    let fee_ratio = state.bidding_state.fee_ratio;
+    let auction_fee_amount =
+        fee.clone() * (fee_ratio * INT_CONVERSION_K as f64) as u64 / INT_CONVERSION_K;
+    let owner_fee_amount = fee - auction_fee_amount.clone();
+    let fee_to = state.stats.fee_to;
+    state.balances.credit(fee_to, owner_fee_amount.clone())?;
+    state
+        .balances
+        .credit(auction_principal(), auction_fee_amount.clone())?;
+
+    let mut charged = TxFee::from(FeeBreakdown {
+        fee_to: owner_fee_amount,
+        auction: auction_fee_amount,
+        burned: burned_amount,
+    });
+    charged.recipient = Some(fee_to);
+    Ok(charged)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::{Operation, TransactionStatus};
+    use crate::types::{
+        ApprovalRecord, ApprovalsPage, Operation, TransactionOrder, TransactionStatus,
+        TransactionStatusFilter, TransactionsPage, TxRecord,
+    };
+    use candid::CandidType;
     use common::types::Metadata;
     use ic_kit::mock_principals::{alice, bob, john};
     use ic_kit::MockContext;
     use std::collections::HashSet;
     use std::iter::FromIterator;
 
-    use crate::canister::MAX_TRANSACTION_QUERY_LEN;
+    use crate::canister::{
+        MAX_ALLOWANCES_QUERY_LEN, MAX_TRANSACTION_QUERY_HARD_CAP, MAX_TRANSACTION_QUERY_LEN,
+    };
     use ic_canister::Canister;
 
     fn test_canister() -> TokenCanister {
@@ -238,6 +663,8 @@ mod tests {
             fee: Nat::from(0),
             feeTo: alice(),
             isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
         });
 
         canister
@@ -293,6 +720,201 @@ mod tests {
         assert_eq!(canister.balanceOf(auction_principal()), Nat::from(25));
     }
 
+    #[test]
+    fn fee_burn() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+        canister.setFeeBurnRate(3_000).unwrap();
+
+        const COUNT: u64 = 20;
+        let mut burned = Nat::from(0);
+        for _ in 0..COUNT {
+            let id = canister.transfer(bob(), Nat::from(10), None).unwrap();
+            assert_eq!(canister.getTransaction(id).operation, Operation::Transfer);
+            burned += 3u32;
+        }
+
+        assert_eq!(canister.totalSupply(), Nat::from(1000) - burned.clone());
+        assert_eq!(canister.balanceOf(john()), Nat::from(7 * COUNT));
+        assert_eq!(canister.balanceOf(bob()), Nat::from(10 * COUNT));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000 - 20 * COUNT));
+
+        let burned_in_ledger = canister
+            .getTransactions(Nat::from(0), Nat::from(100))
+            .into_iter()
+            .filter(|tx| tx.operation == Operation::Burn)
+            .fold(Nat::from(0), |sum, tx| sum + tx.amount);
+        assert_eq!(burned_in_ledger, burned);
+    }
+
+    #[test]
+    fn fee_burn_with_auction_enabled() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(100);
+        canister.state.borrow_mut().stats.fee_to = john();
+        canister.state.borrow_mut().bidding_state.fee_ratio = 0.5;
+        canister.setFeeBurnRate(2_000).unwrap();
+
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        assert_eq!(canister.totalSupply(), Nat::from(980));
+        assert_eq!(canister.balanceOf(john()), Nat::from(40));
+        assert_eq!(canister.balanceOf(auction_principal()), Nat::from(40));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(800));
+    }
+
+    #[test]
+    fn fee_breakdown_sums_to_fee() {
+        for (burn_rate, fee_ratio) in [
+            (0, 0.0),
+            (0, 1.0),
+            (2_000, 0.5),
+            (3_333, 0.3),
+            (10_000, 0.5),
+        ] {
+            let canister = test_canister();
+            let context = MockContext::new().with_caller(alice()).inject();
+            {
+                let mut state = canister.state.borrow_mut();
+                state.stats.fee = Nat::from(7);
+                state.stats.fee_to = john();
+                state.bidding_state.fee_ratio = fee_ratio;
+            }
+            canister.setFeeBurnRate(burn_rate).unwrap();
+
+            let mut ids = vec![
+                canister.transfer(bob(), Nat::from(100), None).unwrap(),
+                canister.transferIncludeFee(bob(), Nat::from(100)).unwrap(),
+                canister.approve(bob(), Nat::from(500), None, None).unwrap(),
+            ];
+            context.update_caller(bob());
+            ids.push(
+                canister
+                    .transferFrom(alice(), bob(), Nat::from(50))
+                    .unwrap(),
+            );
+            ids.push(
+                canister
+                    .transferFromIncludeFee(alice(), bob(), Nat::from(50))
+                    .unwrap(),
+            );
+
+            let mut to_fee_to = Nat::from(0);
+            let mut to_auction = Nat::from(0);
+            for id in ids {
+                let tx = canister.getTransaction(id);
+                let breakdown = tx.fee_breakdown.unwrap();
+                assert_eq!(tx.fee, Nat::from(7));
+                assert_eq!(breakdown.total(), tx.fee);
+                assert_eq!(breakdown.burned, Nat::from(7 * burn_rate / 10_000));
+                to_fee_to += breakdown.fee_to;
+                to_auction += breakdown.auction;
+            }
+
+            assert_eq!(canister.balanceOf(john()), to_fee_to);
+            assert_eq!(canister.balanceOf(auction_principal()), to_auction);
+        }
+    }
+
+    #[test]
+    fn fee_payments_attributed_to_recipient_at_the_time() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().bidding_state.fee_ratio = 0.0;
+        canister.setFeeTo(john());
+        let first = canister.transfer(bob(), Nat::from(100), None).unwrap();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+
+        canister.setFeeTo(bob());
+        context.update_caller(bob());
+        let third = canister.transfer(alice(), Nat::from(10), None).unwrap();
+        let fourth = canister
+            .transferFrom(alice(), john(), Nat::from(10))
+            .unwrap();
+
+        let page = canister.getFeeTransactions(None, Nat::from(10));
+        let payments: Vec<_> = page
+            .payments
+            .iter()
+            .map(|payment| {
+                (
+                    payment.tx_id.clone(),
+                    payment.recipient,
+                    payment.amount.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(payments.len(), 4);
+        assert_eq!(payments[0], (first, john(), Nat::from(10)));
+        assert_eq!(payments[1].1, john());
+        assert_eq!(payments[2], (third, bob(), Nat::from(10)));
+        assert_eq!(payments[3], (fourth.clone(), bob(), Nat::from(10)));
+        assert_eq!(page.total, Nat::from(4));
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(canister.totalFeesToRecipient(), Nat::from(40));
+
+        let page = canister.getFeeTransactions(None, Nat::from(3));
+        assert_eq!(page.next_cursor, Some(fourth.clone()));
+        let page = canister.getFeeTransactions(page.next_cursor, Nat::from(3));
+        assert_eq!(page.payments.len(), 1);
+        assert_eq!(page.payments[0].tx_id, fourth);
+    }
+
+    #[test]
+    fn fee_payments_exclude_auction_and_burn() {
+        let canister = test_canister();
+        {
+            let mut state = canister.state.borrow_mut();
+            state.stats.fee = Nat::from(10);
+            state.stats.fee_to = john();
+            state.bidding_state.fee_ratio = 0.5;
+        }
+        canister.setFeeBurnRate(2_000).unwrap();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        assert_eq!(canister.totalFeesToRecipient(), Nat::from(4));
+
+        canister.state.borrow_mut().bidding_state.fee_ratio = 1.0;
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        assert_eq!(
+            canister
+                .getFeeTransactions(None, Nat::from(10))
+                .payments
+                .len(),
+            1
+        );
+        assert_eq!(canister.totalFeesToRecipient(), Nat::from(4));
+    }
+
+    #[test]
+    fn zero_fee_breakdown() {
+        let canister = test_canister();
+        let id = canister.transfer(bob(), Nat::from(100), None).unwrap();
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.fee_breakdown, Some(FeeBreakdown::default()));
+    }
+
+    #[test]
+    fn set_fee_burn_rate_invalid() {
+        let canister = test_canister();
+        assert_eq!(
+            canister.setFeeBurnRate(10_001),
+            Err(TxError::InvalidFeeBurnRate)
+        );
+        assert_eq!(canister.setFeeBurnRate(10_000), Ok(()));
+        assert_eq!(canister.getFeeBurnRate(), 10_000);
+
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.setFeeBurnRate(0),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(canister.getFeeBurnRate(), 10_000);
+    }
+
     #[test]
     fn transfer_insufficient_balance() {
         let canister = test_canister();
@@ -319,7 +941,25 @@ mod tests {
     }
 
     #[test]
-    fn transfer_wrong_caller() {
+    fn transfer_below_min_amount() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.min_transfer_amount = Nat::from(100);
+
+        assert_eq!(
+            canister.transfer(bob(), Nat::from(99), None),
+            Err(TxError::AmountTooSmall)
+        );
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+
+        assert!(canister.transfer(bob(), Nat::from(100), None).is_ok());
+        assert_eq!(canister.balanceOf(bob()), Nat::from(100));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(890));
+    }
+
+    #[test]
+    fn transfer_wrong_caller() {
         let canister = test_canister();
         MockContext::new().with_caller(bob()).inject();
         assert_eq!(
@@ -360,27 +1000,24 @@ mod tests {
     fn mint_test_token() {
         let canister = test_canister();
         MockContext::new().with_caller(bob()).inject();
+        canister.state.borrow_mut().stats.is_test_token = true;
+
+        // Test tokens are given out with the faucet, so only the minters can mint.
         assert_eq!(
-            canister.mint(alice(), Nat::from(100u32)),
+            canister.mint(alice(), Nat::from(100u32), None),
             Err(TxError::Unauthorized {
                 owner: alice().to_string(),
                 caller: bob().to_string(),
             })
         );
-
-        canister.state.borrow_mut().stats.is_test_token = true;
-
-        assert!(canister.mint(alice(), Nat::from(2000)).is_ok());
-        assert!(canister.mint(bob(), Nat::from(5000)).is_ok());
-        assert_eq!(canister.balanceOf(alice()), Nat::from(3000));
-        assert_eq!(canister.balanceOf(bob()), Nat::from(5000));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
     }
 
     #[test]
     fn mint_by_owner() {
         let canister = test_canister();
-        assert!(canister.mint(alice(), Nat::from(2000)).is_ok());
-        assert!(canister.mint(bob(), Nat::from(5000)).is_ok());
+        assert!(canister.mint(alice(), Nat::from(2000), None).is_ok());
+        assert!(canister.mint(bob(), Nat::from(5000), None).is_ok());
         assert_eq!(canister.balanceOf(alice()), Nat::from(3000));
         assert_eq!(canister.balanceOf(bob()), Nat::from(5000));
         assert_eq!(canister.getMetadata().totalSupply, Nat::from(8000));
@@ -396,7 +1033,7 @@ mod tests {
         const COUNT: usize = 5;
         let mut ts = ic_kit::ic::time().into();
         for i in 0..COUNT {
-            let id = canister.mint(bob(), Nat::from(100 + i)).unwrap();
+            let id = canister.mint(bob(), Nat::from(100 + i), None).unwrap();
             assert_eq!(canister.historySize(), 2 + i);
             let tx = canister.getTransaction(id);
             assert_eq!(tx.amount, Nat::from(100 + i));
@@ -411,6 +1048,306 @@ mod tests {
         }
     }
 
+    #[test]
+    fn mint_up_to_max_supply() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.max_supply = Some(Nat::from(3000));
+
+        assert!(canister.mint(bob(), Nat::from(1500), None).is_ok());
+        assert!(canister.mint(bob(), Nat::from(500), None).is_ok());
+        assert_eq!(canister.totalSupply(), Nat::from(3000));
+        assert_eq!(canister.getMetadata().maxSupply, Some(Nat::from(3000)));
+        assert_eq!(
+            canister.getTokenInfo().metadata.maxSupply,
+            Some(Nat::from(3000))
+        );
+    }
+
+    #[test]
+    fn mint_over_max_supply() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.max_supply = Some(Nat::from(1500));
+
+        assert_eq!(
+            canister.mint(bob(), Nat::from(501), None),
+            Err(TxError::MaxSupplyExceeded)
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+        assert_eq!(canister.totalSupply(), Nat::from(1000));
+        assert_eq!(canister.historySize(), 1);
+    }
+
+    #[test]
+    fn mint_with_reason() {
+        let canister = test_canister();
+        let id = canister
+            .mint(bob(), Nat::from(100), Some("grant".to_string()))
+            .unwrap();
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.reason, Some("grant".to_string()));
+
+        let encoded = candid::Encode!(&tx).unwrap();
+        let decoded = candid::Decode!(&encoded, TxRecord).unwrap();
+        assert_eq!(decoded.reason, Some("grant".to_string()));
+
+        let ids = canister
+            .batchMint(
+                vec![(bob(), Nat::from(100)), (john(), Nat::from(100))],
+                Some("bridge-in".to_string()),
+            )
+            .unwrap();
+        for id in ids {
+            assert_eq!(
+                canister.getTransaction(id).reason,
+                Some("bridge-in".to_string())
+            );
+        }
+
+        let id = canister.mint(bob(), Nat::from(100), None).unwrap();
+        assert_eq!(canister.getTransaction(id).reason, None);
+    }
+
+    #[test]
+    fn mint_reason_too_long() {
+        let canister = test_canister();
+        let history_size = canister.historySize();
+        let reason = "a".repeat(MAX_MINT_REASON_LEN + 1);
+
+        assert_eq!(
+            canister.mint(bob(), Nat::from(100), Some(reason.clone())),
+            Err(TxError::ReasonTooLong {
+                max: MAX_MINT_REASON_LEN as u64
+            })
+        );
+        assert_eq!(
+            canister.batchMint(vec![(bob(), Nat::from(100))], Some(reason)),
+            Err(TxError::ReasonTooLong {
+                max: MAX_MINT_REASON_LEN as u64
+            })
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+        assert_eq!(canister.historySize(), history_size);
+
+        let reason = "a".repeat(MAX_MINT_REASON_LEN);
+        assert!(canister.mint(bob(), Nat::from(100), Some(reason)).is_ok());
+    }
+
+    #[test]
+    fn transfer_with_memo() {
+        let canister = test_canister();
+        let deposit = b"deposit-42".to_vec();
+        let first = canister
+            .transferWithMemo(bob(), Nat::from(100), None, deposit.clone())
+            .unwrap();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        canister
+            .transferWithMemo(john(), Nat::from(100), None, b"other".to_vec())
+            .unwrap();
+        let second = canister
+            .transferWithMemo(john(), Nat::from(50), None, deposit.clone())
+            .unwrap();
+
+        assert_eq!(
+            canister.getTransaction(first.clone()).memo,
+            Some(deposit.clone())
+        );
+        assert_eq!(canister.getTransaction(Nat::from(2)).memo, None);
+
+        let page = canister.findTransactionsByMemo(deposit.clone(), None, Nat::from(10));
+        assert_eq!(page_ids(&page), vec![first.clone(), second.clone()]);
+        assert_eq!(page.total, Nat::from(2));
+        assert_eq!(page.next_cursor, None);
+
+        let page = canister.findTransactionsByMemo(deposit.clone(), None, Nat::from(1));
+        assert_eq!(page_ids(&page), vec![first]);
+        assert_eq!(page.next_cursor, Some(second.clone()));
+
+        let page = canister.findTransactionsByMemo(deposit, page.next_cursor, Nat::from(1));
+        assert_eq!(page_ids(&page), vec![second]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = canister.findTransactionsByMemo(b"never used".to_vec(), None, Nat::from(10));
+        assert!(page.records.is_empty());
+        assert_eq!(page.total, Nat::from(0));
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn caller_transactions() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.approve(bob(), Nat::from(500), None, None).unwrap();
+        let direct = canister.transfer(john(), Nat::from(100), None).unwrap();
+
+        context.update_caller(bob());
+        let first = canister
+            .transferFrom(alice(), john(), Nat::from(10))
+            .unwrap();
+        canister.transfer(john(), Nat::from(5), None).unwrap();
+        let second = canister
+            .transferFrom(alice(), bob(), Nat::from(20))
+            .unwrap();
+
+        let page = canister.getCallerTransactions(bob(), None, Nat::from(10));
+        assert_eq!(page.total, Nat::from(3));
+        let delegated: Vec<_> = page
+            .records
+            .iter()
+            .filter(|tx| tx.from != bob())
+            .map(|tx| tx.index.clone())
+            .collect();
+        assert_eq!(delegated, vec![first.clone(), second.clone()]);
+
+        let page = canister.getCallerTransactions(bob(), Some(second.clone()), Nat::from(10));
+        assert_eq!(page_ids(&page), vec![second]);
+
+        // The spender's transfers are not listed for the owner of the tokens.
+        let page = canister.getCallerTransactions(alice(), None, Nat::from(10));
+        assert!(page_ids(&page).contains(&direct));
+        assert!(!page_ids(&page).contains(&first));
+        assert!(canister
+            .getCallerTransactions(john(), None, Nat::from(10))
+            .records
+            .is_empty());
+    }
+
+    #[test]
+    fn transfer_with_memo_too_long() {
+        let canister = test_canister();
+        let history_size = canister.historySize();
+        assert_eq!(
+            canister.transferWithMemo(bob(), Nat::from(100), None, vec![0; MAX_MEMO_LEN + 1]),
+            Err(TxError::MemoTooLong {
+                max: MAX_MEMO_LEN as u64
+            })
+        );
+        assert_eq!(canister.historySize(), history_size);
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+
+        assert!(canister
+            .transferWithMemo(bob(), Nat::from(100), None, vec![0; MAX_MEMO_LEN])
+            .is_ok());
+    }
+
+    #[test]
+    fn ledger_tip() {
+        let canister = test_canister();
+        let id = canister.transfer(bob(), Nat::from(100), None).unwrap();
+        let tx = canister.getTransaction(id.clone());
+        assert_eq!(
+            tx.parent_hash,
+            Some(crate::ledger::tx_hash(
+                &canister.getTransaction(Nat::from(0))
+            ))
+        );
+
+        let tip = canister.getLedgerTip();
+        assert_eq!(tip.last_id, id);
+        assert_eq!(tip.last_hash, crate::ledger::tx_hash(&tx));
+    }
+
+    #[test]
+    fn certified_tip() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+
+        let tip = canister.getLedgerTip();
+        let certified = canister.getCertifiedTip();
+        assert_eq!(certified.last_id, tip.last_id);
+        assert_eq!(certified.last_hash, tip.last_hash);
+
+        let tree = crate::certified::tip_tree(&tip.last_id, &tip.last_hash);
+        assert_eq!(certified.witness, tree.to_cbor());
+    }
+
+    #[test]
+    fn tx_record_without_reason() {
+        // Record written by a version of the canister without the `reason` field.
+        #[derive(CandidType)]
+        struct PreviousTxRecord {
+            caller: Option<Principal>,
+            index: Nat,
+            from: Principal,
+            to: Principal,
+            amount: Nat,
+            fee: Nat,
+            timestamp: candid::Int,
+            status: TransactionStatus,
+            operation: Operation,
+        }
+
+        let encoded = candid::Encode!(&PreviousTxRecord {
+            caller: Some(alice()),
+            index: Nat::from(0),
+            from: alice(),
+            to: bob(),
+            amount: Nat::from(100),
+            fee: Nat::from(0),
+            timestamp: candid::Int::from(0),
+            status: TransactionStatus::Succeeded,
+            operation: Operation::Mint,
+        })
+        .unwrap();
+        let decoded = candid::Decode!(&encoded, TxRecord).unwrap();
+        assert_eq!(decoded.reason, None);
+        assert_eq!(decoded.memo, None);
+        assert_eq!(decoded.parent_hash, None);
+        assert_eq!(decoded.fee_breakdown, None);
+        assert_eq!(decoded.amount, Nat::from(100));
+    }
+
+    #[test]
+    fn user_transactions_index_is_consistent() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.state.borrow_mut().stats.fee = Nat::from(1);
+        canister.state.borrow_mut().stats.fee_to = john();
+
+        canister.mint(bob(), Nat::from(500), None).unwrap();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        canister.approve(bob(), Nat::from(200), None, None).unwrap();
+        canister.burn(Nat::from(10)).unwrap();
+
+        context.update_caller(bob());
+        canister
+            .transferFrom(alice(), john(), Nat::from(50))
+            .unwrap();
+        canister.burnFrom(alice(), Nat::from(20)).unwrap();
+        canister.transferIncludeFee(john(), Nat::from(30)).unwrap();
+
+        let all = canister.getTransactions(Nat::from(0), Nat::from(100));
+        for user in [alice(), bob(), john()] {
+            let expected: Vec<_> = all
+                .iter()
+                .filter(|tx| tx.from == user || tx.to == user || tx.caller == Some(user))
+                .map(|tx| tx.index.clone())
+                .collect();
+            let actual: Vec<_> = canister
+                .getUserTransactions(user, Nat::from(0), Nat::from(100))
+                .into_iter()
+                .map(|tx| tx.index)
+                .collect();
+            assert_eq!(actual, expected);
+
+            let amount = all
+                .iter()
+                .filter(|tx| tx.from == user || tx.to == user || tx.caller == Some(user))
+                .fold(Nat::from(0), |sum, tx| sum + tx.amount.clone());
+            assert_eq!(canister.getUserTransactionAmount(user), amount);
+            assert_eq!(canister.getUserTransactionCount(user), expected.len());
+        }
+
+        // Bob: mint, transfer, approve, transferFrom, burnFrom and transferIncludeFee.
+        assert_eq!(canister.getUserTransactionCount(bob()), 6u32);
+        assert_eq!(canister.getUserTransactionAmount(bob()), 900u32);
+        assert_eq!(canister.getUserTransactionCount(john()), 2u32);
+
+        let bob_txs = canister.getUserTransactions(bob(), Nat::from(1), Nat::from(2));
+        assert_eq!(bob_txs.len(), 2);
+        assert_eq!(bob_txs[0].operation, Operation::Transfer);
+        assert_eq!(bob_txs[1].operation, Operation::Approve);
+    }
+
     #[test]
     fn burn_by_owner() {
         let canister = test_canister();
@@ -419,6 +1356,119 @@ mod tests {
         assert_eq!(canister.getMetadata().totalSupply, Nat::from(900));
     }
 
+    #[test]
+    fn total_burned() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.setFeeBurnRate(5_000).unwrap();
+        assert_eq!(canister.totalBurned(), Nat::from(0));
+
+        canister.burn(Nat::from(100)).unwrap();
+        canister.approve(bob(), Nat::from(50), None, None).unwrap();
+        canister.transfer(john(), Nat::from(100), None).unwrap();
+
+        context.update_caller(bob());
+        canister.burnFrom(alice(), Nat::from(20)).unwrap();
+
+        // 100 burned directly, 20 with an allowance and a half of two fees.
+        assert_eq!(canister.totalBurned(), Nat::from(130));
+        assert_eq!(canister.getTokenInfo().totalBurned, Nat::from(130));
+        assert_eq!(canister.totalSupply(), Nat::from(870));
+    }
+
+    #[test]
+    fn total_minted() {
+        let canister = test_canister();
+        assert_eq!(canister.totalMinted(), Nat::from(1000));
+
+        canister.mint(bob(), Nat::from(500), None).unwrap();
+        canister.burn(Nat::from(200)).unwrap();
+        canister
+            .batchMint(vec![(bob(), Nat::from(100)), (john(), Nat::from(50))], None)
+            .unwrap();
+        canister.mintVested(john(), Nat::from(300), 0, 10).unwrap();
+        canister.burn(Nat::from(100)).unwrap();
+
+        assert_eq!(canister.totalMinted(), Nat::from(1950));
+        assert_eq!(canister.totalBurned(), Nat::from(300));
+        assert_eq!(
+            canister.totalMinted() - canister.totalBurned(),
+            canister.totalSupply()
+        );
+    }
+
+    /// Checks that the balances add up to the total supply, and that there are no holder entries
+    /// with zero balance.
+    fn assert_supply_consistent(canister: &TokenCanister) {
+        let state = canister.state.borrow();
+        let sum = state
+            .balances
+            .0
+            .values()
+            .fold(Nat::from(0), |sum, balance| sum + balance.clone());
+        assert_eq!(sum, state.stats.total_supply);
+        assert!(state.balances.0.values().all(|balance| *balance != 0u32));
+    }
+
+    #[test]
+    fn burn_underflow_keeps_state() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        let history_size = canister.historySize();
+
+        assert_eq!(
+            canister.burn(Nat::from(891)),
+            Err(TxError::InsufficientBalance)
+        );
+        assert_eq!(canister.balanceOf(alice()), Nat::from(890));
+        assert_eq!(canister.totalBurned(), Nat::from(0));
+        assert_eq!(canister.historySize(), history_size);
+        assert_supply_consistent(&canister);
+
+        canister.burn(Nat::from(890)).unwrap();
+        assert_eq!(canister.getTokenInfo().holderNumber, 2);
+        assert_supply_consistent(&canister);
+    }
+
+    #[test]
+    fn transfer_from_underflow_keeps_state() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+        canister.setFeeBurnRate(5_000).unwrap();
+        canister
+            .approve(bob(), Nat::from(2000), None, None)
+            .unwrap();
+        let history_size = canister.historySize();
+        let supply = canister.totalSupply();
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.transferFrom(alice(), bob(), Nat::from(985)),
+            Err(TxError::InsufficientBalance)
+        );
+        assert_eq!(
+            canister.burnFrom(alice(), Nat::from(2011)),
+            Err(TxError::InsufficientAllowance)
+        );
+        assert_eq!(canister.balanceOf(alice()), Nat::from(990));
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(2010));
+        assert_eq!(canister.totalSupply(), supply);
+        assert_eq!(canister.historySize(), history_size);
+        assert_supply_consistent(&canister);
+
+        canister
+            .transferFrom(alice(), bob(), Nat::from(980))
+            .unwrap();
+        assert_eq!(canister.balanceOf(alice()), Nat::from(0));
+        assert_supply_consistent(&canister);
+    }
+
     #[test]
     fn burn_too_much() {
         let canister = test_canister();
@@ -430,6 +1480,46 @@ mod tests {
         assert_eq!(canister.getMetadata().totalSupply, Nat::from(1000));
     }
 
+    #[test]
+    fn burn_zero() {
+        let canister = test_canister();
+        let history_size = canister.historySize();
+        assert_eq!(canister.burn(Nat::from(0)), Err(TxError::AmountTooSmall));
+        assert_eq!(canister.historySize(), history_size);
+
+        // Zero burn is rejected even if the caller has no tokens.
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(canister.burn(Nat::from(0)), Err(TxError::AmountTooSmall));
+    }
+
+    #[test]
+    fn burn_charges_no_fee() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+
+        let id = canister.burn(Nat::from(1000)).unwrap();
+        assert_eq!(canister.getTransaction(id).fee, Nat::from(0));
+        assert_eq!(canister.balanceOf(john()), Nat::from(0));
+        assert_eq!(canister.totalSupply(), Nat::from(0));
+    }
+
+    #[test]
+    fn burn_whole_balance_removes_holder() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        assert_eq!(canister.getTokenInfo().holderNumber, 2);
+
+        MockContext::new().with_caller(bob()).inject();
+        canister.burn(Nat::from(50)).unwrap();
+        assert_eq!(canister.getTokenInfo().holderNumber, 2);
+
+        canister.burn(Nat::from(50)).unwrap();
+        assert_eq!(canister.getTokenInfo().holderNumber, 1);
+        assert!(!canister.state.borrow().balances.0.contains_key(&bob()));
+        assert_supply_consistent(&canister);
+    }
+
     #[test]
     fn burn_by_wrong_user() {
         let canister = test_canister();
@@ -469,11 +1559,94 @@ mod tests {
         }
     }
 
+    #[test]
+    fn burn_from() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.approve(bob(), Nat::from(500), None, None).unwrap();
+
+        context.update_caller(bob());
+        let id = canister.burnFrom(alice(), Nat::from(200)).unwrap();
+        assert_eq!(canister.balanceOf(alice()), Nat::from(800));
+        assert_eq!(canister.getMetadata().totalSupply, Nat::from(800));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(300));
+
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::Burn);
+        assert_eq!(tx.caller, Some(bob()));
+        assert_eq!(tx.from, alice());
+        assert_eq!(tx.amount, Nat::from(200));
+        assert_eq!(tx.fee, Nat::from(0));
+
+        canister.burnFrom(alice(), Nat::from(300)).unwrap();
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+        assert_eq!(canister.getMetadata().totalSupply, Nat::from(500));
+    }
+
+    #[test]
+    fn burn_from_insufficient_allowance() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.burnFrom(alice(), Nat::from(101)),
+            Err(TxError::InsufficientAllowance)
+        );
+        assert_eq!(
+            canister.burnFrom(john(), Nat::from(1)),
+            Err(TxError::InsufficientAllowance)
+        );
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+        assert_eq!(canister.getMetadata().totalSupply, Nat::from(1000));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(100));
+        assert_eq!(canister.historySize(), 2);
+    }
+
+    #[test]
+    fn burn_from_zero() {
+        let canister = test_canister();
+        let history_size = canister.historySize();
+
+        // Zero burn is rejected even without an allowance, so it can't be recorded on behalf of
+        // any account.
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.burnFrom(alice(), Nat::from(0)),
+            Err(TxError::AmountTooSmall)
+        );
+        assert_eq!(canister.historySize(), history_size);
+        assert!(canister
+            .state
+            .borrow()
+            .allowances
+            .get(&alice())
+            .map_or(true, |allowances| !allowances.contains_key(&bob())));
+    }
+
+    #[test]
+    fn burn_from_insufficient_balance() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister
+            .approve(bob(), Nat::from(2000), None, None)
+            .unwrap();
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.burnFrom(alice(), Nat::from(1001)),
+            Err(TxError::InsufficientBalance)
+        );
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(2000));
+        assert_eq!(canister.getMetadata().totalSupply, Nat::from(1000));
+    }
+
     #[test]
     fn transfer_from_with_approve() {
         let canister = test_canister();
         let context = MockContext::new().with_caller(alice()).inject();
-        assert!(canister.approve(bob(), Nat::from(500)).is_ok());
+        assert!(canister.approve(bob(), Nat::from(500), None, None).is_ok());
         context.update_caller(bob());
 
         assert!(canister
@@ -499,7 +1672,7 @@ mod tests {
     fn insufficient_allowance() {
         let canister = test_canister();
         let context = MockContext::new().with_caller(alice()).inject();
-        assert!(canister.approve(bob(), Nat::from(500)).is_ok());
+        assert!(canister.approve(bob(), Nat::from(500), None, None).is_ok());
         context.update_caller(bob());
         assert_eq!(
             canister.transferFrom(alice(), john(), Nat::from(600)),
@@ -510,83 +1683,1166 @@ mod tests {
     }
 
     #[test]
-    fn transfer_from_without_approve() {
+    fn transfer_from_remaining_allowance() {
         let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
         let context = MockContext::new().with_caller(alice()).inject();
+        assert!(canister.approve(bob(), Nat::from(300), None, None).is_ok());
         context.update_caller(bob());
+
+        // The allowance includes the approval fee, so it's 310 at the start.
+        let receipt = canister
+            .transferFromV2(alice(), john(), Nat::from(100))
+            .unwrap();
+        assert_eq!(receipt.remaining_allowance, Nat::from(200));
         assert_eq!(
-            canister.transferFrom(alice(), john(), Nat::from(600)),
+            canister.getTransaction(receipt.tx_id).amount,
+            Nat::from(100)
+        );
+
+        let receipt = canister
+            .transferFromV2(alice(), john(), Nat::from(150))
+            .unwrap();
+        assert_eq!(receipt.remaining_allowance, Nat::from(40));
+
+        let receipt = canister
+            .transferFromV2(alice(), john(), Nat::from(30))
+            .unwrap();
+        assert_eq!(receipt.remaining_allowance, Nat::from(0));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+
+        assert_eq!(
+            canister.transferFromV2(alice(), john(), Nat::from(1)),
             Err(TxError::InsufficientAllowance)
         );
-        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
-        assert_eq!(canister.balanceOf(john()), Nat::from(0));
     }
 
     #[test]
-    fn transfer_from_saved_into_history() {
+    fn spender_whitelist() {
         let canister = test_canister();
-        let context = MockContext::new().with_caller(alice()).inject();
-        canister.state.borrow_mut().stats.fee = Nat::from(10);
-
+        canister.setSpenderWhitelistEnabled(true).unwrap();
+        assert_eq!(
+            canister.approve(bob(), Nat::from(100), None, None),
+            Err(TxError::SpenderNotAllowed)
+        );
+        assert_eq!(
+            canister.increaseAllowance(bob(), Nat::from(100)),
+            Err(TxError::SpenderNotAllowed)
+        );
+
+        canister.addApprovedSpender(bob()).unwrap();
+        assert_eq!(canister.getApprovedSpenders(), vec![bob()]);
+        assert!(canister.approve(bob(), Nat::from(100), None, None).is_ok());
+        assert_eq!(
+            canister.approve(john(), Nat::from(100), None, None),
+            Err(TxError::SpenderNotAllowed)
+        );
+
+        canister.setSpenderWhitelistEnabled(false).unwrap();
+        assert!(canister.approve(john(), Nat::from(100), None, None).is_ok());
+    }
+
+    #[test]
+    fn spender_whitelist_blocks_existing_allowance() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        assert!(canister.approve(bob(), Nat::from(500), None, None).is_ok());
+
+        canister.addApprovedSpender(bob()).unwrap();
+        canister.setSpenderWhitelistEnabled(true).unwrap();
+        canister.removeApprovedSpender(bob()).unwrap();
+        assert_eq!(canister.getApprovedSpenders(), vec![]);
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(100)),
+            Err(TxError::SpenderNotAllowed)
+        );
+
+        context.update_caller(alice());
+        canister.addApprovedSpender(bob()).unwrap();
+        context.update_caller(bob());
+        assert!(canister
+            .transferFrom(alice(), john(), Nat::from(100))
+            .is_ok());
+    }
+
+    #[test]
+    fn spender_whitelist_not_authorized() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.setSpenderWhitelistEnabled(true),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(
+            canister.addApprovedSpender(bob()),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn transfer_from_below_min_amount() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.state.borrow_mut().stats.min_transfer_amount = Nat::from(100);
+        assert!(canister.approve(bob(), Nat::from(500), None, None).is_ok());
+        context.update_caller(bob());
+
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(50)),
+            Err(TxError::AmountTooSmall)
+        );
+        assert!(canister
+            .transferFrom(alice(), john(), Nat::from(100))
+            .is_ok());
+        assert_eq!(canister.balanceOf(alice()), Nat::from(900));
+        assert_eq!(canister.balanceOf(john()), Nat::from(100));
+    }
+
+    #[test]
+    fn transfer_from_without_approve() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        context.update_caller(bob());
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(600)),
+            Err(TxError::InsufficientAllowance)
+        );
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+        assert_eq!(canister.balanceOf(john()), Nat::from(0));
+    }
+
+    #[test]
+    fn transfer_from_zero_without_approve() {
+        let canister = test_canister();
+        canister.setAllowanceNotifications(true);
+        let history_size = canister.historySize();
+
+        // The zero transfer from an account that gave no allowance is neither recorded nor
+        // notified to the owner.
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(0)),
+            Err(TxError::AmountTooSmall)
+        );
+        assert_eq!(canister.historySize(), history_size);
+        assert!(canister.getPendingOwnerNotifications().is_empty());
+    }
+
+    #[test]
+    fn transfer_from_saved_into_history() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+
         canister
             .transferFrom(bob(), john(), Nat::from(10))
             .unwrap_err();
         assert_eq!(canister.historySize(), 1);
 
-        canister.approve(bob(), Nat::from(1000)).unwrap();
-        context.update_caller(bob());
+        canister
+            .approve(bob(), Nat::from(1000), None, None)
+            .unwrap();
+        context.update_caller(bob());
+
+        const COUNT: usize = 5;
+        let mut ts = ic_kit::ic::time().into();
+        for i in 0..COUNT {
+            let id = canister
+                .transferFrom(alice(), john(), Nat::from(100 + i))
+                .unwrap();
+            assert_eq!(canister.historySize(), 3 + i);
+            let tx = canister.getTransaction(id);
+            assert_eq!(tx.caller, Some(bob()));
+            assert_eq!(tx.amount, Nat::from(100 + i));
+            assert_eq!(tx.fee, Nat::from(10));
+            assert_eq!(tx.operation, Operation::TransferFrom);
+            assert_eq!(tx.status, TransactionStatus::Succeeded);
+            assert_eq!(tx.index, i + 2);
+            assert_eq!(tx.from, alice());
+            assert_eq!(tx.to, john());
+            assert!(ts < tx.timestamp);
+            ts = tx.timestamp;
+        }
+    }
+
+    /// Returns the approvals of the user with `created_at` set to zero, to compare them with the
+    /// expected values.
+    fn approvals_without_time(
+        canister: &TokenCanister,
+        who: Principal,
+    ) -> Vec<(Principal, Allowance)> {
+        canister
+            .getUserApprovals(who)
+            .into_iter()
+            .map(|(spender, allowance)| {
+                (
+                    spender,
+                    Allowance {
+                        created_at: 0,
+                        ..allowance
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn multiple_approves() {
+        let canister = test_canister();
+        assert!(canister.approve(bob(), Nat::from(500), None, None).is_ok());
+        assert_eq!(
+            approvals_without_time(&canister, alice()),
+            vec![(
+                bob(),
+                Allowance {
+                    value: Nat::from(500),
+                    ..Default::default()
+                }
+            )]
+        );
+
+        assert!(canister.approve(bob(), Nat::from(200), None, None).is_ok());
+        assert_eq!(
+            approvals_without_time(&canister, alice()),
+            vec![(
+                bob(),
+                Allowance {
+                    value: Nat::from(200),
+                    ..Default::default()
+                }
+            )]
+        );
+
+        assert!(canister
+            .approve(john(), Nat::from(1000), None, None)
+            .is_ok());
+
+        // Convert vectors to sets before comparing to make comparison unaffected by the element
+        // order.
+        assert_eq!(
+            HashSet::<(Principal, Nat)>::from_iter(
+                canister
+                    .getUserApprovals(alice())
+                    .into_iter()
+                    .map(|(spender, allowance)| (spender, allowance.value))
+            ),
+            HashSet::from_iter(vec![(bob(), Nat::from(200)), (john(), Nat::from(1000))])
+        );
+    }
+
+    fn expire_allowance(canister: &TokenCanister, owner: Principal, spender: Principal) {
+        let mut state = canister.state.borrow_mut();
+        let allowance = state
+            .allowances
+            .get_mut(&owner)
+            .and_then(|inner| inner.get_mut(&spender))
+            .unwrap();
+        allowance.expires_at = Some(ic_kit::ic::time());
+    }
+
+    #[test]
+    fn expired_approve() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        let expires_at = ic_kit::ic::time() + 1_000;
+        assert!(canister
+            .approve(bob(), Nat::from(500), Some(expires_at), None)
+            .is_ok());
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(500));
+        assert_eq!(
+            approvals_without_time(&canister, alice()),
+            vec![(
+                bob(),
+                Allowance {
+                    value: Nat::from(500),
+                    expires_at: Some(expires_at),
+                    ..Default::default()
+                }
+            )]
+        );
+
+        context.update_caller(bob());
+        assert!(canister
+            .transferFrom(alice(), john(), Nat::from(100))
+            .is_ok());
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(400));
+
+        expire_allowance(&canister, alice(), bob());
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+        assert_eq!(canister.getUserApprovals(alice()), vec![]);
+        assert_eq!(canister.getAllowanceSize(), 0);
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(100)),
+            Err(TxError::InsufficientAllowance)
+        );
+        assert_eq!(canister.balanceOf(john()), Nat::from(100));
+    }
+
+    #[test]
+    fn expired_approve_purged() {
+        let canister = test_canister();
+        let expires_at = ic_kit::ic::time() + 1_000;
+        assert!(canister
+            .approve(bob(), Nat::from(500), Some(expires_at), None)
+            .is_ok());
+
+        expire_allowance(&canister, alice(), bob());
+        assert!(canister.approve(john(), Nat::from(500), None, None).is_ok());
+        assert!(!canister.state.borrow().allowances[&alice()].contains_key(&bob()));
+    }
+
+    #[test]
+    fn get_allowances_pages() {
+        let canister = test_canister();
+        const COUNT: usize = MAX_ALLOWANCES_QUERY_LEN + 500;
+        {
+            let mut state = canister.state.borrow_mut();
+            for i in 0..COUNT {
+                let owner = Principal::from_slice(&[1, (i >> 8) as u8, i as u8]);
+                let allowance = Allowance {
+                    value: Nat::from(i + 1),
+                    ..Default::default()
+                };
+                _set_allowance(&mut state, owner, bob(), allowance);
+            }
+        }
+        assert_eq!(canister.getAllowanceSize(), COUNT);
+
+        let first = canister.getAllowances(0, COUNT);
+        assert_eq!(first.len(), MAX_ALLOWANCES_QUERY_LEN);
+
+        let second = canister.getAllowances(MAX_ALLOWANCES_QUERY_LEN, COUNT);
+        assert_eq!(second.len(), COUNT - MAX_ALLOWANCES_QUERY_LEN);
+
+        let owners = first
+            .iter()
+            .chain(second.iter())
+            .map(|(owner, _, _)| *owner)
+            .collect::<HashSet<_>>();
+        assert_eq!(owners.len(), COUNT);
+        assert!(first.last().unwrap().0 < second[0].0);
+    }
+
+    #[test]
+    fn export_approvals() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+
+        let expires_at = ic_kit::ic::time() + 1_000_000;
+        canister
+            .approve(
+                bob(),
+                Nat::from(100),
+                Some(expires_at),
+                Some((Nat::from(50), 60)),
+            )
+            .unwrap();
+        canister.approveUnlimited(john()).unwrap();
+        context.update_caller(bob());
+        canister.approve(john(), Nat::from(10), None, None).unwrap();
+
+        let page = canister.exportApprovals(0, 2);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.records.len(), 2);
+
+        let mut expected = vec![(alice(), bob()), (alice(), john()), (bob(), john())];
+        expected.sort();
+        let rest = canister.exportApprovals(2, 2);
+        assert_eq!(rest.total, 3);
+        let keys = page
+            .records
+            .iter()
+            .chain(rest.records.iter())
+            .map(|r| (r.owner, r.spender))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+
+        let record = page
+            .records
+            .iter()
+            .chain(rest.records.iter())
+            .find(|r| r.owner == alice() && r.spender == bob())
+            .unwrap()
+            .clone();
+        assert_eq!(record.value, Nat::from(100));
+        assert_eq!(record.expires_at, Some(expires_at));
+        assert_eq!(
+            record.spending_limit.map(|limit| limit.limit_per_period),
+            Some(Nat::from(50))
+        );
+        assert!(!record.unlimited);
+        assert!(record.created_at > 0);
+
+        // Consuming the allowance doesn't change the creation time.
+        context.update_caller(bob());
+        canister
+            .transferFrom(alice(), bob(), Nat::from(10))
+            .unwrap();
+        let updated = canister
+            .exportApprovals(0, 3)
+            .records
+            .into_iter()
+            .find(|r| r.owner == alice() && r.spender == bob())
+            .unwrap();
+        assert_eq!(updated.value, Nat::from(90));
+        assert_eq!(updated.created_at, record.created_at);
+    }
+
+    #[test]
+    fn export_approvals_limit() {
+        let canister = test_canister();
+        {
+            let mut state = canister.state.borrow_mut();
+            for i in 0..MAX_ALLOWANCES_QUERY_LEN + 1 {
+                let owner = Principal::from_slice(&[4, (i >> 8) as u8, i as u8]);
+                let allowance = Allowance {
+                    value: Nat::from(1),
+                    ..Default::default()
+                };
+                _set_allowance(&mut state, owner, bob(), allowance);
+            }
+        }
+
+        let page = canister.exportApprovals(0, usize::MAX);
+        assert_eq!(page.total, MAX_ALLOWANCES_QUERY_LEN + 1);
+        assert_eq!(page.records.len(), MAX_ALLOWANCES_QUERY_LEN);
+        assert_eq!(
+            canister
+                .exportApprovals(MAX_ALLOWANCES_QUERY_LEN, 10)
+                .records
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn approvals_page_candid_round_trip() {
+        let page = ApprovalsPage {
+            records: vec![ApprovalRecord {
+                owner: alice(),
+                spender: bob(),
+                value: Nat::from(100),
+                expires_at: Some(42),
+                spending_limit: Some(SpendingLimit {
+                    limit_per_period: Nat::from(10),
+                    period: 1_000,
+                    remaining: Nat::from(5),
+                    resets_at: 500,
+                }),
+                unlimited: false,
+                created_at: 7,
+            }],
+            total: 1,
+        };
+
+        let encoded = candid::Encode!(&page).unwrap();
+        let decoded = candid::Decode!(&encoded, ApprovalsPage).unwrap();
+        assert_eq!(decoded, page);
+    }
+
+    #[test]
+    fn allowance_usage_statistics() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.approve(bob(), Nat::from(500), None, None).unwrap();
+
+        let details = canister.getUserApprovalsDetailed(alice());
+        assert_eq!(details.len(), 1);
+        assert_eq!(details[0].spender, bob());
+        assert_eq!(details[0].times_used, 0);
+        assert_eq!(details[0].last_used, None);
+
+        context.update_caller(bob());
+        let mut last_used = 0;
+        for i in 1..=3 {
+            canister
+                .transferFrom(alice(), john(), Nat::from(100))
+                .unwrap();
+
+            let details = canister.getUserApprovalsDetailed(alice());
+            assert_eq!(details[0].times_used, i);
+            assert!(details[0].last_used.unwrap() > last_used);
+            assert_eq!(details[0].value, Nat::from(500 - 100 * i));
+            last_used = details[0].last_used.unwrap();
+        }
+
+        // Failed transfers are not counted.
+        assert!(canister
+            .transferFrom(alice(), john(), Nat::from(1000))
+            .is_err());
+        let details = canister.getUserApprovalsDetailed(alice());
+        assert_eq!(details[0].times_used, 3);
+        assert_eq!(details[0].last_used, Some(last_used));
+    }
+
+    #[test]
+    fn allowance_without_usage_statistics_decoded() {
+        #[derive(CandidType)]
+        struct PreviousAllowance {
+            value: Nat,
+            expires_at: Option<Timestamp>,
+            one_time: bool,
+            spending_limit: Option<SpendingLimit>,
+            unlimited: bool,
+            created_at: Timestamp,
+        }
+
+        let encoded = candid::Encode!(&PreviousAllowance {
+            value: Nat::from(100),
+            expires_at: None,
+            one_time: false,
+            spending_limit: None,
+            unlimited: false,
+            created_at: 10,
+        })
+        .unwrap();
+        let decoded = candid::Decode!(&encoded, Allowance).unwrap();
+        assert_eq!(
+            decoded,
+            Allowance {
+                value: Nat::from(100),
+                created_at: 10,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn spending_limit() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        const PERIOD_SEC: u64 = 30 * 24 * 60 * 60;
+        assert!(canister
+            .approve(
+                bob(),
+                Nat::from(500),
+                None,
+                Some((Nat::from(100), PERIOD_SEC))
+            )
+            .is_ok());
+
+        context.update_caller(bob());
+        assert!(canister
+            .transferFrom(alice(), john(), Nat::from(60))
+            .is_ok());
+        assert!(canister
+            .transferFrom(alice(), john(), Nat::from(40))
+            .is_ok());
+
+        let resets_at = canister.getUserApprovals(alice())[0]
+            .1
+            .spending_limit
+            .as_ref()
+            .unwrap()
+            .resets_at;
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(1)),
+            Err(TxError::SpendingLimitExceeded { resets_at })
+        );
+        assert_eq!(canister.balanceOf(john()), Nat::from(100));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(400));
+    }
+
+    #[test]
+    fn spending_limit_reset() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        assert!(canister
+            .approve(bob(), Nat::from(500), None, Some((Nat::from(100), 60)))
+            .is_ok());
+
+        context.update_caller(bob());
+        assert!(canister
+            .transferFrom(alice(), john(), Nat::from(100))
+            .is_ok());
+        let limit = canister.getUserApprovals(alice())[0]
+            .1
+            .spending_limit
+            .clone()
+            .unwrap();
+        assert_eq!(limit.remaining, Nat::from(0));
+
+        // Move the period end into the past to simulate the period rollover.
+        canister
+            .state
+            .borrow_mut()
+            .allowances
+            .get_mut(&alice())
+            .and_then(|inner| inner.get_mut(&bob()))
+            .and_then(|allowance| allowance.spending_limit.as_mut())
+            .unwrap()
+            .resets_at = ic_kit::ic::time();
+
+        let limit = canister.getUserApprovals(alice())[0]
+            .1
+            .spending_limit
+            .clone()
+            .unwrap();
+        assert_eq!(limit.remaining, Nat::from(100));
+        assert!(limit.resets_at > ic_kit::ic::time());
+
+        assert!(canister
+            .transferFrom(alice(), john(), Nat::from(100))
+            .is_ok());
+        assert_eq!(canister.balanceOf(john()), Nat::from(200));
+    }
+
+    #[test]
+    fn spending_limit_zero_period() {
+        let canister = test_canister();
+        assert_eq!(
+            canister.approve(bob(), Nat::from(500), None, Some((Nat::from(100), 0))),
+            Err(TxError::InvalidSpendingLimit)
+        );
+    }
+
+    #[test]
+    fn user_approvals_pages() {
+        let canister = test_canister();
+        let spender = |i: u8| Principal::from_slice(&[3, i]);
+        for i in (0..10).step_by(2) {
+            assert!(canister
+                .approve(spender(i), Nat::from(100), None, None)
+                .is_ok());
+        }
+        assert_eq!(canister.getUserApprovalsCount(alice()), 5);
+
+        let first = canister.getUserApprovalsPage(alice(), 0, 3);
+        assert_eq!(
+            first.iter().map(|(p, _)| *p).collect::<Vec<_>>(),
+            vec![spender(0), spender(2), spender(4)]
+        );
+
+        // An approval given between the requests doesn't affect the order of the existing entries.
+        assert!(canister
+            .approve(spender(9), Nat::from(100), None, None)
+            .is_ok());
+        assert_eq!(canister.getUserApprovalsCount(alice()), 6);
+
+        let second = canister.getUserApprovalsPage(alice(), 3, 3);
+        assert_eq!(
+            second.iter().map(|(p, _)| *p).collect::<Vec<_>>(),
+            vec![spender(6), spender(8), spender(9)]
+        );
+
+        assert_eq!(canister.getUserApprovalsPage(alice(), 6, 3), vec![]);
+        assert_eq!(canister.getUserApprovals(alice()).len(), 6);
+    }
+
+    #[test]
+    fn purge_allowances() {
+        let canister = test_canister();
+        const COUNT: usize = 5000;
+        {
+            let mut state = canister.state.borrow_mut();
+            for i in 0..COUNT {
+                let owner = Principal::from_slice(&[2, (i >> 8) as u8, i as u8]);
+                // Every other entry is expired, the rest have zero value.
+                let allowance = if i % 2 == 0 {
+                    Allowance {
+                        value: Nat::from(100),
+                        expires_at: Some(ic_kit::ic::time()),
+                        ..Default::default()
+                    }
+                } else {
+                    Allowance::default()
+                };
+                state.approved_totals.entry(owner).or_default().total += allowance.value.clone();
+                state
+                    .allowances
+                    .entry(owner)
+                    .or_default()
+                    .insert(bob(), allowance);
+            }
+        }
+        assert!(canister.approve(john(), Nat::from(100), None, None).is_ok());
+        assert_eq!(canister.state.borrow().allowances.len(), COUNT + 1);
+
+        let mut removed = 0;
+        loop {
+            let purged = canister.purgeAllowances(1000);
+            assert!(purged <= 1000);
+            if purged == 0 {
+                break;
+            }
+            removed += purged;
+        }
+
+        assert_eq!(removed, COUNT as u64);
+        assert_eq!(canister.state.borrow().allowances.len(), 1);
+        assert_eq!(canister.state.borrow().approved_totals.len(), 1);
+        assert_eq!(canister.allowance(alice(), john()), Nat::from(100));
+        assert_eq!(canister.purgeAllowances(1000), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn purge_allowances_not_authorized() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+        canister.purgeAllowances(1000);
+    }
+
+    fn assert_spender_index_consistent(canister: &TokenCanister) {
+        let state = canister.state.borrow();
+        let forward = state
+            .allowances
+            .iter()
+            .flat_map(|(&owner, inner)| inner.keys().map(move |&spender| (spender, owner)))
+            .collect::<HashSet<_>>();
+        let reverse = state
+            .spender_index
+            .0
+            .iter()
+            .flat_map(|(&spender, owners)| owners.iter().map(move |&owner| (spender, owner)))
+            .collect::<HashSet<_>>();
+        assert_eq!(forward, reverse);
+    }
+
+    #[test]
+    fn approvals_for_spender() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.transfer(bob(), Nat::from(300), None).unwrap();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+        canister
+            .approve(john(), Nat::from(200), None, None)
+            .unwrap();
+
+        context.update_caller(bob());
+        canister.approve(john(), Nat::from(50), None, None).unwrap();
+
+        let mut expected = vec![(alice(), Nat::from(200)), (bob(), Nat::from(50))];
+        expected.sort();
+        assert_eq!(canister.approvalsForSpender(john(), 0, 10), expected);
+        assert_eq!(
+            canister.approvalsForSpender(john(), 1, 10),
+            expected[1..].to_vec()
+        );
+        assert_eq!(
+            canister.approvalsForSpender(bob(), 0, 10),
+            vec![(alice(), Nat::from(100))]
+        );
+        assert_spender_index_consistent(&canister);
+
+        // Allowance consumed completely.
+        context.update_caller(john());
+        canister
+            .transferFrom(alice(), john(), Nat::from(200))
+            .unwrap();
+        assert_eq!(
+            canister.approvalsForSpender(john(), 0, 10),
+            vec![(bob(), Nat::from(50))]
+        );
+        assert_spender_index_consistent(&canister);
+
+        context.update_caller(alice());
+        canister.revokeAllApprovals().unwrap();
+        assert_eq!(canister.approvalsForSpender(bob(), 0, 10), vec![]);
+        assert_spender_index_consistent(&canister);
+
+        context.update_caller(bob());
+        canister.decreaseAllowance(john(), Nat::from(50)).unwrap();
+        assert_eq!(canister.approvalsForSpender(john(), 0, 10), vec![]);
+        assert!(canister.state.borrow().spender_index.0.is_empty());
+    }
+
+    #[test]
+    fn approvals_for_spender_expired() {
+        let canister = test_canister();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+        canister
+            .approve(john(), Nat::from(100), None, None)
+            .unwrap();
+
+        expire_allowance(&canister, alice(), bob());
+        assert_eq!(canister.approvalsForSpender(bob(), 0, 10), vec![]);
+
+        // Expired entries are removed from the index with the next update of the owner allowances.
+        canister
+            .approve(john(), Nat::from(200), None, None)
+            .unwrap();
+        assert_spender_index_consistent(&canister);
+        assert_eq!(canister.approvalsForSpender(bob(), 0, 10), vec![]);
+        assert_eq!(
+            canister.approvalsForSpender(john(), 0, 10),
+            vec![(alice(), Nat::from(200))]
+        );
+    }
+
+    #[test]
+    fn spender_index_survives_upgrade() {
+        let canister = test_canister();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+
+        canister.__pre_upgrade_inst();
+        canister.state.borrow_mut().spender_index = Default::default();
+        canister.__post_upgrade_inst();
+
+        assert_eq!(
+            canister.approvalsForSpender(bob(), 0, 10),
+            vec![(alice(), Nat::from(100))]
+        );
+        assert_spender_index_consistent(&canister);
+    }
+
+    fn brute_force_approved_total(canister: &TokenCanister, owner: Principal) -> Nat {
+        canister
+            .getUserApprovalsPage(owner, 0, usize::MAX)
+            .into_iter()
+            .fold(Nat::from(0), |sum, (_, allowance)| sum + allowance.value)
+    }
+
+    #[test]
+    fn approved_total() {
+        let canister = test_canister();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+        canister
+            .approve(john(), Nat::from(200), None, None)
+            .unwrap();
+        assert_eq!(canister.getApprovedTotal(alice()), Nat::from(300));
+
+        canister.approve(bob(), Nat::from(50), None, None).unwrap();
+        assert_eq!(canister.getApprovedTotal(alice()), Nat::from(250));
+
+        expire_allowance(&canister, alice(), john());
+        assert_eq!(canister.getApprovedTotal(alice()), Nat::from(50));
+
+        canister.increaseAllowance(bob(), Nat::from(10)).unwrap();
+        assert_eq!(canister.getApprovedTotal(alice()), Nat::from(60));
+
+        canister.revokeAllApprovals().unwrap();
+        assert_eq!(canister.getApprovedTotal(alice()), Nat::from(0));
+        assert_eq!(canister.getApprovedTotal(bob()), Nat::from(0));
+    }
+
+    #[test]
+    fn approved_total_out_of_sync() {
+        let canister = test_canister();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+        canister
+            .approve(john(), Nat::from(200), None, None)
+            .unwrap();
+        canister
+            .state
+            .borrow_mut()
+            .approved_totals
+            .get_mut(&alice())
+            .unwrap()
+            .total = Nat::from(50);
+
+        // The removed allowances are larger than the aggregate, which is clamped at zero.
+        canister.approve(bob(), Nat::from(0), None, None).unwrap();
+        assert_eq!(canister.getApprovedTotal(alice()), Nat::from(0));
+
+        canister.approve(john(), Nat::from(10), None, None).unwrap();
+        assert_eq!(canister.getApprovedTotal(alice()), Nat::from(10));
+        assert_eq!(
+            canister.getApprovedTotal(alice()),
+            brute_force_approved_total(&canister, alice())
+        );
+
+        expire_allowance(&canister, alice(), john());
+        canister
+            .state
+            .borrow_mut()
+            .approved_totals
+            .get_mut(&alice())
+            .unwrap()
+            .total = Nat::from(0);
+        assert_eq!(canister.state.borrow_mut().purge_allowances(10), 1);
+        assert_eq!(canister.getApprovedTotal(alice()), Nat::from(0));
+    }
+
+    #[test]
+    fn approved_total_random_operations() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.transfer(bob(), Nat::from(500), None).unwrap();
+
+        let owners = [alice(), bob()];
+        let spenders = [alice(), bob(), john()];
+
+        // Simple linear congruential generator, so the test is deterministic.
+        let mut seed: u64 = 42;
+        let mut next = |max: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % max
+        };
+
+        for _ in 0..500 {
+            let owner = owners[next(2) as usize];
+            let spender = spenders[next(3) as usize];
+            let amount = Nat::from(next(100));
+
+            context.update_caller(owner);
+            match next(7) {
+                0 => {
+                    let _ = canister.approve(spender, amount, None, None);
+                }
+                1 => {
+                    let expires_at = ic_kit::ic::time() + next(3);
+                    let _ = canister.approve(spender, amount, Some(expires_at), None);
+                }
+                2 => {
+                    let _ = canister.increaseAllowance(spender, amount);
+                }
+                3 => {
+                    let _ = canister.decreaseAllowance(spender, amount);
+                }
+                4 => {
+                    context.update_caller(spender);
+                    let _ = canister.transferFrom(owner, john(), amount);
+                }
+                5 => {
+                    let _ = canister.revokeAllApprovals();
+                }
+                _ => {
+                    canister
+                        .state
+                        .borrow_mut()
+                        .purge_allowances(next(3) as usize);
+                }
+            }
+
+            for owner in owners {
+                assert_eq!(
+                    canister.getApprovedTotal(owner),
+                    brute_force_approved_total(&canister, owner)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn increase_allowance_without_approve() {
+        let canister = test_canister();
+        assert!(canister.increaseAllowance(bob(), Nat::from(300)).is_ok());
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(300));
+
+        assert!(canister.increaseAllowance(bob(), Nat::from(200)).is_ok());
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(500));
+        assert_eq!(canister.getAllowanceSize(), 1);
+    }
+
+    #[test]
+    fn increase_allowance_with_fee() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+
+        let id = canister.increaseAllowance(bob(), Nat::from(300)).unwrap();
+        assert_eq!(canister.getTransaction(id).amount, Nat::from(300));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(310));
+
+        let id = canister.increaseAllowance(bob(), Nat::from(200)).unwrap();
+        assert_eq!(canister.getTransaction(id).amount, Nat::from(500));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(510));
+
+        let id = canister.decreaseAllowance(bob(), Nat::from(100)).unwrap();
+        assert_eq!(canister.getTransaction(id).amount, Nat::from(400));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(410));
+
+        context.update_caller(bob());
+        assert!(canister
+            .transferFrom(alice(), bob(), Nat::from(400))
+            .is_ok());
+        assert_eq!(canister.balanceOf(bob()), Nat::from(400));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+    }
+
+    #[test]
+    fn decrease_allowance() {
+        let canister = test_canister();
+        assert!(canister.approve(bob(), Nat::from(500), None, None).is_ok());
+
+        assert!(canister.decreaseAllowance(bob(), Nat::from(200)).is_ok());
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(300));
+    }
+
+    #[test]
+    fn decrease_allowance_below_zero() {
+        let canister = test_canister();
+        assert!(canister.approve(bob(), Nat::from(500), None, None).is_ok());
+
+        assert!(canister.decreaseAllowance(bob(), Nat::from(600)).is_ok());
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+        assert_eq!(canister.getUserApprovals(alice()), vec![]);
+        assert_eq!(canister.getAllowanceSize(), 0);
+    }
+
+    #[test]
+    fn zero_approve_removes_entry() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+        assert_eq!(canister.getAllowanceSize(), 1);
+
+        canister.approve(bob(), Nat::from(0), None, None).unwrap();
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+        assert_eq!(canister.getAllowanceSize(), 0);
+        assert!(canister.state.borrow().allowances.is_empty());
+    }
+
+    #[test]
+    fn consumed_allowance_removes_entry() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+        assert_eq!(canister.getAllowanceSize(), 1);
 
-        const COUNT: usize = 5;
-        let mut ts = ic_kit::ic::time().into();
-        for i in 0..COUNT {
-            let id = canister
-                .transferFrom(alice(), john(), Nat::from(100 + i))
-                .unwrap();
-            assert_eq!(canister.historySize(), 3 + i);
-            let tx = canister.getTransaction(id);
-            assert_eq!(tx.caller, Some(bob()));
-            assert_eq!(tx.amount, Nat::from(100 + i));
-            assert_eq!(tx.fee, Nat::from(10));
-            assert_eq!(tx.operation, Operation::TransferFrom);
-            assert_eq!(tx.status, TransactionStatus::Succeeded);
-            assert_eq!(tx.index, i + 2);
-            assert_eq!(tx.from, alice());
-            assert_eq!(tx.to, john());
-            assert!(ts < tx.timestamp);
-            ts = tx.timestamp;
-        }
+        MockContext::new().with_caller(bob()).inject();
+        canister
+            .transferFrom(alice(), bob(), Nat::from(100))
+            .unwrap();
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+        assert_eq!(canister.getAllowanceSize(), 0);
+        assert!(canister.state.borrow().allowances.is_empty());
     }
 
     #[test]
-    fn multiple_approves() {
+    fn self_approval() {
         let canister = test_canister();
-        assert!(canister.approve(bob(), Nat::from(500)).is_ok());
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+
+        assert_eq!(
+            canister.approve(alice(), Nat::from(100), None, None),
+            Err(TxError::SelfApproval)
+        );
+        assert_eq!(
+            canister.increaseAllowance(alice(), Nat::from(100)),
+            Err(TxError::SelfApproval)
+        );
+        assert_eq!(
+            canister.approveUnlimited(alice()),
+            Err(TxError::SelfApproval)
+        );
+        assert_eq!(
+            canister.approveOnce(alice(), Nat::from(100)),
+            Err(TxError::SelfApproval)
+        );
         assert_eq!(
-            canister.getUserApprovals(alice()),
-            vec![(bob(), Nat::from(500))]
+            canister.approveExpected(alice(), Nat::from(0), Nat::from(100)),
+            Err(TxError::SelfApproval)
         );
 
-        assert!(canister.approve(bob(), Nat::from(200)).is_ok());
+        assert_eq!(canister.getAllowanceSize(), 0);
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+        assert_eq!(canister.historySize(), 1);
+    }
+
+    #[test]
+    fn anonymous_approval() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+
+        let anonymous = Principal::anonymous();
+        assert_eq!(
+            canister.approve(anonymous, Nat::from(100), None, None),
+            Err(TxError::AnonymousPrincipal)
+        );
+        assert_eq!(
+            canister.increaseAllowance(anonymous, Nat::from(100)),
+            Err(TxError::AnonymousPrincipal)
+        );
         assert_eq!(
-            canister.getUserApprovals(alice()),
-            vec![(bob(), Nat::from(200))]
+            canister.approveUnlimited(anonymous),
+            Err(TxError::AnonymousPrincipal)
         );
 
-        assert!(canister.approve(john(), Nat::from(1000)).is_ok());
+        assert_eq!(canister.getAllowanceSize(), 0);
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+        assert_eq!(canister.historySize(), 1);
+    }
 
-        // Convert vectors to sets before comparing to make comparison unaffected by the element
-        // order.
+    #[test]
+    fn change_allowance_with_fee() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+
+        let id = canister.increaseAllowance(bob(), Nat::from(300)).unwrap();
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::Approve);
+        assert_eq!(tx.amount, Nat::from(300));
+        assert_eq!(tx.fee, Nat::from(10));
+
+        let id = canister.decreaseAllowance(bob(), Nat::from(100)).unwrap();
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::Approve);
+        assert_eq!(tx.amount, Nat::from(200));
+        assert_eq!(tx.fee, Nat::from(10));
+
+        assert_eq!(canister.balanceOf(alice()), Nat::from(980));
+        assert_eq!(canister.balanceOf(john()), Nat::from(20));
+    }
+
+    #[test]
+    fn change_allowance_approve_fee_disabled() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+        canister.setApproveFeeEnabled(false).unwrap();
+
+        let id = canister.approve(bob(), Nat::from(100), None, None).unwrap();
+        assert_eq!(canister.getTransaction(id).fee, Nat::from(0));
+        // The transfer fee is still included into the allowance.
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(110));
+
+        let id = canister.increaseAllowance(bob(), Nat::from(300)).unwrap();
+        assert_eq!(canister.getTransaction(id).fee, Nat::from(0));
+
+        let id = canister.decreaseAllowance(bob(), Nat::from(100)).unwrap();
+        assert_eq!(canister.getTransaction(id).fee, Nat::from(0));
+
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+        assert_eq!(canister.balanceOf(john()), Nat::from(0));
+
+        // Transfers are still charged.
+        MockContext::new().with_caller(bob()).inject();
+        canister
+            .transferFrom(alice(), bob(), Nat::from(100))
+            .unwrap();
+        assert_eq!(canister.balanceOf(john()), Nat::from(10));
+    }
+
+    #[test]
+    fn set_approve_fee_enabled_not_authorized() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
         assert_eq!(
-            HashSet::<&(Principal, Nat)>::from_iter(canister.getUserApprovals(alice()).iter()),
-            HashSet::from_iter(vec![(bob(), Nat::from(200)), (john(), Nat::from(1000))].iter())
+            canister.setApproveFeeEnabled(false),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
         );
+        assert!(canister.state.borrow().stats.approve_fee_enabled);
     }
 
     #[test]
     fn approve_over_balance() {
         let canister = test_canister();
         let context = MockContext::new().with_caller(alice()).inject();
-        assert!(canister.approve(bob(), Nat::from(1500)).is_ok());
+        assert!(canister.approve(bob(), Nat::from(1500), None, None).is_ok());
         context.update_caller(bob());
         assert!(canister
             .transferFrom(alice(), john(), Nat::from(500))
@@ -609,7 +2865,7 @@ mod tests {
         canister.state.borrow_mut().stats.fee_to = bob();
         let context = MockContext::new().with_caller(alice()).inject();
 
-        assert!(canister.approve(bob(), Nat::from(1500)).is_ok());
+        assert!(canister.approve(bob(), Nat::from(1500), None, None).is_ok());
         assert_eq!(canister.balanceOf(bob()), Nat::from(100));
         context.update_caller(bob());
 
@@ -630,7 +2886,9 @@ mod tests {
         const COUNT: usize = 5;
         let mut ts = ic_kit::ic::time().into();
         for i in 0..COUNT {
-            let id = canister.approve(bob(), Nat::from(100 + i)).unwrap();
+            let id = canister
+                .approve(bob(), Nat::from(100 + i), None, None)
+                .unwrap();
             assert_eq!(canister.historySize(), 2 + i);
             let tx = canister.getTransaction(id);
             assert_eq!(tx.amount, Nat::from(100 + i));
@@ -662,11 +2920,372 @@ mod tests {
         assert_eq!(txs[0].index, Nat::from(COUNT));
     }
 
+    fn page_ids(page: &TransactionsPage) -> Vec<Nat> {
+        page.records.iter().map(|tx| tx.index.clone()).collect()
+    }
+
+    #[test]
+    fn get_transactions_page() {
+        let canister = test_canister();
+        for _ in 0..4 {
+            canister.transfer(bob(), Nat::from(10), None).unwrap();
+        }
+
+        let page = canister.getTransactionsPage(None, Nat::from(3), None, None, None);
+        assert_eq!(page_ids(&page), vec![0u32, 1, 2]);
+        assert_eq!(page.next_cursor, Some(Nat::from(3)));
+
+        let page = canister.getTransactionsPage(page.next_cursor, Nat::from(3), None, None, None);
+        assert_eq!(page_ids(&page), vec![3u32, 4]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = canister.getTransactionsPage(
+            None,
+            Nat::from(3),
+            Some(TransactionOrder::Descending),
+            None,
+            None,
+        );
+        assert_eq!(page_ids(&page), vec![4u32, 3, 2]);
+        assert_eq!(page.next_cursor, Some(Nat::from(1)));
+
+        let page = canister.getTransactionsPage(
+            page.next_cursor,
+            Nat::from(3),
+            Some(TransactionOrder::Descending),
+            None,
+            None,
+        );
+        assert_eq!(page_ids(&page), vec![1u32, 0]);
+        assert_eq!(page.next_cursor, None);
+
+        // Cursors past the end of the history.
+        let page =
+            canister.getTransactionsPage(Some(Nat::from(100)), Nat::from(3), None, None, None);
+        assert!(page.records.is_empty());
+        assert_eq!(page.next_cursor, None);
+        let page = canister.getTransactionsPage(
+            Some(Nat::from(100)),
+            Nat::from(2),
+            Some(TransactionOrder::Descending),
+            None,
+            None,
+        );
+        assert_eq!(page_ids(&page), vec![4u32, 3]);
+    }
+
+    #[test]
+    fn get_transactions_page_metadata() {
+        let canister = test_canister();
+        for _ in 0..5 {
+            canister.transfer(bob(), Nat::from(10), None).unwrap();
+        }
+
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let page = canister.getTransactionsPage(cursor, Nat::from(2), None, None, None);
+            assert_eq!(page.total, canister.historySize());
+            pages += 1;
+
+            let last = page.records.last().unwrap().index.clone();
+            match page.next_cursor {
+                Some(next) => {
+                    assert!(last < Nat::from(5));
+                    cursor = Some(next);
+                }
+                None => {
+                    assert_eq!(last, Nat::from(5));
+                    break;
+                }
+            }
+        }
+        assert_eq!(pages, 3);
+
+        // A page ending exactly at the last record has no next page.
+        let page = canister.getTransactionsPage(Some(Nat::from(4)), Nat::from(2), None, None, None);
+        assert_eq!(page.records.len(), 2);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn get_transactions_page_filtered() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        canister.burn(Nat::from(10)).unwrap();
+        canister.transfer(john(), Nat::from(100), None).unwrap();
+        canister.mint(bob(), Nat::from(10), None).unwrap();
+        canister.burn(Nat::from(10)).unwrap();
+
+        let mut cursor = None;
+        let mut seen = vec![];
+        loop {
+            let page = canister.getTransactionsPage(
+                cursor,
+                Nat::from(1),
+                None,
+                Some(Operation::Burn),
+                None,
+            );
+            assert!(page.records.len() <= 1);
+            assert!(page
+                .records
+                .iter()
+                .all(|tx| tx.operation == Operation::Burn));
+            seen.extend(page_ids(&page));
+            cursor = match page.next_cursor {
+                Some(cursor) => Some(cursor),
+                None => break,
+            };
+        }
+        assert_eq!(seen, vec![2u32, 5]);
+
+        let page = canister.getTransactionsPage(
+            None,
+            Nat::from(10),
+            Some(TransactionOrder::Descending),
+            Some(Operation::Mint),
+            None,
+        );
+        assert_eq!(page_ids(&page), vec![4u32, 0]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = canister.getUserTransactionsPage(
+            bob(),
+            Nat::from(0),
+            Nat::from(10),
+            None,
+            Some(Operation::Mint),
+        );
+        assert_eq!(page_ids(&page), vec![4u32]);
+        assert_eq!(page.total, Nat::from(2));
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn get_transactions_by_time() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        canister.transfer(john(), Nat::from(100), None).unwrap();
+        let now = ic_kit::ic::time();
+
+        let page = canister.getTransactionsByTime(0, now + 1, None, Nat::from(2));
+        assert_eq!(page_ids(&page), vec![0u32, 1]);
+        assert_eq!(page.total, Nat::from(3));
+
+        let page = canister.getTransactionsByTime(0, now + 1, page.next_cursor, Nat::from(2));
+        assert_eq!(page_ids(&page), vec![2u32]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = canister.getTransactionsByTime(now + 1, u64::MAX, None, Nat::from(10));
+        assert!(page.records.is_empty());
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn get_user_transactions_page() {
+        let canister = test_canister();
+        for _ in 0..4 {
+            canister.transfer(bob(), Nat::from(10), None).unwrap();
+        }
+        canister.transfer(john(), Nat::from(10), None).unwrap();
+
+        let page = canister.getUserTransactionsPage(bob(), Nat::from(0), Nat::from(3), None, None);
+        assert_eq!(page.records.len(), 3);
+        assert_eq!(page.total, Nat::from(4));
+        assert_eq!(page.next_cursor, Some(Nat::from(3)));
+
+        let page = canister.getUserTransactionsPage(bob(), Nat::from(3), Nat::from(3), None, None);
+        assert_eq!(page.records.len(), 1);
+        assert_eq!(page.total, Nat::from(4));
+        assert_eq!(page.next_cursor, None);
+
+        let page = canister.getUserTransactionsPage(bob(), Nat::from(2), Nat::from(2), None, None);
+        assert_eq!(page.records.len(), 2);
+        assert_eq!(page.next_cursor, None);
+
+        let page =
+            canister.getUserTransactionsPage(alice(), Nat::from(0), Nat::from(100), None, None);
+        assert_eq!(page.records.len(), 6);
+        assert_eq!(page.total, Nat::from(6));
+        assert_eq!(page.next_cursor, None);
+
+        let page = canister.getUserTransactionsPage(
+            alice(),
+            Nat::from(0),
+            Nat::from(MAX_TRANSACTION_QUERY_LEN + 1),
+            None,
+            None,
+        );
+        assert_eq!(page.records.len(), 6);
+
+        let page = canister.getUserTransactionsPage(
+            bob(),
+            Nat::from(0),
+            Nat::from(3),
+            Some(TransactionOrder::Descending),
+            None,
+        );
+        assert_eq!(page_ids(&page), vec![4u32, 3, 2]);
+        assert_eq!(page.next_cursor, Some(Nat::from(3)));
+
+        let page = canister.getUserTransactionsPage(
+            bob(),
+            Nat::from(3),
+            Nat::from(3),
+            Some(TransactionOrder::Descending),
+            None,
+        );
+        assert_eq!(page_ids(&page), vec![1u32]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn get_transactions_page_clamps_limit() {
+        let canister = test_canister();
+        for _ in 0..MAX_TRANSACTION_QUERY_LEN + 10 {
+            canister.transfer(bob(), Nat::from(1), None).unwrap();
+        }
+
+        let page = canister.getTransactionsPage(
+            None,
+            Nat::from(MAX_TRANSACTION_QUERY_LEN + 1),
+            Some(TransactionOrder::Descending),
+            None,
+            None,
+        );
+        assert_eq!(page.records.len(), MAX_TRANSACTION_QUERY_LEN);
+        assert_eq!(page.next_cursor, Some(Nat::from(10)));
+    }
+
+    #[test]
+    fn get_transactions_page_backwards_while_appending() {
+        let canister = test_canister();
+        for _ in 0..10 {
+            canister.transfer(bob(), Nat::from(1), None).unwrap();
+        }
+
+        let mut seen = vec![];
+        let mut cursor = None;
+        loop {
+            let page = canister.getTransactionsPage(
+                cursor,
+                Nat::from(3),
+                Some(TransactionOrder::Descending),
+                None,
+                None,
+            );
+            seen.extend(page_ids(&page));
+
+            // New transactions are added between the page requests.
+            canister.transfer(bob(), Nat::from(1), None).unwrap();
+
+            cursor = match page.next_cursor {
+                Some(cursor) => Some(cursor),
+                None => break,
+            };
+        }
+
+        let expected: Vec<Nat> = (0..=10u32).rev().map(Nat::from).collect();
+        assert_eq!(seen, expected);
+    }
+
     #[test]
-    #[should_panic]
     fn get_transactions_over_limit() {
         let canister = test_canister();
-        canister.getTransactions(Nat::from(0), Nat::from(MAX_TRANSACTION_QUERY_LEN + 1));
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+
+        let limit = Nat::from(MAX_TRANSACTION_QUERY_LEN + 1);
+        assert_eq!(
+            canister.getTransactions(Nat::from(0), limit.clone()).len(),
+            2
+        );
+        assert_eq!(
+            canister
+                .getUserTransactions(bob(), Nat::from(0), limit)
+                .len(),
+            1
+        );
+
+        let huge = Nat::from(u64::MAX) * Nat::from(u64::MAX);
+        assert_eq!(
+            canister.getTransactions(Nat::from(0), huge.clone()).len(),
+            2
+        );
+        assert_eq!(
+            canister
+                .getUserTransactions(alice(), Nat::from(0), huge)
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn get_transactions_clamped_to_max_query_limit() {
+        let canister = test_canister();
+        for _ in 0..5 {
+            canister.transfer(bob(), Nat::from(10), None).unwrap();
+        }
+        canister.setMaxQueryLimit(3).unwrap();
+        assert_eq!(canister.getMaxQueryLimit(), 3);
+
+        assert_eq!(
+            canister.getTransactions(Nat::from(0), Nat::from(3)).len(),
+            3
+        );
+        assert_eq!(
+            canister.getTransactions(Nat::from(0), Nat::from(4)).len(),
+            3
+        );
+        assert_eq!(
+            canister
+                .getUserTransactions(bob(), Nat::from(1), Nat::from(10))
+                .len(),
+            3
+        );
+        assert_eq!(
+            canister
+                .getTransactionsSafe(Nat::from(0), Nat::from(4))
+                .unwrap_err(),
+            TxError::QueryLimitExceeded { max: 3 }
+        );
+        assert_eq!(
+            canister
+                .getTransactionsPage(None, Nat::from(10), None, None, None)
+                .records
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn set_max_query_limit_validation() {
+        let canister = test_canister();
+        assert_eq!(
+            canister.getMaxQueryLimit(),
+            MAX_TRANSACTION_QUERY_LEN as u64
+        );
+
+        let expected = Err(TxError::InvalidQueryLimit {
+            max: MAX_TRANSACTION_QUERY_HARD_CAP,
+        });
+        assert_eq!(canister.setMaxQueryLimit(0), expected);
+        assert_eq!(
+            canister.setMaxQueryLimit(MAX_TRANSACTION_QUERY_HARD_CAP + 1),
+            expected
+        );
+        assert_eq!(
+            canister.setMaxQueryLimit(MAX_TRANSACTION_QUERY_HARD_CAP),
+            Ok(())
+        );
+        assert_eq!(canister.getMaxQueryLimit(), MAX_TRANSACTION_QUERY_HARD_CAP);
+
+        MockContext::new().with_caller(bob()).inject();
+        assert!(matches!(
+            canister.setMaxQueryLimit(10),
+            Err(TxError::Unauthorized { .. })
+        ));
+        assert_eq!(canister.getMaxQueryLimit(), MAX_TRANSACTION_QUERY_HARD_CAP);
     }
 
     #[test]
@@ -675,4 +3294,181 @@ mod tests {
         let canister = test_canister();
         canister.getTransaction(Nat::from(2));
     }
+
+    #[test]
+    fn get_transaction_safe() {
+        let canister = test_canister();
+        let id = canister.transfer(bob(), Nat::from(100), None).unwrap();
+        let tx = canister.getTransactionSafe(id.clone()).unwrap();
+        assert_eq!(tx.index, id);
+        assert_eq!(tx.amount, Nat::from(100));
+
+        let result = canister.getTransactionSafe(Nat::from(2));
+        assert_eq!(result.unwrap_err(), TxError::TransactionDoesNotExist);
+
+        // Callers decoding the reply through the candid interface get the error, not a reject.
+        let result = canister.getTransactionSafe(Nat::from(2));
+        let encoded = candid::Encode!(&result).unwrap();
+        let decoded = candid::Decode!(&encoded, Result<TxRecord, TxError>).unwrap();
+        assert_eq!(decoded.unwrap_err(), TxError::TransactionDoesNotExist);
+    }
+
+    #[test]
+    fn get_transactions_by_ids() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        canister.transfer(john(), Nat::from(50), None).unwrap();
+
+        let ids = vec![
+            Nat::from(2),
+            Nat::from(10),
+            Nat::from(0),
+            Nat::from(2),
+            Nat::from(3),
+        ];
+        let txs = canister.getTransactionsByIds(ids);
+        assert_eq!(txs.len(), 5);
+        assert_eq!(txs[0].as_ref().unwrap().index, Nat::from(2));
+        assert_eq!(txs[0].as_ref().unwrap().amount, Nat::from(50));
+        assert!(txs[1].is_none());
+        assert_eq!(txs[2].as_ref().unwrap().index, Nat::from(0));
+        assert_eq!(txs[3].as_ref().unwrap().index, Nat::from(2));
+        assert!(txs[4].is_none());
+
+        assert!(canister.getTransactionsByIds(vec![]).is_empty());
+
+        let ids = vec![Nat::from(1); MAX_TRANSACTION_QUERY_LEN + 1];
+        assert_eq!(
+            canister.getTransactionsByIds(ids).len(),
+            MAX_TRANSACTION_QUERY_LEN
+        );
+    }
+
+    #[test]
+    fn get_transactions_safe() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        let txs = canister
+            .getTransactionsSafe(Nat::from(0), Nat::from(10))
+            .unwrap();
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[1].amount, Nat::from(100));
+
+        let expected = TxError::QueryLimitExceeded {
+            max: MAX_TRANSACTION_QUERY_LEN as u64,
+        };
+        let result =
+            canister.getTransactionsSafe(Nat::from(0), Nat::from(MAX_TRANSACTION_QUERY_LEN + 1));
+        let encoded = candid::Encode!(&result).unwrap();
+        assert_eq!(result.unwrap_err(), expected);
+
+        let decoded = candid::Decode!(&encoded, Result<Vec<TxRecord>, TxError>).unwrap();
+        assert_eq!(decoded.unwrap_err(), expected);
+    }
+
+    #[test]
+    fn failed_transfer_recorded_only_when_enabled() {
+        let canister = test_canister();
+        assert!(canister.transfer(bob(), Nat::from(2000), None).is_err());
+        assert_eq!(canister.historySize(), Nat::from(1));
+
+        canister.setRecordFailedTransactions(true).unwrap();
+        assert_eq!(
+            canister.transfer(bob(), Nat::from(2000), None),
+            Err(TxError::InsufficientBalance)
+        );
+        // The setting change is recorded as id 1.
+        assert_eq!(canister.historySize(), Nat::from(3));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+
+        let tx = canister.getTransaction(Nat::from(2));
+        assert_eq!(tx.operation, Operation::Transfer);
+        assert_eq!(tx.from, alice());
+        assert_eq!(tx.to, bob());
+        assert_eq!(tx.amount, Nat::from(2000));
+        assert_eq!(tx.fee, Nat::from(0));
+        assert_eq!(
+            tx.status,
+            TransactionStatus::Failed {
+                error: TxError::InsufficientBalance
+            }
+        );
+
+        // Successful transfers are recorded as before.
+        let id = canister.transfer(bob(), Nat::from(100), None).unwrap();
+        assert_eq!(id, Nat::from(3));
+        assert_eq!(
+            canister.getTransaction(id).status,
+            TransactionStatus::Succeeded
+        );
+
+        canister.setRecordFailedTransactions(false).unwrap();
+        assert!(canister.transfer(bob(), Nat::from(2000), None).is_err());
+        assert_eq!(canister.historySize(), Nat::from(5));
+    }
+
+    #[test]
+    fn failed_transfer_from_recorded() {
+        let canister = test_canister();
+        canister.setRecordFailedTransactions(true).unwrap();
+
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(100)),
+            Err(TxError::InsufficientAllowance)
+        );
+
+        let tx = canister.getTransaction(Nat::from(2));
+        assert_eq!(tx.operation, Operation::TransferFrom);
+        assert_eq!(tx.caller, Some(bob()));
+        assert_eq!(tx.from, alice());
+        assert_eq!(tx.to, john());
+        assert!(tx.status.is_failed());
+        assert_eq!(canister.balanceOf(john()), Nat::from(0));
+    }
+
+    #[test]
+    fn failed_transactions_not_counted_for_user() {
+        let canister = test_canister();
+        canister.setRecordFailedTransactions(true).unwrap();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        assert!(canister.transfer(bob(), Nat::from(5000), None).is_err());
+
+        assert_eq!(canister.getUserTransactionCount(bob()), Nat::from(1));
+        assert_eq!(canister.getUserTransactionAmount(bob()), Nat::from(100));
+        assert_eq!(canister.getUserTransactionCount(alice()), Nat::from(2));
+
+        // The failed record is still listed in the user's history.
+        let txs = canister.getUserTransactions(bob(), Nat::from(0), Nat::from(10));
+        assert_eq!(txs.len(), 2);
+    }
+
+    #[test]
+    fn get_transactions_page_by_status() {
+        let canister = test_canister();
+        canister.setRecordFailedTransactions(true).unwrap();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        assert!(canister.transfer(bob(), Nat::from(5000), None).is_err());
+        canister.transfer(john(), Nat::from(100), None).unwrap();
+        assert!(canister.transfer(john(), Nat::from(5000), None).is_err());
+
+        let page = canister.getTransactionsPage(
+            None,
+            Nat::from(10),
+            None,
+            None,
+            Some(TransactionStatusFilter::Failed),
+        );
+        assert_eq!(page_ids(&page), vec![Nat::from(3), Nat::from(5)]);
+
+        let page = canister.getTransactionsPage(
+            None,
+            Nat::from(10),
+            None,
+            Some(Operation::Transfer),
+            Some(TransactionStatusFilter::Succeeded),
+        );
+        assert_eq!(page_ids(&page), vec![Nat::from(2), Nat::from(4)]);
+    }
 }
@@ -0,0 +1,56 @@
+/// Runs the periodic tasks of the canister: the scheduled emission, the automatic auctions and
+/// the refunds of the skipped bids, the archiving, the delivery of the ledger events and the
+/// backfill of the ledger indexes.
+///
+/// The `ic-cdk` version used by the canister has no timers API, so the tasks are checked on every
+/// heartbeat and run when they are due. Unlike the timers, the heartbeat doesn't have to be
+/// registered again after upgrade. If none of the tasks is enabled or pending, the heartbeat
+/// returns after checking their flags.
+#[cfg(not(feature = "no_api"))]
+#[ic_cdk_macros::heartbeat]
+fn heartbeat() {
+    use crate::canister::{is20_archive, is20_auction, is20_emission, is20_events};
+    use crate::ledger::{INDEX_BACKFILL_BATCH, USER_STATS_BACKFILL_BATCH};
+    use crate::state::CanisterState;
+    use ic_storage::IcStorage;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Set by the first heartbeat after the canister is installed or upgraded. The flag is not
+        /// saved to the stable memory, so it's cleared by every upgrade.
+        static STARTED: Cell<bool> = Cell::new(false);
+    }
+
+    let state = CanisterState::get();
+    // The flag of the automatic auctions is checked before any other work, so the heartbeat does
+    // nothing auction related while they are disabled.
+    if state.borrow().bidding_state.auto_auction {
+        is20_auction::auction_tick(&mut state.borrow_mut());
+    }
+
+    // The certified data is cleared on upgrade, and every new transaction sets it again. There are
+    // no custom upgrade hooks, so it's restored on the first heartbeat after upgrade instead.
+    if !STARTED.with(|started| started.replace(true)) {
+        crate::certified::certify_tip(&state.borrow().ledger);
+    }
+
+    if !state.borrow().has_periodic_tasks() {
+        return;
+    }
+
+    is20_emission::emission_tick(&mut state.borrow_mut());
+    is20_auction::refund_tick(&state);
+    is20_archive::archive_tick(&state);
+    is20_events::events_tick(&state);
+    {
+        let mut state = state.borrow_mut();
+        let state = &mut *state;
+        state.ledger.backfill_user_stats(USER_STATS_BACKFILL_BATCH);
+        state.ledger.backfill_user_index(INDEX_BACKFILL_BATCH);
+        state.ledger.backfill_caller_index(INDEX_BACKFILL_BATCH);
+        state.ledger.backfill_operation_counts(INDEX_BACKFILL_BATCH);
+        state
+            .ledger
+            .backfill_indexes(INDEX_BACKFILL_BATCH, &state.balances.0);
+    }
+}
@@ -1,50 +1,167 @@
+use crate::canister::is20_permit::decode_permit;
 use crate::state::CanisterState;
+use crate::types::AuctionController;
 use candid::{Nat, Principal};
+use common::types::SignedTx;
 use ic_cdk_macros::inspect_message;
 use ic_storage::IcStorage;
 
 static PUBLIC_METHODS: &[&str] = &[
     "allowance",
+    "approvalsForSpender",
     "auctionInfo",
+    "getAuctionBidders",
+    "getAuctionCount",
+    "getAuctionStats",
+    "getAuctions",
+    "getAutoAuctionLog",
     "balanceOf",
+    "balanceOfAt",
     "biddingInfo",
+    "getBackupProgress",
+    "circulatingSupply",
     "decimals",
+    "exportApprovals",
+    "exportTransactions",
+    "findTransactionsByMemo",
     "getAllowanceSize",
+    "getBlocksSince",
+    "getCallerTransactions",
+    "getArchiveInfo",
+    "getAllowances",
+    "getApprovedSpenders",
+    "getApprovedTotal",
+    "getBalanceCheckpointSpacing",
+    "getCertifiedTip",
+    "getDelegate",
+    "getDeliveryQueueStats",
+    "getEmissionInfo",
+    "getFeePool",
+    "getFeeBurnRate",
+    "getFeeTransactions",
     "getHolders",
+    "getLedgerStats",
+    "getLedgerTip",
+    "getMaxQueryLimit",
     "getMetadata",
+    "getMinters",
+    "getNotificationStatus",
+    "getNonCirculatingAccounts",
+    "getOperationCounts",
+    "getPendingOwnerNotifications",
+    "getPastVotes",
+    "getPermitNonce",
     "getTokenInfo",
     "getTransaction",
+    "getTransactionIndexByTime",
+    "getTransactionSafe",
     "getTransactions",
+    "getTransactionsByAmount",
+    "getTransactionsByIds",
+    "getTransactionsByTime",
+    "getTransactionsPage",
+    "getTransactionsSafe",
+    "getUnnotifiedTransactions",
     "getUserApprovals",
+    "getUserApprovalsCount",
+    "getUserApprovalsDetailed",
+    "getUserApprovalsPage",
+    "getUserStats",
     "getUserTransactionAmount",
+    "getUserTransactionCount",
     "getUserTransactions",
+    "getUserTransactionsPage",
+    "getVestingInfo",
+    "getVotes",
     "historySize",
     "logo",
+    "myBid",
     "name",
     "owner",
+    "queryTransactions",
+    "queryTransactionsByFilter",
     "symbol",
+    "totalBurned",
+    "totalFeesToRecipient",
+    "totalMinted",
     "totalSupply",
+    "verifyLedger",
     "isTestToken",
+    "listSubscribers",
 ];
 
 static OWNER_METHODS: &[&str] = &[
+    "adminRevokeApprovals",
+    "addApprovedSpender",
+    "addAuctionBidder",
+    "archiveTransactions",
+    "addMinter",
+    "approveSubscriber",
+    "backupLedger",
+    "addNonCirculatingAccount",
+    "batchMint",
     "mint",
+    "mintAndNotify",
+    "mintVested",
+    "ownerTransferFrom",
+    "purgeAllowances",
+    "removeApprovedSpender",
+    "removeAuctionBidder",
+    "removeMinter",
+    "removeNonCirculatingAccount",
+    "removeSubscriber",
+    "renounceMinting",
+    "restoreLedger",
+    "setApproveFeeEnabled",
+    "setArchive",
+    "setAuctionController",
+    "setAuctionIncentive",
+    "setAuctionPayoutMode",
+    "setAuctionWhitelistEnabled",
+    "setAutoAuction",
+    "setBalanceCheckpointSpacing",
     "setAuctionPeriod",
+    "setClaimRoot",
+    "setEmission",
+    "setFaucetLimit",
     "setFee",
+    "setFeeBurnRate",
+    "setFeeRatioBounds",
     "setFeeTo",
     "setLogo",
+    "setMinBid",
     "setMinCycles",
+    "setMintLimit",
+    "setMintLimitOwnerExempt",
+    "setMaxQueryLimit",
+    "setMinTransferAmount",
     "setName",
     "setOwner",
+    "setRecordFailedTransactions",
+    "setSpenderWhitelistEnabled",
+    "testBurn",
     "toggleTest",
 ];
 
 static TRANSACTION_METHODS: &[&str] = &[
     "approve",
+    "approveAndNotify",
+    "approveExpected",
+    "approveOnce",
+    "approveUnlimited",
+    "batchApprove",
     "burn",
+    "burnAndNotify",
+    "decreaseAllowance",
+    "delegate",
+    "flushOwnerNotifications",
+    "increaseAllowance",
+    "revokeAllApprovals",
+    "setAllowanceNotifications",
     "transfer",
     "transferAndNotify",
     "transferIncludeFee",
+    "transferWithMemo",
 ];
 
 /// This function checks if the canister should accept ingress message or not. We allow query
@@ -62,7 +179,11 @@ fn inspect_message() {
 
     match &method[..] {
         // These are query methods, so no checks are needed.
-        "mint" if state.stats.is_test_token => ic_cdk::api::call::accept_message(),
+        "mint" | "batchMint" | "mintAndNotify" | "mintVested"
+            if state.minters.contains(&caller) =>
+        {
+            ic_cdk::api::call::accept_message()
+        }
         m if PUBLIC_METHODS.contains(&m) => ic_cdk::api::call::accept_message(),
         // Owner
         m if OWNER_METHODS.contains(&m) && caller == state.stats.owner => {
@@ -72,6 +193,18 @@ fn inspect_message() {
         m if OWNER_METHODS.contains(&m) => {
             ic_cdk::println!("Owner method is called not by an owner. Rejecting.")
         }
+        m if approval_spenders(m)
+            .iter()
+            .any(|spender| *spender == caller || *spender == Principal::anonymous()) =>
+        {
+            // Approvals to the caller itself or to the anonymous principal always fail.
+            ic_cdk::println!("Approval to the caller or to the anonymous principal. Rejecting.")
+        }
+        m if TRANSACTION_METHODS.contains(&m) && state.restore_next.is_some() => {
+            ic_cdk::println!(
+                "Transaction method is called while the ledger is restored. Rejecting."
+            )
+        }
         m if TRANSACTION_METHODS.contains(&m) => {
             // These methods require the caller to have some balance, so we check if the caller
             // has any token to their name.
@@ -84,22 +217,71 @@ fn inspect_message() {
                 ic_cdk::println!("Transaction method is called not by a stakeholder. Rejecting.");
             }
         }
-        "transferFrom" => {
+        "transferFrom" | "transferFromIncludeFee" | "transferFromV2" => {
             // Check if the caller has allowance for this transfer.
-            let allowances = &state.allowances;
             let (from, _, value) = ic_cdk::api::call::arg_data::<(Principal, Principal, Nat)>();
-            if let Some(user_allowances) = allowances.get(&caller) {
-                if let Some(allowance) = user_allowances.get(&from) {
-                    if value <= *allowance {
-                        ic_cdk::api::call::accept_message();
-                    } else {
-                        ic_cdk::println!("Allowance amount is less then the requested transfer amount. Rejecting.");
-                    }
-                } else {
-                    ic_cdk::println!("Caller is not allowed to transfer tokens for the requested principal. Rejecting.");
-                }
+            if value > 0u32 && value <= state.allowance(from, caller) {
+                ic_cdk::api::call::accept_message();
             } else {
-                ic_cdk::println!("Caller is not allowed to transfer tokens for the requested principal. Rejecting.");
+                ic_cdk::println!(
+                    "Allowance amount is less then the requested transfer amount. Rejecting."
+                );
+            }
+        }
+        "burnFrom" => {
+            // Check if the caller has allowance for this burn.
+            let (from, value) = ic_cdk::api::call::arg_data::<(Principal, Nat)>();
+            if value > 0u32 && value <= state.allowance(from, caller) {
+                ic_cdk::api::call::accept_message();
+            } else {
+                ic_cdk::println!(
+                    "Allowance amount is less then the requested burn amount. Rejecting."
+                );
+            }
+        }
+        "faucet" => {
+            // Anyone can get test tokens, so we only check if the token is a test token.
+            if state.stats.is_test_token {
+                ic_cdk::api::call::accept_message();
+            } else {
+                ic_cdk::println!(
+                    "Faucet is called for a token that is not a test token. Rejecting."
+                );
+            }
+        }
+        "claimMint" => {
+            // Claimers might not have any balance yet, so we only check if the caller can claim.
+            if state.claims.root.is_some() && !state.claims.claimed.contains(&caller) {
+                ic_cdk::api::call::accept_message();
+            } else {
+                ic_cdk::println!("No claim list is set or the caller already claimed. Rejecting.");
+            }
+        }
+        "claimAuctionReward" => {
+            // Bidders might not have any balance yet, so we only check if the caller has any
+            // unclaimed reward.
+            let has_reward = state
+                .bidding_state
+                .auction_rewards
+                .values()
+                .filter_map(|rewards| rewards.get(&caller))
+                .any(|reward| reward.transaction_id.is_none());
+            if has_reward {
+                ic_cdk::api::call::accept_message();
+            } else {
+                ic_cdk::println!("The caller has no unclaimed auction rewards. Rejecting.");
+            }
+        }
+        "permit" => {
+            // Permits are submitted by relayers, so we check if the owner that signed the permit
+            // has any balance to pay the approval fee. The signature itself is verified by the
+            // method.
+            let (signed,) = ic_cdk::api::call::arg_data::<(SignedTx,)>();
+            match decode_permit(&signed) {
+                Some((_, owner, ..)) if state.balances.0.contains_key(&owner) => {
+                    ic_cdk::api::call::accept_message();
+                }
+                _ => ic_cdk::println!("Permit owner is not a stakeholder. Rejecting."),
             }
         }
         "notify" => {
@@ -115,24 +297,70 @@ fn inspect_message() {
             }
         }
         "runAuction" => {
-            // We allow running auction only to the owner or any of the cycle bidders.
+            // We allow running auction only to the owner or any of the cycle bidders, unless the
+            // auction is restricted to the owner.
             let state = CanisterState::get();
             let state = state.borrow();
             let bidding_state = &state.bidding_state;
-            if bidding_state.is_auction_due()
-                && (bidding_state.bids.contains_key(&caller) || caller == state.stats.owner)
-            {
+            let bidder_allowed = bidding_state.controller == AuctionController::Anyone
+                && bidding_state.bids.contains_key(&caller);
+            if bidding_state.is_auction_due() && (bidder_allowed || caller == state.stats.owner) {
                 ic_cdk::api::call::accept_message();
             } else {
                 ic_cdk::println!("Auction is not due yet or auction run method is called not by owner or bidder. Rejecting.");
             }
         }
-        "bidCycles" => {
+        "subscribe" | "unsubscribe" => {
+            // Only the callers that hold some tokens can subscribe, so the subscription slots
+            // can't be taken for free. Unsubscribing is allowed to any caller with a subscription.
+            if state.balances.0.contains_key(&caller)
+                || state.subscriptions.subscribers.contains_key(&caller)
+            {
+                ic_cdk::api::call::accept_message();
+            } else {
+                ic_cdk::println!("Subscription method is called not by a stakeholder. Rejecting.");
+            }
+        }
+        "bidCycles" | "cancelBid" | "claimBidRefund" => {
             // We reject this message, because a call with cycles cannot be made through ingress,
-            // only from the wallet canister.
+            // only from the wallet canister. The cancelled and skipped bids are refunded to the
+            // calling canister, so they can't be refunded through ingress either.
         }
         _ => {
             ic_cdk::println!("The method called is not listed in the access checks. This is probably a code error.");
         }
     }
 }
+
+/// Returns the spenders given in the arguments of the approval methods, or an empty list for other
+/// methods.
+#[cfg(not(feature = "no_api"))]
+fn approval_spenders(method: &str) -> Vec<Principal> {
+    use crate::types::Timestamp;
+    use ic_cdk::api::call::arg_data;
+
+    match method {
+        "approve" => {
+            let (spender, ..) =
+                arg_data::<(Principal, Nat, Option<Timestamp>, Option<(Nat, u64)>)>();
+            vec![spender]
+        }
+        "approveAndNotify" | "approveOnce" | "increaseAllowance" => {
+            let (spender, _) = arg_data::<(Principal, Nat)>();
+            vec![spender]
+        }
+        "approveExpected" => {
+            let (spender, ..) = arg_data::<(Principal, Nat, Nat)>();
+            vec![spender]
+        }
+        "approveUnlimited" => {
+            let (spender,) = arg_data::<(Principal,)>();
+            vec![spender]
+        }
+        "batchApprove" => {
+            let (approvals,) = arg_data::<(Vec<(Principal, Nat)>,)>();
+            approvals.into_iter().map(|(spender, _)| spender).collect()
+        }
+        _ => vec![],
+    }
+}
@@ -0,0 +1,348 @@
+//! This module contains APIs for moving the old transactions from the ledger to an archive
+//! canister.
+//!
+//! The archiving is checked on every canister heartbeat. When the number of the transactions kept
+//! in the ledger exceeds the configured threshold, a batch of the oldest transactions is sent to
+//! the archive canister with the `append_transactions` call. The transactions are removed from the
+//! ledger only after the archive accepted them, so a failed call leaves the ledger unchanged and
+//! the batch is sent again later.
+
+use crate::canister::{check_caller, record_config_change, TokenCanister};
+use crate::state::CanisterState;
+use crate::types::{ArchivedRange, TxError, TxRecord};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_canister::virtual_canister_call;
+use ic_cdk::api::call::CallResult;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Current configuration of the archiving.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct ArchiveInfo {
+    /// Canister the transactions are moved to.
+    pub canister_id: Option<Principal>,
+
+    /// Number of the transactions kept in the ledger, above which the oldest transactions are
+    /// moved to the archive. Zero if automatic archiving is disabled.
+    pub threshold: u64,
+
+    /// Maximum number of the transactions moved to the archive in one call.
+    pub batch_size: u64,
+
+    /// Ranges of the transactions moved to the archives.
+    pub ranges: Vec<ArchivedRange>,
+}
+
+pub(crate) fn set_archive(
+    canister: &TokenCanister,
+    canister_id: Principal,
+    threshold: u64,
+    batch_size: u64,
+) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    if batch_size == 0 {
+        return Err(TxError::InvalidArchiveConfig);
+    }
+
+    let old_archive = match state.archive.canister_id {
+        Some(old_id) => format!(
+            "[{}, {}, {}]",
+            old_id, state.archive.threshold, state.archive.batch_size
+        ),
+        None => "none".to_string(),
+    };
+    state.archive.canister_id = Some(canister_id);
+    state.archive.threshold = threshold;
+    state.archive.batch_size = batch_size;
+    record_config_change(
+        &mut state,
+        "archive",
+        old_archive,
+        format!("[{}, {}, {}]", canister_id, threshold, batch_size),
+    );
+
+    Ok(())
+}
+
+pub(crate) fn archive_info(canister: &TokenCanister) -> ArchiveInfo {
+    let archive = &canister.state.borrow().archive;
+    ArchiveInfo {
+        canister_id: archive.canister_id,
+        threshold: archive.threshold,
+        batch_size: archive.batch_size,
+        ranges: archive.ranges.clone(),
+    }
+}
+
+/// Sends a batch of the oldest transactions to the archive canister and removes them from the
+/// ledger. Returns the number of the archived transactions, which is zero if the archive is not
+/// configured, the ledger is empty or another batch is being archived.
+pub(crate) async fn archive_transactions(
+    state: Rc<RefCell<CanisterState>>,
+) -> Result<u64, TxError> {
+    let (canister_id, start, batch) = {
+        let mut state = state.borrow_mut();
+        let canister_id = match state.archive.canister_id {
+            Some(canister_id) if !state.archive.in_progress => canister_id,
+            _ => return Ok(0),
+        };
+
+        let batch_size = state.archive.batch_size as usize;
+        let batch = state.ledger.oldest(batch_size);
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        state.archive.in_progress = true;
+        (canister_id, state.ledger.first_id(), batch)
+    };
+
+    let length = batch.len();
+    let result = send_to_archive(canister_id, batch).await;
+
+    let mut state = state.borrow_mut();
+    state.archive.in_progress = false;
+    if let Err((_, msg)) = result {
+        return Err(TxError::ArchiveFailed { cdk_msg: msg });
+    }
+
+    // The ledger could have removed some of the sent records on its own while the call was
+    // awaited, so we only remove the ones still in the history.
+    state.ledger.remove_before(start + length as u64);
+    state
+        .archive
+        .add_range(canister_id, Nat::from(start), Nat::from(length));
+
+    Ok(length as u64)
+}
+
+/// Starts archiving a batch of the transactions if the ledger is above the archiving threshold.
+pub(crate) fn archive_tick(state: &Rc<RefCell<CanisterState>>) {
+    let is_due = {
+        let state = state.borrow();
+        state.archive.is_due(state.ledger.history_len())
+    };
+
+    if is_due {
+        let state = state.clone();
+        ic_cdk::spawn(async move {
+            // A failed batch is sent again on one of the next heartbeats.
+            let _ = archive_transactions(state).await;
+        });
+    }
+}
+
+async fn send_to_archive(canister_id: Principal, batch: Vec<TxRecord>) -> CallResult<()> {
+    virtual_canister_call!(canister_id, "append_transactions", (batch,), ()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::types::Metadata;
+    use ic_canister::{register_failing_virtual_responder, register_virtual_responder, Canister};
+    use ic_kit::mock_principals::{alice, bob, john, xtc};
+    use ic_kit::MockContext;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn test_canister() -> TokenCanister {
+        MockContext::new().with_caller(alice()).inject();
+
+        let canister = TokenCanister::init_instance();
+        canister.init(Metadata {
+            logo: "".to_string(),
+            name: "".to_string(),
+            symbol: "".to_string(),
+            decimals: 8,
+            totalSupply: Nat::from(1000),
+            owner: alice(),
+            fee: Nat::from(0),
+            feeTo: alice(),
+            isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
+        });
+
+        canister
+    }
+
+    fn ids(records: &[TxRecord]) -> Vec<Nat> {
+        records.iter().map(|tx| tx.index.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn archive_batch() {
+        let received = Rc::new(RefCell::new(vec![]));
+        let received_clone = received.clone();
+        register_virtual_responder(
+            xtc(),
+            "append_transactions",
+            move |(records,): (Vec<TxRecord>,)| {
+                received.borrow_mut().extend(ids(&records));
+            },
+        );
+
+        let canister = test_canister();
+        for _ in 0..3 {
+            canister.transfer(bob(), Nat::from(10), None).unwrap();
+        }
+        // The configuration is recorded as the last transaction.
+        canister.setArchive(xtc(), 0, 3).unwrap();
+
+        assert_eq!(canister.archiveTransactions().await, Ok(3));
+        assert_eq!(
+            *received_clone.borrow(),
+            vec![Nat::from(0), Nat::from(1), Nat::from(2)]
+        );
+        assert_eq!(canister.historySize(), Nat::from(5));
+        assert_eq!(canister.state.borrow().ledger.first_id(), 3);
+
+        assert_eq!(canister.archiveTransactions().await, Ok(2));
+        assert_eq!(canister.archiveTransactions().await, Ok(0));
+        assert_eq!(
+            canister.getArchiveInfo().ranges,
+            vec![ArchivedRange {
+                canister_id: xtc(),
+                start: Nat::from(0),
+                length: Nat::from(5),
+            }]
+        );
+
+        // New transactions keep their ids after the archiving.
+        let id = canister.transfer(bob(), Nat::from(10), None).unwrap();
+        assert_eq!(id, Nat::from(5));
+        assert_eq!(canister.getTransaction(id).amount, Nat::from(10));
+    }
+
+    #[tokio::test]
+    async fn archived_transactions_point_to_archive() {
+        register_virtual_responder(xtc(), "append_transactions", |_: (Vec<TxRecord>,)| {});
+
+        let canister = test_canister();
+        for _ in 0..3 {
+            canister.transfer(bob(), Nat::from(10), None).unwrap();
+        }
+        // The configuration is recorded as the last transaction.
+        canister.setArchive(xtc(), 0, 3).unwrap();
+        canister.archiveTransactions().await.unwrap();
+
+        assert_eq!(
+            canister.getTransactionSafe(Nat::from(1)).unwrap_err(),
+            TxError::TransactionArchived { canister_id: xtc() }
+        );
+        assert_eq!(
+            canister.getTransactionSafe(Nat::from(10)).unwrap_err(),
+            TxError::TransactionDoesNotExist
+        );
+
+        let result = canister.queryTransactions(Nat::from(1), Nat::from(3));
+        assert_eq!(ids(&result.records), vec![Nat::from(3)]);
+        assert_eq!(
+            result.archived,
+            vec![ArchivedRange {
+                canister_id: xtc(),
+                start: Nat::from(1),
+                length: Nat::from(2),
+            }]
+        );
+
+        let result = canister.queryTransactions(Nat::from(3), Nat::from(10));
+        assert_eq!(ids(&result.records), vec![Nat::from(3), Nat::from(4)]);
+        assert!(result.archived.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_archive_keeps_transactions() {
+        register_failing_virtual_responder(
+            xtc(),
+            "append_transactions",
+            "something's wrong".into(),
+        );
+
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(10), None).unwrap();
+        canister.setArchive(xtc(), 0, 10).unwrap();
+
+        assert!(matches!(
+            canister.archiveTransactions().await,
+            Err(TxError::ArchiveFailed { .. })
+        ));
+        assert_eq!(canister.state.borrow().ledger.history_len(), 3);
+        assert!(!canister.state.borrow().archive.in_progress);
+        assert!(canister.getArchiveInfo().ranges.is_empty());
+        assert_eq!(
+            canister.getTransaction(Nat::from(0)).amount,
+            Nat::from(1000)
+        );
+    }
+
+    #[tokio::test]
+    async fn archive_to_new_canister() {
+        let counter = Rc::new(AtomicU32::new(0));
+        let counter_clone = counter.clone();
+        register_virtual_responder(xtc(), "append_transactions", |_: (Vec<TxRecord>,)| {});
+        register_virtual_responder(john(), "append_transactions", move |_: (Vec<TxRecord>,)| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(10), None).unwrap();
+        canister.setArchive(xtc(), 0, 1).unwrap();
+        canister.archiveTransactions().await.unwrap();
+        canister.setArchive(john(), 0, 1).unwrap();
+        canister.archiveTransactions().await.unwrap();
+
+        assert_eq!(counter_clone.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            canister.getArchiveInfo().ranges,
+            vec![
+                ArchivedRange {
+                    canister_id: xtc(),
+                    start: Nat::from(0),
+                    length: Nat::from(1),
+                },
+                ArchivedRange {
+                    canister_id: john(),
+                    start: Nat::from(1),
+                    length: Nat::from(1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn archive_due() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(10), None).unwrap();
+        let state = canister.state.borrow();
+        assert!(!state.archive.is_due(state.ledger.history_len()));
+        drop(state);
+
+        canister.setArchive(xtc(), 1, 10).unwrap();
+        let state = canister.state.borrow();
+        assert!(state.archive.is_due(state.ledger.history_len()));
+        drop(state);
+
+        canister.setArchive(xtc(), 4, 10).unwrap();
+        let state = canister.state.borrow();
+        assert!(!state.archive.is_due(state.ledger.history_len()));
+    }
+
+    #[test]
+    fn set_archive_validation() {
+        let canister = test_canister();
+        assert_eq!(
+            canister.setArchive(xtc(), 10, 0),
+            Err(TxError::InvalidArchiveConfig)
+        );
+
+        MockContext::new().with_caller(bob()).inject();
+        assert!(matches!(
+            canister.setArchive(xtc(), 10, 10),
+            Err(TxError::Unauthorized { .. })
+        ));
+        assert_eq!(canister.getArchiveInfo().canister_id, None);
+    }
+}
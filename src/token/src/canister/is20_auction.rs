@@ -1,17 +1,37 @@
 //! This module contains APIs from IS20 standard providing cycle auction related functionality.
 
-use crate::canister::dip20_transactions::_transfer;
-use crate::canister::TokenCanister;
-use crate::ledger::Ledger;
-use crate::state::{AuctionHistory, Balances, BiddingState, CanisterState};
-use crate::types::{AuctionInfo, StatsData, Timestamp};
+use crate::canister::dip20_transactions::{_transfer, is_canister};
+use crate::canister::{check_caller, record_config_change, TokenCanister};
+use crate::ledger::{to_id, Ledger};
+use crate::state::{
+    AuctionHistory, AuctionReward, AuctionTotals, Balances, BiddingState, CanisterState,
+    DeliveryQueue, PendingDelivery, SponsoredBid,
+};
+use crate::types::{
+    AuctionController, AuctionIncentive, AuctionInfo, AuctionPayoutMode, StatsData, Timestamp,
+    TxError, TxReceipt,
+};
 use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_canister::virtual_canister_call;
+use ic_cdk::api::call::CallResult;
 use ic_kit::ic;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 // Minimum bidding amount is required, for every update call costs cycles, and we want bidding
 // to add cycles rather then to decrease them. 1M is chosen as one ingress call costs 590K cycles.
-const MIN_BIDDING_AMOUNT: u64 = 1_000_000;
+// The owner can change the minimum with `setMinBid`.
+pub const MIN_BIDDING_AMOUNT: u64 = 1_000_000;
+
+/// Maximum number of the runs kept in the automatic auction log.
+pub const MAX_AUTO_AUCTION_LOG_LEN: usize = 100;
+
+/// Maximum number of the auctions returned by one `getAuctions` call.
+pub const MAX_AUCTIONS_QUERY_LEN: usize = 100;
+
+/// Number of basis points in the whole auction pool.
+const BASIS_POINTS: u64 = 10_000;
 
 /// Current information about upcoming auction and current cycle bids.
 #[derive(CandidType, Debug, Clone, Deserialize)]
@@ -32,18 +52,71 @@ pub struct BiddingInfo {
     /// Total cycles accumulated since the last auction.
     total_cycles: u64,
 
-    /// The amount of cycles the caller bid for the upcoming auction.
+    /// Minimum amount of cycles accepted by one `bid_cycles` call.
+    min_bid: u64,
+
+    /// Bounds the `fee_ratio` computed by the auctions is clamped into.
+    min_fee_ratio: f64,
+    max_fee_ratio: f64,
+
+    /// If set, the auction is run automatically on the heartbeat every `auction_period`.
+    auto_auction: bool,
+
+    /// How the next auction will pay the distributed tokens to the bidders.
+    payout_mode: AuctionPayoutMode,
+
+    /// Who is allowed to run the auctions with `runAuction`.
+    controller: AuctionController,
+
+    /// Share of the auction pool paid to the caller of `runAuction`, in basis points.
+    incentive_rate: u64,
+
+    /// The total amount of cycles the caller bid for the upcoming auction. The same as `myBid`.
     caller_cycles: u64,
 
+    /// Bids for the upcoming auction made on behalf of other principals.
+    sponsored_bids: Vec<SponsoredBid>,
+
+    /// Cycles of the skipped bids paid by the caller that can be claimed with `claimBidRefund`.
+    caller_refund_credit: u64,
+
     /// The amount of fees accumulated since the last auction and that will be distributed on the
-    /// next auction.
+    /// next auction. The same as `getFeePool`.
     accumulated_fees: Nat,
 }
 
+/// Lifetime statistics of the held auctions.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct AuctionStats {
+    pub auction_count: u64,
+
+    /// Total cycles collected from the bidders. The cycles of the skipped bids are not included.
+    pub total_cycles_collected: Nat,
+
+    /// Total tokens distributed to the bidders. The caller incentives are not included.
+    pub total_tokens_distributed: Nat,
+
+    /// Average number of the bidders in one auction. `0` if no auctions were held.
+    pub average_participants: f64,
+
+    /// IC time of the last auction. `None` if no auctions were held.
+    pub last_auction_time: Option<Timestamp>,
+}
+
+/// Auction run automatically on the heartbeat.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct AutoAuctionRun {
+    /// IC time of the run.
+    pub time: Timestamp,
+
+    /// Id of the held auction, or the reason it was not held.
+    pub result: Result<usize, AuctionError>,
+}
+
 #[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
 pub enum AuctionError {
-    /// Provided cycles in the `bid_cycles` call is less then the minimum allowed amount.
-    BiddingTooSmall,
+    /// Provided cycles in the `bid_cycles` call is less then the `min` allowed amount.
+    BiddingTooSmall { min: u64 },
 
     /// There are no cycle bids pending, so the auction cannot be held.
     NoBids,
@@ -53,41 +126,343 @@ pub enum AuctionError {
 
     /// The specified period between the auctions is not passed yet.
     TooEarlyToBeginAuction,
+
+    /// The caller has no bid pending for the next auction, or the whole bid was made on its
+    /// behalf by the sponsors.
+    NoBid,
+
+    /// The cycles of the cancelled bid could not be sent back to the bidder, so the bid is kept.
+    RefundFailed { cdk_msg: String },
+
+    /// The auction can be run only by the owner.
+    Unauthorized,
+
+    /// The auction whitelist is enabled and the bidder is not in it.
+    NotWhitelisted,
+
+    /// Bids can't be made on behalf of the anonymous principal.
+    AnonymousBidder,
+
+    /// The caller has no cycles of skipped bids to claim.
+    NoRefundCredit,
 }
 
-pub(crate) fn bid_cycles(canister: &TokenCanister, bidder: Principal) -> Result<u64, AuctionError> {
+/// Accepts the cycles attached to the call as a bid of the `bidder`, or of the caller if the
+/// `bidder` is not given. Bids made on behalf of other principals are listed in `BiddingInfo`.
+/// Repeated bids of the same bidder are added to its bid for the next auction.
+///
+/// If the `bidder` share of the fees distributed by the next auction is less than
+/// `min_expected_tokens`, the bid is skipped and its cycles are refunded. If several minimums are
+/// given for the same bidder, the largest one is used.
+pub(crate) fn bid_cycles(
+    canister: &TokenCanister,
+    bidder: Option<Principal>,
+    min_expected_tokens: Option<Nat>,
+) -> Result<u64, AuctionError> {
     let amount = ic::msg_cycles_available();
-    if amount < MIN_BIDDING_AMOUNT {
-        return Err(AuctionError::BiddingTooSmall);
+    let caller = ic::caller();
+    let bidder = bidder.unwrap_or(caller);
+    if bidder != caller && bidder == Principal::anonymous() {
+        return Err(AuctionError::AnonymousBidder);
+    }
+
+    let mut state = canister.state.borrow_mut();
+    if !state.auction_whitelist.is_allowed(&bidder) {
+        return Err(AuctionError::NotWhitelisted);
     }
 
-    let bidding_state = &mut canister.state.borrow_mut().bidding_state;
+    let bidding_state = &mut state.bidding_state;
+    let min = bidding_state.min_bid();
+    if amount < min {
+        return Err(AuctionError::BiddingTooSmall { min });
+    }
 
     let amount_accepted = ic::msg_cycles_accept(amount);
     bidding_state.cycles_since_auction += amount_accepted;
     *bidding_state.bids.entry(bidder).or_insert(0) += amount_accepted;
+    if bidder != caller {
+        bidding_state.sponsored_bids.push(SponsoredBid {
+            sponsor: caller,
+            bidder,
+            cycles: amount_accepted,
+        });
+    }
+
+    if let Some(min) = min_expected_tokens {
+        bidding_state.expect_min_tokens(bidder, min);
+    }
 
     Ok(amount_accepted)
 }
 
+/// Removes the cycles the `bidder` bid itself from its pending bid and sends them back to the
+/// `bidder` canister with the `wallet_receive` call. Returns the amount of the refunded cycles.
+///
+/// The cycles bid on behalf of the `bidder` by the sponsors stay in the bid, so the bidder can't
+/// take them. If the whole bid was made by the sponsors, [AuctionError::NoBid] is returned.
+///
+/// The cycles are removed from the bid before the refund call, so they can't be cancelled twice
+/// while the call is awaited. If the call fails, the bid is restored together with the bidder's
+/// minimum of expected tokens. The cycles not accepted by the bidder are returned to the token
+/// canister and also stay in the bid.
+pub(crate) async fn cancel_bid(
+    state: Rc<RefCell<CanisterState>>,
+    bidder: Principal,
+) -> Result<u64, AuctionError> {
+    let (amount, min_expected_tokens) = {
+        let bidding_state = &mut state.borrow_mut().bidding_state;
+        let bid = bidding_state.bid(&bidder);
+        let amount = bid.saturating_sub(bidding_state.sponsored_cycles(&bidder));
+        if amount == 0 {
+            return Err(AuctionError::NoBid);
+        }
+
+        bidding_state.cycles_since_auction -= amount;
+        // The minimum of expected tokens stays with the sponsored part of the bid.
+        if amount == bid {
+            bidding_state.bids.remove(&bidder);
+            (amount, bidding_state.min_expected_tokens.remove(&bidder))
+        } else {
+            bidding_state.bids.insert(bidder, bid - amount);
+            (amount, None)
+        }
+    };
+
+    let result = send_refund(bidder, amount).await;
+    let kept = match result {
+        Ok(()) => ic::msg_cycles_refunded(),
+        Err(_) => amount,
+    };
+
+    if kept > 0 {
+        // If the auction was held while the refund was awaited, the kept cycles are bid for the
+        // next one.
+        let bidding_state = &mut state.borrow_mut().bidding_state;
+        bidding_state.cycles_since_auction += kept;
+        *bidding_state.bids.entry(bidder).or_insert(0) += kept;
+        if let Some(min) = min_expected_tokens {
+            bidding_state.expect_min_tokens(bidder, min);
+        }
+    }
+
+    match result {
+        Ok(()) => Ok(amount - kept),
+        Err((_, msg)) => Err(AuctionError::RefundFailed { cdk_msg: msg }),
+    }
+}
+
+async fn send_refund(bidder: Principal, amount: u64) -> CallResult<()> {
+    virtual_canister_call!(bidder, "wallet_receive", (), (), amount).await
+}
+
+/// Sends the `amount` of cycles of the skipped bids back to the `payer` of the bids. The cycles
+/// that could not be sent, or were not accepted by the payer, are kept as the payer refund credit.
+/// Returns the amount of the sent cycles.
+async fn refund_skipped_bid(
+    state: &Rc<RefCell<CanisterState>>,
+    payer: Principal,
+    amount: u64,
+) -> CallResult<u64> {
+    let result = send_refund(payer, amount).await;
+    let kept = match result {
+        Ok(()) => ic::msg_cycles_refunded(),
+        Err(_) => amount,
+    };
+
+    if kept > 0 {
+        let bidding_state = &mut state.borrow_mut().bidding_state;
+        *bidding_state.refund_credits.entry(payer).or_insert(0) += kept;
+    }
+
+    result.map(|_| amount - kept)
+}
+
+/// Sends back the cycles of the bids skipped by the auctions. Returns the amount of the sent
+/// cycles.
+pub(crate) async fn send_pending_refunds(state: Rc<RefCell<CanisterState>>) -> u64 {
+    // The refunds are taken out of the state before the calls, so they are not sent twice by the
+    // next heartbeats.
+    let pending = std::mem::take(&mut state.borrow_mut().bidding_state.pending_refunds);
+
+    let mut sent = 0;
+    for (payer, amount) in pending {
+        if let Ok(amount) = refund_skipped_bid(&state, payer, amount).await {
+            sent += amount;
+        }
+    }
+
+    sent
+}
+
+/// Sends the refund credit of the `payer` back to it. Returns the amount of the sent cycles.
+pub(crate) async fn claim_bid_refund(
+    state: Rc<RefCell<CanisterState>>,
+    payer: Principal,
+) -> Result<u64, AuctionError> {
+    let amount = state
+        .borrow_mut()
+        .bidding_state
+        .refund_credits
+        .remove(&payer)
+        .ok_or(AuctionError::NoRefundCredit)?;
+
+    refund_skipped_bid(&state, payer, amount)
+        .await
+        .map_err(|(_, msg)| AuctionError::RefundFailed { cdk_msg: msg })
+}
+
+/// Spawns the sending of the refunds of the skipped bids, if there are any.
+pub(crate) fn refund_tick(state: &Rc<RefCell<CanisterState>>) {
+    if state.borrow().bidding_state.pending_refunds.is_empty() {
+        return;
+    }
+
+    let state = state.clone();
+    ic_cdk::spawn(async move {
+        send_pending_refunds(state).await;
+    });
+}
+
+pub(crate) fn set_fee_ratio_bounds(
+    canister: &TokenCanister,
+    min: f64,
+    max: f64,
+) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    // Written this way to reject NaN bounds too.
+    if !(0.0 <= min && min <= max && max <= 1.0) {
+        return Err(TxError::InvalidFeeRatioBounds);
+    }
+
+    let bidding_state = &mut state.bidding_state;
+    let (old_min, old_max) = bidding_state.fee_ratio_bounds();
+    bidding_state.fee_ratio_bounds = Some((min, max));
+    bidding_state.set_fee_ratio(bidding_state.fee_ratio);
+    record_config_change(
+        &mut state,
+        "fee_ratio_bounds",
+        format!("[{}, {}]", old_min, old_max),
+        format!("[{}, {}]", min, max),
+    );
+
+    Ok(())
+}
+
 pub(crate) fn bidding_info(canister: &TokenCanister) -> BiddingInfo {
     let state = canister.state.borrow();
     let bidding_state = &state.bidding_state;
     let balances = &state.balances;
 
+    let (min_fee_ratio, max_fee_ratio) = bidding_state.fee_ratio_bounds();
     BiddingInfo {
         fee_ratio: bidding_state.fee_ratio,
+        min_fee_ratio,
+        max_fee_ratio,
+        auto_auction: bidding_state.auto_auction,
+        payout_mode: bidding_state.payout_mode,
+        controller: bidding_state.controller,
+        incentive_rate: bidding_state.incentive_rate,
         last_auction: bidding_state.last_auction,
         auction_period: bidding_state.auction_period,
         total_cycles: bidding_state.cycles_since_auction,
-        caller_cycles: bidding_state.bids.get(&ic::caller()).cloned().unwrap_or(0),
-        accumulated_fees: accumulated_fees(balances),
+        min_bid: bidding_state.min_bid(),
+        caller_cycles: bidding_state.bid(&ic::caller()),
+        sponsored_bids: bidding_state.sponsored_bids.clone(),
+        caller_refund_credit: bidding_state
+            .refund_credits
+            .get(&ic::caller())
+            .cloned()
+            .unwrap_or(0),
+        accumulated_fees: accumulated_fees(balances, bidding_state),
+    }
+}
+
+pub(crate) fn auction_stats(canister: &TokenCanister) -> AuctionStats {
+    let state = canister.state.borrow();
+    let totals = state
+        .auction_stats
+        .clone()
+        .unwrap_or_else(|| AuctionTotals::from_history(&state.auction_history.0));
+
+    let average_participants = match totals.auction_count {
+        0 => 0.0,
+        count => totals.participants as f64 / count as f64,
+    };
+
+    AuctionStats {
+        auction_count: totals.auction_count,
+        total_cycles_collected: totals.cycles_collected,
+        total_tokens_distributed: totals.tokens_distributed,
+        average_participants,
+        last_auction_time: totals.last_auction_time,
     }
 }
 
+/// Total amount of cycles the caller bid for the next auction.
+pub(crate) fn my_bid(canister: &TokenCanister) -> u64 {
+    canister.state.borrow().bidding_state.bid(&ic::caller())
+}
+
+/// Amount of tokens the auction would distribute to the bidders if it was run now.
+pub(crate) fn fee_pool(canister: &TokenCanister) -> Nat {
+    let state = canister.state.borrow();
+    accumulated_fees(&state.balances, &state.bidding_state)
+}
+
+/// Runs the auction if it's due and the caller is allowed to run it. The caller is paid the
+/// configured incentive from the auction pool.
 pub(crate) fn run_auction(canister: &TokenCanister) -> Result<AuctionInfo, AuctionError> {
+    let caller = ic::caller();
     let mut state = canister.state.borrow_mut();
+    if state.bidding_state.controller == AuctionController::OwnerOnly && caller != state.stats.owner
+    {
+        return Err(AuctionError::Unauthorized);
+    }
+
+    perform_due_auction(&mut state, Some(caller))
+}
+
+/// Runs the auction if the automatic auctions are enabled and the auction is due. The result is
+/// added to the automatic auction log, including the `NoBids` error of the periods without bids.
+/// Returns the result, or `None` if no auction was run.
+pub(crate) fn auction_tick(state: &mut CanisterState) -> Option<Result<AuctionInfo, AuctionError>> {
+    if !state.bidding_state.auto_auction || !state.bidding_state.is_auction_due() {
+        return None;
+    }
+
+    let result = perform_due_auction(state, None);
+    let log = &mut state.bidding_state.auto_auction_log;
+    if log.len() >= MAX_AUTO_AUCTION_LOG_LEN {
+        log.remove(0);
+    }
+
+    log.push(AutoAuctionRun {
+        time: ic::time(),
+        result: result
+            .as_ref()
+            .map(|info| info.auction_id)
+            .map_err(Clone::clone),
+    });
+
+    Some(result)
+}
+
+pub(crate) fn auto_auction_log(canister: &TokenCanister) -> Vec<AutoAuctionRun> {
+    canister
+        .state
+        .borrow()
+        .bidding_state
+        .auto_auction_log
+        .clone()
+}
 
+/// Runs the auction if it's due. If the `caller` is given, it's paid the auction incentive.
+fn perform_due_auction(
+    state: &mut CanisterState,
+    caller: Option<Principal>,
+) -> Result<AuctionInfo, AuctionError> {
     if !state.bidding_state.is_auction_due() {
         return Err(AuctionError::TooEarlyToBeginAuction);
     }
@@ -97,11 +472,26 @@ pub(crate) fn run_auction(canister: &TokenCanister) -> Result<AuctionInfo, Aucti
         ref mut balances,
         ref mut auction_history,
         ref mut ledger,
+        ref mut delivery_queue,
+        ref mut auction_stats,
         ref stats,
         ..
-    } = &mut *state;
+    } = state;
+
+    let result = perform_auction(ledger, bidding_state, balances, auction_history, caller);
+    let result = result.map(|mut info| {
+        info.notifications_dispatched = queue_reward_notifications(ledger, delivery_queue, &info);
+        if let Some(last) = auction_history.0.last_mut() {
+            last.notifications_dispatched = info.notifications_dispatched;
+        }
+
+        match auction_stats {
+            Some(totals) => totals.add(&info),
+            None => *auction_stats = Some(AuctionTotals::from_history(&auction_history.0)),
+        }
 
-    let result = perform_auction(ledger, bidding_state, balances, auction_history);
+        info
+    });
     reset_bidding_state(stats, bidding_state);
 
     result
@@ -121,38 +511,157 @@ pub(crate) fn auction_info(
         .ok_or(AuctionError::AuctionNotFound)
 }
 
+/// Returns up to `limit` held auctions starting from the `start` id, oldest first. The `limit` is
+/// clamped to `MAX_AUCTIONS_QUERY_LEN`.
+pub(crate) fn auctions(canister: &TokenCanister, start: usize, limit: usize) -> Vec<AuctionInfo> {
+    let state = canister.state.borrow();
+    let history = &state.auction_history.0;
+    let start = start.min(history.len());
+    let end = start
+        .saturating_add(limit.min(MAX_AUCTIONS_QUERY_LEN))
+        .min(history.len());
+    history[start..end].to_vec()
+}
+
+pub(crate) fn auction_count(canister: &TokenCanister) -> usize {
+    canister.state.borrow().auction_history.0.len()
+}
+
 fn perform_auction(
     ledger: &mut Ledger,
     bidding_state: &mut BiddingState,
     balances: &mut Balances,
     auction_history: &mut AuctionHistory,
+    caller: Option<Principal>,
 ) -> Result<AuctionInfo, AuctionError> {
     if bidding_state.bids.is_empty() {
         return Err(AuctionError::NoBids);
     }
 
-    let total_amount = accumulated_fees(balances);
+    let pool = accumulated_fees(balances, bidding_state);
+    let incentive_amount = match caller {
+        Some(_) => pool.clone() * bidding_state.incentive_rate / BASIS_POINTS,
+        None => Nat::from(0),
+    };
+    let total_amount = pool - incentive_amount.clone();
     let mut transferred_amount = Nat::from(0);
-    let total_cycles = bidding_state.cycles_since_auction;
+    let mut total_cycles = bidding_state.cycles_since_auction;
+
+    // Skipping a bid increases the shares of the other bids, so the shares are computed again
+    // until no more bids are skipped.
+    let mut refunded_bids = vec![];
+    loop {
+        let below_min: Vec<(Principal, u64)> = bidding_state
+            .bids
+            .iter()
+            .filter(|(bidder, _)| !refunded_bids.iter().any(|(skipped, _)| skipped == *bidder))
+            .filter(|(bidder, cycles)| {
+                bidding_state
+                    .min_expected_tokens
+                    .get(bidder)
+                    .map_or(false, |min| {
+                        bid_share(&total_amount, **cycles, total_cycles) < *min
+                    })
+            })
+            .map(|(bidder, cycles)| (*bidder, *cycles))
+            .collect();
+
+        if below_min.is_empty() {
+            break;
+        }
+
+        for (_, cycles) in &below_min {
+            total_cycles -= cycles;
+        }
+        refunded_bids.extend(below_min);
+    }
+
+    // The sponsored cycles of the skipped bids are sent back to the sponsors that paid them.
+    let refunds: Vec<(Principal, u64)> = refunded_bids
+        .iter()
+        .flat_map(|(bidder, cycles)| bidding_state.bid_payers(bidder, *cycles))
+        .collect();
+    bidding_state.pending_refunds.extend(refunds);
+
+    let auction_id = auction_history.0.len();
+    let payout_mode = bidding_state.payout_mode;
+    let first_id = Nat::from(ledger.len());
+    let caller_incentive = match caller {
+        Some(caller) if incentive_amount > Nat::from(0) => {
+            // The incentive is a share of the accumulated fees, so it's always covered by the
+            // auction balance.
+            _transfer(
+                balances,
+                auction_principal(),
+                caller,
+                incentive_amount.clone(),
+            )
+            .ok()
+            .map(|_| AuctionIncentive {
+                caller,
+                amount: incentive_amount.clone(),
+                transaction_id: ledger.auction(auction_principal(), caller, incentive_amount),
+            })
+        }
+        _ => None,
+    };
 
-    let first_id = ledger.len();
+    let mut transaction_ids = vec![];
+    let mut rewards = HashMap::new();
 
     for (bidder, cycles) in &bidding_state.bids {
-        let amount = total_amount.clone() * *cycles / total_cycles;
-        _transfer(balances, auction_principal(), *bidder, amount.clone());
-        ledger.auction(*bidder, amount.clone());
-        transferred_amount += amount;
+        if refunded_bids.iter().any(|(skipped, _)| skipped == bidder) {
+            continue;
+        }
+
+        let amount = bid_share(&total_amount, *cycles, total_cycles);
+        match payout_mode {
+            AuctionPayoutMode::Push => {
+                // The distributed amounts are proportional to the bids, so their sum never exceeds
+                // the accumulated fees.
+                if _transfer(balances, auction_principal(), *bidder, amount.clone()).is_ok() {
+                    transaction_ids.push(ledger.auction(
+                        auction_principal(),
+                        *bidder,
+                        amount.clone(),
+                    ));
+                    transferred_amount += amount;
+                }
+            }
+            AuctionPayoutMode::Claim => {
+                // The rewards stay in the auction balance until they are claimed, and are not
+                // counted in the accumulated fees of the next auctions.
+                transferred_amount += amount.clone();
+                rewards.insert(
+                    *bidder,
+                    AuctionReward {
+                        amount,
+                        transaction_id: None,
+                    },
+                );
+            }
+        }
+    }
+
+    if !rewards.is_empty() {
+        bidding_state.auction_rewards.insert(auction_id, rewards);
     }
 
-    let last_id = ledger.len() - 1;
+    let last_id = Nat::from(ledger.last_id());
     let result = AuctionInfo {
-        auction_id: auction_history.0.len(),
+        auction_id,
         auction_time: ic::time(),
         tokens_distributed: transferred_amount,
         cycles_collected: total_cycles,
         fee_ratio: bidding_state.fee_ratio,
         first_transaction_id: first_id,
         last_transaction_id: last_id,
+        transaction_ids,
+        notifications_dispatched: false,
+        refunded_bids,
+        payout_mode,
+        caller_incentive,
+        participants: bidding_state.bids.len() as u64,
     };
 
     auction_history.0.push(result.clone());
@@ -160,11 +669,79 @@ fn perform_auction(
     Ok(result)
 }
 
+/// Pays the caller's reward of the auction `auction_id` held in the `Claim` payout mode. Returns
+/// the id of the `Auction` transaction paying the reward.
+pub(crate) fn claim_auction_reward(canister: &TokenCanister, auction_id: usize) -> TxReceipt {
+    let caller = ic::caller();
+    let mut state = canister.state.borrow_mut();
+    let CanisterState {
+        ref mut bidding_state,
+        ref mut balances,
+        ref mut ledger,
+        ..
+    } = *state;
+
+    let reward = bidding_state
+        .auction_rewards
+        .get_mut(&auction_id)
+        .and_then(|rewards| rewards.get_mut(&caller))
+        .ok_or(TxError::NoAuctionReward)?;
+    if reward.transaction_id.is_some() {
+        return Err(TxError::AlreadyClaimed);
+    }
+
+    _transfer(balances, auction_principal(), caller, reward.amount.clone())?;
+    let id = ledger.auction(auction_principal(), caller, reward.amount.clone());
+    reward.transaction_id = Some(id.clone());
+
+    Ok(id)
+}
+
+/// Amount of the `total_amount` of fees distributed to the bid of `cycles`.
+fn bid_share(total_amount: &Nat, cycles: u64, total_cycles: u64) -> Nat {
+    if total_cycles == 0 {
+        return Nat::from(0);
+    }
+
+    total_amount.clone() * cycles / total_cycles
+}
+
+/// Queues the `auction_reward` notifications of the bidder canisters that received tokens in the
+/// auction. The notifications are sent from the delivery queue on the heartbeat, so their failures
+/// don't affect the auction. Returns `true` if any notifications were queued.
+fn queue_reward_notifications(
+    ledger: &Ledger,
+    delivery_queue: &mut DeliveryQueue,
+    info: &AuctionInfo,
+) -> bool {
+    let mut queued = false;
+    for id in &info.transaction_ids {
+        let tx = match ledger.get(to_id(id)) {
+            Some(tx) if tx.amount > 0u32 && is_canister(&tx.to) => tx,
+            _ => continue,
+        };
+
+        delivery_queue.push(PendingDelivery {
+            subscriber: tx.to,
+            callback: tx.to,
+            record: tx.clone(),
+            attempts: 0,
+            next_attempt: ic::time(),
+            auction_id: Some(info.auction_id),
+        });
+        queued = true;
+    }
+
+    queued
+}
+
 fn reset_bidding_state(stats: &StatsData, bidding_state: &mut BiddingState) {
-    bidding_state.fee_ratio = get_fee_ratio(stats.min_cycles, ic::balance());
+    bidding_state.set_fee_ratio(get_fee_ratio(stats.min_cycles, ic::balance()));
     bidding_state.cycles_since_auction = 0;
     bidding_state.last_auction = ic::time();
     bidding_state.bids = HashMap::new();
+    bidding_state.sponsored_bids.clear();
+    bidding_state.min_expected_tokens.clear();
 }
 
 fn get_fee_ratio(min_cycles: u64, current_cycles: u64) -> f64 {
@@ -190,24 +767,31 @@ pub fn auction_principal() -> Principal {
     Principal::management_canister()
 }
 
-pub fn accumulated_fees(balances: &Balances) -> Nat {
-    balances
+pub fn accumulated_fees(balances: &Balances, bidding_state: &BiddingState) -> Nat {
+    let balance = balances
         .0
         .get(&auction_principal())
         .cloned()
-        .unwrap_or_else(|| Nat::from(0))
+        .unwrap_or_else(|| Nat::from(0));
+    let unclaimed = bidding_state.unclaimed_rewards();
+    if balance > unclaimed {
+        balance - unclaimed
+    } else {
+        Nat::from(0)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::canister::is20_events::retry_deliveries;
     use common::types::Metadata;
-    use ic_kit::mock_principals::{alice, bob};
+    use ic_kit::mock_principals::{alice, bob, john, xtc};
     use ic_kit::MockContext;
     use test_case::test_case;
 
-    use crate::types::TxError;
-    use ic_canister::Canister;
+    use crate::types::{Operation, TxError, MAX_AUCTION_INCENTIVE_RATE};
+    use ic_canister::{register_failing_virtual_responder, register_virtual_responder, Canister};
 
     fn test_context() -> (&'static mut MockContext, TokenCanister) {
         let context = MockContext::new().with_caller(alice()).inject();
@@ -223,6 +807,8 @@ mod tests {
             fee: Nat::from(0),
             feeTo: alice(),
             isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
         });
 
         (context, canister)
@@ -244,7 +830,7 @@ mod tests {
         context.update_caller(bob());
         context.update_msg_cycles(2_000_000);
 
-        canister.bidCycles(bob()).unwrap();
+        canister.bidCycles(Some(bob()), None).unwrap();
         let info = canister.biddingInfo();
         assert_eq!(info.total_cycles, 2_000_000);
         assert_eq!(info.caller_cycles, 2_000_000);
@@ -255,37 +841,139 @@ mod tests {
         assert_eq!(info.caller_cycles, 0);
     }
 
+    #[test]
+    fn bidding_for_caller_by_default() {
+        let (context, canister) = test_context();
+        context.update_caller(bob());
+        context.update_msg_cycles(2_000_000);
+
+        assert_eq!(canister.bidCycles(None, None), Ok(2_000_000));
+        let info = canister.biddingInfo();
+        assert_eq!(info.caller_cycles, 2_000_000);
+        assert!(info.sponsored_bids.is_empty());
+
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        let info = canister.biddingInfo();
+        assert_eq!(info.caller_cycles, 4_000_000);
+        assert!(info.sponsored_bids.is_empty());
+    }
+
+    #[test]
+    fn bidding_on_behalf_of_other_principal() {
+        let (context, canister) = test_context();
+        context.update_caller(bob());
+        context.update_msg_cycles(2_000_000);
+
+        assert_eq!(canister.bidCycles(Some(john()), None), Ok(2_000_000));
+        let info = canister.biddingInfo();
+        assert_eq!(info.caller_cycles, 0);
+        assert_eq!(
+            info.sponsored_bids,
+            vec![SponsoredBid {
+                sponsor: bob(),
+                bidder: john(),
+                cycles: 2_000_000,
+            }]
+        );
+
+        context.update_caller(john());
+        assert_eq!(canister.biddingInfo().caller_cycles, 2_000_000);
+
+        context.update_msg_cycles(2_000_000);
+        assert_eq!(
+            canister.bidCycles(Some(Principal::anonymous()), None),
+            Err(AuctionError::AnonymousBidder)
+        );
+
+        // The sponsored bids are listed until the auction is held.
+        canister.runAuction().unwrap();
+        assert!(canister.biddingInfo().sponsored_bids.is_empty());
+    }
+
     #[test]
     fn bidding_cycles_under_limit() {
         let (context, canister) = test_context();
         context.update_msg_cycles(MIN_BIDDING_AMOUNT - 1);
         assert_eq!(
-            canister.bidCycles(alice()),
-            Err(AuctionError::BiddingTooSmall)
+            canister.bidCycles(Some(alice()), None),
+            Err(AuctionError::BiddingTooSmall {
+                min: MIN_BIDDING_AMOUNT
+            })
+        );
+    }
+
+    #[test]
+    fn bidding_at_min_bid() {
+        let (context, canister) = test_context();
+        canister.setMinBid(5_000_000).unwrap();
+        assert_eq!(canister.biddingInfo().min_bid, 5_000_000);
+
+        context.update_msg_cycles(4_999_999);
+        assert_eq!(
+            canister.bidCycles(Some(alice()), None),
+            Err(AuctionError::BiddingTooSmall { min: 5_000_000 })
+        );
+
+        context.update_msg_cycles(5_000_000);
+        assert_eq!(canister.bidCycles(Some(alice()), None), Ok(5_000_000));
+
+        context.update_caller(bob());
+        assert!(matches!(
+            canister.setMinBid(1),
+            Err(TxError::Unauthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn min_bid_changed_with_pending_bids() {
+        let (context, canister) = test_context();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(alice()), None).unwrap();
+
+        canister.setMinBid(3_000_000).unwrap();
+        context.update_msg_cycles(2_000_000);
+        assert_eq!(
+            canister.bidCycles(Some(bob()), None),
+            Err(AuctionError::BiddingTooSmall { min: 3_000_000 })
         );
+
+        // The bids placed before the change stay in the auction.
+        assert_eq!(canister.biddingInfo().caller_cycles, 2_000_000);
+        let result = canister.runAuction().unwrap();
+        assert_eq!(result.cycles_collected, 2_000_000);
+
+        // Lowering the minimum applies to the next bids.
+        canister.setMinBid(1_000).unwrap();
+        context.update_msg_cycles(1_000);
+        assert_eq!(canister.bidCycles(Some(bob()), None), Ok(1_000));
     }
 
     #[test]
     fn bidding_multiple_times() {
         let (context, canister) = test_context();
         context.update_msg_cycles(2_000_000);
-        canister.bidCycles(alice()).unwrap();
+        canister.bidCycles(Some(alice()), None).unwrap();
 
         context.update_msg_cycles(2_000_000);
-        canister.bidCycles(alice()).unwrap();
+        canister.bidCycles(Some(alice()), None).unwrap();
 
         assert_eq!(canister.biddingInfo().caller_cycles, 4_000_000);
+        assert_eq!(canister.myBid(), 4_000_000);
     }
 
     #[test]
-    fn auction_test() {
+    fn topped_up_bid_weighs_sum() {
         let (context, canister) = test_context();
+        context.update_msg_cycles(1_000_000);
+        canister.bidCycles(None, None).unwrap();
         context.update_msg_cycles(2_000_000);
-        canister.bidCycles(alice()).unwrap();
-
-        context.update_msg_cycles(4_000_000);
-        canister.bidCycles(bob()).unwrap();
+        canister.bidCycles(None, None).unwrap();
+        assert_eq!(canister.myBid(), 3_000_000);
 
+        context.update_msg_cycles(3_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        assert_eq!(canister.myBid(), 3_000_000);
         canister
             .state
             .borrow_mut()
@@ -295,56 +983,1087 @@ mod tests {
 
         let result = canister.runAuction().unwrap();
         assert_eq!(result.cycles_collected, 6_000_000);
-        assert_eq!(result.first_transaction_id, Nat::from(1));
-        assert_eq!(result.last_transaction_id, Nat::from(2));
-        assert_eq!(result.tokens_distributed, Nat::from(6_000));
+        let state = canister.state.borrow();
+        assert_eq!(state.balances.0[&bob()], 3_000);
+        assert_eq!(state.balances.0[&alice()], Nat::from(1000) + 3_000);
+        drop(state);
 
-        assert_eq!(canister.state.borrow().balances.0[&bob()], 4_000);
-
-        let retrieved_result = canister.auctionInfo(result.auction_id).unwrap();
-        assert_eq!(retrieved_result, result);
+        assert_eq!(canister.myBid(), 0);
     }
 
     #[test]
-    fn auction_without_bids() {
-        let (_, canister) = test_context();
-        assert_eq!(canister.runAuction(), Err(AuctionError::NoBids));
+    fn auction_whitelist() {
+        let (context, canister) = test_context();
+        canister.setAuctionWhitelistEnabled(true).unwrap();
+
+        context.update_msg_cycles(2_000_000);
+        assert_eq!(
+            canister.bidCycles(Some(bob()), None),
+            Err(AuctionError::NotWhitelisted)
+        );
+        assert_eq!(canister.biddingInfo().total_cycles, 0);
+
+        canister.addAuctionBidder(bob()).unwrap();
+        assert_eq!(canister.getAuctionBidders(), vec![bob()]);
+        assert_eq!(canister.bidCycles(Some(bob()), None), Ok(2_000_000));
+
+        canister.setAuctionWhitelistEnabled(false).unwrap();
+        context.update_msg_cycles(2_000_000);
+        assert_eq!(canister.bidCycles(Some(john()), None), Ok(2_000_000));
     }
 
     #[test]
-    fn auction_not_in_time() {
+    fn removed_bidder_keeps_pending_bid() {
         let (context, canister) = test_context();
+        canister.addAuctionBidder(bob()).unwrap();
+        canister.setAuctionWhitelistEnabled(true).unwrap();
         context.update_msg_cycles(2_000_000);
-        canister.bidCycles(alice()).unwrap();
-
-        {
-            let state = &mut canister.state.borrow_mut().bidding_state;
-            state.last_auction = ic::time() - 100_000;
-            state.auction_period = 1_000_000_000;
-        }
+        canister.bidCycles(Some(bob()), None).unwrap();
 
+        canister.removeAuctionBidder(bob()).unwrap();
+        assert_eq!(canister.getAuctionBidders(), vec![]);
+        context.update_msg_cycles(2_000_000);
         assert_eq!(
-            canister.runAuction(),
-            Err(AuctionError::TooEarlyToBeginAuction)
+            canister.bidCycles(Some(bob()), None),
+            Err(AuctionError::NotWhitelisted)
         );
+
+        // Bids accepted before the removal stay in the auction.
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(1_000));
+        let result = canister.runAuction().unwrap();
+        assert_eq!(result.cycles_collected, 2_000_000);
+        assert_eq!(canister.state.borrow().balances.0[&bob()], 1_000);
+
+        // Bidding is open to everyone again when the whitelist is disabled.
+        canister.setAuctionWhitelistEnabled(false).unwrap();
+        context.update_msg_cycles(2_000_000);
+        assert_eq!(canister.bidCycles(Some(bob()), None), Ok(2_000_000));
     }
 
     #[test]
-    fn fee_ratio_update() {
+    fn auction_whitelist_not_authorized() {
         let (context, canister) = test_context();
-        context.update_balance(1_000_000_000);
+        context.update_caller(bob());
+        assert!(matches!(
+            canister.setAuctionWhitelistEnabled(true),
+            Err(TxError::Unauthorized { .. })
+        ));
+        assert!(matches!(
+            canister.addAuctionBidder(bob()),
+            Err(TxError::Unauthorized { .. })
+        ));
+        assert!(matches!(
+            canister.removeAuctionBidder(bob()),
+            Err(TxError::Unauthorized { .. })
+        ));
+    }
 
-        canister.state.borrow_mut().stats.min_cycles = 1_000_000;
-        canister.runAuction().unwrap_err();
+    /// Moves the last auction time back by one period, so the next auction is due.
+    fn skip_period(canister: &TokenCanister) {
+        let bidding_state = &mut canister.state.borrow_mut().bidding_state;
+        bidding_state.last_auction = ic::time() - bidding_state.auction_period;
+    }
 
-        assert_eq!(canister.state.borrow().bidding_state.fee_ratio, 0.125);
+    fn tick(canister: &TokenCanister) -> Option<Result<AuctionInfo, AuctionError>> {
+        auction_tick(&mut canister.state.borrow_mut())
     }
 
     #[test]
-    fn setting_min_cycles() {
-        let (_, canister) = test_context();
-        canister.setMinCycles(100500).unwrap();
-        assert_eq!(canister.getMinCycles(), 100500);
+    fn auto_auction() {
+        let (context, canister) = test_context();
+        canister.state.borrow_mut().bidding_state.last_auction = ic::time();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+
+        // Disabled by default.
+        skip_period(&canister);
+        assert_eq!(tick(&canister), None);
+
+        canister.setAutoAuction(true).unwrap();
+        assert!(canister.biddingInfo().auto_auction);
+        let info = tick(&canister).unwrap().unwrap();
+        assert_eq!(info.cycles_collected, 2_000_000);
+        assert_eq!(canister.getAuctionCount(), 1);
+
+        // The next auction is only due after another period.
+        assert_eq!(tick(&canister), None);
+
+        // Periods without bids are recorded in the log.
+        skip_period(&canister);
+        assert_eq!(tick(&canister), Some(Err(AuctionError::NoBids)));
+        assert_eq!(canister.getAuctionCount(), 1);
+
+        let log = canister.getAutoAuctionLog();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].result, Ok(0));
+        assert_eq!(log[1].result, Err(AuctionError::NoBids));
+
+        canister.setAutoAuction(false).unwrap();
+        skip_period(&canister);
+        assert_eq!(tick(&canister), None);
+    }
+
+    #[test]
+    fn auto_auction_survives_upgrade() {
+        let (_, canister) = test_context();
+        canister.setAutoAuction(true).unwrap();
+
+        canister.__pre_upgrade_inst();
+        canister.state.borrow_mut().bidding_state = Default::default();
+        canister.__post_upgrade_inst();
+
+        assert!(canister.biddingInfo().auto_auction);
+        skip_period(&canister);
+        assert_eq!(tick(&canister), Some(Err(AuctionError::NoBids)));
+    }
+
+    #[test]
+    fn auto_auction_log_bounded() {
+        let (_, canister) = test_context();
+        canister.setAutoAuction(true).unwrap();
+        for _ in 0..MAX_AUTO_AUCTION_LOG_LEN + 5 {
+            skip_period(&canister);
+            tick(&canister).unwrap().unwrap_err();
+        }
+
+        assert_eq!(canister.getAutoAuctionLog().len(), MAX_AUTO_AUCTION_LOG_LEN);
+    }
+
+    #[tokio::test]
+    async fn cancel_bid_refunds_once() {
+        register_virtual_responder(bob(), "wallet_receive", |_: ()| {});
+
+        let (context, canister) = test_context();
+        context.update_caller(bob());
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+
+        assert_eq!(canister.cancelBid().await, Ok(2_000_000));
+        assert_eq!(canister.biddingInfo().caller_cycles, 0);
+        assert_eq!(canister.biddingInfo().total_cycles, 0);
+
+        assert_eq!(canister.cancelBid().await, Err(AuctionError::NoBid));
+    }
+
+    #[tokio::test]
+    async fn failed_refund_keeps_bid() {
+        register_failing_virtual_responder(bob(), "wallet_receive", "trapped".into());
+
+        let (context, canister) = test_context();
+        context.update_caller(bob());
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+
+        assert!(matches!(
+            canister.cancelBid().await,
+            Err(AuctionError::RefundFailed { .. })
+        ));
+        let info = canister.biddingInfo();
+        assert_eq!(info.caller_cycles, 2_000_000);
+        assert_eq!(info.total_cycles, 2_000_000);
+    }
+
+    #[tokio::test]
+    async fn failed_refund_keeps_min_expected_tokens() {
+        register_failing_virtual_responder(bob(), "wallet_receive", "trapped".into());
+
+        let (context, canister) = test_context();
+        context.update_caller(bob());
+        context.update_msg_cycles(2_000_000);
+        canister
+            .bidCycles(Some(bob()), Some(Nat::from(3_000)))
+            .unwrap();
+        context.update_caller(john());
+        context.update_msg_cycles(4_000_000);
+        canister.bidCycles(None, None).unwrap();
+
+        context.update_caller(bob());
+        assert!(matches!(
+            canister.cancelBid().await,
+            Err(AuctionError::RefundFailed { .. })
+        ));
+        assert_eq!(
+            canister.state.borrow().bidding_state.min_expected_tokens[&bob()],
+            Nat::from(3_000)
+        );
+
+        // The restored bid is still skipped if its share is below the minimum.
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(6_000));
+        context.update_caller(alice());
+        let result = canister.runAuction().unwrap();
+        assert_eq!(result.refunded_bids, vec![(bob(), 2_000_000)]);
+    }
+
+    #[tokio::test]
+    async fn cancel_bid_keeps_sponsored_cycles() {
+        register_virtual_responder(bob(), "wallet_receive", |_: ()| {});
+
+        let (context, canister) = test_context();
+        context.update_caller(john());
+        context.update_msg_cycles(4_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+
+        // The whole bid was paid by the sponsor, so the bidder can't cancel it.
+        context.update_caller(bob());
+        assert_eq!(canister.cancelBid().await, Err(AuctionError::NoBid));
+        assert_eq!(canister.biddingInfo().caller_cycles, 4_000_000);
+
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(None, None).unwrap();
+        assert_eq!(canister.cancelBid().await, Ok(2_000_000));
+
+        let info = canister.biddingInfo();
+        assert_eq!(info.caller_cycles, 4_000_000);
+        assert_eq!(info.total_cycles, 4_000_000);
+        assert_eq!(
+            info.sponsored_bids,
+            vec![SponsoredBid {
+                sponsor: john(),
+                bidder: bob(),
+                cycles: 4_000_000,
+            }]
+        );
+        assert_eq!(canister.cancelBid().await, Err(AuctionError::NoBid));
+    }
+
+    #[tokio::test]
+    async fn failed_refund_keeps_sponsored_bids() {
+        register_failing_virtual_responder(bob(), "wallet_receive", "trapped".into());
+
+        let (context, canister) = test_context();
+        context.update_caller(john());
+        context.update_msg_cycles(4_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        context.update_caller(bob());
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(None, None).unwrap();
+
+        assert!(matches!(
+            canister.cancelBid().await,
+            Err(AuctionError::RefundFailed { .. })
+        ));
+        let info = canister.biddingInfo();
+        assert_eq!(info.caller_cycles, 6_000_000);
+        assert_eq!(info.total_cycles, 6_000_000);
+        assert_eq!(info.sponsored_bids.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancel_bid_after_auction() {
+        register_virtual_responder(bob(), "wallet_receive", |_: ()| {});
+
+        let (context, canister) = test_context();
+        context.update_caller(bob());
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        canister.runAuction().unwrap();
+
+        assert_eq!(canister.cancelBid().await, Err(AuctionError::NoBid));
+    }
+
+    #[test]
+    fn auction_test() {
+        let (context, canister) = test_context();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(alice()), None).unwrap();
+
+        context.update_msg_cycles(4_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(6_000));
+
+        let result = canister.runAuction().unwrap();
+        assert_eq!(result.cycles_collected, 6_000_000);
+        assert_eq!(result.first_transaction_id, Nat::from(1));
+        assert_eq!(result.last_transaction_id, Nat::from(2));
+        assert_eq!(result.tokens_distributed, Nat::from(6_000));
+
+        assert_eq!(canister.state.borrow().balances.0[&bob()], 4_000);
+
+        assert_eq!(result.transaction_ids.len(), 2);
+        for id in &result.transaction_ids {
+            let tx = canister.getTransaction(id.clone());
+            assert_eq!(tx.operation, Operation::Auction);
+            assert_eq!(tx.from, auction_principal());
+            assert_eq!(tx.caller, None);
+            let expected = if tx.to == bob() { 4_000 } else { 2_000 };
+            assert_eq!(tx.amount, Nat::from(expected));
+        }
+
+        let retrieved_result = canister.auctionInfo(result.auction_id).unwrap();
+        assert_eq!(retrieved_result, result);
+    }
+
+    #[tokio::test]
+    async fn bid_below_min_expected_tokens_refunded() {
+        register_virtual_responder(bob(), "wallet_receive", |_: ()| {});
+
+        let (context, canister) = test_context();
+        context.update_caller(bob());
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(None, Some(Nat::from(3_000))).unwrap();
+        context.update_caller(john());
+        context.update_msg_cycles(4_000_000);
+        canister.bidCycles(None, Some(Nat::from(3_000))).unwrap();
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(6_000));
+
+        // Bob's share of 2_000 is below his minimum, so all the fees go to John.
+        let result = canister.runAuction().unwrap();
+        assert_eq!(result.refunded_bids, vec![(bob(), 2_000_000)]);
+        assert_eq!(result.cycles_collected, 4_000_000);
+        assert_eq!(result.tokens_distributed, Nat::from(6_000));
+        assert_eq!(result.transaction_ids.len(), 1);
+        assert_eq!(canister.state.borrow().balances.0[&john()], 6_000);
+        assert!(canister.state.borrow().balances.0.get(&bob()).is_none());
+        assert_eq!(canister.auctionInfo(0).unwrap(), result);
+
+        assert_eq!(
+            send_pending_refunds(canister.state.clone()).await,
+            2_000_000
+        );
+        assert!(canister
+            .state
+            .borrow()
+            .bidding_state
+            .pending_refunds
+            .is_empty());
+        context.update_caller(bob());
+        assert_eq!(canister.biddingInfo().caller_refund_credit, 0);
+    }
+
+    #[tokio::test]
+    async fn skipped_sponsored_bid_refunded_to_sponsor() {
+        register_virtual_responder(bob(), "wallet_receive", |_: ()| {});
+        register_failing_virtual_responder(john(), "wallet_receive", "trapped".into());
+
+        let (context, canister) = test_context();
+        context.update_caller(bob());
+        context.update_msg_cycles(1_000_000);
+        canister.bidCycles(None, Some(Nat::from(3_000))).unwrap();
+        context.update_caller(john());
+        canister.bidCycles(Some(bob()), None).unwrap();
+        context.update_caller(alice());
+        context.update_msg_cycles(4_000_000);
+        canister.bidCycles(None, None).unwrap();
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(6_000));
+
+        let result = canister.runAuction().unwrap();
+        assert_eq!(result.refunded_bids, vec![(bob(), 2_000_000)]);
+
+        // Bob gets back only his own cycles, the sponsored ones go back to John.
+        assert_eq!(
+            send_pending_refunds(canister.state.clone()).await,
+            1_000_000
+        );
+        context.update_caller(bob());
+        assert_eq!(canister.biddingInfo().caller_refund_credit, 0);
+        context.update_caller(john());
+        assert_eq!(canister.biddingInfo().caller_refund_credit, 1_000_000);
+    }
+
+    #[test]
+    fn skipping_bid_increases_other_shares() {
+        let (context, canister) = test_context();
+        for (bidder, min) in [(alice(), 1_500), (bob(), 2_500), (john(), 2_500)] {
+            context.update_msg_cycles(2_000_000);
+            canister
+                .bidCycles(Some(bidder), Some(Nat::from(min)))
+                .unwrap();
+        }
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(6_000));
+
+        // Once all the bids below 2_000 are skipped, Alice's share grows to 3_000.
+        let result = canister.runAuction().unwrap();
+        let mut refunded = result.refunded_bids.clone();
+        refunded.sort();
+        let mut expected = vec![(bob(), 2_000_000), (john(), 2_000_000)];
+        expected.sort();
+        assert_eq!(refunded, expected);
+        assert_eq!(canister.state.borrow().balances.0[&alice()], 3_000);
+    }
+
+    #[tokio::test]
+    async fn failed_skipped_bid_refund_claimable() {
+        let (context, canister) = test_context();
+        context.update_caller(xtc());
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(None, Some(Nat::from(1))).unwrap();
+        context.update_caller(alice());
+        canister.runAuction().unwrap();
+
+        register_failing_virtual_responder(xtc(), "wallet_receive", "trapped".into());
+        assert_eq!(send_pending_refunds(canister.state.clone()).await, 0);
+        context.update_caller(xtc());
+        assert_eq!(canister.biddingInfo().caller_refund_credit, 2_000_000);
+
+        assert!(matches!(
+            canister.claimBidRefund().await,
+            Err(AuctionError::RefundFailed { .. })
+        ));
+        assert_eq!(canister.biddingInfo().caller_refund_credit, 2_000_000);
+
+        register_virtual_responder(xtc(), "wallet_receive", |_: ()| {});
+        assert_eq!(canister.claimBidRefund().await, Ok(2_000_000));
+        assert_eq!(canister.biddingInfo().caller_refund_credit, 0);
+        assert_eq!(
+            canister.claimBidRefund().await,
+            Err(AuctionError::NoRefundCredit)
+        );
+    }
+
+    #[test]
+    fn auction_rewards_claimed() {
+        let (context, canister) = test_context();
+        canister
+            .setAuctionPayoutMode(AuctionPayoutMode::Claim)
+            .unwrap();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(alice()), None).unwrap();
+        context.update_msg_cycles(4_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(6_000));
+
+        let result = canister.runAuction().unwrap();
+        assert_eq!(result.payout_mode, AuctionPayoutMode::Claim);
+        assert_eq!(result.tokens_distributed, Nat::from(6_000));
+        assert!(result.transaction_ids.is_empty());
+        assert!(canister.state.borrow().balances.0.get(&bob()).is_none());
+
+        // The unclaimed rewards are not distributed again.
+        assert_eq!(canister.biddingInfo().accumulated_fees, Nat::from(0));
+
+        context.update_caller(bob());
+        let id = canister.claimAuctionReward(0).unwrap();
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::Auction);
+        assert_eq!(tx.from, auction_principal());
+        assert_eq!(tx.to, bob());
+        assert_eq!(tx.amount, Nat::from(4_000));
+        assert_eq!(canister.state.borrow().balances.0[&bob()], 4_000);
+        assert_eq!(
+            canister.state.borrow().balances.0[&auction_principal()],
+            2_000
+        );
+
+        assert_eq!(canister.claimAuctionReward(0), Err(TxError::AlreadyClaimed));
+    }
+
+    #[test]
+    fn auction_reward_not_participated() {
+        let (context, canister) = test_context();
+        canister
+            .setAuctionPayoutMode(AuctionPayoutMode::Claim)
+            .unwrap();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        canister.runAuction().unwrap();
+
+        context.update_caller(john());
+        assert_eq!(
+            canister.claimAuctionReward(0),
+            Err(TxError::NoAuctionReward)
+        );
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.claimAuctionReward(1),
+            Err(TxError::NoAuctionReward)
+        );
+    }
+
+    #[test]
+    fn push_auction_rewards_not_claimable() {
+        let (context, canister) = test_context();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        canister.runAuction().unwrap();
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.claimAuctionReward(0),
+            Err(TxError::NoAuctionReward)
+        );
+    }
+
+    #[test]
+    fn auction_rewards_survive_upgrade() {
+        let (context, canister) = test_context();
+        canister
+            .setAuctionPayoutMode(AuctionPayoutMode::Claim)
+            .unwrap();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(1_000));
+        canister.runAuction().unwrap();
+
+        canister.__pre_upgrade_inst();
+        canister.state.borrow_mut().bidding_state = Default::default();
+        canister.__post_upgrade_inst();
+
+        context.update_caller(bob());
+        assert!(canister.claimAuctionReward(0).is_ok());
+        assert_eq!(canister.state.borrow().balances.0[&bob()], 1_000);
+    }
+
+    #[test]
+    fn auction_payout_mode_owner_only() {
+        let (context, canister) = test_context();
+        context.update_caller(bob());
+        assert!(matches!(
+            canister.setAuctionPayoutMode(AuctionPayoutMode::Claim),
+            Err(TxError::Unauthorized { .. })
+        ));
+        assert_eq!(canister.biddingInfo().payout_mode, AuctionPayoutMode::Push);
+    }
+
+    #[test]
+    fn auction_history_pages() {
+        let (context, canister) = test_context();
+        canister.state.borrow_mut().bidding_state.auction_period = 0;
+        for i in 0..5 {
+            context.update_msg_cycles(MIN_BIDDING_AMOUNT + i);
+            canister.bidCycles(Some(bob()), None).unwrap();
+            canister.runAuction().unwrap();
+        }
+
+        assert_eq!(canister.getAuctionCount(), 5);
+
+        let page = canister.getAuctions(1, 3);
+        assert_eq!(page.len(), 3);
+        for (i, auction) in page.iter().enumerate() {
+            assert_eq!(auction.auction_id, i + 1);
+            assert_eq!(auction.cycles_collected, MIN_BIDDING_AMOUNT + i as u64 + 1);
+            assert_eq!(canister.auctionInfo(auction.auction_id).unwrap(), *auction);
+        }
+
+        let page = canister.getAuctions(4, 10);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].auction_id, 4);
+
+        assert!(canister.getAuctions(5, 10).is_empty());
+        assert!(canister.getAuctions(usize::MAX, usize::MAX).is_empty());
+        assert_eq!(canister.getAuctions(0, usize::MAX).len(), 5);
+    }
+
+    #[test]
+    fn auction_history_limit_clamped() {
+        let (_, canister) = test_context();
+        let auction = AuctionInfo {
+            auction_id: 0,
+            auction_time: 0,
+            tokens_distributed: Nat::from(0),
+            cycles_collected: 0,
+            fee_ratio: 0.0,
+            first_transaction_id: Nat::from(0),
+            last_transaction_id: Nat::from(0),
+            transaction_ids: vec![],
+            notifications_dispatched: false,
+            refunded_bids: vec![],
+            payout_mode: AuctionPayoutMode::Push,
+            caller_incentive: None,
+            participants: 0,
+        };
+        canister.state.borrow_mut().auction_history.0 = (0..MAX_AUCTIONS_QUERY_LEN + 10)
+            .map(|auction_id| AuctionInfo {
+                auction_id,
+                ..auction.clone()
+            })
+            .collect();
+
+        assert_eq!(
+            canister.getAuctions(0, usize::MAX).len(),
+            MAX_AUCTIONS_QUERY_LEN
+        );
+        assert_eq!(
+            canister
+                .getAuctions(MAX_AUCTIONS_QUERY_LEN, usize::MAX)
+                .len(),
+            10
+        );
+    }
+
+    #[test]
+    fn fee_ratio_clamped_to_bounds() {
+        let (context, canister) = test_context();
+        canister.state.borrow_mut().bidding_state.auction_period = 0;
+        canister.setFeeRatioBounds(0.2, 0.8).unwrap();
+
+        // Zero min cycles turn the auction off, so the computed ratio is 0.0.
+        canister.setMinCycles(0).unwrap();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(alice()), None).unwrap();
+        canister.runAuction().unwrap();
+        assert_eq!(canister.biddingInfo().fee_ratio, 0.2);
+
+        // The balance is below min cycles, so the computed ratio is 1.0.
+        canister.setMinCycles(u64::MAX).unwrap();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(alice()), None).unwrap();
+        canister.runAuction().unwrap();
+
+        let info = canister.biddingInfo();
+        assert_eq!(info.fee_ratio, 0.8);
+        assert_eq!(info.min_fee_ratio, 0.2);
+        assert_eq!(info.max_fee_ratio, 0.8);
+
+        // The current ratio is clamped when the bounds change.
+        canister.setFeeRatioBounds(0.1, 0.5).unwrap();
+        assert_eq!(canister.biddingInfo().fee_ratio, 0.5);
+    }
+
+    #[test_case(-0.1, 0.5)]
+    #[test_case(0.5, 1.1)]
+    #[test_case(0.6, 0.5)]
+    #[test_case(f64::NAN, 0.5)]
+    #[test_case(0.0, f64::NAN)]
+    fn invalid_fee_ratio_bounds(min: f64, max: f64) {
+        let (_, canister) = test_context();
+        assert_eq!(
+            canister.setFeeRatioBounds(min, max),
+            Err(TxError::InvalidFeeRatioBounds)
+        );
+
+        let info = canister.biddingInfo();
+        assert_eq!((info.min_fee_ratio, info.max_fee_ratio), (0.0, 1.0));
+    }
+
+    #[test]
+    fn fee_ratio_bounds_owner_only() {
+        let (context, canister) = test_context();
+        context.update_caller(bob());
+        assert!(matches!(
+            canister.setFeeRatioBounds(0.0, 0.5),
+            Err(TxError::Unauthorized { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn auction_winners_notified() {
+        let rewards = Rc::new(RefCell::new(vec![]));
+        let rewards_clone = rewards.clone();
+        register_virtual_responder(
+            bob(),
+            "auction_reward",
+            move |(auction_id, amount): (usize, Nat)| {
+                rewards_clone.borrow_mut().push((auction_id, amount));
+            },
+        );
+        register_failing_virtual_responder(xtc(), "auction_reward", "trapped".into());
+
+        let (context, canister) = test_context();
+        let user = Principal::self_authenticating(&[1; 32]);
+        for bidder in [bob(), xtc(), user] {
+            context.update_msg_cycles(2_000_000);
+            canister.bidCycles(Some(bidder), None).unwrap();
+        }
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(6_000));
+
+        // Failed notifications don't affect the auction.
+        let result = canister.runAuction().unwrap();
+        assert_eq!(result.tokens_distributed, Nat::from(6_000));
+        assert!(result.notifications_dispatched);
+        assert!(canister.auctionInfo(0).unwrap().notifications_dispatched);
+
+        // Only the bidder canisters are notified.
+        assert_eq!(canister.state.borrow().delivery_queue.pending.len(), 2);
+        assert_eq!(retry_deliveries(canister.state.clone()).await, 1);
+        assert_eq!(*rewards.borrow(), vec![(0, Nat::from(2_000))]);
+        assert_eq!(canister.state.borrow().balances.0[&bob()], 2_000);
+
+        let state = canister.state.borrow();
+        let pending = &state.delivery_queue.pending;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].record.to, xtc());
+        assert_eq!(pending[0].attempts, 1);
+        assert_eq!(pending[0].auction_id, Some(0));
+    }
+
+    #[test]
+    fn auction_without_winner_canisters() {
+        let (context, canister) = test_context();
+        context.update_msg_cycles(2_000_000);
+        canister
+            .bidCycles(Some(Principal::self_authenticating(&[1; 32])), None)
+            .unwrap();
+
+        let result = canister.runAuction().unwrap();
+        assert!(!result.notifications_dispatched);
+        assert!(canister.state.borrow().delivery_queue.pending.is_empty());
+    }
+
+    /// Replays the balance changes of the `who` principal recorded in the history, assuming all
+    /// fees go to the auction.
+    fn ledger_balance(canister: &TokenCanister, who: Principal) -> Nat {
+        let mut balance = Nat::from(0);
+        let history = canister.getTransactions(Nat::from(0), Nat::from(1000));
+        for tx in history.iter().filter(|tx| !tx.status.is_failed()) {
+            if tx.to == who
+                && matches!(
+                    tx.operation,
+                    Operation::Mint | Operation::Auction | Operation::Transfer
+                )
+            {
+                balance += tx.amount.clone();
+            }
+            if tx.from == who && matches!(tx.operation, Operation::Auction | Operation::Transfer) {
+                balance -= tx.amount.clone();
+            }
+            if tx.from == who {
+                balance -= tx.fee.clone();
+            }
+            if who == auction_principal() {
+                balance += tx.fee.clone();
+            }
+        }
+
+        balance
+    }
+
+    #[test]
+    fn auction_payouts_reconcile_with_ledger() {
+        let (context, canister) = test_context();
+        {
+            let mut state = canister.state.borrow_mut();
+            state.stats.fee = Nat::from(10);
+            state.bidding_state.fee_ratio = 1.0;
+        }
+
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        canister.transfer(john(), Nat::from(100), None).unwrap();
+        context.update_caller(bob());
+        canister.transfer(john(), Nat::from(50), None).unwrap();
+        {
+            let state = canister.state.borrow();
+            assert_eq!(accumulated_fees(&state.balances, &state.bidding_state), 30);
+        }
+
+        context.update_msg_cycles(1_000_000);
+        canister.bidCycles(Some(alice()), None).unwrap();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+
+        let result = canister.runAuction().unwrap();
+        assert_eq!(result.tokens_distributed, Nat::from(30));
+        let paid = result
+            .transaction_ids
+            .iter()
+            .map(|id| canister.getTransaction(id.clone()).amount)
+            .fold(Nat::from(0), |sum, amount| sum + amount);
+        assert_eq!(paid, result.tokens_distributed);
+
+        for who in [alice(), bob(), john(), auction_principal()] {
+            assert_eq!(ledger_balance(&canister, who), canister.balanceOf(who));
+        }
+        assert_eq!(canister.balanceOf(auction_principal()), Nat::from(0));
+        assert_eq!(
+            canister.getUserStats(auction_principal()).sent,
+            Nat::from(30)
+        );
+        assert_eq!(canister.getUserStats(bob()).received, Nat::from(120));
+    }
+
+    #[test]
+    fn fee_pool_matches_auction() {
+        let (context, canister) = test_context();
+        {
+            let mut state = canister.state.borrow_mut();
+            state.stats.fee = Nat::from(10);
+            state.bidding_state.fee_ratio = 0.5;
+        }
+
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        canister
+            .transferWithMemo(bob(), Nat::from(100), None, vec![1])
+            .unwrap();
+        canister.transferIncludeFee(john(), Nat::from(100)).unwrap();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+        assert_eq!(canister.getFeePool(), Nat::from(20));
+
+        context.update_caller(bob());
+        canister
+            .transferFrom(alice(), john(), Nat::from(50))
+            .unwrap();
+        canister.transfer(john(), Nat::from(30), None).unwrap();
+        assert_eq!(canister.getFeePool(), Nat::from(30));
+        assert_eq!(canister.biddingInfo().accumulated_fees, Nat::from(30));
+
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        let pool = canister.getFeePool();
+        let result = canister.runAuction().unwrap();
+        assert_eq!(result.tokens_distributed, pool);
+        assert_eq!(canister.getFeePool(), Nat::from(0));
+    }
+
+    #[test]
+    fn auction_caller_incentive() {
+        let (context, canister) = test_context();
+        canister.setAuctionIncentive(500).unwrap();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        context.update_msg_cycles(4_000_000);
+        canister.bidCycles(Some(xtc()), None).unwrap();
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(6_000));
+
+        // 5% of the pool goes to the caller, the rest is split between the bidders.
+        context.update_caller(john());
+        let result = canister.runAuction().unwrap();
+        let incentive = result.caller_incentive.clone().unwrap();
+        assert_eq!(incentive.caller, john());
+        assert_eq!(incentive.amount, Nat::from(300));
+        assert_eq!(result.tokens_distributed, Nat::from(5_700));
+
+        let tx = canister.getTransaction(incentive.transaction_id.clone());
+        assert_eq!(tx.operation, Operation::Auction);
+        assert_eq!(tx.from, auction_principal());
+        assert_eq!(tx.to, john());
+        assert_eq!(tx.amount, Nat::from(300));
+        assert_eq!(result.first_transaction_id, incentive.transaction_id);
+
+        let state = canister.state.borrow();
+        assert_eq!(state.balances.0[&john()], 300);
+        assert_eq!(state.balances.0[&bob()], 1_900);
+        assert_eq!(state.balances.0[&xtc()], 3_800);
+        assert_eq!(state.balances.0[&auction_principal()], 0);
+    }
+
+    #[test]
+    fn auto_auction_without_incentive() {
+        let (context, canister) = test_context();
+        canister.setAuctionIncentive(500).unwrap();
+        canister.setAutoAuction(true).unwrap();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        canister
+            .state
+            .borrow_mut()
+            .balances
+            .0
+            .insert(auction_principal(), Nat::from(6_000));
+
+        skip_period(&canister);
+        let result = tick(&canister).unwrap().unwrap();
+        assert_eq!(result.caller_incentive, None);
+        assert_eq!(result.tokens_distributed, Nat::from(6_000));
+    }
+
+    #[test]
+    fn owner_only_auction() {
+        let (context, canister) = test_context();
+        canister
+            .setAuctionController(AuctionController::OwnerOnly)
+            .unwrap();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+
+        context.update_caller(bob());
+        assert_eq!(canister.runAuction(), Err(AuctionError::Unauthorized));
+        assert_eq!(canister.biddingInfo().caller_cycles, 2_000_000);
+
+        context.update_caller(alice());
+        assert!(canister.runAuction().is_ok());
+    }
+
+    #[test]
+    fn auction_controller_and_incentive_owner_only() {
+        let (context, canister) = test_context();
+        assert_eq!(
+            canister.setAuctionIncentive(MAX_AUCTION_INCENTIVE_RATE + 1),
+            Err(TxError::InvalidAuctionIncentive)
+        );
+
+        context.update_caller(bob());
+        assert!(matches!(
+            canister.setAuctionController(AuctionController::OwnerOnly),
+            Err(TxError::Unauthorized { .. })
+        ));
+        assert!(matches!(
+            canister.setAuctionIncentive(100),
+            Err(TxError::Unauthorized { .. })
+        ));
+
+        let info = canister.biddingInfo();
+        assert_eq!(info.controller, AuctionController::Anyone);
+        assert_eq!(info.incentive_rate, 0);
+    }
+
+    #[test]
+    fn auction_stats_aggregated() {
+        let (context, canister) = test_context();
+        assert_eq!(canister.getAuctionStats().auction_count, 0);
+        assert_eq!(canister.getAuctionStats().last_auction_time, None);
+
+        let rounds: [(&[(Principal, u64)], u64); 3] = [
+            (&[(alice(), 2_000_000), (bob(), 4_000_000)], 600),
+            (&[(bob(), 1_000_000)], 100),
+            (
+                &[
+                    (alice(), 1_000_000),
+                    (bob(), 1_000_000),
+                    (john(), 2_000_000),
+                ],
+                400,
+            ),
+        ];
+        for (bids, fees) in rounds {
+            for (bidder, cycles) in bids {
+                context.update_msg_cycles(*cycles);
+                canister.bidCycles(Some(*bidder), None).unwrap();
+            }
+            canister
+                .state
+                .borrow_mut()
+                .balances
+                .0
+                .insert(auction_principal(), Nat::from(fees));
+            canister.state.borrow_mut().bidding_state.auction_period = 0;
+            canister.runAuction().unwrap();
+        }
+
+        let stats = canister.getAuctionStats();
+        assert_eq!(stats.auction_count, 3);
+        assert_eq!(stats.total_cycles_collected, Nat::from(11_000_000));
+        assert_eq!(stats.total_tokens_distributed, Nat::from(1_100));
+        assert_eq!(stats.average_participants, 2.0);
+        assert_eq!(
+            stats.last_auction_time,
+            Some(canister.auctionInfo(2).unwrap().auction_time)
+        );
+
+        canister.__pre_upgrade_inst();
+        canister.state.borrow_mut().auction_stats = None;
+        canister.state.borrow_mut().auction_history = Default::default();
+        canister.__post_upgrade_inst();
+        assert_eq!(canister.getAuctionStats(), stats);
+    }
+
+    #[test]
+    fn auction_stats_backfilled_from_history() {
+        let (context, canister) = test_context();
+        for _ in 0..2 {
+            context.update_msg_cycles(2_000_000);
+            canister.bidCycles(Some(bob()), None).unwrap();
+            canister.state.borrow_mut().bidding_state.auction_period = 0;
+            canister.runAuction().unwrap();
+        }
+
+        // The state saved before the aggregates were added, with an auction held before the
+        // number of participants was recorded.
+        {
+            let mut state = canister.state.borrow_mut();
+            state.auction_stats = None;
+            state.auction_history.0[0].participants = 0;
+        }
+        let stats = canister.getAuctionStats();
+        assert_eq!(stats.auction_count, 2);
+        assert_eq!(stats.total_cycles_collected, Nat::from(4_000_000));
+        assert_eq!(stats.average_participants, 1.0);
+
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(bob()), None).unwrap();
+        canister.runAuction().unwrap();
+        assert_eq!(canister.getAuctionStats().auction_count, 3);
+        assert_eq!(
+            canister
+                .state
+                .borrow()
+                .auction_stats
+                .as_ref()
+                .map(|totals| totals.auction_count),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn auction_without_bids() {
+        let (_, canister) = test_context();
+        assert_eq!(canister.runAuction(), Err(AuctionError::NoBids));
+    }
+
+    #[test]
+    fn auction_not_in_time() {
+        let (context, canister) = test_context();
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(Some(alice()), None).unwrap();
+
+        {
+            let state = &mut canister.state.borrow_mut().bidding_state;
+            state.last_auction = ic::time() - 100_000;
+            state.auction_period = 1_000_000_000;
+        }
+
+        assert_eq!(
+            canister.runAuction(),
+            Err(AuctionError::TooEarlyToBeginAuction)
+        );
+    }
+
+    #[test]
+    fn fee_ratio_update() {
+        let (context, canister) = test_context();
+        context.update_balance(1_000_000_000);
+
+        canister.state.borrow_mut().stats.min_cycles = 1_000_000;
+        canister.runAuction().unwrap_err();
+
+        assert_eq!(canister.state.borrow().bidding_state.fee_ratio, 0.125);
+    }
+
+    #[test]
+    fn setting_min_cycles() {
+        let (_, canister) = test_context();
+        canister.setMinCycles(100500).unwrap();
+        assert_eq!(canister.getMinCycles(), 100500);
     }
 
     #[test]
@@ -364,7 +2083,71 @@ mod tests {
     fn setting_auction_period() {
         let (_, canister) = test_context();
         canister.setAuctionPeriod(100500).unwrap();
-        assert_eq!(canister.biddingInfo().auction_period, 100500 * 1000000);
+        assert_eq!(
+            canister.biddingInfo().auction_period,
+            100500 * 1_000_000_000
+        );
+    }
+
+    #[test]
+    fn default_auction_period_is_one_day() {
+        let (_, canister) = test_context();
+        assert_eq!(
+            canister.biddingInfo().auction_period,
+            24 * 60 * 60 * 1_000_000_000
+        );
+    }
+
+    #[test]
+    fn auction_period_too_short() {
+        let (_, canister) = test_context();
+        assert_eq!(
+            canister.setAuctionPeriod(60 * 60 - 1),
+            Err(TxError::AuctionPeriodTooShort { min: 60 * 60 })
+        );
+        assert!(canister.setAuctionPeriod(60 * 60).is_ok());
+        assert_eq!(
+            canister.biddingInfo().auction_period,
+            60 * 60 * 1_000_000_000
+        );
+    }
+
+    #[test]
+    fn mis_scaled_auction_period_migrated() {
+        let (context, canister) = test_context();
+        {
+            // The state of the canister before the fix, with the default period in microseconds.
+            let bidding_state = &mut canister.state.borrow_mut().bidding_state;
+            bidding_state.auction_period = 24 * 60 * 60 * 1_000_000;
+            bidding_state.auction_period_in_ns = false;
+            // The mis-scaled period passed since the last auction, but the day didn't.
+            bidding_state.last_auction = ic::time() - 24 * 60 * 60 * 1_000_000;
+        }
+        canister.__pre_upgrade_inst();
+        canister.state.borrow_mut().bidding_state = Default::default();
+        canister.__post_upgrade_inst();
+        assert_eq!(
+            canister.biddingInfo().auction_period,
+            24 * 60 * 60 * 1_000_000_000
+        );
+
+        context.update_msg_cycles(2_000_000);
+        canister.bidCycles(None, None).unwrap();
+        assert_eq!(
+            canister.runAuction(),
+            Err(AuctionError::TooEarlyToBeginAuction)
+        );
+
+        // The migration is done only once.
+        canister
+            .state
+            .borrow_mut()
+            .bidding_state
+            .migrate_auction_period();
+        assert_eq!(
+            canister.biddingInfo().auction_period,
+            24 * 60 * 60 * 1_000_000_000
+        );
     }
 
     #[test]
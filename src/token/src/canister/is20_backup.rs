@@ -0,0 +1,485 @@
+//! This module contains APIs for backing up the ledger to another canister and restoring it into a
+//! new token.
+//!
+//! The backup is sent to the target canister in chunks with the `receive_backup` call. The progress
+//! is kept in the state, so a backup interrupted by a failed call is resumed from the first chunk
+//! not confirmed by the target. The backup covers the records added before it was started, and its
+//! last chunk carries the number of the records and the hash of the last record, which the target
+//! must confirm.
+//!
+//! The chunks are restored with `restoreLedger` in the same order. The restore is only allowed
+//! into a token that has no records other than the initial mint, and it replaces the ledger, the
+//! balances and the supply of the token with the restored ones.
+
+use crate::canister::{check_caller, TokenCanister};
+use crate::ledger::{to_id, tx_hash, GENESIS_HASH};
+use crate::state::CanisterState;
+use crate::types::{
+    BackupAck, BackupChunk, BackupEnd, BackupProgress, FeePayment, Operation, TxError,
+};
+use candid::{Nat, Principal};
+use ic_canister::virtual_canister_call;
+use ic_cdk::api::call::CallResult;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Maximum number of the records in one backup chunk.
+pub const BACKUP_CHUNK_LEN: usize = 1_000;
+
+/// Maximum number of the chunks sent by one `backupLedger` call.
+pub const MAX_BACKUP_CHUNKS: usize = 10;
+
+pub(crate) fn backup_progress(canister: &TokenCanister) -> BackupProgress {
+    progress(&canister.state.borrow())
+}
+
+/// Sends up to `max_chunks` chunks of up to `chunk_len` records of the ledger to the `target`
+/// canister, starting from the `start` record. An unfinished backup to the same target from the
+/// same `start` record is resumed from the first chunk not confirmed by the target, otherwise a
+/// new backup of the records added so far is started.
+pub(crate) async fn backup_ledger(
+    state: Rc<RefCell<CanisterState>>,
+    target: Principal,
+    start: Nat,
+    chunk_len: usize,
+    max_chunks: usize,
+) -> Result<BackupProgress, TxError> {
+    {
+        let mut state = state.borrow_mut();
+        check_caller(state.stats.owner)?;
+
+        if state.backup.in_progress {
+            return Err(TxError::BackupInProgress);
+        }
+
+        let start = to_id(&start);
+        let backup = &state.backup;
+        let resume = backup.target == Some(target) && backup.start == start && !backup.completed;
+        if !resume {
+            if start < state.ledger.first_id() || start >= state.ledger.len() {
+                return Err(TxError::TransactionDoesNotExist);
+            }
+
+            let end = state.ledger.len();
+            let backup = &mut state.backup;
+            backup.target = Some(target);
+            backup.start = start;
+            backup.next = start;
+            backup.end = end;
+            backup.completed = false;
+        }
+
+        state.backup.in_progress = true;
+    }
+
+    let result = send_chunks(&state, target, chunk_len, max_chunks).await;
+
+    let mut state = state.borrow_mut();
+    state.backup.in_progress = false;
+    result.map(|_| progress(&state))
+}
+
+async fn send_chunks(
+    state: &Rc<RefCell<CanisterState>>,
+    target: Principal,
+    chunk_len: usize,
+    max_chunks: usize,
+) -> Result<(), TxError> {
+    for _ in 0..max_chunks {
+        let chunk = {
+            let state = state.borrow();
+            let backup = &state.backup;
+            if backup.completed {
+                return Ok(());
+            }
+
+            // The records of the backup could be removed from the history while the backup was
+            // interrupted.
+            if backup.next < state.ledger.first_id() {
+                return Err(TxError::TransactionDoesNotExist);
+            }
+
+            let len = chunk_len.min((backup.end - backup.next) as usize);
+            let records = state.ledger.get_range(backup.next, len).to_vec();
+            let next = backup.next + records.len() as u64;
+            let end = match records.last() {
+                Some(last) if next == backup.end => Some(BackupEnd {
+                    record_count: backup.end - backup.start,
+                    tip_hash: tx_hash(last),
+                }),
+                _ => None,
+            };
+
+            BackupChunk {
+                start: Nat::from(backup.next),
+                fee_payments: state.ledger.fee_payments_in(backup.next, next),
+                records,
+                end,
+            }
+        };
+
+        let ack = send_chunk(target, chunk.clone())
+            .await
+            .map_err(|(_, msg)| TxError::BackupFailed { cdk_msg: msg })?;
+
+        let mut state = state.borrow_mut();
+        let backup = &mut state.backup;
+        let next = backup.next + chunk.records.len() as u64;
+        let confirmed = ack.record_count == next - backup.start
+            && match &chunk.end {
+                Some(end) => ack.tip_hash.as_ref() == Some(&end.tip_hash),
+                None => true,
+            };
+
+        if !confirmed {
+            // The target lost some of the records, so the backup is sent again from the start.
+            backup.next = backup.start;
+            return Err(TxError::BackupVerificationFailed);
+        }
+
+        backup.next = next;
+        backup.completed = chunk.end.is_some();
+    }
+
+    Ok(())
+}
+
+async fn send_chunk(target: Principal, chunk: BackupChunk) -> CallResult<BackupAck> {
+    virtual_canister_call!(target, "receive_backup", (chunk,), BackupAck).await
+}
+
+/// Restores the `chunk` of a backup into the ledger. The first chunk must start from the first
+/// record of the ledger, and the token must have no records other than the initial mint. The next
+/// chunks must follow each other without gaps. The records and the hash chain of the whole chunk
+/// are checked before any of them is restored, so a rejected chunk leaves the ledger unchanged.
+pub(crate) fn restore_ledger(
+    canister: &TokenCanister,
+    chunk: BackupChunk,
+) -> Result<BackupProgress, TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    let expected = match state.restore_next {
+        Some(next) if next == state.ledger.len() => next,
+        Some(_) => return Err(TxError::RestoreNotAllowed),
+        None if state.ledger.len() > 1 || state.backup.in_progress => {
+            return Err(TxError::RestoreNotAllowed)
+        }
+        None => 0,
+    };
+
+    if to_id(&chunk.start) != expected {
+        return Err(TxError::RestoreOutOfOrder {
+            expected: Nat::from(expected),
+        });
+    }
+
+    let mut tip_hash = match expected {
+        0 => GENESIS_HASH.to_vec(),
+        _ => state.ledger.tip_hash(),
+    };
+    for (id, tx) in (expected..).zip(&chunk.records) {
+        // The records added before the ledger was hash-chained have no parent hash.
+        let chained = tx
+            .parent_hash
+            .as_ref()
+            .map_or(true, |parent_hash| *parent_hash == tip_hash);
+        if tx.index != Nat::from(id) || !chained {
+            return Err(TxError::BackupVerificationFailed);
+        }
+
+        tip_hash = tx_hash(tx);
+    }
+
+    let next = expected + chunk.records.len() as u64;
+    if let Some(end) = &chunk.end {
+        if end.record_count != next || end.tip_hash != tip_hash {
+            return Err(TxError::BackupVerificationFailed);
+        }
+    }
+
+    if expected == 0 {
+        state.ledger = Default::default();
+        state.stats.total_supply = Nat::from(0);
+        state.stats.total_burned = Nat::from(0);
+        state.stats.total_minted = Some(Nat::from(0));
+    }
+
+    // The supply is restored as it was, even if it exceeds the current limits of the token.
+    let stats = &mut state.stats;
+    for tx in chunk.records.iter().filter(|tx| !tx.status.is_failed()) {
+        match tx.operation {
+            Operation::Mint => {
+                stats.total_minted = Some(stats.total_minted() + tx.amount.clone());
+                stats.total_supply += tx.amount.clone();
+            }
+            Operation::Burn => {
+                stats.total_burned += tx.amount.clone();
+                stats.total_supply -= tx.amount.clone();
+            }
+            _ => {}
+        }
+    }
+
+    let mut payments: HashMap<Nat, FeePayment> = chunk
+        .fee_payments
+        .into_iter()
+        .map(|payment| (payment.tx_id.clone(), payment))
+        .collect();
+    for tx in chunk.records {
+        let payment = payments.remove(&tx.index);
+        state.ledger.restore(tx, payment);
+    }
+
+    state.balances.0 = state.ledger.replayed_balances();
+    state.restore_next = match chunk.end {
+        Some(_) => None,
+        None => Some(next),
+    };
+
+    Ok(progress(&state))
+}
+
+fn progress(state: &CanisterState) -> BackupProgress {
+    let backup = &state.backup;
+    BackupProgress {
+        target: backup.target,
+        start: Nat::from(backup.start),
+        next: Nat::from(backup.next),
+        end: Nat::from(backup.end),
+        completed: backup.completed,
+        restore_next: state.restore_next.map(Nat::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TxRecord;
+    use common::types::Metadata;
+    use ic_canister::{register_failing_virtual_responder, register_virtual_responder, Canister};
+    use ic_kit::mock_principals::{alice, bob, john, xtc};
+    use ic_kit::MockContext;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            logo: "".to_string(),
+            name: "".to_string(),
+            symbol: "".to_string(),
+            decimals: 8,
+            totalSupply: Nat::from(1000),
+            owner: alice(),
+            fee: Nat::from(0),
+            feeTo: alice(),
+            isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
+        }
+    }
+
+    fn test_canister() -> TokenCanister {
+        MockContext::new().with_caller(alice()).inject();
+
+        let canister = TokenCanister::init_instance();
+        canister.init(metadata());
+
+        canister
+    }
+
+    /// Registers a backup target storing the received chunks.
+    fn register_target(chunks: &Rc<RefCell<Vec<BackupChunk>>>) {
+        let chunks = chunks.clone();
+        register_virtual_responder(xtc(), "receive_backup", move |(chunk,): (BackupChunk,)| {
+            let mut chunks = chunks.borrow_mut();
+            chunks.push(chunk);
+            let records: Vec<&TxRecord> = chunks.iter().flat_map(|chunk| &chunk.records).collect();
+            BackupAck {
+                record_count: records.len() as u64,
+                tip_hash: records.last().map(|tx| tx_hash(tx)),
+            }
+        });
+    }
+
+    fn ids(chunks: &[BackupChunk]) -> Vec<Nat> {
+        chunks
+            .iter()
+            .flat_map(|chunk| chunk.records.iter().map(|tx| tx.index.clone()))
+            .collect()
+    }
+
+    /// Makes a backup of a ledger with 6 records in chunks of 2 records.
+    async fn make_backup(canister: &TokenCanister) -> Vec<BackupChunk> {
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        canister.transfer(john(), Nat::from(50), None).unwrap();
+        canister.mint(bob(), Nat::from(30), None).unwrap();
+        canister.burn(Nat::from(20)).unwrap();
+        canister.transfer(bob(), Nat::from(5), None).unwrap();
+
+        let chunks = Rc::new(RefCell::new(vec![]));
+        register_target(&chunks);
+        let progress = backup_ledger(canister.state.clone(), xtc(), Nat::from(0), 2, 10)
+            .await
+            .unwrap();
+        assert!(progress.completed);
+
+        chunks.take()
+    }
+
+    #[tokio::test]
+    async fn interrupted_backup_is_resumed() {
+        let canister = test_canister();
+        for _ in 0..5 {
+            canister.transfer(bob(), Nat::from(10), None).unwrap();
+        }
+
+        let chunks = Rc::new(RefCell::new(vec![]));
+        register_target(&chunks);
+        let state = canister.state.clone();
+        let progress = backup_ledger(state.clone(), xtc(), Nat::from(0), 2, 1)
+            .await
+            .unwrap();
+        assert_eq!(progress.next, Nat::from(2));
+        assert_eq!(progress.end, Nat::from(6));
+        assert!(!progress.completed);
+
+        // Records added after the backup was started are not included.
+        canister.transfer(bob(), Nat::from(10), None).unwrap();
+
+        register_failing_virtual_responder(xtc(), "receive_backup", "target is down".into());
+        assert!(matches!(
+            backup_ledger(state.clone(), xtc(), Nat::from(0), 2, 10).await,
+            Err(TxError::BackupFailed { .. })
+        ));
+        assert_eq!(canister.getBackupProgress().next, Nat::from(2));
+        assert!(!canister.state.borrow().backup.in_progress);
+
+        register_target(&chunks);
+        let progress = backup_ledger(state.clone(), xtc(), Nat::from(0), 2, 10)
+            .await
+            .unwrap();
+        assert_eq!(progress.next, Nat::from(6));
+        assert!(progress.completed);
+
+        let chunks = chunks.borrow();
+        assert_eq!(ids(&chunks), (0..6u32).map(Nat::from).collect::<Vec<_>>());
+        assert_eq!(
+            chunks.last().unwrap().end,
+            Some(BackupEnd {
+                record_count: 6,
+                tip_hash: tx_hash(canister.state.borrow().ledger.get(5).unwrap()),
+            })
+        );
+        assert!(chunks[..2].iter().all(|chunk| chunk.end.is_none()));
+
+        MockContext::new().with_caller(bob()).inject();
+        assert!(matches!(
+            backup_ledger(state, xtc(), Nat::from(0), 2, 10).await,
+            Err(TxError::Unauthorized { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_backup_is_restarted() {
+        register_virtual_responder(xtc(), "receive_backup", |_: (BackupChunk,)| BackupAck {
+            record_count: 0,
+            tip_hash: None,
+        });
+
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(10), None).unwrap();
+        canister.transfer(bob(), Nat::from(10), None).unwrap();
+
+        let state = canister.state.clone();
+        assert_eq!(
+            backup_ledger(state, xtc(), Nat::from(1), 1, 10).await,
+            Err(TxError::BackupVerificationFailed)
+        );
+
+        let progress = canister.getBackupProgress();
+        assert_eq!(progress.start, Nat::from(1));
+        assert_eq!(progress.next, Nat::from(1));
+        assert!(!progress.completed);
+    }
+
+    #[tokio::test]
+    async fn interrupted_restore() {
+        let source = test_canister();
+        let chunks = make_backup(&source).await;
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            source.restoreLedger(chunks[0].clone()),
+            Err(TxError::RestoreNotAllowed)
+        );
+
+        let tip_hash = source.state.borrow().ledger.tip_hash();
+        let balances = source.state.borrow().balances.0.clone();
+        let hashes: Vec<Vec<u8>> = source.state.borrow().ledger.iter().map(tx_hash).collect();
+        let (total_supply, total_minted, total_burned) = (
+            source.totalSupply(),
+            source.totalMinted(),
+            source.state.borrow().stats.total_burned.clone(),
+        );
+
+        // Restore into a new token.
+        *source.state.borrow_mut() = CanisterState::default();
+        let canister = source;
+        canister.init(metadata());
+
+        assert_eq!(
+            canister.restoreLedger(chunks[1].clone()),
+            Err(TxError::RestoreOutOfOrder {
+                expected: Nat::from(0)
+            })
+        );
+
+        let progress = canister.restoreLedger(chunks[0].clone()).unwrap();
+        assert_eq!(progress.restore_next, Some(Nat::from(2)));
+        assert_eq!(
+            canister.restoreLedger(chunks[0].clone()),
+            Err(TxError::RestoreOutOfOrder {
+                expected: Nat::from(2)
+            })
+        );
+
+        let mut tampered = chunks[1].clone();
+        tampered.records[0].amount = Nat::from(1);
+        assert_eq!(
+            canister.restoreLedger(tampered),
+            Err(TxError::BackupVerificationFailed)
+        );
+
+        let mut wrong_end = chunks[2].clone();
+        wrong_end.end.as_mut().unwrap().record_count = 5;
+        canister.restoreLedger(chunks[1].clone()).unwrap();
+        assert_eq!(
+            canister.restoreLedger(wrong_end),
+            Err(TxError::BackupVerificationFailed)
+        );
+        assert_eq!(canister.historySize(), Nat::from(4));
+
+        MockContext::new().with_caller(bob()).inject();
+        assert!(matches!(
+            canister.restoreLedger(chunks[2].clone()),
+            Err(TxError::Unauthorized { .. })
+        ));
+
+        MockContext::new().with_caller(alice()).inject();
+        let progress = canister.restoreLedger(chunks[2].clone()).unwrap();
+        assert_eq!(progress.restore_next, None);
+
+        let state = canister.state.borrow();
+        assert_eq!(state.ledger.tip_hash(), tip_hash);
+        assert_eq!(state.ledger.iter().map(tx_hash).collect::<Vec<_>>(), hashes);
+        assert_eq!(state.balances.0, balances);
+        assert_eq!(state.stats.total_supply, total_supply);
+        assert_eq!(state.stats.total_minted(), total_minted);
+        assert_eq!(state.stats.total_burned, total_burned);
+        drop(state);
+
+        assert_eq!(
+            canister.restoreLedger(chunks[0].clone()),
+            Err(TxError::RestoreNotAllowed)
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! This module contains APIs for claiming tokens from a published allocation list.
+//!
+//! The owner commits the root hash of the merkle tree of the list with `setClaimRoot`, and every
+//! principal in the list mints its allocation with `claimMint`, providing the proof that the entry
+//! is in the tree. See the `merkle` module for the tree structure.
+
+use crate::canister::dip20_transactions::_mint;
+use crate::canister::{check_caller, record_config_change, to_hex, TokenCanister};
+use crate::merkle;
+use crate::types::{TxError, TxReceipt};
+use candid::Nat;
+use ic_kit::ic;
+
+pub(crate) fn set_claim_root(canister: &TokenCanister, root: Vec<u8>) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    let new_root = to_hex(&root);
+    let old_root = state
+        .claims
+        .root
+        .replace(root)
+        .map_or_else(|| "none".to_string(), |root| to_hex(&root));
+    record_config_change(&mut state, "claim_root", old_root, new_root);
+    Ok(())
+}
+
+pub(crate) fn claim_mint(canister: &TokenCanister, amount: Nat, proof: Vec<Vec<u8>>) -> TxReceipt {
+    let caller = ic::caller();
+    let mut state = canister.state.borrow_mut();
+    if state.claims.claimed.contains(&caller) {
+        return Err(TxError::AlreadyClaimed);
+    }
+
+    let leaf = merkle::leaf_hash(&caller, &amount);
+    match &state.claims.root {
+        Some(root) if merkle::verify(root, leaf, &proof) => {}
+        _ => return Err(TxError::InvalidProof),
+    }
+
+    let id = _mint(&mut state, caller, caller, amount, None)?;
+    state.claims.claimed.insert(caller);
+
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Operation;
+    use candid::Principal;
+    use common::types::Metadata;
+    use ic_canister::Canister;
+    use ic_kit::mock_principals::{alice, bob, john, xtc};
+    use ic_kit::MockContext;
+
+    fn test_canister() -> TokenCanister {
+        MockContext::new().with_caller(alice()).inject();
+
+        let canister = TokenCanister::init_instance();
+        canister.init(Metadata {
+            logo: "".to_string(),
+            name: "".to_string(),
+            symbol: "".to_string(),
+            decimals: 8,
+            totalSupply: Nat::from(1000),
+            owner: alice(),
+            fee: Nat::from(0),
+            feeTo: alice(),
+            isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
+        });
+
+        canister
+    }
+
+    /// Builds a tree of four entries and returns its root and the proofs of the entries.
+    fn test_tree(entries: &[(Principal, u64); 4]) -> (Vec<u8>, Vec<Vec<Vec<u8>>>) {
+        let leaves: Vec<_> = entries
+            .iter()
+            .map(|(account, amount)| merkle::leaf_hash(account, &Nat::from(*amount)))
+            .collect();
+        let left = merkle::node_hash(&leaves[0], &leaves[1]);
+        let right = merkle::node_hash(&leaves[2], &leaves[3]);
+        let root = merkle::node_hash(&left, &right);
+
+        let proofs = vec![
+            vec![leaves[1].clone(), right.clone()],
+            vec![leaves[0].clone(), right],
+            vec![leaves[3].clone(), left.clone()],
+            vec![leaves[2].clone(), left],
+        ];
+
+        (root, proofs)
+    }
+
+    fn entries() -> [(Principal, u64); 4] {
+        [(bob(), 100), (john(), 200), (xtc(), 300), (alice(), 400)]
+    }
+
+    #[test]
+    fn claim_mint() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        let (root, proofs) = test_tree(&entries());
+        canister.setClaimRoot(root).unwrap();
+
+        context.update_caller(john());
+        let id = canister
+            .claimMint(Nat::from(200), proofs[1].clone())
+            .unwrap();
+        assert_eq!(canister.balanceOf(john()), Nat::from(200));
+        assert_eq!(canister.totalSupply(), Nat::from(1200));
+
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::Mint);
+        assert_eq!(tx.to, john());
+        assert_eq!(tx.amount, Nat::from(200));
+
+        context.update_caller(xtc());
+        assert!(canister
+            .claimMint(Nat::from(300), proofs[2].clone())
+            .is_ok());
+        assert_eq!(canister.balanceOf(xtc()), Nat::from(300));
+    }
+
+    #[test]
+    fn claim_mint_invalid_proof() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        let (root, proofs) = test_tree(&entries());
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.claimMint(Nat::from(100), proofs[0].clone()),
+            Err(TxError::InvalidProof)
+        );
+
+        context.update_caller(alice());
+        canister.setClaimRoot(root).unwrap();
+
+        context.update_caller(bob());
+        // Wrong amount.
+        assert_eq!(
+            canister.claimMint(Nat::from(101), proofs[0].clone()),
+            Err(TxError::InvalidProof)
+        );
+        // Proof of another entry.
+        assert_eq!(
+            canister.claimMint(Nat::from(100), proofs[1].clone()),
+            Err(TxError::InvalidProof)
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+        assert_eq!(canister.totalSupply(), Nat::from(1000));
+    }
+
+    #[test]
+    fn claim_mint_twice() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        let (root, proofs) = test_tree(&entries());
+        canister.setClaimRoot(root).unwrap();
+
+        context.update_caller(bob());
+        canister
+            .claimMint(Nat::from(100), proofs[0].clone())
+            .unwrap();
+        assert_eq!(
+            canister.claimMint(Nat::from(100), proofs[0].clone()),
+            Err(TxError::AlreadyClaimed)
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(100));
+    }
+
+    #[test]
+    fn claim_mint_over_max_supply() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.state.borrow_mut().stats.max_supply = Some(Nat::from(1150));
+        let (root, proofs) = test_tree(&entries());
+        canister.setClaimRoot(root).unwrap();
+
+        context.update_caller(john());
+        assert_eq!(
+            canister.claimMint(Nat::from(200), proofs[1].clone()),
+            Err(TxError::MaxSupplyExceeded)
+        );
+
+        // The failed claim can be repeated when the cap allows it.
+        canister.state.borrow_mut().stats.max_supply = Some(Nat::from(1200));
+        assert!(canister
+            .claimMint(Nat::from(200), proofs[1].clone())
+            .is_ok());
+    }
+
+    #[test]
+    fn set_claim_root_not_owner() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.setClaimRoot(vec![0; 32]),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+    }
+}
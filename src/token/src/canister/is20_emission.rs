@@ -0,0 +1,285 @@
+//! This module contains APIs for the scheduled emission of the tokens.
+//!
+//! The emission is checked on every canister heartbeat. When the emission period has passed since
+//! the last emission, the configured amount is minted to the receiving principal.
+
+use crate::canister::dip20_transactions::_mint;
+use crate::canister::{check_caller, record_config_change, TokenCanister};
+use crate::state::{CanisterState, EmissionSchedule};
+use crate::types::{Timestamp, TxError};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_kit::ic;
+
+/// Current configuration of the scheduled emission.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct EmissionInfo {
+    /// Principal receiving the emitted tokens.
+    pub to: Principal,
+
+    /// Amount of tokens minted every period.
+    pub amount_per_period: Nat,
+
+    /// Length of the period in nanoseconds.
+    pub period: Timestamp,
+
+    pub enabled: bool,
+
+    /// IC time of the last emission.
+    pub last_run: Timestamp,
+
+    /// IC time after which the next emission will be made. `None` if the emission is disabled.
+    pub next_run: Option<Timestamp>,
+}
+
+pub(crate) fn set_emission(
+    canister: &TokenCanister,
+    to: Principal,
+    amount_per_period: Nat,
+    period_sec: u64,
+    enabled: bool,
+) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    if period_sec == 0 {
+        return Err(TxError::InvalidEmissionPeriod);
+    }
+
+    let schedule = EmissionSchedule {
+        to,
+        amount_per_period,
+        // IC timestamp is in nanoseconds.
+        period: period_sec.saturating_mul(1_000_000_000),
+        enabled,
+        last_run: ic::time(),
+    };
+    let new_schedule = render_schedule(&schedule);
+    let old_schedule = state
+        .emission
+        .replace(schedule)
+        .map_or_else(|| "none".to_string(), |schedule| render_schedule(&schedule));
+    record_config_change(&mut state, "emission", old_schedule, new_schedule);
+
+    Ok(())
+}
+
+/// Renders the `schedule` for the `Configure` record as `[to, amount_per_period, period, enabled]`.
+fn render_schedule(schedule: &EmissionSchedule) -> String {
+    format!(
+        "[{}, {}, {}, {}]",
+        schedule.to, schedule.amount_per_period, schedule.period, schedule.enabled
+    )
+}
+
+pub(crate) fn emission_info(canister: &TokenCanister) -> Option<EmissionInfo> {
+    let state = canister.state.borrow();
+    let schedule = state.emission.as_ref()?;
+
+    Some(EmissionInfo {
+        to: schedule.to,
+        amount_per_period: schedule.amount_per_period.clone(),
+        period: schedule.period,
+        enabled: schedule.enabled,
+        last_run: schedule.last_run,
+        next_run: if schedule.enabled {
+            Some(schedule.next_run())
+        } else {
+            None
+        },
+    })
+}
+
+/// Mints the emission, if it is due, and returns the id of the mint transaction. If the emission
+/// would exceed the maximum supply, only the amount left under the cap is minted. When the cap is
+/// reached, the emission is disabled.
+///
+/// At most one emission is made per call, even if several periods have passed since the last one.
+pub(crate) fn emission_tick(state: &mut CanisterState) -> Option<Nat> {
+    let schedule = state.emission.as_mut().filter(|s| s.is_due())?;
+    schedule.last_run = ic::time();
+    let to = schedule.to;
+    let mut amount = schedule.amount_per_period.clone();
+
+    if let Some(max_supply) = &state.stats.max_supply {
+        let available = if *max_supply > state.stats.total_supply {
+            max_supply.clone() - state.stats.total_supply.clone()
+        } else {
+            Nat::from(0)
+        };
+
+        if available <= amount {
+            // The maximum supply is reached with this emission, so there is nothing more to emit.
+            amount = available;
+            schedule.enabled = false;
+        }
+    }
+
+    if amount == 0u32 {
+        return None;
+    }
+
+    // The amount is limited by the maximum supply above, so the mint can only fail if the mint
+    // limit is exceeded. In this case the emission of this period is skipped.
+    _mint(state, ic::id(), to, amount, None).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Operation;
+    use common::types::Metadata;
+    use ic_canister::Canister;
+    use ic_kit::mock_principals::{alice, bob};
+    use ic_kit::MockContext;
+
+    const PERIOD_SEC: u64 = 60;
+
+    fn test_canister() -> TokenCanister {
+        MockContext::new().with_caller(alice()).inject();
+
+        let canister = TokenCanister::init_instance();
+        canister.init(Metadata {
+            logo: "".to_string(),
+            name: "".to_string(),
+            symbol: "".to_string(),
+            decimals: 8,
+            totalSupply: Nat::from(1000),
+            owner: alice(),
+            fee: Nat::from(0),
+            feeTo: alice(),
+            isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
+        });
+
+        canister
+    }
+
+    /// Moves the last emission time back by one period, so the next emission is due.
+    fn skip_period(canister: &TokenCanister) {
+        let mut state = canister.state.borrow_mut();
+        let emission = state.emission.as_mut().unwrap();
+        emission.last_run = ic::time() - emission.period;
+    }
+
+    fn tick(canister: &TokenCanister) -> Option<Nat> {
+        emission_tick(&mut canister.state.borrow_mut())
+    }
+
+    #[test]
+    fn emission() {
+        let canister = test_canister();
+        canister
+            .setEmission(bob(), Nat::from(100), PERIOD_SEC, true)
+            .unwrap();
+
+        let info = canister.getEmissionInfo().unwrap();
+        assert_eq!(info.period, PERIOD_SEC * 1_000_000_000);
+        assert_eq!(info.next_run, Some(info.last_run + info.period));
+
+        assert_eq!(tick(&canister), None);
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+
+        skip_period(&canister);
+        let id = tick(&canister).unwrap();
+        assert_eq!(canister.balanceOf(bob()), Nat::from(100));
+        assert_eq!(canister.totalSupply(), Nat::from(1100));
+
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::Mint);
+        assert_eq!(tx.to, bob());
+        assert_eq!(tx.amount, Nat::from(100));
+
+        // The next emission is only due after another period.
+        assert_eq!(tick(&canister), None);
+        skip_period(&canister);
+        assert!(tick(&canister).is_some());
+        assert_eq!(canister.balanceOf(bob()), Nat::from(200));
+    }
+
+    #[test]
+    fn emission_disabled() {
+        let canister = test_canister();
+        canister
+            .setEmission(bob(), Nat::from(100), PERIOD_SEC, false)
+            .unwrap();
+        assert_eq!(canister.getEmissionInfo().unwrap().next_run, None);
+
+        skip_period(&canister);
+        assert_eq!(tick(&canister), None);
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+    }
+
+    #[test]
+    fn emission_enables_periodic_tasks() {
+        let canister = test_canister();
+        assert!(!canister.state.borrow().has_periodic_tasks());
+
+        canister
+            .setEmission(bob(), Nat::from(100), PERIOD_SEC, true)
+            .unwrap();
+        assert!(canister.state.borrow().has_periodic_tasks());
+
+        canister
+            .setEmission(bob(), Nat::from(100), PERIOD_SEC, false)
+            .unwrap();
+        assert!(!canister.state.borrow().has_periodic_tasks());
+    }
+
+    #[test]
+    fn emission_stops_at_max_supply() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.max_supply = Some(Nat::from(1150));
+        canister
+            .setEmission(bob(), Nat::from(100), PERIOD_SEC, true)
+            .unwrap();
+
+        skip_period(&canister);
+        assert!(tick(&canister).is_some());
+        skip_period(&canister);
+        assert!(tick(&canister).is_some());
+        assert_eq!(canister.balanceOf(bob()), Nat::from(150));
+        assert_eq!(canister.totalSupply(), Nat::from(1150));
+        assert!(!canister.getEmissionInfo().unwrap().enabled);
+
+        skip_period(&canister);
+        assert_eq!(tick(&canister), None);
+        assert_eq!(canister.totalSupply(), Nat::from(1150));
+    }
+
+    #[test]
+    fn set_emission_invalid() {
+        let canister = test_canister();
+        assert_eq!(
+            canister.setEmission(bob(), Nat::from(100), 0, true),
+            Err(TxError::InvalidEmissionPeriod)
+        );
+
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.setEmission(bob(), Nat::from(100), PERIOD_SEC, true),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(canister.getEmissionInfo(), None);
+    }
+
+    #[test]
+    fn emission_survives_upgrade() {
+        let canister = test_canister();
+        canister
+            .setEmission(bob(), Nat::from(100), PERIOD_SEC, true)
+            .unwrap();
+        let info = canister.getEmissionInfo();
+
+        canister.__pre_upgrade_inst();
+        canister.state.borrow_mut().emission = None;
+        canister.__post_upgrade_inst();
+        assert_eq!(canister.getEmissionInfo(), info);
+
+        skip_period(&canister);
+        assert!(tick(&canister).is_some());
+    }
+}
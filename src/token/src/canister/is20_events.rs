@@ -0,0 +1,538 @@
+//! This module contains APIs for pushing the new transactions to the subscribed canisters.
+//!
+//! Any principal can subscribe a callback canister to the ledger events, and the events are sent
+//! after the owner approved the subscription. Every subscriber keeps the id of the next
+//! transaction to send, so the transactions are not copied into a queue. The events are sent on
+//! the canister heartbeat with the `on_is20_event` call, so the transactions never wait for the
+//! subscribers. Events that failed to be delivered are put into the delivery queue and sent again
+//! with an exponential backoff, until they run out of attempts.
+//!
+//! The delivery queue also holds the notifications of the auction winners, which are queued when
+//! the auction is held.
+
+use crate::canister::{check_caller, TokenCanister};
+use crate::ledger::to_id;
+use crate::state::{CanisterState, PendingDelivery, Subscriber, MAX_SUBSCRIBERS};
+use crate::types::{Timestamp, TxError, TxRecord};
+use candid::{CandidType, Deserialize, Principal};
+use ic_canister::virtual_canister_call;
+use ic_cdk::api::call::CallResult;
+use ic_kit::ic;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Maximum number of the events sent to every subscriber by one dispatch.
+const MAX_EVENTS_PER_DISPATCH: usize = 100;
+
+/// State of the queue of the events that failed to be delivered.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct DeliveryQueueStats {
+    /// Number of the events waiting for the next attempt.
+    pub queued: u64,
+
+    /// Number of the queued events with the next attempt due.
+    pub due: u64,
+
+    /// Time of the earliest next attempt, or `None` if the queue is empty.
+    pub next_attempt: Option<Timestamp>,
+
+    /// Number of the events dropped after running out of attempts or from a full queue.
+    pub dropped: u64,
+}
+
+/// Subscribes the `callback` canister to the events of the caller. A subscription made by the
+/// owner is approved at once, other subscriptions wait for the owner's approval. Subscribing
+/// again replaces the callback and requires a new approval.
+pub(crate) fn subscribe(canister: &TokenCanister, callback: Principal) -> Result<(), TxError> {
+    let caller = ic::caller();
+    if caller == Principal::anonymous() || callback == Principal::anonymous() {
+        return Err(TxError::AnonymousPrincipal);
+    }
+
+    let mut state = canister.state.borrow_mut();
+    let subscribers = &state.subscriptions.subscribers;
+    if !subscribers.contains_key(&caller) && subscribers.len() >= MAX_SUBSCRIBERS {
+        return Err(TxError::TooManySubscribers {
+            max: MAX_SUBSCRIBERS as u64,
+        });
+    }
+
+    let subscriber = Subscriber {
+        callback,
+        approved: caller == state.stats.owner,
+        next_id: state.ledger.len(),
+        delivered: 0,
+        failed: 0,
+    };
+    state.subscriptions.subscribers.insert(caller, subscriber);
+
+    Ok(())
+}
+
+/// Removes the subscription of the caller.
+pub(crate) fn unsubscribe(canister: &TokenCanister) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    match state.subscriptions.subscribers.remove(&ic::caller()) {
+        Some(_) => Ok(()),
+        None => Err(TxError::SubscriberNotFound),
+    }
+}
+
+/// Approves the subscription of the `subscriber`. The subscriber receives the transactions made
+/// after the approval.
+pub(crate) fn approve_subscriber(
+    canister: &TokenCanister,
+    subscriber: Principal,
+) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    let next_id = state.ledger.len();
+    let subscriber = state
+        .subscriptions
+        .subscribers
+        .get_mut(&subscriber)
+        .ok_or(TxError::SubscriberNotFound)?;
+    if !subscriber.approved {
+        subscriber.approved = true;
+        subscriber.next_id = next_id;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn remove_subscriber(
+    canister: &TokenCanister,
+    subscriber: Principal,
+) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    match state.subscriptions.subscribers.remove(&subscriber) {
+        Some(_) => Ok(()),
+        None => Err(TxError::SubscriberNotFound),
+    }
+}
+
+pub(crate) fn list_subscribers(
+    canister: &TokenCanister,
+) -> Result<Vec<(Principal, Subscriber)>, TxError> {
+    let state = canister.state.borrow();
+    check_caller(state.stats.owner)?;
+
+    let mut subscribers: Vec<_> = state
+        .subscriptions
+        .subscribers
+        .iter()
+        .map(|(principal, subscriber)| (*principal, subscriber.clone()))
+        .collect();
+    subscribers.sort_unstable_by_key(|(principal, _)| *principal);
+    Ok(subscribers)
+}
+
+pub(crate) fn delivery_queue_stats(canister: &TokenCanister) -> DeliveryQueueStats {
+    let queue = &canister.state.borrow().delivery_queue;
+    let now = ic::time();
+    DeliveryQueueStats {
+        queued: queue.pending.len() as u64,
+        due: queue
+            .pending
+            .iter()
+            .filter(|delivery| delivery.next_attempt <= now)
+            .count() as u64,
+        next_attempt: queue
+            .pending
+            .iter()
+            .map(|delivery| delivery.next_attempt)
+            .min(),
+        dropped: queue.dropped,
+    }
+}
+
+/// Sends up to `MAX_EVENTS_PER_DISPATCH` new transactions to every approved subscriber. Returns
+/// the number of the delivered events, which is zero if another dispatch is in progress. Events
+/// that failed to be delivered are put into the delivery queue.
+///
+/// Transactions removed from the ledger before they were sent are skipped.
+pub(crate) async fn dispatch_events(state: Rc<RefCell<CanisterState>>) -> u64 {
+    let batches: Vec<(Principal, Principal, Vec<TxRecord>)> = {
+        let state = state.borrow();
+        if state.subscriptions.in_progress {
+            return 0;
+        }
+
+        let first_id = state.ledger.first_id();
+        state
+            .subscriptions
+            .subscribers
+            .iter()
+            .filter(|(_, subscriber)| subscriber.approved)
+            .map(|(principal, subscriber)| {
+                let start = subscriber.next_id.max(first_id);
+                let records = state.ledger.get_range(start, MAX_EVENTS_PER_DISPATCH);
+                (*principal, subscriber.callback, records.to_vec())
+            })
+            .filter(|(_, _, records)| !records.is_empty())
+            .collect()
+    };
+
+    state.borrow_mut().subscriptions.in_progress = true;
+    let mut delivered = 0;
+    for (principal, callback, records) in batches {
+        for tx in records {
+            let result = send_event(callback, &tx).await;
+
+            // The subscription could have been removed or changed while the call was awaited.
+            let mut state = state.borrow_mut();
+            let state = &mut *state;
+            let subscriber = match state.subscriptions.subscribers.get_mut(&principal) {
+                Some(subscriber) if subscriber.callback == callback => subscriber,
+                _ => break,
+            };
+
+            subscriber.next_id = subscriber.next_id.max(to_id(&tx.index) + 1);
+            match result {
+                Ok(()) => {
+                    subscriber.delivered += 1;
+                    delivered += 1;
+                }
+                Err(_) => {
+                    subscriber.failed += 1;
+                    let delivery = PendingDelivery {
+                        subscriber: principal,
+                        callback,
+                        record: tx,
+                        attempts: 0,
+                        next_attempt: 0,
+                        auction_id: None,
+                    };
+                    state.delivery_queue.retry(delivery, ic::time());
+                }
+            }
+        }
+    }
+
+    state.borrow_mut().subscriptions.in_progress = false;
+    delivered
+}
+
+/// Sends the events and the auction reward notifications from the delivery queue with the next
+/// attempt due. Returns the number of the delivered events, which is zero if another retry is in
+/// progress. Events of the removed or changed subscriptions are dropped from the queue without
+/// sending.
+pub(crate) async fn retry_deliveries(state: Rc<RefCell<CanisterState>>) -> u64 {
+    let due = {
+        let mut state = state.borrow_mut();
+        if state.delivery_queue.in_progress {
+            return 0;
+        }
+
+        state.delivery_queue.in_progress = true;
+        state.delivery_queue.take_due(ic::time())
+    };
+
+    let mut delivered = 0;
+    for delivery in due {
+        if let Some(auction_id) = delivery.auction_id {
+            let result = send_auction_reward(auction_id, &delivery.record).await;
+            match result {
+                Ok(()) => delivered += 1,
+                Err(_) => state
+                    .borrow_mut()
+                    .delivery_queue
+                    .retry(delivery, ic::time()),
+            }
+
+            continue;
+        }
+
+        let is_subscribed = |state: &CanisterState| {
+            state
+                .subscriptions
+                .subscribers
+                .get(&delivery.subscriber)
+                .map_or(false, |subscriber| subscriber.callback == delivery.callback)
+        };
+        if !is_subscribed(&*state.borrow()) {
+            continue;
+        }
+
+        let result = send_event(delivery.callback, &delivery.record).await;
+
+        let mut state = state.borrow_mut();
+        let state = &mut *state;
+        if let Some(subscriber) = state
+            .subscriptions
+            .subscribers
+            .get_mut(&delivery.subscriber)
+            .filter(|subscriber| subscriber.callback == delivery.callback)
+        {
+            match result {
+                Ok(()) => {
+                    subscriber.delivered += 1;
+                    delivered += 1;
+                }
+                Err(_) => {
+                    subscriber.failed += 1;
+                    state.delivery_queue.retry(delivery, ic::time());
+                }
+            }
+        }
+    }
+
+    state.borrow_mut().delivery_queue.in_progress = false;
+    delivered
+}
+
+/// Starts sending the new transactions if some of the subscribers didn't receive them yet, and
+/// the queued events with the next attempt due.
+pub(crate) fn events_tick(state: &Rc<RefCell<CanisterState>>) {
+    let (events_due, retries_due) = {
+        let state = state.borrow();
+        (
+            state.subscriptions.is_due(state.ledger.len()),
+            state.delivery_queue.is_due(ic::time()),
+        )
+    };
+
+    if events_due {
+        let state = state.clone();
+        ic_cdk::spawn(async move {
+            dispatch_events(state).await;
+        });
+    }
+
+    if retries_due {
+        let state = state.clone();
+        ic_cdk::spawn(async move {
+            retry_deliveries(state).await;
+        });
+    }
+}
+
+async fn send_event(callback: Principal, tx: &TxRecord) -> CallResult<()> {
+    virtual_canister_call!(callback, "on_is20_event", (tx.clone(),), ()).await
+}
+
+async fn send_auction_reward(auction_id: usize, tx: &TxRecord) -> CallResult<()> {
+    virtual_canister_call!(tx.to, "auction_reward", (auction_id, tx.amount.clone()), ()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{DELIVERY_RETRY_DELAY, MAX_DELIVERY_ATTEMPTS};
+    use candid::Nat;
+    use common::types::Metadata;
+    use ic_canister::{register_failing_virtual_responder, register_virtual_responder, Canister};
+    use ic_kit::mock_principals::{alice, bob, john, xtc};
+    use ic_kit::MockContext;
+
+    fn test_canister() -> TokenCanister {
+        MockContext::new().with_caller(alice()).inject();
+
+        let canister = TokenCanister::init_instance();
+        canister.init(Metadata {
+            logo: "".to_string(),
+            name: "".to_string(),
+            symbol: "".to_string(),
+            decimals: 8,
+            totalSupply: Nat::from(1000),
+            owner: alice(),
+            fee: Nat::from(0),
+            feeTo: alice(),
+            isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
+        });
+
+        canister
+    }
+
+    fn subscriber(canister: &TokenCanister, principal: Principal) -> Subscriber {
+        canister.state.borrow().subscriptions.subscribers[&principal].clone()
+    }
+
+    #[tokio::test]
+    async fn events_delivered_after_approval() {
+        let received = Rc::new(RefCell::new(vec![]));
+        let received_clone = received.clone();
+        register_virtual_responder(xtc(), "on_is20_event", move |(tx,): (TxRecord,)| {
+            received.borrow_mut().push(tx.index);
+        });
+
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+        canister.subscribe(xtc()).unwrap();
+        assert!(!subscriber(&canister, bob()).approved);
+
+        MockContext::new().with_caller(alice()).inject();
+        canister.transfer(bob(), Nat::from(10), None).unwrap();
+        assert_eq!(dispatch_events(canister.state.clone()).await, 0);
+
+        canister.approveSubscriber(bob()).unwrap();
+        let first = canister.transfer(bob(), Nat::from(10), None).unwrap();
+        let second = canister.transfer(john(), Nat::from(10), None).unwrap();
+        assert_eq!(dispatch_events(canister.state.clone()).await, 2);
+        assert_eq!(*received_clone.borrow(), vec![first, second]);
+        assert_eq!(dispatch_events(canister.state.clone()).await, 0);
+
+        let subscribers = canister.listSubscribers().unwrap();
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].0, bob());
+        assert_eq!(subscribers[0].1.delivered, 2);
+
+        MockContext::new().with_caller(bob()).inject();
+        canister.unsubscribe().unwrap();
+        assert_eq!(canister.unsubscribe(), Err(TxError::SubscriberNotFound));
+        canister.transfer(alice(), Nat::from(5), None).unwrap();
+        assert_eq!(dispatch_events(canister.state.clone()).await, 0);
+        assert_eq!(received_clone.borrow().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failing_subscriber_does_not_block_transfers() {
+        register_failing_virtual_responder(john(), "on_is20_event", "something's wrong".into());
+        register_virtual_responder(xtc(), "on_is20_event", |_: (TxRecord,)| {});
+
+        let canister = test_canister();
+        canister.subscribe(john()).unwrap();
+        MockContext::new().with_caller(bob()).inject();
+        canister.subscribe(xtc()).unwrap();
+
+        MockContext::new().with_caller(alice()).inject();
+        canister.approveSubscriber(bob()).unwrap();
+        for _ in 0..3 {
+            canister.transfer(bob(), Nat::from(10), None).unwrap();
+        }
+        assert_eq!(dispatch_events(canister.state.clone()).await, 3);
+        assert_eq!(canister.balanceOf(bob()), Nat::from(30));
+
+        let failing = subscriber(&canister, alice());
+        assert_eq!(failing.failed, 3);
+        assert_eq!(failing.delivered, 0);
+        assert_eq!(failing.next_id, 4);
+        assert_eq!(subscriber(&canister, bob()).delivered, 3);
+        assert!(!canister.state.borrow().subscriptions.in_progress);
+
+        // Failed events are not sent again with the new events, they wait in the delivery queue.
+        assert_eq!(dispatch_events(canister.state.clone()).await, 0);
+        assert_eq!(subscriber(&canister, alice()).failed, 3);
+        assert_eq!(canister.getDeliveryQueueStats().queued, 3);
+    }
+
+    /// Makes all the queued events due.
+    fn make_due(canister: &TokenCanister) {
+        for delivery in &mut canister.state.borrow_mut().delivery_queue.pending {
+            delivery.next_attempt = 0;
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_dropped_after_max_attempts() {
+        register_failing_virtual_responder(john(), "on_is20_event", "something's wrong".into());
+
+        let canister = test_canister();
+        canister.subscribe(john()).unwrap();
+        canister.transfer(bob(), Nat::from(10), None).unwrap();
+        canister.transfer(bob(), Nat::from(10), None).unwrap();
+        assert_eq!(dispatch_events(canister.state.clone()).await, 0);
+
+        let stats = canister.getDeliveryQueueStats();
+        assert_eq!(stats.queued, 2);
+        assert_eq!(stats.due, 0);
+        assert_eq!(stats.next_attempt, Some(ic::time() + DELIVERY_RETRY_DELAY));
+        assert_eq!(stats.dropped, 0);
+
+        // The queue is kept over upgrades.
+        canister.__pre_upgrade_inst();
+        canister.state.borrow_mut().delivery_queue = Default::default();
+        canister.__post_upgrade_inst();
+        assert_eq!(canister.getDeliveryQueueStats(), stats);
+
+        // Nothing is sent before the next attempt is due.
+        retry_deliveries(canister.state.clone()).await;
+        assert_eq!(subscriber(&canister, alice()).failed, 2);
+
+        for attempt in 2..MAX_DELIVERY_ATTEMPTS {
+            make_due(&canister);
+            assert_eq!(canister.getDeliveryQueueStats().due, 2);
+            assert_eq!(retry_deliveries(canister.state.clone()).await, 0);
+
+            // The delay is doubled after every attempt.
+            let state = canister.state.borrow();
+            let pending = &state.delivery_queue.pending;
+            assert_eq!(pending.len(), 2);
+            assert_eq!(pending[0].attempts, attempt);
+            assert_eq!(
+                pending[0].next_attempt,
+                ic::time() + DELIVERY_RETRY_DELAY * (1 << (attempt - 1))
+            );
+        }
+
+        make_due(&canister);
+        retry_deliveries(canister.state.clone()).await;
+        let stats = canister.getDeliveryQueueStats();
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.next_attempt, None);
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(
+            subscriber(&canister, alice()).failed,
+            2 * MAX_DELIVERY_ATTEMPTS as u64
+        );
+        assert!(!canister.state.borrow().delivery_queue.in_progress);
+    }
+
+    #[tokio::test]
+    async fn queued_delivery_sent_after_recovery() {
+        register_failing_virtual_responder(john(), "on_is20_event", "something's wrong".into());
+
+        let canister = test_canister();
+        canister.subscribe(john()).unwrap();
+        let id = canister.transfer(bob(), Nat::from(10), None).unwrap();
+        dispatch_events(canister.state.clone()).await;
+
+        let received = Rc::new(RefCell::new(vec![]));
+        let received_clone = received.clone();
+        register_virtual_responder(john(), "on_is20_event", move |(tx,): (TxRecord,)| {
+            received.borrow_mut().push(tx.index);
+        });
+        make_due(&canister);
+        assert_eq!(retry_deliveries(canister.state.clone()).await, 1);
+        assert_eq!(*received_clone.borrow(), vec![id]);
+        assert_eq!(canister.getDeliveryQueueStats().queued, 0);
+        assert_eq!(subscriber(&canister, alice()).delivered, 1);
+    }
+
+    #[test]
+    fn subscriptions_are_bounded() {
+        let canister = test_canister();
+        for i in 0..MAX_SUBSCRIBERS {
+            MockContext::new()
+                .with_caller(Principal::from_slice(&[i as u8]))
+                .inject();
+            canister.subscribe(xtc()).unwrap();
+        }
+
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.subscribe(xtc()),
+            Err(TxError::TooManySubscribers {
+                max: MAX_SUBSCRIBERS as u64
+            })
+        );
+        assert!(canister.listSubscribers().is_err());
+        assert!(canister.approveSubscriber(bob()).is_err());
+
+        MockContext::new().with_caller(alice()).inject();
+        assert_eq!(
+            canister.approveSubscriber(bob()),
+            Err(TxError::SubscriberNotFound)
+        );
+        let removed = canister.listSubscribers().unwrap()[0].0;
+        canister.removeSubscriber(removed).unwrap();
+
+        MockContext::new().with_caller(bob()).inject();
+        canister.subscribe(xtc()).unwrap();
+    }
+}
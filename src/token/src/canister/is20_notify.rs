@@ -1,17 +1,23 @@
 //! API methods of IS20 standard related to transaction notification mechanism.
 
 use crate::canister::TokenCanister;
-use crate::types::{TxError, TxReceipt, TxRecord};
+use crate::ledger::to_id;
+use crate::state::{BridgeExit, PendingOwnerNotification, MAX_OWNER_NOTIFICATION_ATTEMPTS};
+use crate::types::{
+    NotificationFailure, NotificationStatus, Operation, TxError, TxReceipt, TxRecord,
+    UnnotifiedPage,
+};
 use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_canister::virtual_canister_call;
 use ic_cdk::api::call::CallResult;
 
 pub(crate) async fn notify(canister: &TokenCanister, transaction_id: Nat) -> TxReceipt {
-    let tx = {
+    let (tx, exit) = {
         let mut state = canister.state.borrow_mut();
         let tx = state
             .ledger
-            .get(&transaction_id)
+            .get(to_id(&transaction_id))
+            .cloned()
             .ok_or(TxError::TransactionDoesNotExist)?;
 
         // We remove the notification here to prevent a concurrent call from being able to send the
@@ -21,17 +27,35 @@ pub(crate) async fn notify(canister: &TokenCanister, transaction_id: Nat) -> TxR
             return Err(TxError::AlreadyNotified);
         }
 
-        tx
+        let exit = state.bridge_exits.get(&transaction_id).cloned();
+        (tx, exit)
     };
 
-    match send_notification(&tx).await {
-        Ok(()) => Ok(tx.index),
+    let result = match &exit {
+        Some(exit) => send_burn_notification(&tx, exit).await,
+        None => send_notification(&tx).await,
+    };
+
+    match result {
+        Ok(()) => {
+            let mut state = canister.state.borrow_mut();
+            state.bridge_exits.remove(&transaction_id);
+            state.notification_failures.remove(&transaction_id);
+            Ok(tx.index)
+        }
         Err((_, description)) => {
-            canister
-                .state
-                .borrow_mut()
-                .notifications
-                .insert(transaction_id);
+            let mut state = canister.state.borrow_mut();
+            let failure = state
+                .notification_failures
+                .entry(transaction_id.clone())
+                .or_insert(NotificationFailure {
+                    attempts: 0,
+                    cdk_msg: String::new(),
+                });
+            failure.attempts = failure.attempts.saturating_add(1);
+            failure.cdk_msg = description.clone();
+            state.notifications.insert(transaction_id);
+
             Err(TxError::NotificationFailed {
                 cdk_msg: description,
             })
@@ -39,6 +63,74 @@ pub(crate) async fn notify(canister: &TokenCanister, transaction_id: Nat) -> TxR
     }
 }
 
+/// Returns the notification state of the `transaction_id` transaction.
+pub(crate) fn notification_status(
+    canister: &TokenCanister,
+    transaction_id: Nat,
+) -> Result<NotificationStatus, TxError> {
+    let state = canister.state.borrow();
+    if to_id(&transaction_id) >= state.ledger.len() {
+        return Err(TxError::TransactionDoesNotExist);
+    }
+
+    if !state.notifications.contains(&transaction_id) {
+        return Ok(NotificationStatus::NotPending);
+    }
+
+    Ok(match state.notification_failures.get(&transaction_id) {
+        Some(failure) => NotificationStatus::Failed {
+            attempts: failure.attempts,
+            cdk_msg: failure.cdk_msg.clone(),
+        },
+        None => NotificationStatus::Pending,
+    })
+}
+
+/// Returns up to `limit` ids of the transactions waiting for a notification in ascending order,
+/// starting from the `cursor` id. If the `receiver` is given, only the transactions to the
+/// `receiver` are returned, and at most `max_scan` pending notifications are checked in one call.
+/// The transactions removed from the ledger history have no known receiver, so they are only
+/// returned without the `receiver` filter.
+pub(crate) fn unnotified_transactions(
+    canister: &TokenCanister,
+    receiver: Option<Principal>,
+    cursor: Option<Nat>,
+    limit: usize,
+    max_scan: usize,
+) -> UnnotifiedPage {
+    let state = canister.state.borrow();
+    let pending = match cursor {
+        Some(cursor) => state.notifications.range(cursor..),
+        None => state.notifications.range(..),
+    };
+
+    let mut ids = vec![];
+    let mut next_cursor = None;
+    for (scanned, id) in pending.enumerate() {
+        if ids.len() >= limit || scanned >= max_scan {
+            next_cursor = Some(id.clone());
+            break;
+        }
+
+        let matches = match receiver {
+            Some(receiver) => state
+                .ledger
+                .get(to_id(id))
+                .map_or(false, |tx| tx.to == receiver),
+            None => true,
+        };
+        if matches {
+            ids.push(id.clone());
+        }
+    }
+
+    UnnotifiedPage {
+        ids,
+        total: Nat::from(state.notifications.len()),
+        next_cursor,
+    }
+}
+
 pub(crate) async fn transfer_and_notify(
     canister: &TokenCanister,
     to: Principal,
@@ -49,6 +141,90 @@ pub(crate) async fn transfer_and_notify(
     notify(canister, id).await
 }
 
+pub(crate) async fn approve_and_notify(
+    canister: &TokenCanister,
+    spender: Principal,
+    value: Nat,
+) -> TxReceipt {
+    let id = canister.approve(spender, value, None, None)?;
+    canister.state.borrow_mut().notifications.insert(id.clone());
+    notify(canister, id).await
+}
+
+pub(crate) async fn mint_and_notify(
+    canister: &TokenCanister,
+    to: Principal,
+    amount: Nat,
+) -> TxReceipt {
+    let id = canister.mint(to, amount, None)?;
+    canister.state.borrow_mut().notifications.insert(id.clone());
+    notify(canister, id).await
+}
+
+/// Burns the caller's tokens and notifies the `bridge` canister about the burn with the given
+/// `payload`. The burn is not reverted if the notification fails, it can be retried with `notify`.
+pub(crate) async fn burn_and_notify(
+    canister: &TokenCanister,
+    amount: Nat,
+    bridge: Principal,
+    payload: Vec<u8>,
+) -> TxReceipt {
+    let id = canister.burn(amount)?;
+    {
+        let mut state = canister.state.borrow_mut();
+        state.notifications.insert(id.clone());
+        state
+            .bridge_exits
+            .insert(id.clone(), BridgeExit { bridge, payload });
+    }
+
+    notify(canister, id).await
+}
+
+pub(crate) fn set_allowance_notifications(canister: &TokenCanister, enabled: bool) {
+    let owner = ic_kit::ic::caller();
+    let notifications = &mut canister.state.borrow_mut().owner_notifications;
+    if enabled {
+        notifications.enabled.insert(owner);
+    } else {
+        notifications.enabled.remove(&owner);
+        notifications.pending.retain(|n| n.owner != owner);
+    }
+}
+
+/// Tries to deliver all the pending allowance notifications. Notifications that failed to be
+/// delivered are put back into the queue until they run out of attempts. Returns the number of
+/// delivered notifications.
+pub(crate) async fn flush_owner_notifications(canister: &TokenCanister) -> u64 {
+    // The queue is taken out of the state, so a concurrent call would not send the same
+    // notifications again.
+    let pending = std::mem::take(&mut canister.state.borrow_mut().owner_notifications.pending);
+    let mut delivered = 0;
+    for mut notification in pending {
+        match send_allowance_notification(&notification).await {
+            Ok(()) => delivered += 1,
+            Err(_) => {
+                notification.attempts += 1;
+                if notification.attempts < MAX_OWNER_NOTIFICATION_ATTEMPTS {
+                    canister
+                        .state
+                        .borrow_mut()
+                        .owner_notifications
+                        .push(notification);
+                }
+            }
+        }
+    }
+
+    delivered
+}
+
+pub(crate) fn pending_owner_notifications(
+    canister: &TokenCanister,
+) -> Vec<PendingOwnerNotification> {
+    canister.state.borrow().owner_notifications.pending.clone()
+}
+
 #[derive(CandidType, Deserialize, Debug, PartialEq)]
 pub struct TransactionNotification {
     /// Transaction id.
@@ -64,7 +240,96 @@ pub struct TransactionNotification {
     pub amount: Nat,
 }
 
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+pub struct ApprovalNotification {
+    /// Transaction id of the approval.
+    pub tx_id: Nat,
+
+    /// Id of the principal (user, canister) that gave the allowance.
+    pub owner: Principal,
+
+    /// Id of the token canister.
+    pub token_id: Principal,
+
+    /// Amount of tokens the spender is allowed to transfer.
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+pub struct AllowanceNotification {
+    /// Transaction id of the transfer.
+    pub tx_id: Nat,
+
+    /// Id of the principal (user, canister) that used the allowance.
+    pub spender: Principal,
+
+    /// Id of the token canister.
+    pub token_id: Principal,
+
+    /// Amount of tokens transferred from the owner account.
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+pub struct BurnNotification {
+    /// Transaction id of the burn.
+    pub tx_id: Nat,
+
+    /// Id of the principal (user, canister) whose tokens were burned.
+    pub from: Principal,
+
+    /// Id of the token canister.
+    pub token_id: Principal,
+
+    /// Amount of tokens burned.
+    pub amount: Nat,
+
+    /// Payload given to `burnAndNotify`.
+    pub payload: Vec<u8>,
+}
+
 async fn send_notification(tx: &TxRecord) -> CallResult<()> {
+    match tx.operation {
+        Operation::Approve | Operation::ApproveUnlimited => send_approval_notification(tx).await,
+        _ => send_transaction_notification(tx).await,
+    }
+}
+
+async fn send_approval_notification(tx: &TxRecord) -> CallResult<()> {
+    let notification = ApprovalNotification {
+        tx_id: tx.index.clone(),
+        owner: tx.from,
+        token_id: ic_kit::ic::id(),
+        amount: tx.amount.clone(),
+    };
+
+    virtual_canister_call!(tx.to, "approval_notification", (notification,), ()).await
+}
+
+async fn send_burn_notification(tx: &TxRecord, exit: &BridgeExit) -> CallResult<()> {
+    let notification = BurnNotification {
+        tx_id: tx.index.clone(),
+        from: tx.from,
+        token_id: ic_kit::ic::id(),
+        amount: tx.amount.clone(),
+        payload: exit.payload.clone(),
+    };
+
+    virtual_canister_call!(exit.bridge, "burn_notification", (notification,), ()).await
+}
+
+async fn send_allowance_notification(pending: &PendingOwnerNotification) -> CallResult<()> {
+    let notification = AllowanceNotification {
+        tx_id: pending.tx_id.clone(),
+        spender: pending.spender,
+        token_id: ic_kit::ic::id(),
+        amount: pending.amount.clone(),
+    };
+
+    virtual_canister_call!(pending.owner, "allowance_notification", (notification,), ()).await
+}
+
+async fn send_transaction_notification(tx: &TxRecord) -> CallResult<()> {
     let notification = TransactionNotification {
         tx_id: tx.index.clone(),
         from: tx.from,
@@ -80,7 +345,7 @@ mod tests {
     use super::*;
     use common::types::Metadata;
     use ic_canister::{register_failing_virtual_responder, register_virtual_responder, Canister};
-    use ic_kit::mock_principals::{alice, bob};
+    use ic_kit::mock_principals::{alice, bob, john};
     use ic_kit::MockContext;
     use std::rc::Rc;
     use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
@@ -99,6 +364,8 @@ mod tests {
             fee: Nat::from(0),
             feeTo: alice(),
             isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
         });
 
         canister
@@ -163,8 +430,21 @@ mod tests {
 
         let canister = test_canister();
         let id = canister.transfer(bob(), Nat::from(100u32), None).unwrap();
+        assert_eq!(
+            canister.getNotificationStatus(id.clone()),
+            Ok(NotificationStatus::Pending)
+        );
+
         let response = canister.notify(id.clone()).await;
         assert!(response.is_err());
+        let response = canister.notify(id.clone()).await;
+        assert!(response.is_err());
+        assert!(matches!(
+            canister.getNotificationStatus(id.clone()),
+            Ok(NotificationStatus::Failed { attempts: 2, .. })
+        ));
+        let page = canister.getUnnotifiedTransactions(Some(bob()), None, Nat::from(10));
+        assert_eq!(page.ids, vec![id.clone()]);
 
         register_virtual_responder(
             bob(),
@@ -172,7 +452,38 @@ mod tests {
             move |_: (TransactionNotification,)| {},
         );
         let response = canister.notify(id.clone()).await;
-        assert!(response.is_ok())
+        assert!(response.is_ok());
+        assert_eq!(
+            canister.getNotificationStatus(id.clone()),
+            Ok(NotificationStatus::NotPending)
+        );
+        let page = canister.getUnnotifiedTransactions(Some(bob()), None, Nat::from(10));
+        assert!(page.ids.is_empty());
+        assert!(canister.state.borrow().notification_failures.is_empty());
+    }
+
+    #[test]
+    fn unnotified_transactions_paginated() {
+        let canister = test_canister();
+        let first = canister.transfer(bob(), Nat::from(10), None).unwrap();
+        let second = canister.transfer(john(), Nat::from(10), None).unwrap();
+        let third = canister.transfer(bob(), Nat::from(10), None).unwrap();
+
+        let page = canister.getUnnotifiedTransactions(Some(bob()), None, Nat::from(1));
+        assert_eq!(page.ids, vec![first]);
+        assert_eq!(page.next_cursor, Some(second.clone()));
+
+        let page = canister.getUnnotifiedTransactions(Some(bob()), page.next_cursor, Nat::from(1));
+        assert_eq!(page.ids, vec![third.clone()]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = canister.getUnnotifiedTransactions(None, Some(second.clone()), Nat::from(10));
+        assert_eq!(page.ids, vec![second, third]);
+
+        assert_eq!(
+            canister.getNotificationStatus(canister.historySize()),
+            Err(TxError::TransactionDoesNotExist)
+        );
     }
 
     #[tokio::test]
@@ -197,4 +508,293 @@ mod tests {
         let response = canister.notify(id.clone()).await;
         assert_eq!(response, Err(TxError::AlreadyNotified));
     }
+
+    #[tokio::test]
+    async fn approve_and_notify_success() {
+        let is_notified = Rc::new(AtomicBool::new(false));
+        let is_notified_clone = is_notified.clone();
+        register_virtual_responder(
+            bob(),
+            "approval_notification",
+            move |(notification,): (ApprovalNotification,)| {
+                is_notified.swap(true, Ordering::Relaxed);
+                assert_eq!(notification.owner, alice());
+                assert_eq!(notification.amount, 100);
+            },
+        );
+
+        let canister = test_canister();
+        let id = canister
+            .approveAndNotify(bob(), Nat::from(100))
+            .await
+            .unwrap();
+        assert!(is_notified_clone.load(Ordering::Relaxed));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(100));
+
+        let response = canister.notify(id).await;
+        assert_eq!(response, Err(TxError::AlreadyNotified));
+    }
+
+    #[tokio::test]
+    async fn approve_and_notify_failure() {
+        register_failing_virtual_responder(
+            bob(),
+            "approval_notification",
+            "spender is down".into(),
+        );
+
+        let canister = test_canister();
+        let response = canister.approveAndNotify(bob(), Nat::from(100)).await;
+        assert!(matches!(response, Err(TxError::NotificationFailed { .. })));
+
+        // The approval stands even if the notification failed.
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(100));
+        let id = canister.historySize() - 1;
+        assert!(canister.state.borrow().notifications.contains(&id));
+
+        register_virtual_responder(
+            bob(),
+            "approval_notification",
+            move |_: (ApprovalNotification,)| {},
+        );
+        let response = canister.notify(id.clone()).await;
+        assert_eq!(response, Ok(id));
+    }
+
+    fn transfer_from_alice(canister: &TokenCanister) -> Nat {
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+
+        MockContext::new().with_caller(bob()).inject();
+        let id = canister
+            .transferFrom(alice(), john(), Nat::from(50))
+            .unwrap();
+        MockContext::new().with_caller(alice()).inject();
+
+        id
+    }
+
+    #[test]
+    fn owner_notifications_disabled_by_default() {
+        let canister = test_canister();
+        transfer_from_alice(&canister);
+        assert!(canister.getPendingOwnerNotifications().is_empty());
+    }
+
+    #[test]
+    fn owner_notification_queued() {
+        let canister = test_canister();
+        canister.setAllowanceNotifications(true);
+        let id = transfer_from_alice(&canister);
+
+        assert_eq!(
+            canister.getPendingOwnerNotifications(),
+            vec![PendingOwnerNotification {
+                owner: alice(),
+                tx_id: id,
+                spender: bob(),
+                amount: Nat::from(50),
+                attempts: 0,
+            }]
+        );
+
+        canister.setAllowanceNotifications(false);
+        assert!(canister.getPendingOwnerNotifications().is_empty());
+    }
+
+    #[tokio::test]
+    async fn owner_notification_delivered() {
+        let is_notified = Rc::new(AtomicBool::new(false));
+        let is_notified_clone = is_notified.clone();
+        register_virtual_responder(
+            alice(),
+            "allowance_notification",
+            move |(notification,): (AllowanceNotification,)| {
+                is_notified.swap(true, Ordering::Relaxed);
+                assert_eq!(notification.spender, bob());
+                assert_eq!(notification.amount, 50);
+            },
+        );
+
+        let canister = test_canister();
+        canister.setAllowanceNotifications(true);
+        transfer_from_alice(&canister);
+
+        assert_eq!(canister.flushOwnerNotifications().await, 1);
+        assert!(is_notified_clone.load(Ordering::Relaxed));
+        assert!(canister.getPendingOwnerNotifications().is_empty());
+    }
+
+    #[tokio::test]
+    async fn owner_notification_retried() {
+        register_failing_virtual_responder(
+            alice(),
+            "allowance_notification",
+            "owner is down".into(),
+        );
+
+        let canister = test_canister();
+        canister.setAllowanceNotifications(true);
+        transfer_from_alice(&canister);
+
+        // The transfer stands even if the notification cannot be delivered.
+        assert_eq!(canister.balanceOf(john()), Nat::from(50));
+
+        for attempt in 1..MAX_OWNER_NOTIFICATION_ATTEMPTS {
+            assert_eq!(canister.flushOwnerNotifications().await, 0);
+            assert_eq!(canister.getPendingOwnerNotifications()[0].attempts, attempt);
+        }
+
+        assert_eq!(canister.flushOwnerNotifications().await, 0);
+        assert!(canister.getPendingOwnerNotifications().is_empty());
+    }
+
+    #[tokio::test]
+    async fn mint_and_notify_success() {
+        let is_notified = Rc::new(AtomicBool::new(false));
+        let is_notified_clone = is_notified.clone();
+        register_virtual_responder(
+            bob(),
+            "transaction_notification",
+            move |(notification,): (TransactionNotification,)| {
+                is_notified.swap(true, Ordering::Relaxed);
+                assert_eq!(notification.from, alice());
+                assert_eq!(notification.amount, 100);
+            },
+        );
+
+        let canister = test_canister();
+        let id = canister.mintAndNotify(bob(), Nat::from(100)).await.unwrap();
+        assert!(is_notified_clone.load(Ordering::Relaxed));
+        assert_eq!(canister.balanceOf(bob()), Nat::from(100));
+        assert_eq!(
+            canister.getTransaction(id.clone()).operation,
+            Operation::Mint
+        );
+
+        let response = canister.notify(id).await;
+        assert_eq!(response, Err(TxError::AlreadyNotified));
+    }
+
+    #[tokio::test]
+    async fn mint_and_notify_failure() {
+        register_failing_virtual_responder(
+            bob(),
+            "transaction_notification",
+            "something's wrong".into(),
+        );
+
+        let canister = test_canister();
+        let response = canister.mintAndNotify(bob(), Nat::from(100)).await;
+        assert!(matches!(response, Err(TxError::NotificationFailed { .. })));
+        assert_eq!(canister.balanceOf(bob()), Nat::from(100));
+
+        register_virtual_responder(
+            bob(),
+            "transaction_notification",
+            move |_: (TransactionNotification,)| {},
+        );
+        let id = canister.historySize() - Nat::from(1);
+        assert_eq!(canister.notify(id.clone()).await, Ok(id));
+    }
+
+    #[tokio::test]
+    async fn mint_and_notify_not_authorized() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+
+        let response = canister.mintAndNotify(bob(), Nat::from(100)).await;
+        assert_eq!(
+            response,
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+    }
+
+    #[tokio::test]
+    async fn burn_and_notify_success() {
+        let is_notified = Rc::new(AtomicBool::new(false));
+        let is_notified_clone = is_notified.clone();
+        register_virtual_responder(
+            bob(),
+            "burn_notification",
+            move |(notification,): (BurnNotification,)| {
+                is_notified.swap(true, Ordering::Relaxed);
+                assert_eq!(notification.from, alice());
+                assert_eq!(notification.amount, 100);
+                assert_eq!(notification.payload, vec![1, 2, 3]);
+            },
+        );
+
+        let canister = test_canister();
+        let id = canister
+            .burnAndNotify(Nat::from(100), bob(), vec![1, 2, 3])
+            .await
+            .unwrap();
+        assert!(is_notified_clone.load(Ordering::Relaxed));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(900));
+        assert_eq!(canister.totalSupply(), Nat::from(900));
+        assert_eq!(
+            canister.getTransaction(id.clone()).operation,
+            Operation::Burn
+        );
+        assert!(canister.state.borrow().bridge_exits.is_empty());
+
+        let response = canister.notify(id).await;
+        assert_eq!(response, Err(TxError::AlreadyNotified));
+        assert_eq!(canister.totalSupply(), Nat::from(900));
+    }
+
+    #[tokio::test]
+    async fn burn_and_notify_retry() {
+        register_failing_virtual_responder(bob(), "burn_notification", "bridge is down".into());
+
+        let canister = test_canister();
+        let response = canister
+            .burnAndNotify(Nat::from(100), bob(), vec![1, 2, 3])
+            .await;
+        assert!(matches!(response, Err(TxError::NotificationFailed { .. })));
+
+        // The burn is not reverted.
+        assert_eq!(canister.balanceOf(alice()), Nat::from(900));
+        assert_eq!(canister.totalSupply(), Nat::from(900));
+
+        let id = canister.historySize() - Nat::from(1);
+        let response = canister.notify(id.clone()).await;
+        assert!(matches!(response, Err(TxError::NotificationFailed { .. })));
+
+        let counter = Rc::new(AtomicU32::new(0));
+        let counter_copy = counter.clone();
+        register_virtual_responder(
+            bob(),
+            "burn_notification",
+            move |(notification,): (BurnNotification,)| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                assert_eq!(notification.payload, vec![1, 2, 3]);
+            },
+        );
+        assert_eq!(canister.notify(id.clone()).await, Ok(id.clone()));
+        assert_eq!(canister.notify(id).await, Err(TxError::AlreadyNotified));
+        assert_eq!(counter_copy.load(Ordering::Relaxed), 1);
+
+        // The supply is reduced only once.
+        assert_eq!(canister.totalSupply(), Nat::from(900));
+        assert_eq!(
+            canister.getTransactions(Nat::from(0), Nat::from(10)).len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn burn_and_notify_insufficient_balance() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+
+        let response = canister.burnAndNotify(Nat::from(100), john(), vec![]).await;
+        assert_eq!(response, Err(TxError::InsufficientBalance));
+        assert!(canister.state.borrow().notifications.is_empty());
+        assert!(canister.state.borrow().bridge_exits.is_empty());
+    }
 }
@@ -0,0 +1,201 @@
+//! API methods of IS20 standard for signature based approvals (permits).
+//!
+//! A permit allows the owner of a self-authenticating principal to sign an approval offline, while
+//! any other principal (relayer) submits it to the canister.
+
+use crate::canister::dip20_transactions::_approve;
+use crate::canister::TokenCanister;
+use crate::types::{Allowance, Timestamp, TxError, TxReceipt};
+use candid::{Nat, Principal};
+use common::types::SignedTx;
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use ic_kit::ic;
+
+/// DER prefix of an Ed25519 public key, as it is used to create self-authenticating principals.
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// CBOR-serialized payload of a permit: `(token, owner, spender, value, expires_at, nonce)`. The
+/// `token` is the id of the token canister, so that a permit can't be applied to another token.
+pub type PermitPayload = (Principal, Principal, Principal, Nat, Option<Timestamp>, u64);
+
+pub(crate) fn permit(canister: &TokenCanister, signed: SignedTx) -> TxReceipt {
+    let (token, owner, spender, value, expires_at, nonce) =
+        decode_permit(&signed).ok_or(TxError::InvalidSignature)?;
+    verify_signature(&signed, owner)?;
+    if token != ic::id() {
+        return Err(TxError::InvalidSignature);
+    }
+
+    let mut state = canister.state.borrow_mut();
+    let expected = state.permit_nonces.get(&owner).copied().unwrap_or(0);
+    if nonce != expected {
+        return Err(TxError::InvalidNonce { expected });
+    }
+
+    let allowance = Allowance {
+        value,
+        expires_at,
+        ..Default::default()
+    };
+    let id = _approve(&mut state, owner, spender, allowance)?;
+    state.permit_nonces.insert(owner, expected + 1);
+
+    Ok(id)
+}
+
+pub(crate) fn permit_nonce(canister: &TokenCanister, owner: Principal) -> u64 {
+    canister
+        .state
+        .borrow()
+        .permit_nonces
+        .get(&owner)
+        .copied()
+        .unwrap_or(0)
+}
+
+pub(crate) fn decode_permit(signed: &SignedTx) -> Option<PermitPayload> {
+    serde_cbor::from_slice(&signed.serialized_tx).ok()
+}
+
+fn verify_signature(signed: &SignedTx, owner: Principal) -> Result<(), TxError> {
+    let signer = Principal::self_authenticating(&signed.publickey);
+    if signer != owner {
+        return Err(TxError::Unauthorized {
+            owner: owner.to_string(),
+            caller: signer.to_string(),
+        });
+    }
+
+    let raw_key = signed
+        .publickey
+        .strip_prefix(&ED25519_DER_PREFIX[..])
+        .ok_or(TxError::InvalidSignature)?;
+    let public_key = PublicKey::from_bytes(raw_key).map_err(|_| TxError::InvalidSignature)?;
+    let signature =
+        Signature::try_from(&signed.signature[..]).map_err(|_| TxError::InvalidSignature)?;
+
+    public_key
+        .verify(&signed.serialized_tx, &signature)
+        .map_err(|_| TxError::InvalidSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::types::Metadata;
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+    use ic_canister::Canister;
+    use ic_kit::mock_principals::{alice, bob};
+    use ic_kit::MockContext;
+
+    fn test_canister() -> TokenCanister {
+        MockContext::new().with_caller(alice()).inject();
+
+        let canister = TokenCanister::init_instance();
+        canister.init(Metadata {
+            logo: "".to_string(),
+            name: "".to_string(),
+            symbol: "".to_string(),
+            decimals: 8,
+            totalSupply: Nat::from(1000),
+            owner: alice(),
+            fee: Nat::from(0),
+            feeTo: alice(),
+            isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
+        });
+
+        canister
+    }
+
+    fn test_keypair() -> (Keypair, Vec<u8>) {
+        let secret = SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public: PublicKey = (&secret).into();
+        let mut der = ED25519_DER_PREFIX.to_vec();
+        der.extend_from_slice(public.as_bytes());
+
+        (Keypair { secret, public }, der)
+    }
+
+    fn signed_permit(
+        keypair: &Keypair,
+        der: &[u8],
+        payload: (Principal, Principal, Principal, u64, Option<Timestamp>, u64),
+    ) -> SignedTx {
+        let serialized_tx = serde_cbor::to_vec(&payload).unwrap();
+        SignedTx {
+            publickey: der.to_vec(),
+            signature: keypair.sign(&serialized_tx).to_bytes().to_vec(),
+            serialized_tx,
+        }
+    }
+
+    #[test]
+    fn permit_approves() {
+        let canister = test_canister();
+        let (keypair, der) = test_keypair();
+        let owner = Principal::self_authenticating(&der);
+
+        let signed = signed_permit(&keypair, &der, (ic::id(), owner, bob(), 100, None, 0));
+        assert!(canister.permit(signed).is_ok());
+        assert_eq!(canister.allowance(owner, bob()), Nat::from(100));
+        assert_eq!(canister.getPermitNonce(owner), 1);
+    }
+
+    #[test]
+    fn permit_replay() {
+        let canister = test_canister();
+        let (keypair, der) = test_keypair();
+        let owner = Principal::self_authenticating(&der);
+
+        let signed = signed_permit(&keypair, &der, (ic::id(), owner, bob(), 100, None, 0));
+        assert!(canister.permit(signed.clone()).is_ok());
+        assert_eq!(
+            canister.permit(signed),
+            Err(TxError::InvalidNonce { expected: 1 })
+        );
+    }
+
+    #[test]
+    fn permit_wrong_owner() {
+        let canister = test_canister();
+        let (keypair, der) = test_keypair();
+
+        let signed = signed_permit(&keypair, &der, (ic::id(), alice(), bob(), 100, None, 0));
+        assert!(matches!(
+            canister.permit(signed),
+            Err(TxError::Unauthorized { .. })
+        ));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+    }
+
+    #[test]
+    fn permit_invalid_signature() {
+        let canister = test_canister();
+        let (keypair, der) = test_keypair();
+        let owner = Principal::self_authenticating(&der);
+
+        let mut signed = signed_permit(&keypair, &der, (ic::id(), owner, bob(), 100, None, 0));
+        signed.serialized_tx =
+            serde_cbor::to_vec(&(ic::id(), owner, bob(), 1000u64, None::<Timestamp>, 0u64))
+                .unwrap();
+        assert_eq!(canister.permit(signed), Err(TxError::InvalidSignature));
+        assert_eq!(canister.allowance(owner, bob()), Nat::from(0));
+        assert_eq!(canister.getPermitNonce(owner), 0);
+    }
+
+    #[test]
+    fn permit_for_other_token() {
+        let canister = test_canister();
+        let (keypair, der) = test_keypair();
+        let owner = Principal::self_authenticating(&der);
+
+        let signed = signed_permit(&keypair, &der, (bob(), owner, bob(), 100, None, 0));
+        assert_eq!(canister.permit(signed), Err(TxError::InvalidSignature));
+        assert_eq!(canister.allowance(owner, bob()), Nat::from(0));
+        assert_eq!(canister.getPermitNonce(owner), 0);
+    }
+}
@@ -1,7 +1,11 @@
-use crate::canister::dip20_transactions::{_charge_fee, _transfer};
-use crate::canister::TokenCanister;
+use crate::canister::dip20_transactions::{
+    _allowance_with_fee, _approve, _burn, _charge_approve_fee, _charge_fee, _check_mint_reason,
+    _check_spender, _check_unlocked, _mint, _record_failure, _set_allowance, _transfer,
+    _transfer_from,
+};
+use crate::canister::{check_caller, record_config_change, TokenCanister};
 use crate::state::CanisterState;
-use crate::types::{TxError, TxReceipt};
+use crate::types::{Allowance, FeeBreakdown, Operation, TxError, TxReceipt, UNLIMITED_ALLOWANCE};
 use candid::{Nat, Principal};
 use ic_kit::ic;
 
@@ -11,29 +15,41 @@ use ic_kit::ic;
 /// Note, that the `value` cannot be less than the `fee` amount. If the value given is too small,
 /// transaction will fail with `TxError::AmountTooSmall` error.
 pub fn transfer_include_fee(canister: &TokenCanister, to: Principal, value: Nat) -> TxReceipt {
+    let result = _transfer_include_fee(canister, to, value.clone());
+    _record_failure(
+        canister,
+        &result,
+        Operation::Transfer,
+        ic::caller(),
+        to,
+        value,
+    );
+    result
+}
+
+fn _transfer_include_fee(canister: &TokenCanister, to: Principal, value: Nat) -> TxReceipt {
     let from = ic::caller();
     let mut state = canister.state.borrow_mut();
 
-    let CanisterState {
-        ref mut balances,
-        ref bidding_state,
-        ref stats,
-        ..
-    } = &mut *state;
+    let fee = state.stats.fee.clone();
 
-    let (fee, fee_to) = stats.fee_info();
-    let fee_ratio = bidding_state.fee_ratio;
-
-    if value <= fee {
+    if value <= fee || value < state.stats.min_transfer_amount {
         return Err(TxError::AmountTooSmall);
     }
 
-    if balances.balance_of(&from) < value {
+    if state.balances.balance_of(&from) < value {
         return Err(TxError::InsufficientBalance);
     }
 
-    _charge_fee(balances, from, fee_to, fee.clone(), fee_ratio);
-    _transfer(balances, from, to, value.clone() - fee.clone());
+    _check_unlocked(&state, from, &value)?;
+
+    let fee = _charge_fee(&mut state, from, fee)?;
+    _transfer(
+        &mut state.balances,
+        from,
+        to,
+        value.clone() - fee.total.clone(),
+    )?;
 
     let id = state.ledger.transfer(from, to, value, fee);
     state.notifications.insert(id.clone());
@@ -41,9 +57,419 @@ pub fn transfer_include_fee(canister: &TokenCanister, to: Principal, value: Nat)
     Ok(id)
 }
 
+/// Transfers `value` amount of tokens from `from` to `to` using the allowance given to the caller,
+/// applying American style fee. The `from` account and the allowance are reduced exactly by `value`,
+/// and the recipient receives `value - fee`.
+///
+/// If the `value` is not greater than the `fee`, transaction fails with `TxError::AmountTooSmall`
+/// error.
+pub fn transfer_from_include_fee(
+    canister: &TokenCanister,
+    from: Principal,
+    to: Principal,
+    value: Nat,
+) -> TxReceipt {
+    _transfer_from(canister, from, to, value, true).map(|receipt| receipt.tx_id)
+}
+
+/// Moves `value` amount of tokens from `from` to `to` without requiring an allowance and without
+/// charging a fee.
+///
+/// This method can only be called by the owner of a test token. For tokens with `is_test_token`
+/// flag disabled it always returns `TxError::Unauthorized` error.
+pub fn owner_transfer_from(
+    canister: &TokenCanister,
+    from: Principal,
+    to: Principal,
+    value: Nat,
+) -> TxReceipt {
+    let caller = ic::caller();
+    let mut state = canister.state.borrow_mut();
+
+    if !state.stats.is_test_token {
+        return Err(TxError::Unauthorized {
+            owner: state.stats.owner.to_string(),
+            caller: caller.to_string(),
+        });
+    }
+
+    check_caller(state.stats.owner)?;
+
+    _transfer(&mut state.balances, from, to, value.clone())?;
+
+    let id = state.ledger.clawback(caller, from, to, value);
+    Ok(id)
+}
+
+/// Burns `amount` of tokens from the `from` account without requiring an allowance. The operation
+/// is recorded in the ledger as `Operation::Burn` with the owner as the caller.
+///
+/// Only the owner of a test token is allowed to call this method.
+pub fn test_burn(canister: &TokenCanister, from: Principal, amount: Nat) -> TxReceipt {
+    let caller = ic::caller();
+    let mut state = canister.state.borrow_mut();
+
+    if !state.stats.is_test_token {
+        return Err(TxError::Unauthorized {
+            owner: state.stats.owner.to_string(),
+            caller: caller.to_string(),
+        });
+    }
+
+    check_caller(state.stats.owner)?;
+
+    _burn(&mut state, from, amount.clone())?;
+
+    let id = state.ledger.burn_from(caller, from, amount);
+    Ok(id)
+}
+
+/// Mints `amount` of test tokens to the caller, if the caller hasn't received more than the faucet
+/// limit in the current 24 hour window.
+///
+/// This method can only be called for test tokens.
+pub fn faucet(canister: &TokenCanister, amount: Nat) -> TxReceipt {
+    let caller = ic::caller();
+    let mut state = canister.state.borrow_mut();
+
+    if !state.stats.is_test_token {
+        return Err(TxError::Unauthorized {
+            owner: state.stats.owner.to_string(),
+            caller: caller.to_string(),
+        });
+    }
+
+    let now = ic::time();
+    state.faucet.prune(now);
+    state.faucet.check(caller, &amount, now)?;
+
+    let id = _mint(&mut state, caller, caller, amount.clone(), None)?;
+    state.faucet.record(caller, amount, now);
+    Ok(id)
+}
+
+/// Sets the amount of test tokens every principal can receive with `faucet` in 24 hours.
+///
+/// This method can only be called by the canister owner.
+pub fn set_faucet_limit(canister: &TokenCanister, amount: Nat) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    let old_limit = std::mem::replace(&mut state.faucet.limit, amount.clone());
+    record_config_change(&mut state, "faucet_limit", old_limit, amount);
+    Ok(())
+}
+
+/// Maximum number of entries in a `batchMint` call. Larger batches might not fit into the
+/// instruction limit of a single message.
+pub const MAX_BATCH_MINT_LEN: usize = 1000;
+
+/// Mints tokens to multiple recipients in one call and returns the transaction ids of the mints.
+///
+/// If the batch has more than `MAX_BATCH_MINT_LEN` entries, or the total amount would make the
+/// total supply exceed the maximum supply, no tokens are minted.
+pub fn batch_mint(
+    canister: &TokenCanister,
+    mints: Vec<(Principal, Nat)>,
+    reason: Option<String>,
+) -> Result<Vec<Nat>, TxError> {
+    if mints.len() > MAX_BATCH_MINT_LEN {
+        return Err(TxError::BatchTooLarge {
+            max: MAX_BATCH_MINT_LEN as u64,
+        });
+    }
+    _check_mint_reason(&reason)?;
+
+    let caller = ic::caller();
+    let mut state = canister.state.borrow_mut();
+    let total = mints
+        .iter()
+        .fold(Nat::from(0), |total, (_, amount)| total + amount.clone());
+    if state.stats.exceeds_max_supply(&total) {
+        return Err(TxError::MaxSupplyExceeded);
+    }
+    state.check_mint_limit(caller, &total)?;
+
+    // The total amount is checked against the maximum supply and the mint limit above, so none of
+    // the mints can fail after some of them are applied.
+    mints
+        .into_iter()
+        .map(|(to, amount)| _mint(&mut state, caller, to, amount, reason.clone()))
+        .collect()
+}
+
+/// Sets allowances for multiple spenders in one call. The usual `approve` fee is charged for every
+/// entry.
+///
+/// If the caller cannot pay the fees for all the entries, or any of the spenders is the caller
+/// or the anonymous principal, no allowances are changed.
+pub fn batch_approve(
+    canister: &TokenCanister,
+    approvals: Vec<(Principal, Nat)>,
+) -> Result<Vec<Nat>, TxError> {
+    let owner = ic::caller();
+    let mut state = canister.state.borrow_mut();
+    for (spender, _) in &approvals {
+        _check_spender(&state, owner, *spender)?;
+    }
+
+    let total_fee = state.stats.approve_fee() * approvals.len() as u64;
+    if state.balances.balance_of(&owner) < total_fee {
+        return Err(TxError::InsufficientBalance);
+    }
+
+    let mut ids = Vec::with_capacity(approvals.len());
+    for (spender, value) in approvals {
+        let fee = _charge_approve_fee(&mut state, owner)?;
+        let allowance = Allowance {
+            value: _allowance_with_fee(&state.stats, value.clone()),
+            ..Default::default()
+        };
+        _set_allowance(&mut state, owner, spender, allowance);
+        ids.push(state.ledger.approve(owner, spender, value, fee));
+    }
+
+    Ok(ids)
+}
+
+/// Allows the `spender` to make a single `transferFrom` call of up to `value` amount. After the
+/// first successful transfer the allowance is revoked, even if the spender transferred less than
+/// allowed.
+pub fn approve_once(canister: &TokenCanister, spender: Principal, value: Nat) -> TxReceipt {
+    let owner = ic::caller();
+    let mut state = canister.state.borrow_mut();
+    _approve(
+        &mut state,
+        owner,
+        spender,
+        Allowance {
+            value,
+            one_time: true,
+            ..Default::default()
+        },
+    )
+}
+
+/// Allows the `spender` to transfer any amount of tokens from the caller account. `transferFrom`
+/// calls don't decrease such allowance, and `allowance` method reports `UNLIMITED_ALLOWANCE` value
+/// for it. The allowance can be revoked by approving zero amount.
+pub fn approve_unlimited(canister: &TokenCanister, spender: Principal) -> TxReceipt {
+    let owner = ic::caller();
+    let mut state = canister.state.borrow_mut();
+    _check_spender(&state, owner, spender)?;
+
+    let fee = _charge_approve_fee(&mut state, owner)?;
+    let allowance = Allowance {
+        value: Nat::from(UNLIMITED_ALLOWANCE),
+        unlimited: true,
+        ..Default::default()
+    };
+    _set_allowance(&mut state, owner, spender, allowance);
+
+    let id = state.ledger.approve_unlimited(owner, spender, fee);
+    Ok(id)
+}
+
+/// Sets the allowance of the `spender` to `new_value` only if the current allowance is equal to
+/// `expected_current`. Otherwise `TxError::AllowanceChanged` error with the current allowance is
+/// returned and nothing is changed.
+pub fn approve_expected(
+    canister: &TokenCanister,
+    spender: Principal,
+    expected_current: Nat,
+    new_value: Nat,
+) -> TxReceipt {
+    let owner = ic::caller();
+    let mut state = canister.state.borrow_mut();
+
+    let current = state.allowance(owner, spender);
+    if current != expected_current {
+        return Err(TxError::AllowanceChanged { current });
+    }
+
+    _approve(
+        &mut state,
+        owner,
+        spender,
+        Allowance {
+            value: new_value,
+            ..Default::default()
+        },
+    )
+}
+
+/// Removes all allowances given by the caller and returns the number of removed allowances.
+///
+/// The approval fee is charged only once regardless of the number of allowances. A separate
+/// `Approve` record with zero amount is written into the ledger for every revoked allowance, the
+/// fee is recorded in the first of them. If the caller has no allowances, no fee is charged.
+pub fn revoke_all_approvals(canister: &TokenCanister) -> Result<u64, TxError> {
+    let owner = ic::caller();
+    let mut state = canister.state.borrow_mut();
+
+    let spenders = _active_spenders(&state, owner);
+    if spenders.is_empty() {
+        state.remove_owner_allowances(owner);
+        return Ok(0);
+    }
+
+    let mut fee = _charge_approve_fee(&mut state, owner)?;
+    state.remove_owner_allowances(owner);
+
+    for spender in &spenders {
+        state.ledger.approve(owner, *spender, Nat::from(0), fee);
+        fee = FeeBreakdown::default().into();
+    }
+
+    Ok(spenders.len() as u64)
+}
+
+/// Removes all allowances given by the `owner` and returns the number of removed allowances. An
+/// `AdminRevoke` record is written into the ledger for every revoked allowance.
+///
+/// This method can only be called by the canister owner. No fee is charged.
+pub fn admin_revoke_approvals(canister: &TokenCanister, owner: Principal) -> Result<u64, TxError> {
+    let caller = ic::caller();
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    let spenders = _active_spenders(&state, owner);
+    state.remove_owner_allowances(owner);
+
+    for spender in &spenders {
+        state.ledger.admin_revoke(caller, owner, *spender);
+    }
+
+    Ok(spenders.len() as u64)
+}
+
+/// Returns the spenders with not expired allowances given by the `owner`, ordered by the
+/// principal.
+fn _active_spenders(state: &CanisterState, owner: Principal) -> Vec<Principal> {
+    let mut spenders = match state.allowances.get(&owner) {
+        Some(inner) => inner
+            .iter()
+            .filter(|(_, allowance)| !allowance.is_expired())
+            .map(|(&spender, _)| spender)
+            .collect::<Vec<_>>(),
+        None => vec![],
+    };
+
+    spenders.sort();
+    spenders
+}
+
+/// Adds the `minter` to the list of principals allowed to mint tokens. An `AddMinter` record is
+/// written into the ledger if the principal was not in the list yet.
+///
+/// This method can only be called by the canister owner.
+pub fn add_minter(canister: &TokenCanister, minter: Principal) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    if state.minters.insert(minter) {
+        state.ledger.add_minter(ic::caller(), minter);
+    }
+
+    Ok(())
+}
+
+/// Removes the `minter` from the list of principals allowed to mint tokens. A `RemoveMinter`
+/// record is written into the ledger if the principal was in the list.
+///
+/// This method can only be called by the canister owner.
+pub fn remove_minter(canister: &TokenCanister, minter: Principal) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    if state.minters.remove(&minter) {
+        state.ledger.remove_minter(ic::caller(), minter);
+    }
+
+    Ok(())
+}
+
+/// Permanently disables minting of new tokens.
+///
+/// This method can only be called by the canister owner.
+pub fn renounce_minting(canister: &TokenCanister) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    let old_disabled = std::mem::replace(&mut state.stats.minting_disabled, true);
+    record_config_change(&mut state, "minting_disabled", old_disabled, true);
+    Ok(())
+}
+
+/// Limits the total amount of tokens all the minters can mint in any `window_sec` seconds to
+/// `max_amount`. Zero `window_sec` removes the limit. The mints made before the call count
+/// against the new limit.
+///
+/// This method can only be called by the canister owner.
+pub fn set_mint_limit(
+    canister: &TokenCanister,
+    max_amount: Nat,
+    window_sec: u64,
+) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    let limit = &mut state.mint_limit;
+    let old_limit = format!("[{}, {}]", limit.max_amount, limit.window);
+    limit.max_amount = max_amount;
+    // IC timestamp is in nanoseconds.
+    limit.window = window_sec.saturating_mul(1_000_000_000);
+    let new_limit = format!("[{}, {}]", limit.max_amount, limit.window);
+    record_config_change(&mut state, "mint_limit", old_limit, new_limit);
+    Ok(())
+}
+
+/// Sets if the mints made by the owner are exempt from the mint limit.
+///
+/// This method can only be called by the canister owner.
+pub fn set_mint_limit_owner_exempt(canister: &TokenCanister, exempt: bool) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    let old_exempt = std::mem::replace(&mut state.mint_limit.owner_exempt, exempt);
+    record_config_change(&mut state, "mint_limit_owner_exempt", old_exempt, exempt);
+    Ok(())
+}
+
+/// Adds the `account` to the list of accounts whose balances are not included in the circulating
+/// supply.
+///
+/// This method can only be called by the canister owner.
+pub fn add_non_circulating_account(
+    canister: &TokenCanister,
+    account: Principal,
+) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    state.non_circulating.insert(account);
+    Ok(())
+}
+
+/// Removes the `account` from the list of non-circulating accounts.
+///
+/// This method can only be called by the canister owner.
+pub fn remove_non_circulating_account(
+    canister: &TokenCanister,
+    account: Principal,
+) -> Result<(), TxError> {
+    let mut state = canister.state.borrow_mut();
+    check_caller(state.stats.owner)?;
+
+    state.non_circulating.remove(&account);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::state::FAUCET_WINDOW;
+    use crate::types::Operation;
     use common::types::Metadata;
     use ic_canister::Canister;
     use ic_kit::mock_principals::{alice, bob, john};
@@ -63,6 +489,8 @@ mod tests {
             fee: Nat::from(0),
             feeTo: alice(),
             isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
         });
 
         canister
@@ -93,6 +521,26 @@ mod tests {
         assert_eq!(canister.balanceOf(john()), Nat::from(100));
     }
 
+    #[test]
+    fn transfer_below_min_amount() {
+        let canister = test_canister();
+
+        let mut state = canister.state.borrow_mut();
+        state.stats.fee = Nat::from(10);
+        state.stats.fee_to = john();
+        state.stats.min_transfer_amount = Nat::from(100);
+        drop(state);
+
+        assert_eq!(
+            canister.transferIncludeFee(bob(), Nat::from(99)),
+            Err(TxError::AmountTooSmall)
+        );
+        assert!(canister.transferIncludeFee(bob(), Nat::from(100)).is_ok());
+        assert_eq!(canister.balanceOf(bob()), Nat::from(90));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(900));
+        assert_eq!(canister.balanceOf(john()), Nat::from(10));
+    }
+
     #[test]
     fn transfer_insufficient_balance() {
         let canister = test_canister();
@@ -103,4 +551,1033 @@ mod tests {
         assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
         assert_eq!(canister.balanceOf(bob()), Nat::from(0));
     }
+
+    #[test]
+    fn transfer_from_include_fee() {
+        let canister = test_canister();
+
+        let mut state = canister.state.borrow_mut();
+        state.stats.fee = Nat::from(10);
+        state.stats.fee_to = john();
+        drop(state);
+
+        canister.approve(bob(), Nat::from(200), None, None).unwrap();
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(210));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(990));
+
+        MockContext::new().with_caller(bob()).inject();
+        let id = canister
+            .transferFromIncludeFee(alice(), bob(), Nat::from(110))
+            .unwrap();
+        assert_eq!(canister.balanceOf(alice()), Nat::from(880));
+        assert_eq!(canister.balanceOf(bob()), Nat::from(100));
+        assert_eq!(canister.balanceOf(john()), Nat::from(20));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(100));
+
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::TransferFromIncludeFee);
+        assert_eq!(tx.caller, Some(bob()));
+        assert_eq!(tx.from, alice());
+        assert_eq!(tx.to, bob());
+        assert_eq!(tx.amount, Nat::from(110));
+        assert_eq!(tx.fee, Nat::from(10));
+
+        assert!(canister
+            .transferFromIncludeFee(alice(), john(), Nat::from(100))
+            .is_ok());
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(780));
+    }
+
+    #[test]
+    fn transfer_from_include_fee_too_small() {
+        let canister = test_canister();
+
+        let mut state = canister.state.borrow_mut();
+        state.stats.fee = Nat::from(10);
+        state.stats.fee_to = john();
+        drop(state);
+
+        canister.approve(bob(), Nat::from(200), None, None).unwrap();
+
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.transferFromIncludeFee(alice(), bob(), Nat::from(10)),
+            Err(TxError::AmountTooSmall)
+        );
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(210));
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+    }
+
+    #[test]
+    fn transfer_from_include_fee_insufficient_allowance() {
+        let canister = test_canister();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.transferFromIncludeFee(alice(), bob(), Nat::from(101)),
+            Err(TxError::InsufficientAllowance)
+        );
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(100));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+    }
+
+    #[test]
+    fn owner_transfer_from_test_token() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.is_test_token = true;
+        canister.transfer(bob(), Nat::from(300), None).unwrap();
+
+        let id = canister
+            .ownerTransferFrom(bob(), john(), Nat::from(200))
+            .unwrap();
+        assert_eq!(canister.balanceOf(bob()), Nat::from(100));
+        assert_eq!(canister.balanceOf(john()), Nat::from(200));
+
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::Clawback);
+        assert_eq!(tx.caller, Some(alice()));
+        assert_eq!(tx.from, bob());
+        assert_eq!(tx.to, john());
+        assert_eq!(tx.amount, Nat::from(200));
+    }
+
+    #[test]
+    fn owner_transfer_from_not_test_token() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(300), None).unwrap();
+
+        assert_eq!(
+            canister.ownerTransferFrom(bob(), john(), Nat::from(200)),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: alice().to_string(),
+            })
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(300));
+        assert_eq!(canister.balanceOf(john()), Nat::from(0));
+    }
+
+    #[test]
+    fn owner_transfer_from_not_owner() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.is_test_token = true;
+        canister.transfer(bob(), Nat::from(300), None).unwrap();
+
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.ownerTransferFrom(bob(), john(), Nat::from(200)),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(300));
+    }
+
+    #[test]
+    fn test_burn() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.is_test_token = true;
+        canister.transfer(bob(), Nat::from(300), None).unwrap();
+
+        let id = canister.testBurn(bob(), Nat::from(100)).unwrap();
+        assert_eq!(canister.balanceOf(bob()), Nat::from(200));
+        assert_eq!(canister.totalSupply(), Nat::from(900));
+
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::Burn);
+        assert_eq!(tx.caller, Some(alice()));
+        assert_eq!(tx.from, bob());
+        assert_eq!(tx.amount, Nat::from(100));
+
+        canister.testBurn(bob(), Nat::from(200)).unwrap();
+        assert_eq!(canister.totalSupply(), Nat::from(700));
+        assert_eq!(canister.getTokenInfo().holderNumber, 1);
+        assert!(canister
+            .getHolders(0, 10)
+            .iter()
+            .all(|(holder, _)| *holder != bob()));
+    }
+
+    #[test]
+    fn test_burn_not_test_token() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(300), None).unwrap();
+
+        assert_eq!(
+            canister.testBurn(bob(), Nat::from(100)),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: alice().to_string(),
+            })
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(300));
+        assert_eq!(canister.totalSupply(), Nat::from(1000));
+    }
+
+    #[test]
+    fn test_burn_not_owner() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.is_test_token = true;
+        canister.transfer(bob(), Nat::from(300), None).unwrap();
+
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.testBurn(bob(), Nat::from(100)),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(300));
+    }
+
+    #[test]
+    fn test_burn_insufficient_balance() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.is_test_token = true;
+
+        assert_eq!(
+            canister.testBurn(bob(), Nat::from(1)),
+            Err(TxError::InsufficientBalance)
+        );
+        assert_eq!(canister.totalSupply(), Nat::from(1000));
+    }
+
+    #[test]
+    fn batch_approve() {
+        let canister = test_canister();
+        let ids = canister
+            .batchApprove(vec![(bob(), Nat::from(100)), (john(), Nat::from(200))])
+            .unwrap();
+        assert_eq!(ids, vec![Nat::from(1), Nat::from(2)]);
+
+        assert_eq!(canister.getAllowanceSize(), 2);
+        assert_eq!(canister.getUserApprovals(alice()).len(), 2);
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(100));
+        assert_eq!(canister.allowance(alice(), john()), Nat::from(200));
+
+        let tx = canister.getTransaction(Nat::from(2));
+        assert_eq!(tx.operation, Operation::Approve);
+        assert_eq!(tx.to, john());
+    }
+
+    #[test]
+    fn batch_approve_with_fee() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(100);
+        canister.state.borrow_mut().stats.fee_to = john();
+
+        canister
+            .batchApprove(vec![(bob(), Nat::from(100)), (john(), Nat::from(200))])
+            .unwrap();
+        assert_eq!(canister.balanceOf(alice()), Nat::from(800));
+        assert_eq!(canister.balanceOf(john()), Nat::from(200));
+    }
+
+    #[test]
+    fn batch_approve_insufficient_balance() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(400);
+
+        assert_eq!(
+            canister.batchApprove(vec![
+                (bob(), Nat::from(100)),
+                (john(), Nat::from(200)),
+                (bob(), Nat::from(300))
+            ]),
+            Err(TxError::InsufficientBalance)
+        );
+        assert_eq!(canister.getAllowanceSize(), 0);
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+        assert_eq!(canister.historySize(), 1);
+    }
+
+    #[test]
+    fn batch_approve_invalid_spender() {
+        let canister = test_canister();
+        assert_eq!(
+            canister.batchApprove(vec![(bob(), Nat::from(100)), (alice(), Nat::from(200))]),
+            Err(TxError::SelfApproval)
+        );
+        assert_eq!(
+            canister.batchApprove(vec![
+                (bob(), Nat::from(100)),
+                (Principal::anonymous(), Nat::from(200))
+            ]),
+            Err(TxError::AnonymousPrincipal)
+        );
+        assert_eq!(canister.getAllowanceSize(), 0);
+        assert_eq!(canister.historySize(), 1);
+    }
+
+    #[test]
+    fn revoke_all_approvals() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+        canister.state.borrow_mut().stats.fee_to = john();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+        canister
+            .approve(john(), Nat::from(200), None, None)
+            .unwrap();
+        assert_eq!(canister.balanceOf(alice()), Nat::from(980));
+
+        assert_eq!(canister.revokeAllApprovals(), Ok(2));
+        assert_eq!(canister.getUserApprovals(alice()), vec![]);
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+        assert_eq!(canister.getAllowanceSize(), 0);
+
+        // Only one fee is charged for the revocation.
+        assert_eq!(canister.balanceOf(alice()), Nat::from(970));
+        assert_eq!(canister.historySize(), 5);
+        let tx = canister.getTransaction(Nat::from(4));
+        assert_eq!(tx.operation, Operation::Approve);
+        assert_eq!(tx.amount, Nat::from(0));
+    }
+
+    #[test]
+    fn revoke_all_approvals_without_approvals() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.fee = Nat::from(10);
+
+        assert_eq!(canister.revokeAllApprovals(), Ok(0));
+        assert_eq!(canister.balanceOf(alice()), Nat::from(1000));
+        assert_eq!(canister.historySize(), 1);
+    }
+
+    #[test]
+    fn approve_expected() {
+        let canister = test_canister();
+        canister.approve(bob(), Nat::from(500), None, None).unwrap();
+
+        let id = canister
+            .approveExpected(bob(), Nat::from(500), Nat::from(200))
+            .unwrap();
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(200));
+        assert_eq!(canister.getTransaction(id).operation, Operation::Approve);
+    }
+
+    #[test]
+    fn approve_expected_after_spending() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.approve(bob(), Nat::from(500), None, None).unwrap();
+
+        // The spender front-runs the allowance change.
+        context.update_caller(bob());
+        canister
+            .transferFrom(alice(), john(), Nat::from(300))
+            .unwrap();
+
+        context.update_caller(alice());
+        assert_eq!(
+            canister.approveExpected(bob(), Nat::from(500), Nat::from(200)),
+            Err(TxError::AllowanceChanged {
+                current: Nat::from(200)
+            })
+        );
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(200));
+        assert_eq!(canister.historySize(), 3);
+    }
+
+    #[test]
+    fn approve_once_partial_use() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.approveOnce(bob(), Nat::from(500)).unwrap();
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(500));
+        assert!(canister.getUserApprovals(alice())[0].1.one_time);
+
+        context.update_caller(bob());
+        let receipt = canister
+            .transferFromV2(alice(), john(), Nat::from(100))
+            .unwrap();
+        assert_eq!(receipt.remaining_allowance, Nat::from(0));
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+        assert_eq!(canister.balanceOf(john()), Nat::from(100));
+
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(100)),
+            Err(TxError::InsufficientAllowance)
+        );
+        assert_eq!(canister.balanceOf(john()), Nat::from(100));
+    }
+
+    #[test]
+    fn approve_once_over_allowance() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.approveOnce(bob(), Nat::from(500)).unwrap();
+
+        // A failed transfer doesn't use up the allowance.
+        context.update_caller(bob());
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(600)),
+            Err(TxError::InsufficientAllowance)
+        );
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(500));
+        assert!(canister
+            .transferFrom(alice(), john(), Nat::from(500))
+            .is_ok());
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+    }
+
+    #[test]
+    fn approve_unlimited() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        let id = canister.approveUnlimited(bob()).unwrap();
+        assert_eq!(
+            canister.allowance(alice(), bob()),
+            Nat::from(UNLIMITED_ALLOWANCE)
+        );
+
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::ApproveUnlimited);
+        assert_eq!(tx.amount, Nat::from(UNLIMITED_ALLOWANCE));
+
+        context.update_caller(bob());
+        for _ in 0..100 {
+            canister
+                .transferFrom(alice(), john(), Nat::from(10))
+                .unwrap();
+            assert_eq!(
+                canister.allowance(alice(), bob()),
+                Nat::from(UNLIMITED_ALLOWANCE)
+            );
+        }
+
+        assert_eq!(canister.balanceOf(john()), Nat::from(1000));
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(10)),
+            Err(TxError::InsufficientBalance)
+        );
+    }
+
+    #[test]
+    fn approve_unlimited_revoke() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.approveUnlimited(bob()).unwrap();
+        canister.approve(bob(), Nat::from(0), None, None).unwrap();
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(0));
+        assert_eq!(canister.getAllowanceSize(), 0);
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.transferFrom(alice(), john(), Nat::from(10)),
+            Err(TxError::InsufficientAllowance)
+        );
+    }
+
+    #[test]
+    fn approve_unlimited_decrease() {
+        let canister = test_canister();
+        canister.approveUnlimited(bob()).unwrap();
+        canister.decreaseAllowance(bob(), Nat::from(10)).unwrap();
+
+        let approvals = canister.getUserApprovals(alice());
+        assert!(!approvals[0].1.unlimited);
+        assert_eq!(approvals[0].1.value, Nat::from(UNLIMITED_ALLOWANCE - 10));
+    }
+
+    #[test]
+    fn admin_revoke_approvals() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.transfer(bob(), Nat::from(500), None).unwrap();
+
+        context.update_caller(bob());
+        canister
+            .approve(alice(), Nat::from(100), None, None)
+            .unwrap();
+        canister
+            .approve(john(), Nat::from(200), None, None)
+            .unwrap();
+        let history_size = canister.historySize();
+
+        context.update_caller(alice());
+        assert_eq!(canister.adminRevokeApprovals(bob()), Ok(2));
+        assert_eq!(canister.getUserApprovals(bob()), vec![]);
+        assert_eq!(canister.approvalsForSpender(john(), 0, 10), vec![]);
+        assert_eq!(canister.getApprovedTotal(bob()), Nat::from(0));
+        assert_eq!(canister.historySize(), history_size.clone() + Nat::from(2));
+
+        let tx = canister.getTransaction(history_size);
+        assert_eq!(tx.operation, Operation::AdminRevoke);
+        assert_eq!(tx.caller, Some(alice()));
+        assert_eq!(tx.from, bob());
+        assert_eq!(tx.amount, Nat::from(0));
+
+        assert_eq!(
+            canister.transferFrom(bob(), alice(), Nat::from(10)),
+            Err(TxError::InsufficientAllowance)
+        );
+        context.update_caller(john());
+        assert_eq!(
+            canister.transferFrom(bob(), john(), Nat::from(10)),
+            Err(TxError::InsufficientAllowance)
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(500));
+    }
+
+    #[test]
+    fn admin_revoke_approvals_not_owner() {
+        let canister = test_canister();
+        canister.approve(bob(), Nat::from(100), None, None).unwrap();
+
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.adminRevokeApprovals(alice()),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(canister.allowance(alice(), bob()), Nat::from(100));
+    }
+
+    #[test]
+    fn add_minter_and_mint() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        let history_size = canister.historySize();
+
+        assert_eq!(canister.addMinter(bob()), Ok(()));
+        assert_eq!(canister.getMinters(), vec![bob()]);
+        assert_eq!(canister.historySize(), history_size.clone() + Nat::from(1));
+
+        let tx = canister.getTransaction(history_size);
+        assert_eq!(tx.operation, Operation::AddMinter);
+        assert_eq!(tx.caller, Some(alice()));
+        assert_eq!(tx.to, bob());
+
+        context.update_caller(bob());
+        assert!(canister.mint(john(), Nat::from(100), None).is_ok());
+        assert_eq!(canister.balanceOf(john()), Nat::from(100));
+        assert_eq!(canister.totalSupply(), Nat::from(1100));
+    }
+
+    #[test]
+    fn add_minter_twice() {
+        let canister = test_canister();
+        canister.addMinter(bob()).unwrap();
+        let history_size = canister.historySize();
+
+        assert_eq!(canister.addMinter(bob()), Ok(()));
+        assert_eq!(canister.getMinters(), vec![bob()]);
+        assert_eq!(canister.historySize(), history_size);
+    }
+
+    #[test]
+    fn remove_minter() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.addMinter(bob()).unwrap();
+        let history_size = canister.historySize();
+
+        assert_eq!(canister.removeMinter(bob()), Ok(()));
+        assert_eq!(canister.getMinters(), vec![]);
+        assert_eq!(
+            canister.getTransaction(history_size).operation,
+            Operation::RemoveMinter
+        );
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.mint(john(), Nat::from(100), None),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(canister.balanceOf(john()), Nat::from(0));
+    }
+
+    #[test]
+    fn add_minter_not_authorized() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+
+        assert_eq!(
+            canister.addMinter(bob()),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(canister.getMinters(), vec![]);
+    }
+
+    #[test]
+    fn batch_mint() {
+        let canister = test_canister();
+        let history_size = canister.historySize();
+
+        let ids = canister
+            .batchMint(
+                vec![(bob(), Nat::from(100)), (john(), Nat::from(200))],
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            ids,
+            vec![history_size.clone(), history_size.clone() + Nat::from(1)]
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(100));
+        assert_eq!(canister.balanceOf(john()), Nat::from(200));
+        assert_eq!(canister.totalSupply(), Nat::from(1300));
+        assert_eq!(canister.getTokenInfo().holderNumber, 3);
+        assert_eq!(canister.historySize(), history_size + Nat::from(2));
+
+        let tx = canister.getTransaction(ids[1].clone());
+        assert_eq!(tx.operation, Operation::Mint);
+        assert_eq!(tx.to, john());
+        assert_eq!(tx.amount, Nat::from(200));
+    }
+
+    #[test]
+    fn batch_mint_over_max_supply() {
+        let canister = test_canister();
+        canister.state.borrow_mut().stats.max_supply = Some(Nat::from(1250));
+        let history_size = canister.historySize();
+
+        assert_eq!(
+            canister.batchMint(
+                vec![(bob(), Nat::from(100)), (john(), Nat::from(200))],
+                None
+            ),
+            Err(TxError::MaxSupplyExceeded)
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+        assert_eq!(canister.totalSupply(), Nat::from(1000));
+        assert_eq!(canister.getTokenInfo().holderNumber, 1);
+        assert_eq!(canister.historySize(), history_size);
+    }
+
+    #[test]
+    fn batch_mint_too_large() {
+        let canister = test_canister();
+        let mints = vec![(bob(), Nat::from(1)); MAX_BATCH_MINT_LEN + 1];
+
+        assert_eq!(
+            canister.batchMint(mints, None),
+            Err(TxError::BatchTooLarge {
+                max: MAX_BATCH_MINT_LEN as u64
+            })
+        );
+        assert_eq!(canister.totalSupply(), Nat::from(1000));
+    }
+
+    #[test]
+    fn batch_mint_by_minter() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.batchMint(vec![(john(), Nat::from(100))], None),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+
+        context.update_caller(alice());
+        canister.addMinter(bob()).unwrap();
+
+        context.update_caller(bob());
+        assert!(canister
+            .batchMint(vec![(john(), Nat::from(100))], None)
+            .is_ok());
+        assert_eq!(canister.balanceOf(john()), Nat::from(100));
+    }
+
+    #[test]
+    fn circulating_supply() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.transfer(bob(), Nat::from(300), None).unwrap();
+        assert_eq!(canister.circulatingSupply(), Nat::from(1000));
+
+        canister.addNonCirculatingAccount(alice()).unwrap();
+        assert_eq!(canister.getNonCirculatingAccounts(), vec![alice()]);
+        assert_eq!(canister.circulatingSupply(), Nat::from(300));
+
+        // Tokens moved out of the treasury become circulating.
+        canister.transfer(john(), Nat::from(200), None).unwrap();
+        assert_eq!(canister.circulatingSupply(), Nat::from(500));
+
+        // Tokens moved into the treasury stop circulating.
+        context.update_caller(bob());
+        canister.transfer(alice(), Nat::from(100), None).unwrap();
+        assert_eq!(canister.circulatingSupply(), Nat::from(400));
+
+        context.update_caller(alice());
+        canister.removeNonCirculatingAccount(alice()).unwrap();
+        assert_eq!(canister.getNonCirculatingAccounts(), vec![]);
+        assert_eq!(canister.circulatingSupply(), Nat::from(1000));
+    }
+
+    #[test]
+    fn circulating_supply_excludes_vesting() {
+        let canister = test_canister();
+        canister.mintVested(bob(), Nat::from(100), 0, 1000).unwrap();
+        assert_eq!(canister.totalSupply(), Nat::from(1100));
+        assert_eq!(canister.circulatingSupply(), Nat::from(1000));
+
+        // Locked tokens of non-circulating accounts are not excluded twice.
+        canister.addNonCirculatingAccount(bob()).unwrap();
+        assert_eq!(canister.circulatingSupply(), Nat::from(1000));
+    }
+
+    #[test]
+    fn add_non_circulating_account_not_authorized() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+
+        assert_eq!(
+            canister.addNonCirculatingAccount(bob()),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(
+            canister.removeNonCirculatingAccount(bob()),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(canister.getNonCirculatingAccounts(), vec![]);
+    }
+
+    /// Moves the mints recorded for the mint limit `nanos` nanoseconds into the past.
+    fn shift_mint_limit_time(canister: &TokenCanister, nanos: u64) {
+        for (time, _) in &mut canister.state.borrow_mut().mint_limit.recent {
+            *time -= nanos;
+        }
+    }
+
+    #[test]
+    fn mint_limit_shared_by_minters() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.addMinter(bob()).unwrap();
+        canister.addMinter(john()).unwrap();
+        canister.setMintLimit(Nat::from(300), 60).unwrap();
+
+        context.update_caller(bob());
+        assert!(canister.mint(bob(), Nat::from(200), None).is_ok());
+
+        context.update_caller(john());
+        assert!(canister.mint(john(), Nat::from(100), None).is_ok());
+        let now = ic::time();
+        assert_eq!(
+            canister.mint(john(), Nat::from(1), None),
+            Err(TxError::MintLimitExceeded {
+                resets_at: now + 60_000_000_000
+            })
+        );
+
+        // The owner shares the budget unless exempted.
+        context.update_caller(alice());
+        assert!(matches!(
+            canister.batchMint(vec![(alice(), Nat::from(1))], None),
+            Err(TxError::MintLimitExceeded { .. })
+        ));
+        canister.setMintLimitOwnerExempt(true).unwrap();
+        assert!(canister.mint(alice(), Nat::from(1000), None).is_ok());
+
+        context.update_caller(bob());
+        assert!(matches!(
+            canister.mint(bob(), Nat::from(1), None),
+            Err(TxError::MintLimitExceeded { .. })
+        ));
+        assert_eq!(canister.totalSupply(), Nat::from(2300));
+    }
+
+    #[test]
+    fn mint_limit_window_rollover() {
+        let canister = test_canister();
+        canister.setMintLimit(Nat::from(300), 60).unwrap();
+
+        canister.mint(bob(), Nat::from(200), None).unwrap();
+        shift_mint_limit_time(&canister, 30_000_000_000);
+        canister.mint(bob(), Nat::from(100), None).unwrap();
+
+        // Minting 150 requires the first mint to leave the window.
+        let first_mint_expires = ic::time() + 30_000_000_000;
+        assert_eq!(
+            canister.mint(bob(), Nat::from(150), None),
+            Err(TxError::MintLimitExceeded {
+                resets_at: first_mint_expires
+            })
+        );
+
+        shift_mint_limit_time(&canister, 30_000_000_000);
+        assert!(canister.mint(bob(), Nat::from(150), None).is_ok());
+        assert!(matches!(
+            canister.mint(bob(), Nat::from(100), None),
+            Err(TxError::MintLimitExceeded { .. })
+        ));
+
+        shift_mint_limit_time(&canister, 60_000_000_000);
+        assert!(canister.mint(bob(), Nat::from(300), None).is_ok());
+        assert_eq!(canister.balanceOf(bob()), Nat::from(750));
+    }
+
+    #[test]
+    fn mint_limit_removed() {
+        let canister = test_canister();
+        canister.setMintLimit(Nat::from(100), 60).unwrap();
+        assert!(canister.mint(bob(), Nat::from(101), None).is_err());
+
+        canister.setMintLimit(Nat::from(100), 0).unwrap();
+        assert!(canister.mint(bob(), Nat::from(101), None).is_ok());
+    }
+
+    #[test]
+    fn set_mint_limit_not_authorized() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+
+        assert_eq!(
+            canister.setMintLimit(Nat::from(100), 60),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(
+            canister.setMintLimitOwnerExempt(true),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn renounce_minting() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.addMinter(bob()).unwrap();
+        canister
+            .setEmission(john(), Nat::from(100), 1, true)
+            .unwrap();
+        assert_eq!(canister.renounceMinting(), Ok(()));
+        assert!(canister.getTokenInfo().mintingDisabled);
+
+        assert_eq!(
+            canister.mint(alice(), Nat::from(100), None),
+            Err(TxError::MintingDisabled)
+        );
+        assert_eq!(
+            canister.batchMint(vec![(alice(), Nat::from(100))], None),
+            Err(TxError::MintingDisabled)
+        );
+        assert_eq!(
+            canister.mintVested(alice(), Nat::from(100), 0, 10),
+            Err(TxError::MintingDisabled)
+        );
+        assert_eq!(canister.getVestingInfo(alice()).schedules, vec![]);
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.mint(bob(), Nat::from(100), None),
+            Err(TxError::MintingDisabled)
+        );
+
+        {
+            let mut state = canister.state.borrow_mut();
+            let emission = state.emission.as_mut().unwrap();
+            emission.last_run = ic::time() - emission.period;
+            assert_eq!(
+                crate::canister::is20_emission::emission_tick(&mut state),
+                None
+            );
+        }
+
+        assert_eq!(canister.totalSupply(), Nat::from(1000));
+        assert_eq!(canister.balanceOf(john()), Nat::from(0));
+    }
+
+    #[test]
+    fn renounce_minting_cannot_be_undone() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.renounceMinting().unwrap();
+
+        // None of the owner settings re-enable minting.
+        canister.toggleTest();
+        canister.setMintLimit(Nat::from(0), 0).unwrap();
+        canister.setMintLimitOwnerExempt(true).unwrap();
+        canister.setFaucetLimit(Nat::from(1000)).unwrap();
+        canister.addMinter(bob()).unwrap();
+        canister.setOwner(bob());
+
+        // The test token faucet is disabled as well.
+        context.update_caller(john());
+        assert_eq!(
+            canister.faucet(Nat::from(100)),
+            Err(TxError::MintingDisabled)
+        );
+
+        context.update_caller(bob());
+        assert_eq!(canister.renounceMinting(), Ok(()));
+        assert_eq!(
+            canister.mint(bob(), Nat::from(100), None),
+            Err(TxError::MintingDisabled)
+        );
+
+        canister.__pre_upgrade_inst();
+        canister.__post_upgrade_inst();
+        assert!(canister.getTokenInfo().mintingDisabled);
+        assert_eq!(
+            canister.mint(bob(), Nat::from(100), None),
+            Err(TxError::MintingDisabled)
+        );
+        assert_eq!(canister.totalSupply(), Nat::from(1000));
+    }
+
+    #[test]
+    fn renounce_minting_not_authorized() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+
+        assert_eq!(
+            canister.renounceMinting(),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert!(!canister.getTokenInfo().mintingDisabled);
+    }
+
+    /// Moves the start of the `holder` faucet window back by `nanos` nanoseconds.
+    fn shift_faucet_time(canister: &TokenCanister, holder: Principal, nanos: u64) {
+        if let Some((start, _)) = canister.state.borrow_mut().faucet.received.get_mut(&holder) {
+            *start -= nanos;
+        }
+    }
+
+    #[test]
+    fn faucet() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.toggleTest();
+        canister.setFaucetLimit(Nat::from(100)).unwrap();
+
+        context.update_caller(bob());
+        let id = canister.faucet(Nat::from(60)).unwrap();
+        assert_eq!(canister.balanceOf(bob()), Nat::from(60));
+        let tx = canister.getTransaction(id);
+        assert_eq!(tx.operation, Operation::Mint);
+        assert_eq!(tx.to, bob());
+
+        assert!(canister.faucet(Nat::from(40)).is_ok());
+        let retry_after = ic::time() + FAUCET_WINDOW;
+        assert_eq!(
+            canister.faucet(Nat::from(1)),
+            Err(TxError::FaucetLimitReached { retry_after })
+        );
+
+        // The limit is per principal.
+        context.update_caller(john());
+        assert!(canister.faucet(Nat::from(100)).is_ok());
+        assert_eq!(canister.balanceOf(john()), Nat::from(100));
+        assert_eq!(canister.totalSupply(), Nat::from(1200));
+    }
+
+    #[test]
+    fn faucet_window_rollover() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.toggleTest();
+        canister.setFaucetLimit(Nat::from(100)).unwrap();
+
+        context.update_caller(bob());
+        canister.faucet(Nat::from(100)).unwrap();
+        shift_faucet_time(&canister, bob(), FAUCET_WINDOW - 1);
+        assert!(matches!(
+            canister.faucet(Nat::from(1)),
+            Err(TxError::FaucetLimitReached { .. })
+        ));
+
+        shift_faucet_time(&canister, bob(), 1);
+        assert!(canister.faucet(Nat::from(100)).is_ok());
+        assert_eq!(canister.balanceOf(bob()), Nat::from(200));
+    }
+
+    #[test]
+    fn faucet_prunes_old_entries() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.toggleTest();
+        canister.setFaucetLimit(Nat::from(100)).unwrap();
+
+        context.update_caller(bob());
+        canister.faucet(Nat::from(100)).unwrap();
+        shift_faucet_time(&canister, bob(), FAUCET_WINDOW);
+
+        context.update_caller(john());
+        canister.faucet(Nat::from(100)).unwrap();
+        let received = &canister.state.borrow().faucet.received;
+        assert!(!received.contains_key(&bob()));
+        assert!(received.contains_key(&john()));
+    }
+
+    #[test]
+    fn faucet_not_test_token() {
+        let canister = test_canister();
+        canister.setFaucetLimit(Nat::from(100)).unwrap();
+
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.faucet(Nat::from(100)),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+    }
+
+    #[test]
+    fn test_token_mint_requires_minter() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        canister.toggleTest();
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.mint(bob(), Nat::from(100), None),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+
+        // The owner can still mint any amount.
+        context.update_caller(alice());
+        assert!(canister.mint(bob(), Nat::from(1000), None).is_ok());
+        assert_eq!(canister.balanceOf(bob()), Nat::from(1000));
+    }
+
+    #[test]
+    fn set_faucet_limit_not_authorized() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+
+        assert_eq!(
+            canister.setFaucetLimit(Nat::from(100)),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+    }
 }
@@ -0,0 +1,263 @@
+//! This module contains APIs for minting tokens with a vesting schedule.
+//!
+//! Vested tokens are counted in the holder balance, but they cannot be transferred until they are
+//! unlocked. The unlocked amount is computed from the IC time on every check, so no periodic
+//! processing is needed.
+
+use crate::canister::dip20_transactions::_mint;
+use crate::canister::TokenCanister;
+use crate::state::VestingSchedule;
+use crate::types::{TxError, TxReceipt};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_kit::ic;
+
+/// Vesting state of a holder returned by `getVestingInfo`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct VestingInfo {
+    /// Amount of tokens that cannot be transferred yet.
+    pub locked: Nat,
+
+    /// Amount of tokens that can be transferred. This is the holder balance minus the locked
+    /// amount.
+    pub unlocked: Nat,
+
+    /// Vesting schedules of the holder that are not fully unlocked yet.
+    pub schedules: Vec<VestingSchedule>,
+}
+
+pub(crate) fn mint_vested(
+    canister: &TokenCanister,
+    to: Principal,
+    amount: Nat,
+    cliff_sec: u64,
+    duration_sec: u64,
+) -> TxReceipt {
+    if cliff_sec > duration_sec {
+        return Err(TxError::InvalidVestingSchedule);
+    }
+
+    let caller = ic::caller();
+    let mut state = canister.state.borrow_mut();
+    let id = _mint(&mut state, caller, to, amount.clone(), None)?;
+
+    let now = ic::time();
+    let schedules = state.vesting.entry(to).or_default();
+    // Fully unlocked schedules don't affect the balance anymore.
+    schedules.retain(|schedule| schedule.locked(now) > 0u32);
+    schedules.push(VestingSchedule {
+        amount,
+        start: now,
+        // IC timestamp is in nanoseconds.
+        cliff: cliff_sec.saturating_mul(1_000_000_000),
+        duration: duration_sec.saturating_mul(1_000_000_000),
+    });
+
+    Ok(id)
+}
+
+pub(crate) fn vesting_info(canister: &TokenCanister, holder: Principal) -> VestingInfo {
+    let state = canister.state.borrow();
+    let now = ic::time();
+    let schedules = match state.vesting.get(&holder) {
+        Some(schedules) => schedules
+            .iter()
+            .filter(|schedule| schedule.locked(now) > 0u32)
+            .cloned()
+            .collect(),
+        None => vec![],
+    };
+
+    VestingInfo {
+        locked: state.locked_balance(&holder),
+        unlocked: state.unlocked_balance(&holder),
+        schedules,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::types::Metadata;
+    use ic_canister::Canister;
+    use ic_kit::mock_principals::{alice, bob, john};
+    use ic_kit::MockContext;
+
+    const DAY_SEC: u64 = 24 * 60 * 60;
+    const DAY: u64 = DAY_SEC * 1_000_000_000;
+
+    fn test_canister() -> TokenCanister {
+        MockContext::new().with_caller(alice()).inject();
+
+        let canister = TokenCanister::init_instance();
+        canister.init(Metadata {
+            logo: "".to_string(),
+            name: "".to_string(),
+            symbol: "".to_string(),
+            decimals: 8,
+            totalSupply: Nat::from(1000),
+            owner: alice(),
+            fee: Nat::from(0),
+            feeTo: alice(),
+            isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
+        });
+
+        canister
+    }
+
+    /// Moves the start of all the `holder` vesting schedules back by `time` nanoseconds.
+    fn pass_time(canister: &TokenCanister, holder: Principal, time: u64) {
+        for schedule in canister
+            .state
+            .borrow_mut()
+            .vesting
+            .get_mut(&holder)
+            .unwrap()
+        {
+            schedule.start -= time;
+        }
+    }
+
+    /// Mints 1000 tokens to bob with a vesting of 10 days and a cliff of 2 days.
+    fn mint_to_bob(canister: &TokenCanister) {
+        canister
+            .mintVested(bob(), Nat::from(1000), 2 * DAY_SEC, 10 * DAY_SEC)
+            .unwrap();
+    }
+
+    #[test]
+    fn vested_before_cliff() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        mint_to_bob(&canister);
+        pass_time(&canister, bob(), DAY);
+
+        assert_eq!(canister.balanceOf(bob()), Nat::from(1000));
+        assert_eq!(canister.totalSupply(), Nat::from(2000));
+        let info = canister.getVestingInfo(bob());
+        assert_eq!(info.locked, Nat::from(1000));
+        assert_eq!(info.unlocked, Nat::from(0));
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.transfer(john(), Nat::from(1), None),
+            Err(TxError::InsufficientUnlockedBalance)
+        );
+        assert_eq!(canister.balanceOf(john()), Nat::from(0));
+    }
+
+    #[test]
+    fn vested_mid_vesting() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        mint_to_bob(&canister);
+        pass_time(&canister, bob(), 4 * DAY);
+
+        let info = canister.getVestingInfo(bob());
+        assert_eq!(info.locked, Nat::from(600));
+        assert_eq!(info.unlocked, Nat::from(400));
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.transfer(john(), Nat::from(401), None),
+            Err(TxError::InsufficientUnlockedBalance)
+        );
+        assert!(canister.transfer(john(), Nat::from(400), None).is_ok());
+        assert_eq!(canister.balanceOf(bob()), Nat::from(600));
+        assert_eq!(canister.getVestingInfo(bob()).unlocked, Nat::from(0));
+
+        // The locked tokens cannot be spent with an allowance either.
+        canister
+            .approve(john(), Nat::from(100), None, None)
+            .unwrap();
+        context.update_caller(john());
+        assert_eq!(
+            canister.transferFrom(bob(), john(), Nat::from(100)),
+            Err(TxError::InsufficientUnlockedBalance)
+        );
+    }
+
+    #[test]
+    fn vested_tokens_not_burned() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        mint_to_bob(&canister);
+        pass_time(&canister, bob(), 4 * DAY);
+
+        context.update_caller(bob());
+        assert_eq!(
+            canister.burn(Nat::from(401)),
+            Err(TxError::InsufficientUnlockedBalance)
+        );
+        assert!(canister.burn(Nat::from(100)).is_ok());
+
+        // The locked tokens cannot be burned with an allowance either.
+        canister
+            .approve(john(), Nat::from(500), None, None)
+            .unwrap();
+        context.update_caller(john());
+        assert_eq!(
+            canister.burnFrom(bob(), Nat::from(301)),
+            Err(TxError::InsufficientUnlockedBalance)
+        );
+        assert_eq!(canister.allowance(bob(), john()), Nat::from(500));
+        assert!(canister.burnFrom(bob(), Nat::from(300)).is_ok());
+
+        assert_eq!(canister.balanceOf(bob()), Nat::from(600));
+        assert_eq!(canister.getVestingInfo(bob()).locked, Nat::from(600));
+        assert_eq!(canister.totalSupply(), Nat::from(1600));
+    }
+
+    #[test]
+    fn vested_fully() {
+        let canister = test_canister();
+        let context = MockContext::new().with_caller(alice()).inject();
+        mint_to_bob(&canister);
+        pass_time(&canister, bob(), 10 * DAY);
+
+        let info = canister.getVestingInfo(bob());
+        assert_eq!(info.locked, Nat::from(0));
+        assert_eq!(info.unlocked, Nat::from(1000));
+        assert!(info.schedules.is_empty());
+
+        context.update_caller(bob());
+        assert!(canister.transfer(john(), Nat::from(1000), None).is_ok());
+        assert_eq!(canister.balanceOf(john()), Nat::from(1000));
+    }
+
+    #[test]
+    fn mint_vested_invalid_schedule() {
+        let canister = test_canister();
+        assert_eq!(
+            canister.mintVested(bob(), Nat::from(1000), 10 * DAY_SEC, DAY_SEC),
+            Err(TxError::InvalidVestingSchedule)
+        );
+        assert_eq!(canister.balanceOf(bob()), Nat::from(0));
+    }
+
+    #[test]
+    fn mint_vested_not_authorized() {
+        let canister = test_canister();
+        MockContext::new().with_caller(bob()).inject();
+        assert_eq!(
+            canister.mintVested(bob(), Nat::from(1000), 0, DAY_SEC),
+            Err(TxError::Unauthorized {
+                owner: alice().to_string(),
+                caller: bob().to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn vesting_survives_upgrade() {
+        let canister = test_canister();
+        mint_to_bob(&canister);
+        let info = canister.getVestingInfo(bob());
+
+        canister.__pre_upgrade_inst();
+        canister.state.borrow_mut().vesting.clear();
+        canister.__post_upgrade_inst();
+        assert_eq!(canister.getVestingInfo(bob()), info);
+    }
+}
@@ -0,0 +1,152 @@
+//! This module contains APIs for the governance vote weights of the token holders.
+//!
+//! Every holder votes with its balance for itself until it delegates the votes to another
+//! principal. The delegations are recorded in the ledger, and the vote weights are replayed from
+//! the ledger records, so the past weights can be checked against the transaction history.
+
+use crate::canister::TokenCanister;
+use crate::types::{TxError, TxReceipt};
+use candid::Principal;
+use ic_kit::ic;
+
+pub(crate) fn delegate(canister: &TokenCanister, to: Principal) -> TxReceipt {
+    let caller = ic::caller();
+    if caller == Principal::anonymous() || to == Principal::anonymous() {
+        return Err(TxError::AnonymousPrincipal);
+    }
+
+    let mut state = canister.state.borrow_mut();
+    Ok(state.ledger.delegate(caller, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Operation;
+    use candid::Nat;
+    use common::types::Metadata;
+    use ic_canister::Canister;
+    use ic_kit::mock_principals::{alice, bob, john, xtc};
+    use ic_kit::MockContext;
+    use num_traits::ToPrimitive;
+
+    fn test_canister() -> TokenCanister {
+        MockContext::new().with_caller(alice()).inject();
+
+        let canister = TokenCanister::init_instance();
+        canister.init(Metadata {
+            logo: "".to_string(),
+            name: "".to_string(),
+            symbol: "".to_string(),
+            decimals: 8,
+            totalSupply: Nat::from(1000),
+            owner: alice(),
+            fee: Nat::from(0),
+            feeTo: alice(),
+            isTestToken: None,
+            minTransferAmount: None,
+            maxSupply: None,
+        });
+
+        canister
+    }
+
+    /// Vote weights of alice, bob, john and xtc.
+    fn votes(canister: &TokenCanister) -> Vec<u32> {
+        [alice(), bob(), john(), xtc()]
+            .iter()
+            .map(|who| canister.getVotes(*who).0.to_u32().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn self_delegation_by_default() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+
+        assert_eq!(canister.getDelegate(alice()), alice());
+        assert_eq!(canister.getDelegate(bob()), bob());
+        assert_eq!(canister.getVotes(alice()), Nat::from(900));
+        assert_eq!(canister.getVotes(bob()), Nat::from(100));
+    }
+
+    #[test]
+    fn transfers_move_votes_between_delegates() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+        let id = canister.delegate(john()).unwrap();
+        assert_eq!(canister.getTransaction(id).operation, Operation::Delegate);
+        assert_eq!(votes(&canister), vec![0, 100, 900, 0]);
+
+        MockContext::new().with_caller(bob()).inject();
+        canister.delegate(xtc()).unwrap();
+        canister.transfer(alice(), Nat::from(30), None).unwrap();
+        assert_eq!(votes(&canister), vec![0, 0, 930, 70]);
+        assert_eq!(canister.balanceOf(alice()), Nat::from(930));
+    }
+
+    #[test]
+    fn chained_redelegations() {
+        let canister = test_canister();
+        canister.transfer(bob(), Nat::from(100), None).unwrap();
+
+        canister.delegate(bob()).unwrap();
+        assert_eq!(votes(&canister), vec![0, 1000, 0, 0]);
+
+        // Delegated votes are not delegated further: bob's delegate only gets bob's balance.
+        MockContext::new().with_caller(bob()).inject();
+        canister.delegate(john()).unwrap();
+        assert_eq!(votes(&canister), vec![0, 900, 100, 0]);
+
+        MockContext::new().with_caller(alice()).inject();
+        canister.delegate(xtc()).unwrap();
+        assert_eq!(votes(&canister), vec![0, 0, 100, 900]);
+
+        // Delegating to the current delegate changes nothing.
+        canister.delegate(xtc()).unwrap();
+        assert_eq!(votes(&canister), vec![0, 0, 100, 900]);
+
+        canister.delegate(alice()).unwrap();
+        assert_eq!(canister.getDelegate(alice()), alice());
+        assert_eq!(votes(&canister), vec![900, 0, 100, 0]);
+    }
+
+    #[test]
+    fn past_votes() {
+        let canister = test_canister();
+        let transfer = canister.transfer(bob(), Nat::from(100), None).unwrap();
+        let delegation = canister.delegate(john()).unwrap();
+
+        MockContext::new().with_caller(bob()).inject();
+        let second_transfer = canister.transfer(alice(), Nat::from(50), None).unwrap();
+        let redelegation = {
+            MockContext::new().with_caller(alice()).inject();
+            canister.delegate(bob()).unwrap()
+        };
+
+        let past = |who, id: &Nat| canister.getPastVotes(who, id.clone()).unwrap();
+        assert_eq!(past(alice(), &Nat::from(0)), Nat::from(1000));
+        assert_eq!(past(alice(), &transfer), Nat::from(900));
+        assert_eq!(past(alice(), &delegation), Nat::from(0));
+        assert_eq!(past(john(), &transfer), Nat::from(0));
+        assert_eq!(past(john(), &delegation), Nat::from(900));
+        assert_eq!(past(john(), &second_transfer), Nat::from(950));
+        assert_eq!(past(bob(), &second_transfer), Nat::from(50));
+        assert_eq!(past(john(), &redelegation), Nat::from(0));
+        assert_eq!(past(bob(), &redelegation), Nat::from(1000));
+
+        assert_eq!(
+            canister.getPastVotes(bob(), canister.historySize()),
+            Err(TxError::TransactionDoesNotExist)
+        );
+    }
+
+    #[test]
+    fn anonymous_delegation() {
+        let canister = test_canister();
+        assert_eq!(
+            canister.delegate(Principal::anonymous()),
+            Err(TxError::AnonymousPrincipal)
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Certification of the ledger tip.
+//!
+//! The certified data of the canister is the root hash of the tree
+//!
+//! ```text
+//! fork
+//! ├── "last_hash" -> leaf(hash of the last transaction)
+//! └── "last_id"   -> leaf(big-endian bytes of the id of the last transaction)
+//! ```
+//!
+//! The tree is hashed and encoded as described in the IC interface specification, so the clients
+//! can reconstruct the root hash from the witness and check it against the certificate.
+
+use crate::ledger::Ledger;
+use candid::Nat;
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+
+pub const LAST_HASH_LABEL: &[u8] = b"last_hash";
+pub const LAST_ID_LABEL: &[u8] = b"last_id";
+
+/// Part of the IC hash tree used for the certification.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashTree {
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+}
+
+impl HashTree {
+    /// Returns the root hash of the tree.
+    pub fn reconstruct(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        match self {
+            HashTree::Fork(left, right) => {
+                hasher.update(domain_sep("ic-hashtree-fork"));
+                hasher.update(left.reconstruct());
+                hasher.update(right.reconstruct());
+            }
+            HashTree::Labeled(label, tree) => {
+                hasher.update(domain_sep("ic-hashtree-labeled"));
+                hasher.update(label);
+                hasher.update(tree.reconstruct());
+            }
+            HashTree::Leaf(value) => {
+                hasher.update(domain_sep("ic-hashtree-leaf"));
+                hasher.update(value);
+            }
+        }
+        hasher.finalize().to_vec()
+    }
+
+    /// Returns the CBOR encoding of the tree.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        serde_cbor::to_vec(&self.to_value()).expect("hash tree is always serializable")
+    }
+
+    fn to_value(&self) -> Value {
+        match self {
+            HashTree::Fork(left, right) => {
+                Value::Array(vec![Value::Integer(1), left.to_value(), right.to_value()])
+            }
+            HashTree::Labeled(label, tree) => Value::Array(vec![
+                Value::Integer(2),
+                Value::Bytes(label.clone()),
+                tree.to_value(),
+            ]),
+            HashTree::Leaf(value) => {
+                Value::Array(vec![Value::Integer(3), Value::Bytes(value.clone())])
+            }
+        }
+    }
+}
+
+/// Returns the tree certifying the given ledger tip.
+pub fn tip_tree(last_id: &Nat, last_hash: &[u8]) -> HashTree {
+    HashTree::Fork(
+        Box::new(HashTree::Labeled(
+            LAST_HASH_LABEL.to_vec(),
+            Box::new(HashTree::Leaf(last_hash.to_vec())),
+        )),
+        Box::new(HashTree::Labeled(
+            LAST_ID_LABEL.to_vec(),
+            Box::new(HashTree::Leaf(last_id.0.to_bytes_be())),
+        )),
+    )
+}
+
+/// Sets the certified data of the canister to the root hash of the tip tree of the `ledger`.
+pub fn certify_tip(ledger: &Ledger) {
+    let tree = tip_tree(&Nat::from(ledger.last_id()), &ledger.tip_hash());
+    ic_kit::ic::set_certified_data(&tree.reconstruct());
+}
+
+fn domain_sep(s: &str) -> Vec<u8> {
+    let mut bytes = vec![s.len() as u8];
+    bytes.extend_from_slice(s.as_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes the CBOR-encoded witness and returns its root hash and the values of its leaves by
+    /// their labels, without using `HashTree`.
+    fn decode_witness(value: &Value, leaves: &mut Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+        let items = match value {
+            Value::Array(items) => items,
+            _ => panic!("tree node is not an array"),
+        };
+
+        let mut hasher = Sha256::new();
+        match (&items[0], &items[1..]) {
+            (Value::Integer(1), [left, right]) => {
+                hasher.update(b"\x10ic-hashtree-fork");
+                hasher.update(decode_witness(left, leaves));
+                hasher.update(decode_witness(right, leaves));
+            }
+            (Value::Integer(2), [Value::Bytes(label), tree]) => {
+                hasher.update(b"\x13ic-hashtree-labeled");
+                hasher.update(label);
+                hasher.update(decode_witness(tree, leaves));
+                if let Value::Array(leaf) = tree {
+                    if let [Value::Integer(3), Value::Bytes(value)] = &leaf[..] {
+                        leaves.push((label.clone(), value.clone()));
+                    }
+                }
+            }
+            (Value::Integer(3), [Value::Bytes(value)]) => {
+                hasher.update(b"\x10ic-hashtree-leaf");
+                hasher.update(value);
+            }
+            _ => panic!("unexpected tree node"),
+        }
+        hasher.finalize().to_vec()
+    }
+
+    #[test]
+    fn witness_reconstruction() {
+        let last_hash = vec![7; 32];
+        let tree = tip_tree(&Nat::from(258), &last_hash);
+        let witness: Value = serde_cbor::from_slice(&tree.to_cbor()).unwrap();
+
+        let mut leaves = vec![];
+        let root = decode_witness(&witness, &mut leaves);
+        assert_eq!(root, tree.reconstruct());
+        assert_eq!(
+            leaves,
+            vec![
+                (LAST_HASH_LABEL.to_vec(), last_hash),
+                (LAST_ID_LABEL.to_vec(), vec![1, 2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn tree_depends_on_tip() {
+        let root = tip_tree(&Nat::from(1), &[0; 32]).reconstruct();
+        assert_ne!(root, tip_tree(&Nat::from(2), &[0; 32]).reconstruct());
+        assert_ne!(root, tip_tree(&Nat::from(1), &[1; 32]).reconstruct());
+    }
+}
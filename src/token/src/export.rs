@@ -0,0 +1,290 @@
+//! Bulk export of the transaction history for off-chain indexers.
+//!
+//! An export chunk is a byte blob starting with the schema version byte (`EXPORT_SCHEMA_VERSION`),
+//! followed by a sequence of CBOR items (RFC 8742), one per transaction record in ascending order
+//! of the ids. Every record is encoded as a CBOR array:
+//!
+//! ```text
+//! [
+//!   index        : uint,
+//!   timestamp    : int,          // nanoseconds
+//!   operation    : text,         // name of the `Operation` variant
+//!   status       : text,         // "Succeeded" or "Failed"
+//!   error        : text / null,  // debug representation of the `TxError` of a failed record
+//!   caller       : bytes / null,
+//!   from         : bytes,
+//!   to           : bytes,
+//!   amount       : bytes,        // big-endian unsigned
+//!   fee          : bytes,        // big-endian unsigned
+//!   reason       : text / null,
+//!   memo         : bytes / null,
+//!   parent_hash  : bytes / null,
+//!   config       : [setting : text, old_value : text, new_value : text] / null,
+//!   fee_breakdown: [fee_to : bytes, auction : bytes, burned : bytes] / null,
+//! ]
+//! ```
+//!
+//! New fields are only appended to the end of the array. Any other change of the layout
+//! increments the schema version.
+
+use crate::ledger::{to_id, Ledger};
+use crate::types::{ExportChunk, TransactionStatus, TxRecord};
+use candid::Nat;
+use num_traits::ToPrimitive;
+use serde_cbor::Value;
+
+/// Version of the schema of the export chunks.
+pub const EXPORT_SCHEMA_VERSION: u8 = 1;
+
+/// Returns the records starting from the `start` id encoded as an export chunk of at most
+/// `max_bytes` bytes. The chunk always contains at least one record if there are records left, so
+/// the export makes progress even with a too small `max_bytes`.
+///
+/// If the `start` record was removed from the history, the chunk starts from the oldest record
+/// kept in the history.
+pub fn export_chunk(ledger: &Ledger, start: u64, max_bytes: usize) -> ExportChunk {
+    let mut data = vec![EXPORT_SCHEMA_VERSION];
+    let mut next = start.max(ledger.first_id());
+    while let Some(tx) = ledger.get(next) {
+        let encoded = encode_record(tx);
+        if data.len() > 1 && data.len() + encoded.len() > max_bytes {
+            break;
+        }
+
+        data.extend_from_slice(&encoded);
+        next += 1;
+    }
+
+    ExportChunk {
+        data,
+        next: if next < ledger.len() {
+            Some(Nat::from(next))
+        } else {
+            None
+        },
+    }
+}
+
+/// Returns the CBOR encoding of the record as described in the module documentation.
+pub fn encode_record(tx: &TxRecord) -> Vec<u8> {
+    let (status, error) = match &tx.status {
+        TransactionStatus::Succeeded => ("Succeeded", Value::Null),
+        TransactionStatus::Failed { error } => ("Failed", Value::Text(format!("{:?}", error))),
+    };
+
+    let value = Value::Array(vec![
+        Value::Integer(to_id(&tx.index).into()),
+        Value::Integer(tx.timestamp.0.to_i128().unwrap_or_default()),
+        Value::Text(format!("{:?}", tx.operation)),
+        Value::Text(status.to_string()),
+        error,
+        tx.caller.map_or(Value::Null, |caller| {
+            Value::Bytes(caller.as_slice().to_vec())
+        }),
+        Value::Bytes(tx.from.as_slice().to_vec()),
+        Value::Bytes(tx.to.as_slice().to_vec()),
+        nat_bytes(&tx.amount),
+        nat_bytes(&tx.fee),
+        tx.reason.clone().map_or(Value::Null, Value::Text),
+        tx.memo.clone().map_or(Value::Null, Value::Bytes),
+        tx.parent_hash.clone().map_or(Value::Null, Value::Bytes),
+        tx.config.clone().map_or(Value::Null, |config| {
+            Value::Array(vec![
+                Value::Text(config.setting),
+                Value::Text(config.old_value),
+                Value::Text(config.new_value),
+            ])
+        }),
+        tx.fee_breakdown.as_ref().map_or(Value::Null, |breakdown| {
+            Value::Array(vec![
+                nat_bytes(&breakdown.fee_to),
+                nat_bytes(&breakdown.auction),
+                nat_bytes(&breakdown.burned),
+            ])
+        }),
+    ]);
+
+    serde_cbor::to_vec(&value).expect("transaction record is always serializable")
+}
+
+fn nat_bytes(value: &Nat) -> Value {
+    Value::Bytes(value.0.to_bytes_be())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Operation, TxError};
+    use candid::Principal;
+    use ic_kit::mock_principals::{alice, bob, john};
+    use ic_kit::MockContext;
+
+    /// Record decoded from an export chunk, with the fields compared by the tests.
+    #[derive(Debug, PartialEq)]
+    struct Decoded {
+        index: u64,
+        operation: String,
+        status: String,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+        fee: Nat,
+        memo: Option<Vec<u8>>,
+        parent_hash: Option<Vec<u8>>,
+    }
+
+    fn decode_chunk(data: &[u8]) -> Vec<Decoded> {
+        assert_eq!(data[0], EXPORT_SCHEMA_VERSION);
+        serde_cbor::Deserializer::from_slice(&data[1..])
+            .into_iter::<Value>()
+            .map(|item| decode_record(item.unwrap()))
+            .collect()
+    }
+
+    fn decode_record(value: Value) -> Decoded {
+        let fields = match value {
+            Value::Array(fields) => fields,
+            _ => panic!("record is not an array"),
+        };
+        assert_eq!(fields.len(), 15);
+
+        let int = |value: &Value| match value {
+            Value::Integer(value) => *value as u64,
+            _ => panic!("not an integer"),
+        };
+        let text = |value: &Value| match value {
+            Value::Text(value) => value.clone(),
+            _ => panic!("not a text"),
+        };
+        let bytes = |value: &Value| match value {
+            Value::Bytes(value) => Some(value.clone()),
+            Value::Null => None,
+            _ => panic!("not bytes"),
+        };
+        let principal = |value: &Value| Principal::from_slice(&bytes(value).unwrap());
+        let nat = |value: &Value| {
+            bytes(value)
+                .unwrap()
+                .iter()
+                .fold(Nat::from(0), |acc, byte| {
+                    acc * Nat::from(256) + Nat::from(*byte)
+                })
+        };
+
+        Decoded {
+            index: int(&fields[0]),
+            operation: text(&fields[2]),
+            status: text(&fields[3]),
+            from: principal(&fields[6]),
+            to: principal(&fields[7]),
+            amount: nat(&fields[8]),
+            fee: nat(&fields[9]),
+            memo: bytes(&fields[11]),
+            parent_hash: bytes(&fields[12]),
+        }
+    }
+
+    fn expected(tx: &TxRecord) -> Decoded {
+        Decoded {
+            index: to_id(&tx.index),
+            operation: format!("{:?}", tx.operation),
+            status: if tx.status.is_failed() {
+                "Failed".to_string()
+            } else {
+                "Succeeded".to_string()
+            },
+            from: tx.from,
+            to: tx.to,
+            amount: tx.amount.clone(),
+            fee: tx.fee.clone(),
+            memo: tx.memo.clone(),
+            parent_hash: tx.parent_hash.clone(),
+        }
+    }
+
+    fn large_ledger(count: u64) -> Ledger {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        ledger.mint(
+            alice(),
+            alice(),
+            Nat::from(u64::MAX) * Nat::from(1000),
+            None,
+        );
+        for i in 1..count {
+            match i % 4 {
+                0 => ledger.transfer(alice(), bob(), Nat::from(i), Nat::from(1)),
+                1 => ledger.transfer_with_memo(
+                    alice(),
+                    john(),
+                    Nat::from(i),
+                    Nat::from(0),
+                    Some(i.to_be_bytes().to_vec()),
+                ),
+                2 => ledger.burn(bob(), Nat::from(i)),
+                _ => ledger.failed(
+                    Operation::Transfer,
+                    john(),
+                    john(),
+                    bob(),
+                    Nat::from(i),
+                    TxError::InsufficientBalance,
+                ),
+            };
+        }
+
+        ledger
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let ledger = large_ledger(5000);
+
+        let mut decoded = vec![];
+        let mut start = 0;
+        let mut chunks = 0;
+        loop {
+            let chunk = export_chunk(&ledger, start, 10_000);
+            assert!(chunk.data.len() <= 10_000);
+            decoded.extend(decode_chunk(&chunk.data));
+            chunks += 1;
+            match chunk.next {
+                Some(next) => start = to_id(&next),
+                None => break,
+            }
+        }
+
+        assert!(chunks > 1);
+        let records: Vec<_> = ledger.iter().map(expected).collect();
+        assert_eq!(decoded.len(), 5000);
+        assert_eq!(decoded, records);
+    }
+
+    #[test]
+    fn export_progresses_with_small_limit() {
+        let ledger = large_ledger(3);
+
+        let chunk = export_chunk(&ledger, 0, 1);
+        assert_eq!(decode_chunk(&chunk.data).len(), 1);
+        assert_eq!(chunk.next, Some(Nat::from(1)));
+
+        let chunk = export_chunk(&ledger, 2, 1);
+        assert_eq!(decode_chunk(&chunk.data)[0].index, 2);
+        assert_eq!(chunk.next, None);
+    }
+
+    #[test]
+    fn export_out_of_history() {
+        let mut ledger = large_ledger(10);
+
+        let chunk = export_chunk(&ledger, 10, 1000);
+        assert_eq!(chunk.data, vec![EXPORT_SCHEMA_VERSION]);
+        assert_eq!(chunk.next, None);
+
+        ledger.remove_before(4);
+        let chunk = export_chunk(&ledger, 0, 1_000_000);
+        let decoded = decode_chunk(&chunk.data);
+        assert_eq!(decoded.first().unwrap().index, 4);
+        assert_eq!(decoded.len(), 6);
+    }
+}
@@ -1,126 +1,2921 @@
-use crate::types::TxRecord;
-use candid::{CandidType, Deserialize, Nat, Principal};
+use crate::certified::certify_tip;
+use crate::ledger::balances::{fee_recipient, replay, BalanceIndex, DEFAULT_CHECKPOINT_SPACING};
+use crate::ledger::votes::VoteIndex;
+use crate::types::{
+    BlocksPage, FeeBreakdown, FeePayment, FeePaymentsPage, LedgerCheckResult, LedgerIssue,
+    LedgerStats, Operation, OperationCounts, Timestamp, TransactionOrder, TransactionStatus,
+    TransactionStatusFilter, TransactionsPage, TxError, TxFilter, TxRecord, UserStats,
+};
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
 use num_traits::ToPrimitive;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+
+mod balances;
+mod votes;
+
+/// Parent hash of the first record of the hash chain.
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
 
 const MAX_HISTORY_LENGTH: usize = 1_000_000;
 const HISTORY_REMOVAL_BATCH_SIZE: usize = 10_000;
 
+/// Maximum number of the records added to the user stats by one backfill call.
+pub const USER_STATS_BACKFILL_BATCH: usize = 10_000;
+
+/// Maximum number of the records added to the ledger indexes or the operation counts by one call
+/// of `backfill_indexes`, `backfill_user_index`, `backfill_caller_index` or
+/// `backfill_operation_counts`.
+pub const INDEX_BACKFILL_BATCH: usize = 10_000;
+
+/// Fee of a new record: the total amount, and its breakdown and the principal the `fee_to` share
+/// was credited to, if the fee was charged with `_charge_fee`.
+pub struct TxFee {
+    pub total: Nat,
+    pub breakdown: Option<FeeBreakdown>,
+    pub recipient: Option<Principal>,
+}
+
+impl From<Nat> for TxFee {
+    fn from(total: Nat) -> Self {
+        Self {
+            total,
+            breakdown: None,
+            recipient: None,
+        }
+    }
+}
+
+impl From<FeeBreakdown> for TxFee {
+    fn from(breakdown: FeeBreakdown) -> Self {
+        Self {
+            total: breakdown.total(),
+            breakdown: Some(breakdown),
+            recipient: None,
+        }
+    }
+}
+
 #[derive(Default, CandidType, Deserialize)]
 pub struct Ledger {
     history: Vec<TxRecord>,
+
+    /// Id of the first record in the history. The ledger works with `u64` ids internally, this
+    /// field is kept as `Nat` to keep the stable state compatible with the older versions.
     vec_offset: Nat,
+
+    /// Transactions related to every principal. The index is kept in the ledger, so every record
+    /// pushed into the history is indexed.
+    ///
+    /// `None` for the ledgers saved before the index was added. Such ledgers are indexed with
+    /// `backfill_user_index`.
+    #[serde(default, rename = "user_index_v2")]
+    user_index: Option<HashMap<Principal, UserTransactions>>,
+
+    /// User index saved by the versions of the canister that kept the ids as `Nat`. It is moved
+    /// into the `user_index` by `migrate_user_index` when the ledger is restored after upgrade.
+    #[serde(default, rename = "user_index")]
+    user_index_v1: Option<HashMap<Principal, UserTransactionsV1>>,
+
+    /// Id of the next record to be added to the `user_index` by `backfill_user_index`. `None` if
+    /// all the records are indexed, and the new records are indexed when they are pushed.
+    #[serde(default)]
+    user_index_next: Option<u64>,
+
+    /// Hash of the last record. `None` if no records were added since the ledger was
+    /// hash-chained.
+    #[serde(default)]
+    last_hash: Option<Vec<u8>>,
+
+    /// Aggregated transactions of every principal. `None` for the ledgers saved before the stats
+    /// were added. Such ledgers start maintaining the stats on the first new record, and the older
+    /// records are added with `backfill_user_stats`.
+    #[serde(default)]
+    user_stats: Option<UserStatsIndex>,
+
+    /// Ids of the transactions made by every caller, in ascending order. `None` for the ledgers
+    /// saved before the index was added. Such ledgers are indexed with `backfill_caller_index`.
+    #[serde(default)]
+    caller_index: Option<HashMap<Principal, Vec<u64>>>,
+
+    /// Id of the next record to be added to the `caller_index` by `backfill_caller_index`. `None`
+    /// if all the records are indexed, and the new records are indexed when they are pushed.
+    #[serde(default)]
+    caller_index_next: Option<u64>,
+
+    /// Fee shares credited to the `fee_to` principals.
+    #[serde(default)]
+    fee_payments: FeePayments,
+
+    /// Balances replayed from the records, with the checkpoints to look up the balances at older
+    /// transactions. `None` for the ledgers saved before the index was added. Such ledgers are
+    /// indexed with `backfill_indexes`.
+    #[serde(default)]
+    balance_index: Option<BalanceIndex>,
+
+    /// Vote weights of the delegates. `None` for the ledgers saved before the index was added.
+    /// Such ledgers are indexed with `backfill_indexes`.
+    #[serde(default)]
+    vote_index: Option<VoteIndex>,
+
+    /// Number of the successful records of every operation over the lifetime of the ledger, by
+    /// the operation names. `None` for the ledgers saved before the counters were added. Such
+    /// ledgers start counting on the first new record, and the records kept in the history are
+    /// counted with `backfill_operation_counts`.
+    #[serde(default)]
+    operation_counts: Option<HashMap<String, u64>>,
+
+    /// Id of the next record saved before the `operation_counts` were maintained to be counted by
+    /// `backfill_operation_counts`.
+    #[serde(default)]
+    operation_counts_next: u64,
+
+    /// Records starting from this id are counted when they are pushed, so the backfill of the
+    /// `operation_counts` stops here.
+    #[serde(default)]
+    operation_counts_end: u64,
+}
+
+/// Index of the fee shares credited to the `fee_to` principals. Fees charged before the index was
+/// added are not included.
+#[derive(Default, CandidType, Deserialize)]
+struct FeePayments {
+    /// Payments in ascending order of the transaction ids. The payments of the transactions
+    /// removed from the history are removed too.
+    payments: Vec<FeePayment>,
+
+    /// Sum of all the payments, including the removed ones.
+    total: Nat,
+}
+
+#[derive(Default, CandidType, Deserialize)]
+struct UserStatsIndex {
+    users: HashMap<Principal, UserStats>,
+
+    /// Id of the next record to be added by the backfill.
+    backfill_next: u64,
+
+    /// Records starting from this id are added to the stats when they are pushed, so the backfill
+    /// stops here.
+    backfill_end: u64,
+}
+
+impl UserStatsIndex {
+    /// Creates the stats for a ledger with the records [first_id, len) to be backfilled.
+    fn new(first_id: u64, len: u64) -> Self {
+        Self {
+            users: HashMap::new(),
+            backfill_next: first_id,
+            backfill_end: len,
+        }
+    }
+}
+
+/// Transactions related to a principal.
+#[derive(Default, CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserTransactions {
+    /// Ids of the transactions in the history, in ascending order.
+    pub ids: Vec<u64>,
+
+    /// Number of the transactions over the lifetime of the ledger. Unlike `ids`, it is not
+    /// decreased when old records are removed from the history. Failed transactions are not
+    /// counted.
+    pub count: u64,
+
+    /// Sum of the amounts of the successful transactions over the lifetime of the ledger.
+    pub amount: Nat,
+}
+
+impl UserTransactions {
+    fn add(&mut self, tx: &TxRecord) {
+        self.ids.push(to_id(&tx.index));
+        if is_aggregated(tx) {
+            self.count += 1;
+            self.amount += tx.amount.clone();
+        }
+    }
+}
+
+/// `UserTransactions` saved by the versions of the canister that kept the ids as `Nat`.
+#[derive(CandidType, Deserialize)]
+struct UserTransactionsV1 {
+    ids: Vec<Nat>,
+    count: u64,
+    amount: Nat,
+}
+
+impl From<UserTransactionsV1> for UserTransactions {
+    fn from(user: UserTransactionsV1) -> Self {
+        Self {
+            ids: user.ids.iter().map(to_id).collect(),
+            count: user.count,
+            amount: user.amount,
+        }
+    }
 }
 
 impl Ledger {
-    pub fn len(&self) -> Nat {
-        self.vec_offset.clone() + self.history.len()
+    pub fn len(&self) -> u64 {
+        self.first_id() + self.history.len() as u64
+    }
+
+    /// Id of the last transaction, or zero if the ledger is empty.
+    pub fn last_id(&self) -> u64 {
+        self.len().saturating_sub(1)
+    }
+
+    /// Id of the oldest transaction kept in the history.
+    pub fn first_id(&self) -> u64 {
+        to_id(&self.vec_offset)
+    }
+
+    /// Number of the transactions kept in the history.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Returns up to `count` oldest transactions kept in the history.
+    pub fn oldest(&self, count: usize) -> Vec<TxRecord> {
+        self.history.iter().take(count).cloned().collect()
+    }
+
+    /// Removes the transactions with the ids less than `end` from the history.
+    pub fn remove_before(&mut self, end: u64) {
+        let count = end
+            .saturating_sub(self.first_id())
+            .min(self.history.len() as u64) as usize;
+        if count == 0 {
+            return;
+        }
+
+        let removed: Vec<_> = self.history.drain(..count).collect();
+        self.vec_offset += count;
+        self.remove_from_index(&removed);
     }
 
     fn next_id(&self) -> Nat {
-        self.vec_offset.clone() + self.history.len()
+        Nat::from(self.len())
     }
 
-    pub fn get(&self, id: &Nat) -> Option<TxRecord> {
-        self.history.get(self.get_index(id)?).cloned()
+    pub fn get(&self, id: u64) -> Option<&TxRecord> {
+        self.history.get(self.get_index(id)?)
     }
 
-    pub fn get_range(&self, start: &Nat, limit: &Nat) -> Vec<TxRecord> {
+    /// Returns up to `limit` records starting from the record with the `start` id. The records
+    /// are borrowed from the history, so the callers only copy the records they return.
+    pub fn get_range(&self, start: u64, limit: usize) -> &[TxRecord] {
         let start = match self.get_index(start) {
-            Some(v) => v,
-            None => {
-                if *start > self.vec_offset.clone() {
-                    usize::MAX
-                } else {
-                    0
+            Some(index) => index.min(self.history.len()),
+            None if start > self.first_id() => self.history.len(),
+            None => 0,
+        };
+        let end = start.saturating_add(limit).min(self.history.len());
+
+        &self.history[start..end]
+    }
+
+    /// Returns up to `limit` records starting from the record with the `end` id and going back to
+    /// the older records. If the `end` record is not added yet, the records are returned from the
+    /// newest one. The records are borrowed from the history, like in `get_range`.
+    pub fn get_range_rev(&self, end: u64, limit: usize) -> impl Iterator<Item = &TxRecord> {
+        let end = match end.checked_sub(self.first_id()) {
+            Some(offset) => offset.saturating_add(1).min(self.history.len() as u64) as usize,
+            None => 0,
+        };
+        let start = end.saturating_sub(limit);
+
+        self.history[start..end].iter().rev()
+    }
+
+    /// Returns up to `limit` records starting from the record with the `cursor` id in the given
+    /// `order`. If the `cursor` is not given, the page starts from the oldest record for the
+    /// ascending order, and from the newest record for the descending order.
+    ///
+    /// If the `operation` or the `status` is given, only the matching records are returned. At most
+    /// `max_scan` records are checked in one call, so the page can have less than `limit` (or even
+    /// no) records while the `next_cursor` is still set. In this case the scan should be continued
+    /// from the `next_cursor`.
+    ///
+    /// Records added after the call don't change the pages requested with the returned cursor, so
+    /// paging through the history never skips or repeats records.
+    pub fn get_page(
+        &self,
+        cursor: Option<u64>,
+        limit: usize,
+        order: TransactionOrder,
+        operation: Option<Operation>,
+        status: Option<TransactionStatusFilter>,
+        max_scan: usize,
+    ) -> TransactionsPage {
+        let filter = TxFilter {
+            operation,
+            status,
+            ..Default::default()
+        };
+        self.query(&filter, cursor, limit, order, max_scan)
+    }
+
+    /// Returns up to `limit` records matching the `filter` starting from the record with the
+    /// `cursor` id in the given `order`, the same way as `get_page`.
+    ///
+    /// The records are taken from the shortest of the caller and user indexes matching the
+    /// `filter`, or from the whole history if the filter has no principals, and the time bounds
+    /// are found with a binary search. Only these records are counted against the `max_scan`
+    /// bound.
+    pub fn query(
+        &self,
+        filter: &TxFilter,
+        cursor: Option<u64>,
+        limit: usize,
+        order: TransactionOrder,
+        max_scan: usize,
+    ) -> TransactionsPage {
+        let mut records = vec![];
+        let mut next_cursor = None;
+        for (scanned, id) in self.filter_candidates(filter, cursor, order).enumerate() {
+            if records.len() >= limit || scanned >= max_scan {
+                next_cursor = Some(Nat::from(id));
+                break;
+            }
+
+            match self.get(id) {
+                Some(tx) if filter.matches(tx) => records.push(tx.clone()),
+                _ => {}
+            }
+        }
+
+        TransactionsPage {
+            records,
+            total: Nat::from(self.len()),
+            next_cursor,
+        }
+    }
+
+    /// Returns the ids of the records that can match the `filter`, starting from the `cursor` id in
+    /// the given `order`.
+    fn filter_candidates(
+        &self,
+        filter: &TxFilter,
+        cursor: Option<u64>,
+        order: TransactionOrder,
+    ) -> Box<dyn Iterator<Item = u64> + '_> {
+        let first_id = self.first_id();
+        let mut start = first_id + filter.after_ts.map_or(0, |ts| self.position_at(ts)) as u64;
+        let mut end = match filter.before_ts {
+            Some(ts) => first_id + self.position_at(ts) as u64,
+            None => self.len(),
+        };
+        match (order, cursor) {
+            (TransactionOrder::Ascending, Some(cursor)) => start = start.max(cursor),
+            (TransactionOrder::Descending, Some(cursor)) => end = end.min(cursor.saturating_add(1)),
+            _ => {}
+        }
+        let end = end.max(start);
+
+        // Ids of the records of the principals of the filter taken from the ledger indexes.
+        let mut indexed: Vec<&[u64]> = vec![];
+        if let (Some(caller), Some(index)) = (filter.caller, self.indexed_callers()) {
+            indexed.push(index.get(&caller).map_or(&[][..], |ids| &ids[..]));
+        }
+        if let Some(index) = self.indexed_users() {
+            for who in [filter.from, filter.to].iter().flatten() {
+                indexed.push(index.get(who).map_or(&[][..], |user| &user.ids[..]));
+            }
+        }
+
+        let ids: Box<dyn DoubleEndedIterator<Item = u64>> =
+            match indexed.into_iter().min_by_key(|ids| ids.len()) {
+                Some(ids) => {
+                    let from = ids.partition_point(|id| *id < start);
+                    let to = ids.partition_point(|id| *id < end);
+                    Box::new(ids[from..to].iter().copied())
                 }
+                None => Box::new(start..end),
+            };
+
+        match order {
+            TransactionOrder::Ascending => Box::new(ids),
+            TransactionOrder::Descending => Box::new(ids.rev()),
+        }
+    }
+
+    /// Returns up to `limit` records with the amounts in the range [min, max] in ascending order,
+    /// starting from the record with the `cursor` id if it is given. If `max` is not given, the
+    /// range has no upper bound.
+    ///
+    /// The records are not indexed by their amounts, so at most `max_scan` records are checked in
+    /// one call, and the scan should be continued from the `next_cursor` the same way as in
+    /// `get_page`.
+    pub fn get_by_amount(
+        &self,
+        min: &Nat,
+        max: Option<&Nat>,
+        cursor: Option<u64>,
+        limit: usize,
+        max_scan: usize,
+    ) -> TransactionsPage {
+        let filter = TxFilter {
+            min_amount: Some(min.clone()),
+            max_amount: max.cloned(),
+            ..Default::default()
+        };
+        self.query(
+            &filter,
+            cursor,
+            limit,
+            TransactionOrder::Ascending,
+            max_scan,
+        )
+    }
+
+    /// Returns up to `limit` records with the timestamps in the range [from, to), starting from the
+    /// record with the `cursor` id if it is given. The `total` of the page is the number of the
+    /// records in the time range.
+    ///
+    /// The timestamps of the records never decrease, so the bounds of the range are found with a
+    /// binary search.
+    pub fn get_by_time(
+        &self,
+        from: Timestamp,
+        to: Timestamp,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> TransactionsPage {
+        let first = self.position_at(from);
+        let end = self.position_at(to).max(first);
+        let filter = TxFilter {
+            after_ts: Some(from),
+            before_ts: Some(to),
+            ..Default::default()
+        };
+
+        // All the records in the time range match the filter, so the scan is not bounded.
+        TransactionsPage {
+            total: Nat::from(end - first),
+            ..self.query(
+                &filter,
+                cursor,
+                limit,
+                TransactionOrder::Ascending,
+                usize::MAX,
+            )
+        }
+    }
+
+    /// Returns the records starting from the `start` id until either `max_count` records are
+    /// taken or their encoded size would exceed `max_bytes`. At least one record is returned if
+    /// there are records left, so the callers make progress even with a too small `max_bytes`.
+    ///
+    /// If the `start` record was removed from the history, the records are returned from the
+    /// oldest record kept in the history.
+    pub fn get_blocks(&self, start: u64, max_count: usize, max_bytes: usize) -> BlocksPage {
+        let start = start.max(self.first_id());
+        let candidates = self.get_range(start, max_count);
+        let mut size = 0;
+        let mut count = 0;
+        for tx in candidates {
+            size += encoded_size(tx);
+            if count > 0 && size > max_bytes {
+                break;
             }
+
+            count += 1;
+        }
+
+        BlocksPage {
+            records: candidates[..count].to_vec(),
+            last_id: Nat::from(self.last_id()),
+            remaining: Nat::from(self.len().saturating_sub(start + count as u64)),
+        }
+    }
+
+    /// Returns the id of the first record with the timestamp not less than `timestamp`, or `None`
+    /// if all the records kept in the history are older.
+    pub fn first_index_at_or_after(&self, timestamp: Timestamp) -> Option<u64> {
+        let position = self.position_at(timestamp);
+        if position < self.history.len() {
+            Some(self.first_id() + position as u64)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the position in the history of the first record with the timestamp not less than
+    /// `timestamp`. The timestamps of the records never decrease, so the position is found with
+    /// a binary search.
+    fn position_at(&self, timestamp: Timestamp) -> usize {
+        let timestamp = Int::from(timestamp);
+        self.history.partition_point(|tx| tx.timestamp < timestamp)
+    }
+
+    /// Returns the number of the stored records, their time span and the estimate of the heap
+    /// memory taken by the ledger. The `stable_bytes` is left zero: the ledger is kept in the heap,
+    /// so the stable memory size is known only to the canister.
+    pub fn stats(&self) -> LedgerStats {
+        let timestamp = |tx: Option<&TxRecord>| {
+            tx.and_then(|tx| tx.timestamp.0.to_u64())
+                .unwrap_or_default()
         };
 
-        let limit = limit.0.to_usize().unwrap_or(usize::MAX);
-        self.history
-            .iter()
+        LedgerStats {
+            record_count: Nat::from(self.history.len()),
+            heap_bytes_estimate: self.heap_size() as u64,
+            stable_bytes: 0,
+            oldest_timestamp: timestamp(self.history.first()),
+            newest_timestamp: timestamp(self.history.last()),
+        }
+    }
+
+    /// Approximate number of bytes allocated for the history and the indexes. The sizes of the
+    /// hash map tables are estimated by the number of their entries.
+    fn heap_size(&self) -> usize {
+        let history = self.history.capacity() * size_of::<TxRecord>()
+            + self.history.iter().map(record_heap_size).sum::<usize>();
+
+        let user_index = self.user_index.as_ref().map_or(0, |index| {
+            index
+                .values()
+                .map(|user| {
+                    size_of::<(Principal, UserTransactions)>()
+                        + user.ids.capacity() * size_of::<u64>()
+                })
+                .sum()
+        });
+
+        let user_stats = self.user_stats.as_ref().map_or(0, |stats| {
+            stats.users.len() * size_of::<(Principal, UserStats)>()
+        });
+
+        let caller_index = self.caller_index.as_ref().map_or(0, |index| {
+            index
+                .values()
+                .map(|ids| size_of::<(Principal, Vec<u64>)>() + ids.capacity() * size_of::<u64>())
+                .sum()
+        });
+
+        let fee_payments = self.fee_payments.payments.capacity() * size_of::<FeePayment>();
+
+        history + user_index + user_stats + caller_index + fee_payments
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TxRecord> {
+        self.history.iter()
+    }
+
+    /// Iterates over the records kept in the history from the newest to the oldest one.
+    pub fn iter_rev(&self) -> impl Iterator<Item = &TxRecord> {
+        self.history.iter().rev()
+    }
+
+    /// Returns the transactions related to the `who` principal in the range [start, start + limit)
+    /// of the user's transactions.
+    pub fn get_user_transactions(
+        &self,
+        who: Principal,
+        start: usize,
+        limit: usize,
+    ) -> Vec<TxRecord> {
+        self.user_records(who, TransactionOrder::Ascending)
             .skip(start)
             .take(limit)
             .cloned()
             .collect()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &TxRecord> {
-        self.history.iter()
+    /// Same as `get_user_transactions`, but also returns the number of the stored transactions of
+    /// the user and the start of the next page, if there is one. The `operation` filter and the
+    /// `max_scan` bound work the same way as in `get_page`.
+    pub fn get_user_page(
+        &self,
+        who: Principal,
+        start: usize,
+        limit: usize,
+        order: TransactionOrder,
+        operation: Option<Operation>,
+        max_scan: usize,
+    ) -> TransactionsPage {
+        let total = match self.indexed_users() {
+            Some(index) => index.get(&who).map_or(0, |user| user.ids.len()),
+            None => self.history.iter().filter(|tx| is_related(tx, who)).count(),
+        };
+
+        let mut records = vec![];
+        let mut next = start;
+        for tx in self.user_records(who, order).skip(start) {
+            if records.len() >= limit || next - start >= max_scan {
+                break;
+            }
+
+            if operation.map_or(true, |operation| tx.operation == operation) {
+                records.push(tx.clone());
+            }
+
+            next += 1;
+        }
+
+        TransactionsPage {
+            records,
+            total: Nat::from(total),
+            next_cursor: if next < total {
+                Some(Nat::from(next))
+            } else {
+                None
+            },
+        }
     }
 
-    fn get_index(&self, id: &Nat) -> Option<usize> {
-        if *id < self.vec_offset {
-            None
-        } else {
-            let index = id.clone() - self.vec_offset.clone();
-            index.0.to_usize()
+    /// Returns the transactions related to the `who` principal in the given `order`. The newest
+    /// transactions are found without going through the older ones.
+    fn user_records(
+        &self,
+        who: Principal,
+        order: TransactionOrder,
+    ) -> Box<dyn Iterator<Item = &TxRecord> + '_> {
+        let ids = match self.indexed_users() {
+            Some(index) => index.get(&who).map_or(&[][..], |user| &user.ids[..]),
+            None => {
+                let related = move |tx: &&TxRecord| is_related(tx, who);
+                return match order {
+                    TransactionOrder::Ascending => Box::new(self.iter().filter(related)),
+                    TransactionOrder::Descending => Box::new(self.iter_rev().filter(related)),
+                };
+            }
+        };
+
+        let record = move |id: &u64| self.get(*id);
+        match order {
+            TransactionOrder::Ascending => Box::new(ids.iter().filter_map(record)),
+            TransactionOrder::Descending => Box::new(ids.iter().rev().filter_map(record)),
         }
     }
 
-    pub fn transfer(&mut self, from: Principal, to: Principal, amount: Nat, fee: Nat) -> Nat {
-        let id = self.next_id();
-        self.push(TxRecord::transfer(id.clone(), from, to, amount, fee));
+    /// Returns up to `limit` transactions made by the `caller`, starting from the `cursor` id if
+    /// it is given. The `total` of the page is the number of the caller's transactions kept in the
+    /// history.
+    pub fn get_caller_page(
+        &self,
+        caller: Principal,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> TransactionsPage {
+        let total = match self.indexed_callers() {
+            Some(index) => index.get(&caller).map_or(0, Vec::len),
+            None => self
+                .history
+                .iter()
+                .filter(|tx| tx.caller == Some(caller))
+                .count(),
+        };
+        let filter = TxFilter {
+            caller: Some(caller),
+            ..Default::default()
+        };
 
-        id
+        // All the indexed records of the caller match the filter, so the scan is not bounded.
+        TransactionsPage {
+            total: Nat::from(total),
+            ..self.query(
+                &filter,
+                cursor,
+                limit,
+                TransactionOrder::Ascending,
+                usize::MAX,
+            )
+        }
     }
 
-    pub fn transfer_from(
+    /// Returns the aggregated transactions of the `who` principal. The stats don't include the
+    /// records that are not backfilled yet, see `backfill_user_stats`.
+    pub fn user_stats(&self, who: Principal) -> UserStats {
+        self.user_stats
+            .as_ref()
+            .and_then(|index| index.users.get(&who).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Returns `true` if all the records kept in the history are included in the user stats.
+    pub fn user_stats_complete(&self) -> bool {
+        match &self.user_stats {
+            Some(index) => index.backfill_next.max(self.first_id()) >= index.backfill_end,
+            None => self.history.is_empty(),
+        }
+    }
+
+    /// Returns `true` if some of the records are yet to be added by `backfill_user_stats`,
+    /// `backfill_indexes`, `backfill_user_index`, `backfill_caller_index` or
+    /// `backfill_operation_counts`.
+    pub fn backfill_pending(&self) -> bool {
+        let indexed = matches!(
+            (&self.balance_index, &self.vote_index),
+            (Some(index), Some(_)) if index.next_id() >= self.len()
+        );
+        !indexed
+            || !self.user_stats_complete()
+            || !self.user_index_complete()
+            || !self.caller_index_complete()
+            || !self.operation_counts_complete()
+    }
+
+    /// Adds up to `max_records` records saved before the user stats were maintained to the stats.
+    /// Returns `true` if there are no more records to add.
+    pub fn backfill_user_stats(&mut self, max_records: usize) -> bool {
+        let first_id = self.first_id();
+        let len = self.len();
+        let index = self
+            .user_stats
+            .get_or_insert_with(|| UserStatsIndex::new(first_id, len));
+
+        // The records removed from the history can't be added anymore.
+        let start = index.backfill_next.max(first_id);
+        let end = start
+            .saturating_add(max_records as u64)
+            .min(index.backfill_end);
+        if start < end {
+            let records = &self.history[(start - first_id) as usize..(end - first_id) as usize];
+            for tx in records {
+                add_to_user_stats(&mut index.users, tx);
+            }
+        }
+
+        index.backfill_next = end.max(start);
+        index.backfill_next >= index.backfill_end
+    }
+
+    /// Returns the user index if all the records kept in the history are indexed. Until then the
+    /// transactions of the users are found by scanning the history.
+    fn indexed_users(&self) -> Option<&HashMap<Principal, UserTransactions>> {
+        self.user_index
+            .as_ref()
+            .filter(|_| self.user_index_next.is_none())
+    }
+
+    /// Returns `true` if all the records kept in the history are included in the user index.
+    fn user_index_complete(&self) -> bool {
+        match &self.user_index {
+            Some(_) => self.user_index_next.is_none(),
+            None => self.history.is_empty(),
+        }
+    }
+
+    /// Moves the user index saved with the `Nat` ids by the earlier versions of the canister into
+    /// the `user_index`. Does nothing if the index is already converted.
+    pub fn migrate_user_index(&mut self) {
+        if let Some(index) = self.user_index_v1.take() {
+            let index = index
+                .into_iter()
+                .map(|(user, transactions)| (user, transactions.into()))
+                .collect();
+            self.user_index = Some(index);
+        }
+    }
+
+    /// Adds up to `max_records` records saved before the user index was maintained to the index.
+    /// Returns `true` if all the records are indexed.
+    pub fn backfill_user_index(&mut self, max_records: usize) -> bool {
+        let first_id = self.first_id();
+        if self.user_index.is_none() {
+            self.user_index = Some(HashMap::new());
+            self.user_index_next = Some(first_id);
+        }
+
+        if let (Some(index), Some(next)) = (&mut self.user_index, self.user_index_next) {
+            let (records, next) = backfill_batch(&self.history, first_id, next, max_records);
+            for tx in records {
+                for user in related_principals(tx) {
+                    index.entry(user).or_default().add(tx);
+                }
+            }
+            self.user_index_next = next;
+        }
+
+        self.user_index_next.is_none()
+    }
+
+    /// Returns the caller index if all the records kept in the history are indexed. Until then the
+    /// transactions of the callers are found by scanning the history.
+    fn indexed_callers(&self) -> Option<&HashMap<Principal, Vec<u64>>> {
+        self.caller_index
+            .as_ref()
+            .filter(|_| self.caller_index_next.is_none())
+    }
+
+    /// Returns `true` if all the records kept in the history are included in the caller index.
+    fn caller_index_complete(&self) -> bool {
+        match &self.caller_index {
+            Some(_) => self.caller_index_next.is_none(),
+            None => self.history.is_empty(),
+        }
+    }
+
+    /// Adds up to `max_records` records saved before the caller index was maintained to the index.
+    /// Returns `true` if all the records are indexed.
+    pub fn backfill_caller_index(&mut self, max_records: usize) -> bool {
+        let first_id = self.first_id();
+        if self.caller_index.is_none() {
+            self.caller_index = Some(HashMap::new());
+            self.caller_index_next = Some(first_id);
+        }
+
+        if let (Some(index), Some(next)) = (&mut self.caller_index, self.caller_index_next) {
+            let (records, next) = backfill_batch(&self.history, first_id, next, max_records);
+            for tx in records {
+                if let Some(caller) = tx.caller {
+                    index.entry(caller).or_default().push(to_id(&tx.index));
+                }
+            }
+            self.caller_index_next = next;
+        }
+
+        self.caller_index_next.is_none()
+    }
+
+    /// Adds up to `max_records` records saved before the balance and vote indexes were maintained
+    /// to the indexes. Returns `true` if all the records are indexed.
+    ///
+    /// The `balances` are the current balances of the principals. They are used to start the
+    /// indexes of the ledgers with the history removed before the indexes were added, see
+    /// `BalanceIndex::new`.
+    pub fn backfill_indexes(
+        &mut self,
+        max_records: usize,
+        balances: &HashMap<Principal, Nat>,
+    ) -> bool {
+        let (first_id, len) = (self.first_id(), self.len());
+        match (&self.balance_index, &self.vote_index) {
+            (Some(index), Some(_)) if index.next_id() >= first_id => {}
+            // The ledger is not indexed yet, or the records not indexed yet were removed from the
+            // history since the last call.
+            _ => self.start_indexes(balances),
+        }
+
+        let payments = &self.fee_payments.payments;
+        if let (Some(index), Some(votes)) = (&mut self.balance_index, &mut self.vote_index) {
+            let start = index.next_id();
+            let end = start.saturating_add(max_records as u64).min(len);
+            for tx in &self.history[(start - first_id) as usize..(end - first_id) as usize] {
+                let recipient = fee_recipient(payments, to_id(&tx.index));
+                index.add(tx, recipient);
+                votes.add(tx, recipient, index);
+            }
+        }
+
+        self.balance_index
+            .as_ref()
+            .map_or(false, |index| index.next_id() >= len)
+    }
+
+    /// Starts the empty balance and vote indexes, keeping the checkpoint spacing. The records are
+    /// added to the indexes by `backfill_indexes`.
+    fn start_indexes(&mut self, balances: &HashMap<Principal, Nat>) {
+        let mut index = BalanceIndex::new(self.first_id(), self.len(), balances);
+        index.set_spacing(self.checkpoint_spacing());
+        self.vote_index = Some(VoteIndex::new(&index));
+        self.balance_index = Some(index);
+    }
+
+    /// Returns the number of the successful records of every operation, sorted by the operation
+    /// names. The operations without records are not included. The counts don't include the
+    /// records that are not backfilled yet, see `backfill_operation_counts`.
+    pub fn operation_counts(&self) -> OperationCounts {
+        let mut counts: Vec<_> = self
+            .operation_counts
+            .iter()
+            .flatten()
+            .map(|(operation, count)| (operation.clone(), Nat::from(*count)))
+            .collect();
+        counts.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        OperationCounts {
+            counts,
+            complete: self.operation_counts_complete(),
+        }
+    }
+
+    /// Returns `true` if all the records kept in the history are counted in the operation counts.
+    fn operation_counts_complete(&self) -> bool {
+        match &self.operation_counts {
+            Some(_) => self.operation_counts_next.max(self.first_id()) >= self.operation_counts_end,
+            None => self.history.is_empty(),
+        }
+    }
+
+    /// Starts the empty operation counts. The records kept in the history are counted by
+    /// `backfill_operation_counts`.
+    fn start_operation_counts(&mut self) {
+        self.operation_counts = Some(HashMap::new());
+        self.operation_counts_next = self.first_id();
+        self.operation_counts_end = self.len();
+    }
+
+    /// Counts up to `max_records` records saved before the operation counts were maintained.
+    /// Returns `true` if there are no more records to count.
+    pub fn backfill_operation_counts(&mut self, max_records: usize) -> bool {
+        if self.operation_counts.is_none() {
+            self.start_operation_counts();
+        }
+
+        // The records removed from the history can't be counted anymore.
+        let first_id = self.first_id();
+        let start = self.operation_counts_next.max(first_id);
+        let end = start
+            .saturating_add(max_records as u64)
+            .min(self.operation_counts_end);
+        if let Some(counts) = self.operation_counts.as_mut().filter(|_| start < end) {
+            for tx in &self.history[(start - first_id) as usize..(end - first_id) as usize] {
+                count_operation(counts, tx);
+            }
+        }
+
+        self.operation_counts_next = end.max(start);
+        self.operation_counts_complete()
+    }
+
+    /// Returns the number and the total amount of the transactions related to the `who`
+    /// principal.
+    pub fn user_totals(&self, who: Principal) -> (u64, Nat) {
+        match self.indexed_users() {
+            Some(index) => index
+                .get(&who)
+                .map(|user| (user.count, user.amount.clone()))
+                .unwrap_or_else(|| (0, Nat::from(0))),
+            None => self
+                .history
+                .iter()
+                .filter(|tx| is_related(tx, who) && is_aggregated(tx))
+                .fold((0, Nat::from(0)), |(count, amount), tx| {
+                    (count + 1, amount + tx.amount.clone())
+                }),
+        }
+    }
+
+    /// Returns the position of the record with the `id` in the history, or `None` if the record
+    /// was removed from the history or the position doesn't fit into `usize`. The position can be
+    /// out of the history bounds.
+    fn get_index(&self, id: u64) -> Option<usize> {
+        id.checked_sub(self.first_id())?.to_usize()
+    }
+
+    /// Records the `operation` rejected with the `error`.
+    pub fn failed(
         &mut self,
+        operation: Operation,
         caller: Principal,
         from: Principal,
         to: Principal,
         amount: Nat,
-        fee: Nat,
+        error: TxError,
     ) -> Nat {
         let id = self.next_id();
-        self.push(TxRecord::transfer_from(
+        self.push(TxRecord::failed(
             id.clone(),
+            operation,
             caller,
             from,
             to,
             amount,
-            fee,
+            error,
         ));
 
         id
     }
 
-    pub fn approve(&mut self, from: Principal, to: Principal, amount: Nat, fee: Nat) -> Nat {
+    pub fn transfer(
+        &mut self,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+        fee: impl Into<TxFee>,
+    ) -> Nat {
+        self.transfer_with_memo(from, to, amount, fee, None)
+    }
+
+    pub fn transfer_with_memo(
+        &mut self,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+        fee: impl Into<TxFee>,
+        memo: Option<Vec<u8>>,
+    ) -> Nat {
+        let fee = fee.into();
         let id = self.next_id();
-        self.push(TxRecord::approve(id.clone(), from, to, amount, fee));
+        let mut record = TxRecord::transfer(id.clone(), from, to, amount, fee.total.clone());
+        record.memo = memo;
+        self.push_with_fee(record, fee);
 
         id
     }
 
-    pub fn mint(&mut self, from: Principal, to: Principal, amount: Nat) -> Nat {
-        let id = self.len();
-        self.push(TxRecord::mint(id.clone(), from, to, amount));
+    pub fn transfer_from(
+        &mut self,
+        caller: Principal,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+        fee: impl Into<TxFee>,
+    ) -> Nat {
+        let fee = fee.into();
+        let id = self.next_id();
+        let record =
+            TxRecord::transfer_from(id.clone(), caller, from, to, amount, fee.total.clone());
+        self.push_with_fee(record, fee);
 
         id
     }
 
-    pub fn burn(&mut self, caller: Principal, amount: Nat) -> Nat {
-        let id = self.next_id();
+    pub fn transfer_from_include_fee(
+        &mut self,
+        caller: Principal,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+        fee: impl Into<TxFee>,
+    ) -> Nat {
+        let fee = fee.into();
+        let id = self.next_id();
+        let record = TxRecord::transfer_from_include_fee(
+            id.clone(),
+            caller,
+            from,
+            to,
+            amount,
+            fee.total.clone(),
+        );
+        self.push_with_fee(record, fee);
+
+        id
+    }
+
+    pub fn approve(
+        &mut self,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+        fee: impl Into<TxFee>,
+    ) -> Nat {
+        let fee = fee.into();
+        let id = self.next_id();
+        let record = TxRecord::approve(id.clone(), from, to, amount, fee.total.clone());
+        self.push_with_fee(record, fee);
+
+        id
+    }
+
+    pub fn approve_unlimited(
+        &mut self,
+        from: Principal,
+        to: Principal,
+        fee: impl Into<TxFee>,
+    ) -> Nat {
+        let fee = fee.into();
+        let id = self.next_id();
+        let record = TxRecord::approve_unlimited(id.clone(), from, to, fee.total.clone());
+        self.push_with_fee(record, fee);
+
+        id
+    }
+
+    pub fn admin_revoke(&mut self, caller: Principal, from: Principal, to: Principal) -> Nat {
+        let id = self.next_id();
+        self.push(TxRecord::admin_revoke(id.clone(), caller, from, to));
+
+        id
+    }
+
+    pub fn add_minter(&mut self, caller: Principal, minter: Principal) -> Nat {
+        let id = self.next_id();
+        self.push(TxRecord::add_minter(id.clone(), caller, minter));
+
+        id
+    }
+
+    pub fn remove_minter(&mut self, caller: Principal, minter: Principal) -> Nat {
+        let id = self.next_id();
+        self.push(TxRecord::remove_minter(id.clone(), caller, minter));
+
+        id
+    }
+
+    pub fn mint(
+        &mut self,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+        reason: Option<String>,
+    ) -> Nat {
+        let id = self.next_id();
+        self.push(TxRecord::mint(id.clone(), from, to, amount, reason));
+
+        id
+    }
+
+    pub fn delegate(&mut self, from: Principal, delegate: Principal) -> Nat {
+        let id = self.next_id();
+        self.push(TxRecord::delegate(id.clone(), from, delegate));
+
+        id
+    }
+
+    pub fn burn(&mut self, caller: Principal, amount: Nat) -> Nat {
+        let id = self.next_id();
         self.push(TxRecord::burn(id.clone(), caller, amount));
 
         id
     }
 
-    pub fn auction(&mut self, to: Principal, amount: Nat) {
+    pub fn burn_from(&mut self, caller: Principal, from: Principal, amount: Nat) -> Nat {
         let id = self.next_id();
-        self.push(TxRecord::auction(id, to, amount))
+        self.push(TxRecord::burn_from(id.clone(), caller, from, amount));
+
+        id
     }
 
-    fn push(&mut self, record: TxRecord) {
+    pub fn clawback(
+        &mut self,
+        caller: Principal,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+    ) -> Nat {
+        let id = self.next_id();
+        self.push(TxRecord::clawback(id.clone(), caller, from, to, amount));
+
+        id
+    }
+
+    /// Records the `setting` changed by the `caller` from `old_value` to `new_value`.
+    pub fn configure(
+        &mut self,
+        caller: Principal,
+        setting: &str,
+        old_value: String,
+        new_value: String,
+    ) -> Nat {
+        let id = self.next_id();
+        self.push(TxRecord::configure(
+            id.clone(),
+            caller,
+            setting.to_string(),
+            old_value,
+            new_value,
+        ));
+
+        id
+    }
+
+    pub fn auction(&mut self, from: Principal, to: Principal, amount: Nat) -> Nat {
+        let id = self.next_id();
+        self.push(TxRecord::auction(id.clone(), from, to, amount));
+
+        id
+    }
+
+    /// Hash of the last record of the ledger, or `GENESIS_HASH` if no records were added since the
+    /// ledger was hash-chained.
+    pub fn tip_hash(&self) -> Vec<u8> {
+        self.last_hash
+            .clone()
+            .unwrap_or_else(|| GENESIS_HASH.to_vec())
+    }
+
+    /// Checks up to `limit` records starting from the `start` id: the ids of the records, the
+    /// hash chain, the order of the timestamps and the fee breakdowns. The check starts from the
+    /// oldest record kept in the history if the `start` record was removed.
+    pub fn verify(&self, start: u64, limit: usize) -> LedgerCheckResult {
+        let start = start.max(self.first_id());
+        let mut minted = Nat::from(0);
+        let mut burned = Nat::from(0);
+        let mut checked = 0;
+        for tx in self.get_range(start, limit) {
+            let id = start + checked;
+            let inconsistent = |issue| LedgerCheckResult::Inconsistent {
+                id: Nat::from(id),
+                issue,
+            };
+            if tx.index != Nat::from(id) {
+                return inconsistent(LedgerIssue::WrongIndex {
+                    stored: tx.index.clone(),
+                });
+            }
+
+            let previous = id.checked_sub(1).and_then(|previous| self.get(previous));
+            if let Some(previous) = previous {
+                if tx.timestamp < previous.timestamp {
+                    return inconsistent(LedgerIssue::TimestampDecreased);
+                }
+            }
+
+            // The first chained record points to the genesis hash. The parent of the oldest record
+            // kept in the history can't be checked if the history was trimmed.
+            let expected_parent = match previous {
+                Some(previous) if previous.parent_hash.is_some() => Some(tx_hash(previous)),
+                Some(_) => tx.parent_hash.as_ref().map(|_| GENESIS_HASH.to_vec()),
+                None if id == 0 => tx.parent_hash.as_ref().map(|_| GENESIS_HASH.to_vec()),
+                None => tx.parent_hash.clone(),
+            };
+            if tx.parent_hash != expected_parent {
+                return inconsistent(LedgerIssue::ParentHashMismatch);
+            }
+
+            if id == self.last_id()
+                && self.last_hash != tx.parent_hash.as_ref().map(|_| tx_hash(tx))
+            {
+                return inconsistent(LedgerIssue::TipHashMismatch);
+            }
+
+            if matches!(&tx.fee_breakdown, Some(breakdown) if breakdown.total() != tx.fee) {
+                return inconsistent(LedgerIssue::FeeBreakdownMismatch);
+            }
+
+            if !tx.status.is_failed() {
+                match tx.operation {
+                    Operation::Mint => minted += tx.amount.clone(),
+                    Operation::Burn => burned += tx.amount.clone(),
+                    _ => {}
+                }
+            }
+
+            checked += 1;
+        }
+
+        let next = start + checked;
+        LedgerCheckResult::Consistent {
+            checked,
+            minted,
+            burned,
+            next: if next < self.len() {
+                Some(Nat::from(next))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns up to `limit` fee payments to the `fee_to` principals, starting from the payment of
+    /// the transaction with the `cursor` id if it is given.
+    pub fn get_fee_payments(&self, cursor: Option<u64>, limit: usize) -> FeePaymentsPage {
+        let payments = &self.fee_payments.payments;
+        let start = match cursor {
+            Some(cursor) => payments.partition_point(|payment| to_id(&payment.tx_id) < cursor),
+            None => 0,
+        };
+        let end = start.saturating_add(limit).min(payments.len());
+
+        FeePaymentsPage {
+            payments: payments[start..end].to_vec(),
+            total: Nat::from(payments.len()),
+            next_cursor: payments.get(end).map(|payment| payment.tx_id.clone()),
+        }
+    }
+
+    /// Returns the balance of `who` right after the `id` transaction, replayed from the ledger
+    /// records. At most the records between two balance checkpoints are replayed.
+    ///
+    /// Returns `TransactionDoesNotExist` error if the transaction was not added or indexed yet, if
+    /// it was removed from the history before the index was started, or if the records needed to
+    /// replay the balance were removed from the history.
+    pub fn balance_at(&self, who: Principal, id: u64) -> Result<Nat, TxError> {
+        let index = match &self.balance_index {
+            Some(index) if id < index.next_id() && id + 1 >= index.start() => index,
+            _ => return Err(TxError::TransactionDoesNotExist),
+        };
+
+        let payments = &self.fee_payments.payments;
+        let (checkpoint, balance) = index.checkpoint_before(who, id + 1);
+        if checkpoint < self.first_id() {
+            return Err(TxError::TransactionDoesNotExist);
+        }
+
+        let records = self.get_range(checkpoint, (id + 1 - checkpoint) as usize);
+        Ok(replay(who, balance, records.iter(), payments))
+    }
+
+    /// Returns the principal voting with the balance of `who`. The delegations that are not
+    /// backfilled yet are not taken into account, see `backfill_indexes`.
+    pub fn delegate_of(&self, who: Principal) -> Principal {
+        self.vote_index
+            .as_ref()
+            .map_or(who, |votes| votes.delegate_of(who))
+    }
+
+    /// Returns the current vote weight of the `delegate`. The weight doesn't include the records
+    /// that are not backfilled yet, see `backfill_indexes`.
+    pub fn votes(&self, delegate: Principal) -> Nat {
+        self.vote_index
+            .as_ref()
+            .map(|votes| votes.votes(delegate))
+            .unwrap_or_default()
+    }
+
+    /// Returns the vote weight of the `delegate` right after the `id` transaction. Returns
+    /// `TransactionDoesNotExist` error if the transaction was not added or indexed yet, or if it
+    /// was removed from the history.
+    pub fn past_votes(&self, delegate: Principal, id: u64) -> Result<Nat, TxError> {
+        match (&self.balance_index, &self.vote_index) {
+            (Some(index), Some(votes))
+                if id < index.next_id() && id + 1 >= index.start() && id >= self.first_id() =>
+            {
+                Ok(votes.past_votes(delegate, id))
+            }
+            _ => Err(TxError::TransactionDoesNotExist),
+        }
+    }
+
+    /// Number of the records between two balance checkpoints.
+    pub fn checkpoint_spacing(&self) -> u64 {
+        self.balance_index
+            .as_ref()
+            .map_or(DEFAULT_CHECKPOINT_SPACING, BalanceIndex::spacing)
+    }
+
+    /// Sets the number of the records between two balance checkpoints. Queries of `balance_at`
+    /// replay up to `spacing` records. The `balances` are used to start the index if the ledger is
+    /// not indexed yet, see `backfill_indexes`.
+    pub fn set_checkpoint_spacing(&mut self, spacing: u64, balances: &HashMap<Principal, Nat>) {
+        if self.balance_index.is_none() {
+            self.start_indexes(balances);
+        }
+
+        if let Some(index) = &mut self.balance_index {
+            index.set_spacing(spacing);
+        }
+    }
+
+    /// Returns the fee payments of the transactions with the ids from `start` to `end`, not
+    /// including `end`.
+    pub fn fee_payments_in(&self, start: u64, end: u64) -> Vec<FeePayment> {
+        let payments = &self.fee_payments.payments;
+        let first = payments.partition_point(|payment| to_id(&payment.tx_id) < start);
+        let last = payments.partition_point(|payment| to_id(&payment.tx_id) < end);
+        payments[first..last].to_vec()
+    }
+
+    /// Adds the `record` restored from a backup. The record keeps its id, timestamp and fee
+    /// breakdown, and the `fee_payment` is the share of its fee credited to the `fee_to`
+    /// principal.
+    pub fn restore(&mut self, record: TxRecord, fee_payment: Option<FeePayment>) {
+        let fee = TxFee {
+            total: record.fee.clone(),
+            recipient: fee_payment.as_ref().map(|payment| payment.recipient),
+            breakdown: record.fee_breakdown.clone(),
+        };
+        self.push_with_fee(record, fee);
+    }
+
+    /// Non-zero balances of all the principals replayed from the records added to the balance
+    /// index. The ledgers restored from a backup are indexed from the first record.
+    pub fn replayed_balances(&self) -> HashMap<Principal, Nat> {
+        self.balance_index
+            .iter()
+            .flat_map(BalanceIndex::balances)
+            .filter(|(_, balance)| **balance > 0u32)
+            .map(|(who, balance)| (*who, balance.clone()))
+            .collect()
+    }
+
+    /// Sum of all the fee shares credited to the `fee_to` principals.
+    pub fn total_fee_payments(&self) -> Nat {
+        self.fee_payments.total.clone()
+    }
+
+    /// Adds the `record` charged with the `fee`. The `fee_to` share of the fee is added to the
+    /// fee payments of the recipient.
+    fn push_with_fee(&mut self, mut record: TxRecord, fee: TxFee) {
+        if let (Some(recipient), Some(breakdown)) = (fee.recipient, &fee.breakdown) {
+            if breakdown.fee_to > 0u32 {
+                self.fee_payments.total += breakdown.fee_to.clone();
+                self.fee_payments.payments.push(FeePayment {
+                    tx_id: record.index.clone(),
+                    recipient,
+                    amount: breakdown.fee_to.clone(),
+                });
+            }
+        }
+
+        record.fee_breakdown = fee.breakdown;
+        self.push(record);
+    }
+
+    fn push(&mut self, mut record: TxRecord) {
+        record.parent_hash = Some(self.tip_hash());
+        self.last_hash = Some(tx_hash(&record));
+
+        // Empty histories are indexed from the next record, and the records of the older ledgers
+        // are added with `backfill_user_index` and `backfill_caller_index` before the new ones.
+        if self.history.is_empty() {
+            self.user_index.get_or_insert_with(HashMap::new);
+            self.caller_index.get_or_insert_with(HashMap::new);
+        }
+        if let (Some(index), None) = (&mut self.user_index, self.user_index_next) {
+            for user in related_principals(&record) {
+                index.entry(user).or_default().add(&record);
+            }
+        }
+        if let (Some(callers), None, Some(caller)) = (
+            &mut self.caller_index,
+            self.caller_index_next,
+            record.caller,
+        ) {
+            callers
+                .entry(caller)
+                .or_default()
+                .push(to_id(&record.index));
+        }
+
+        // The records kept in the history are counted with `backfill_operation_counts`.
+        if self.operation_counts.is_none() {
+            self.start_operation_counts();
+        }
+        if let Some(counts) = &mut self.operation_counts {
+            count_operation(counts, &record);
+        }
+
+        let (first_id, len) = (self.first_id(), self.len());
+        let stats = self
+            .user_stats
+            .get_or_insert_with(|| UserStatsIndex::new(first_id, len));
+        add_to_user_stats(&mut stats.users, &record);
+
+        // Empty ledgers are indexed from the first record, and the records of the older ledgers
+        // are added with `backfill_indexes` before the new ones.
+        let payments = &self.fee_payments.payments;
+        let recipient = fee_recipient(payments, to_id(&record.index));
+        if len == 0 {
+            self.balance_index
+                .get_or_insert_with(|| BalanceIndex::build(0, &[], payments));
+            self.vote_index.get_or_insert_with(VoteIndex::default);
+        }
+        if let (Some(balances), Some(votes)) = (&mut self.balance_index, &mut self.vote_index) {
+            if balances.next_id() == to_id(&record.index) {
+                balances.add(&record, recipient);
+                votes.add(&record, recipient, balances);
+            }
+        }
+
         self.history.push(record);
-        if self.len() > MAX_HISTORY_LENGTH + HISTORY_REMOVAL_BATCH_SIZE {
+        certify_tip(self);
+        if self.len() > (MAX_HISTORY_LENGTH + HISTORY_REMOVAL_BATCH_SIZE) as u64 {
             // We remove first `HISTORY_REMOVAL_BATCH_SIZE` from the history at one go, to prevent
             // often relocation of the history vec.
             // This removal code can later be changed to moving old history records into another
             // storage.
-            self.history = self.history[HISTORY_REMOVAL_BATCH_SIZE..].into();
+            let removed: Vec<_> = self.history.drain(..HISTORY_REMOVAL_BATCH_SIZE).collect();
             self.vec_offset += HISTORY_REMOVAL_BATCH_SIZE;
+            self.remove_from_index(&removed);
+        }
+    }
+
+    fn remove_from_index(&mut self, removed: &[TxRecord]) {
+        let offset = to_id(&self.vec_offset);
+        let payments = &mut self.fee_payments.payments;
+        let removed_payments = payments.partition_point(|payment| to_id(&payment.tx_id) < offset);
+        payments.drain(..removed_payments);
+
+        if let Some(balances) = &mut self.balance_index {
+            balances.remove_before(offset);
+        }
+
+        if let Some(votes) = &mut self.vote_index {
+            votes.remove_before(offset);
+        }
+
+        if let Some(callers) = &mut self.caller_index {
+            let removed_callers: HashSet<_> = removed.iter().filter_map(|tx| tx.caller).collect();
+            for caller in removed_callers {
+                if let Some(ids) = callers.get_mut(&caller) {
+                    ids.retain(|id| *id >= offset);
+                }
+            }
+        }
+
+        let index = match &mut self.user_index {
+            Some(index) => index,
+            None => return,
+        };
+
+        let users: HashSet<_> = removed.iter().flat_map(related_principals).collect();
+        for user in users {
+            if let Some(user) = index.get_mut(&user) {
+                user.ids.retain(|id| *id >= offset);
+            }
+        }
+    }
+}
+
+/// Converts a transaction id received through the candid interface to the id used by the ledger.
+/// Ids that don't fit into `u64` are saturated to `u64::MAX`, which is never a valid id.
+pub fn to_id(id: &Nat) -> u64 {
+    id.0.to_u64().unwrap_or(u64::MAX)
+}
+
+/// Returns the hash of the transaction record: sha256 over the CBOR encoding of the tuple
+/// `(index, caller, from, to, amount, fee, timestamp, status, operation, reason, memo,
+/// parent_hash)`. Numbers are encoded as their big-endian bytes (two's complement for the
+/// timestamp), principals as their bytes, and `status` and `operation` as the fixed tags returned by
+/// `status_tag` and `operation_tag`.
+///
+/// For the records with a `config` change the encoded value is the pair of the above tuple and the
+/// tuple `(setting, old_value, new_value)`, so the hashes of the other records are not affected.
+pub fn tx_hash(tx: &TxRecord) -> Vec<u8> {
+    let fields = (
+        tx.index.0.to_bytes_be(),
+        tx.caller.as_ref().map(|caller| caller.as_slice()),
+        tx.from.as_slice(),
+        tx.to.as_slice(),
+        tx.amount.0.to_bytes_be(),
+        tx.fee.0.to_bytes_be(),
+        tx.timestamp.0.to_signed_bytes_be(),
+        status_tag(&tx.status),
+        operation_tag(tx.operation),
+        &tx.reason,
+        &tx.memo,
+        &tx.parent_hash,
+    );
+    // The fields added later are hashed only if they are set, so the hashes of the older records
+    // don't change.
+    let config = tx
+        .config
+        .as_ref()
+        .map(|config| (&config.setting, &config.old_value, &config.new_value));
+    let fee_breakdown = tx.fee_breakdown.as_ref().map(|breakdown| {
+        (
+            breakdown.fee_to.0.to_bytes_be(),
+            breakdown.auction.0.to_bytes_be(),
+            breakdown.burned.0.to_bytes_be(),
+        )
+    });
+    let encoded = match (config, fee_breakdown) {
+        (None, None) => serde_cbor::to_vec(&fields),
+        (Some(config), None) => serde_cbor::to_vec(&(fields, config)),
+        (config, Some(fee_breakdown)) => serde_cbor::to_vec(&(fields, config, fee_breakdown)),
+    }
+    .expect("transaction record is always serializable");
+
+    Sha256::digest(&encoded).to_vec()
+}
+
+/// Tag of the transaction `status` in the transaction hash. The error of a failed transaction is not
+/// hashed, as the failed transactions don't change the balances. The tags must never change, or
+/// the hashes of the recorded transactions would not match.
+fn status_tag(status: &TransactionStatus) -> &'static str {
+    match status {
+        TransactionStatus::Succeeded => "Succeeded",
+        TransactionStatus::Failed { .. } => "Failed",
+    }
+}
+
+/// Tag of the `operation` in the transaction hash. The tags must never change, or the hashes of the
+/// recorded transactions would not match.
+fn operation_tag(operation: Operation) -> &'static str {
+    match operation {
+        Operation::Approve => "Approve",
+        Operation::Mint => "Mint",
+        Operation::Transfer => "Transfer",
+        Operation::TransferFrom => "TransferFrom",
+        Operation::Burn => "Burn",
+        Operation::Auction => "Auction",
+        Operation::Clawback => "Clawback",
+        Operation::TransferFromIncludeFee => "TransferFromIncludeFee",
+        Operation::ApproveUnlimited => "ApproveUnlimited",
+        Operation::AdminRevoke => "AdminRevoke",
+        Operation::AddMinter => "AddMinter",
+        Operation::RemoveMinter => "RemoveMinter",
+        Operation::Configure => "Configure",
+        Operation::Delegate => "Delegate",
+    }
+}
+
+/// Returns the principals the transaction is related to: the sender, the receiver and the caller.
+fn related_principals(tx: &TxRecord) -> Vec<Principal> {
+    let mut principals = vec![tx.from];
+    if tx.to != tx.from {
+        principals.push(tx.to);
+    }
+    if let Some(caller) = tx.caller {
+        if !principals.contains(&caller) {
+            principals.push(caller);
+        }
+    }
+
+    principals
+}
+
+fn add_to_user_stats(users: &mut HashMap<Principal, UserStats>, tx: &TxRecord) {
+    if !is_aggregated(tx) {
+        return;
+    }
+
+    for user in related_principals(tx) {
+        users.entry(user).or_default().add(user, tx);
+    }
+}
+
+/// Returns `true` if the transaction is counted in the user totals and stats. Failed transactions
+/// and configuration changes are not counted.
+fn is_aggregated(tx: &TxRecord) -> bool {
+    !tx.status.is_failed() && tx.operation != Operation::Configure
+}
+
+/// Returns the size of the record encoded with candid. The size includes the type table, so it is
+/// an upper bound of the size the record takes in a reply with many records.
+fn encoded_size(tx: &TxRecord) -> usize {
+    candid::Encode!(tx).map_or(0, |encoded| encoded.len())
+}
+
+/// Returns the number of bytes allocated by the record outside of its `TxRecord` struct.
+fn record_heap_size(tx: &TxRecord) -> usize {
+    let nat_size = |value: &Nat| (value.0.bits() as usize + 7) / 8;
+
+    let mut size = nat_size(&tx.index) + nat_size(&tx.amount) + nat_size(&tx.fee);
+    size += tx.reason.as_ref().map_or(0, String::len);
+    size += tx.memo.as_ref().map_or(0, Vec::len);
+    size += tx.parent_hash.as_ref().map_or(0, Vec::len);
+    if let Some(config) = &tx.config {
+        size += config.setting.len() + config.old_value.len() + config.new_value.len();
+    }
+    if let Some(breakdown) = &tx.fee_breakdown {
+        size += nat_size(&breakdown.fee_to)
+            + nat_size(&breakdown.auction)
+            + nat_size(&breakdown.burned);
+    }
+
+    size
+}
+
+fn is_related(tx: &TxRecord, who: Principal) -> bool {
+    tx.from == who || tx.to == who || tx.caller == Some(who)
+}
+
+/// Returns up to `max_records` records of the `history` starting with the `first_id` to be added
+/// to an index backfilled up to the `next` id, and the id of the next record to be added after
+/// them. The next id is `None` if all the records are added.
+fn backfill_batch(
+    history: &[TxRecord],
+    first_id: u64,
+    next: u64,
+    max_records: usize,
+) -> (&[TxRecord], Option<u64>) {
+    let len = first_id + history.len() as u64;
+
+    // The records removed from the history can't be added anymore.
+    let start = next.max(first_id).min(len);
+    let end = start.saturating_add(max_records as u64).min(len);
+    let records = &history[(start - first_id) as usize..(end - first_id) as usize];
+    (records, if end < len { Some(end) } else { None })
+}
+
+fn count_operation(counts: &mut HashMap<String, u64>, tx: &TxRecord) {
+    if !tx.status.is_failed() {
+        *counts.entry(format!("{:?}", tx.operation)).or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::mock_principals::{alice, bob, john, xtc};
+    use ic_kit::MockContext;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// Counts the allocations made by every thread, so the tests can check how many allocations
+    /// the ledger queries make.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = Cell::new(0);
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // The counter is not available while the thread is being destroyed.
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Returns the result of `f` and the number of the allocations it made.
+    fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = ALLOCATIONS.with(Cell::get);
+        let result = f();
+        (result, ALLOCATIONS.with(Cell::get) - before)
+    }
+
+    fn ids(records: Vec<TxRecord>) -> Vec<Nat> {
+        records.into_iter().map(|tx| tx.index).collect()
+    }
+
+    #[test]
+    fn user_transactions() {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        ledger.mint(alice(), alice(), Nat::from(1000), None);
+        ledger.transfer(alice(), bob(), Nat::from(100), Nat::from(0));
+        ledger.approve(bob(), john(), Nat::from(50), Nat::from(0));
+        ledger.transfer_from(john(), bob(), alice(), Nat::from(50), Nat::from(0));
+        ledger.burn(alice(), Nat::from(10));
+
+        assert_eq!(
+            ids(ledger.get_user_transactions(alice(), 0, 10)),
+            vec![0u32, 1, 3, 4]
+        );
+        assert_eq!(
+            ids(ledger.get_user_transactions(bob(), 0, 10)),
+            vec![1u32, 2, 3]
+        );
+        assert_eq!(
+            ids(ledger.get_user_transactions(john(), 0, 10)),
+            vec![2u32, 3]
+        );
+        assert_eq!(
+            ids(ledger.get_user_transactions(alice(), 1, 2)),
+            vec![1u32, 3]
+        );
+        assert_eq!(
+            ids(ledger.get_user_transactions(alice(), 10, 2)),
+            vec![] as Vec<Nat>
+        );
+    }
+
+    #[test]
+    fn user_index_backfilled() {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        ledger.mint(alice(), alice(), Nat::from(1000), None);
+        ledger.transfer(alice(), bob(), Nat::from(100), Nat::from(0));
+
+        // Ledger saved before the index was added.
+        ledger.user_index = None;
+        assert_eq!(ids(ledger.get_user_transactions(bob(), 0, 10)), vec![1u32]);
+        assert_eq!(ledger.user_totals(alice()), (2, Nat::from(1100)));
+
+        // The new records don't rebuild the index, the history is scanned until the backfill is
+        // finished.
+        ledger.transfer(bob(), john(), Nat::from(10), Nat::from(0));
+        assert!(ledger.user_index.is_none());
+        assert!(ledger.backfill_pending());
+        assert_eq!(
+            ids(ledger.get_user_transactions(bob(), 0, 10)),
+            vec![1u32, 2]
+        );
+
+        assert!(!ledger.backfill_user_index(2));
+        assert!(ledger.user_index.is_some());
+        assert_eq!(
+            ids(ledger.get_user_transactions(bob(), 0, 10)),
+            vec![1u32, 2]
+        );
+        assert_eq!(ledger.user_totals(bob()), (2, Nat::from(110)));
+
+        // The records pushed during the backfill are added by the backfill too.
+        ledger.transfer(john(), bob(), Nat::from(5), Nat::from(0));
+        assert!(ledger.backfill_user_index(2));
+        assert!(ledger.backfill_user_index(2));
+
+        ledger.transfer(bob(), alice(), Nat::from(1), Nat::from(0));
+        assert_eq!(
+            ids(ledger.get_user_transactions(bob(), 0, 10)),
+            vec![1u32, 2, 3, 4]
+        );
+        assert_eq!(ledger.user_totals(alice()), (3, Nat::from(1101)));
+        assert_eq!(ledger.user_totals(bob()), (4, Nat::from(116)));
+        assert_eq!(ledger.user_totals(john()), (2, Nat::from(15)));
+        assert_eq!(
+            ledger.user_index.as_ref().unwrap()[&bob()].ids,
+            vec![1, 2, 3, 4]
+        );
+    }
+
+    fn page_ids(page: &TransactionsPage) -> Vec<Nat> {
+        page.records.iter().map(|tx| tx.index.clone()).collect()
+    }
+
+    fn mixed_ledger() -> Ledger {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        ledger.mint(alice(), alice(), Nat::from(1000), None);
+        ledger.transfer(alice(), bob(), Nat::from(100), Nat::from(0));
+        ledger.approve(bob(), john(), Nat::from(50), Nat::from(0));
+        ledger.transfer_from(john(), bob(), alice(), Nat::from(50), Nat::from(0));
+        ledger.burn(alice(), Nat::from(10));
+        ledger.mint(alice(), alice(), Nat::from(10), None);
+        ledger
+    }
+
+    fn consistent(checked: u64, minted: u32, burned: u32, next: Option<u32>) -> LedgerCheckResult {
+        LedgerCheckResult::Consistent {
+            checked,
+            minted: Nat::from(minted),
+            burned: Nat::from(burned),
+            next: next.map(Nat::from),
+        }
+    }
+
+    fn inconsistent(id: u32, issue: LedgerIssue) -> LedgerCheckResult {
+        LedgerCheckResult::Inconsistent {
+            id: Nat::from(id),
+            issue,
+        }
+    }
+
+    /// Ledger with `count` transfers with memos of `memo_len` bytes after the initial mint.
+    fn memo_ledger(count: usize, memo_len: usize) -> Ledger {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        ledger.mint(alice(), alice(), Nat::from(1000), None);
+        for i in 0..count {
+            ledger.transfer_with_memo(
+                alice(),
+                bob(),
+                Nat::from(1),
+                Nat::from(0),
+                Some(vec![i as u8; memo_len]),
+            );
+        }
+        ledger
+    }
+
+    #[test]
+    fn blocks_limited_by_bytes() {
+        let ledger = memo_ledger(10, 10_000);
+        let record_size = encoded_size(ledger.get(1).unwrap());
+        assert!(record_size > 10_000);
+
+        let page = ledger.get_blocks(1, 100, record_size * 3 + record_size / 2);
+        assert_eq!(ids(page.records.clone()), vec![1u32, 2, 3]);
+        assert_eq!(page.last_id, Nat::from(10));
+        assert_eq!(page.remaining, Nat::from(7));
+        let size: usize = page.records.iter().map(encoded_size).sum();
+        assert!(size <= record_size * 3 + record_size / 2);
+    }
+
+    #[test]
+    fn blocks_limited_by_count() {
+        let ledger = memo_ledger(10, 100);
+        let page = ledger.get_blocks(0, 4, 1_000_000);
+        assert_eq!(page.records.len(), 4);
+        assert_eq!(page.remaining, Nat::from(7));
+
+        let page = ledger.get_blocks(8, 4, 1_000_000);
+        assert_eq!(page.records.len(), 3);
+        assert_eq!(page.remaining, Nat::from(0));
+        assert_eq!(page.last_id, Nat::from(10));
+    }
+
+    #[test]
+    fn blocks_always_make_progress() {
+        let ledger = memo_ledger(3, 10_000);
+        let page = ledger.get_blocks(2, 100, 10);
+        assert_eq!(page.records.len(), 1);
+        assert_eq!(page.records[0].index, Nat::from(2));
+        assert_eq!(page.remaining, Nat::from(1));
+
+        let page = ledger.get_blocks(4, 100, 10);
+        assert!(page.records.is_empty());
+        assert_eq!(page.remaining, Nat::from(0));
+    }
+
+    #[test]
+    fn verify_consistent_ledger() {
+        let ledger = mixed_ledger();
+        assert_eq!(ledger.verify(0, 100), consistent(6, 1010, 10, None));
+        assert_eq!(ledger.verify(0, 4), consistent(4, 1000, 0, Some(4)));
+        assert_eq!(ledger.verify(4, 4), consistent(2, 10, 10, None));
+        assert_eq!(ledger.verify(6, 4), consistent(0, 0, 0, None));
+    }
+
+    #[test]
+    fn verify_trimmed_ledger() {
+        let mut ledger = mixed_ledger();
+        ledger.remove_before(2);
+        assert_eq!(ledger.verify(0, 100), consistent(4, 10, 10, None));
+    }
+
+    #[test]
+    fn verify_detects_corruption() {
+        let corrupted = |corrupt: fn(&mut Ledger)| {
+            let mut ledger = mixed_ledger();
+            corrupt(&mut ledger);
+            ledger.verify(0, 100)
+        };
+
+        assert_eq!(
+            corrupted(|ledger| ledger.history[2].amount = Nat::from(51)),
+            inconsistent(3, LedgerIssue::ParentHashMismatch)
+        );
+        assert_eq!(
+            corrupted(|ledger| ledger.history[5].amount = Nat::from(1_000_000)),
+            inconsistent(5, LedgerIssue::TipHashMismatch)
+        );
+        assert_eq!(
+            corrupted(|ledger| ledger.history[1].index = Nat::from(7)),
+            inconsistent(
+                1,
+                LedgerIssue::WrongIndex {
+                    stored: Nat::from(7)
+                }
+            )
+        );
+        assert_eq!(
+            corrupted(|ledger| ledger.history[2].timestamp = Int::from(u64::MAX)),
+            inconsistent(3, LedgerIssue::TimestampDecreased)
+        );
+        assert_eq!(
+            corrupted(|ledger| {
+                ledger.history[1].fee_breakdown = Some(FeeBreakdown {
+                    fee_to: Nat::from(1),
+                    ..Default::default()
+                })
+            }),
+            inconsistent(1, LedgerIssue::FeeBreakdownMismatch)
+        );
+        assert_eq!(
+            corrupted(|ledger| ledger.history[4].parent_hash = None),
+            inconsistent(4, LedgerIssue::ParentHashMismatch)
+        );
+    }
+
+    #[test]
+    fn verify_resumes_after_corruption() {
+        let mut ledger = mixed_ledger();
+        ledger.history[2].amount = Nat::from(51);
+        assert_eq!(ledger.verify(0, 3), consistent(3, 1000, 0, Some(3)));
+        assert_eq!(
+            ledger.verify(3, 3),
+            inconsistent(3, LedgerIssue::ParentHashMismatch)
+        );
+    }
+
+    #[test]
+    fn page_filtered_by_operation() {
+        let ledger = mixed_ledger();
+        let mint = Some(Operation::Mint);
+
+        let page = ledger.get_page(None, 10, TransactionOrder::Ascending, mint, None, 100);
+        assert_eq!(page_ids(&page), vec![0u32, 5]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = ledger.get_page(None, 1, TransactionOrder::Ascending, mint, None, 100);
+        assert_eq!(page_ids(&page), vec![0u32]);
+        assert_eq!(page.next_cursor, Some(Nat::from(1)));
+
+        let page = ledger.get_page(
+            None,
+            1,
+            TransactionOrder::Descending,
+            Some(Operation::Burn),
+            None,
+            100,
+        );
+        assert_eq!(page_ids(&page), vec![4u32]);
+        assert_eq!(page.next_cursor, Some(Nat::from(3)));
+    }
+
+    #[test]
+    fn page_scan_bound() {
+        let ledger = mixed_ledger();
+        let mint = Some(Operation::Mint);
+
+        let page = ledger.get_page(None, 10, TransactionOrder::Ascending, mint, None, 2);
+        assert_eq!(page_ids(&page), vec![0u32]);
+        assert_eq!(page.next_cursor, Some(Nat::from(2)));
+
+        // No matching records in the scanned range, but the cursor still moves forward.
+        let page = ledger.get_page(
+            page.next_cursor.as_ref().map(to_id),
+            10,
+            TransactionOrder::Ascending,
+            mint,
+            None,
+            2,
+        );
+        assert!(page.records.is_empty());
+        assert_eq!(page.next_cursor, Some(Nat::from(4)));
+
+        let page = ledger.get_page(
+            page.next_cursor.as_ref().map(to_id),
+            10,
+            TransactionOrder::Ascending,
+            mint,
+            None,
+            2,
+        );
+        assert_eq!(page_ids(&page), vec![5u32]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn user_page_filtered_by_operation() {
+        let ledger = mixed_ledger();
+        let mint = Some(Operation::Mint);
+
+        let page = ledger.get_user_page(alice(), 0, 10, TransactionOrder::Ascending, mint, 100);
+        assert_eq!(page_ids(&page), vec![0u32, 5]);
+        assert_eq!(page.total, Nat::from(5));
+        assert_eq!(page.next_cursor, None);
+
+        let page = ledger.get_user_page(alice(), 0, 10, TransactionOrder::Ascending, mint, 2);
+        assert_eq!(page_ids(&page), vec![0u32]);
+        assert_eq!(page.next_cursor, Some(Nat::from(2)));
+
+        let page = ledger.get_user_page(alice(), 2, 10, TransactionOrder::Ascending, mint, 2);
+        assert!(page.records.is_empty());
+        assert_eq!(page.next_cursor, Some(Nat::from(4)));
+
+        let page = ledger.get_user_page(alice(), 4, 10, TransactionOrder::Ascending, mint, 2);
+        assert_eq!(page_ids(&page), vec![5u32]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = ledger.get_user_page(bob(), 0, 10, TransactionOrder::Ascending, mint, 100);
+        assert!(page.records.is_empty());
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn get_range_borrows_history() {
+        let mut ledger = mixed_ledger();
+        ledger.remove_before(2);
+        assert_eq!(ledger.first_id(), 2);
+        assert_eq!(ledger.len(), 6);
+
+        // The range points into the history, so no records are copied.
+        let range = ledger.get_range(3, 2);
+        assert!(std::ptr::eq(range.as_ptr(), &ledger.history[1]));
+        assert_eq!(ids(range.to_vec()), vec![3u32, 4]);
+
+        assert_eq!(ids(ledger.get_range(0, 2).to_vec()), vec![2u32, 3]);
+        assert_eq!(ids(ledger.get_range(5, 10).to_vec()), vec![5u32]);
+        assert!(ledger.get_range(6, 10).is_empty());
+        assert!(ledger.get_range(u64::MAX, usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn get_range_does_not_allocate() {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        for _ in 0..1000 {
+            ledger.transfer(alice(), bob(), Nat::from(100), Nat::from(1));
+        }
+
+        let (len, allocations) = count_allocations(|| ledger.get_range(100, 500).len());
+        assert_eq!(len, 500);
+        assert_eq!(allocations, 0);
+
+        let (len, allocations) = count_allocations(|| ledger.get_range_rev(600, 500).count());
+        assert_eq!(len, 500);
+        assert_eq!(allocations, 0);
+
+        // Copying the range, as the queries did before the records were borrowed, allocates the
+        // vector and the numbers of every record.
+        let (records, allocations) = count_allocations(|| ledger.get_range(100, 500).to_vec());
+        assert_eq!(records.len(), 500);
+        assert!(allocations > 500, "{}", allocations);
+    }
+
+    #[test]
+    fn nat_user_index_migrated() {
+        let mut ledger = mixed_ledger();
+        let index = ledger.user_index.take().unwrap();
+
+        // The index as saved by the versions of the canister with the `Nat` ids.
+        ledger.user_index_v1 = Some(
+            index
+                .iter()
+                .map(|(user, transactions)| {
+                    let saved = UserTransactionsV1 {
+                        ids: transactions.ids.iter().copied().map(Nat::from).collect(),
+                        count: transactions.count,
+                        amount: transactions.amount.clone(),
+                    };
+                    (*user, saved)
+                })
+                .collect(),
+        );
+
+        // The migration is done when the state is decoded after upgrade.
+        let state = crate::state::CanisterState {
+            ledger,
+            ..Default::default()
+        };
+        let encoded = candid::encode_one(&state).unwrap();
+        let state: crate::state::CanisterState = candid::decode_one(&encoded).unwrap();
+        let mut ledger = state.ledger;
+        assert!(ledger.user_index_v1.is_none());
+        assert_eq!(ledger.user_index.as_ref(), Some(&index));
+        assert_eq!(
+            ledger.user_index.as_ref().unwrap()[&bob()].ids,
+            vec![1, 2, 3]
+        );
+
+        // The converted index is saved under the new name and is not converted again.
+        ledger.migrate_user_index();
+        assert_eq!(ledger.user_index.as_ref(), Some(&index));
+        assert_eq!(
+            ids(ledger.get_user_transactions(alice(), 0, 10)),
+            vec![0u32, 1, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn reverse_iteration() {
+        let mut ledger = mixed_ledger();
+        assert_eq!(
+            ids(ledger.iter_rev().cloned().collect()),
+            vec![5u32, 4, 3, 2, 1, 0]
+        );
+
+        ledger.remove_before(2);
+        assert_eq!(
+            ids(ledger.iter_rev().cloned().collect()),
+            vec![5u32, 4, 3, 2]
+        );
+
+        let rev = |end, limit| ids(ledger.get_range_rev(end, limit).cloned().collect());
+        assert_eq!(rev(4, 2), vec![4u32, 3]);
+        assert_eq!(rev(3, 10), vec![3u32, 2]);
+        assert_eq!(rev(u64::MAX, 3), vec![5u32, 4, 3]);
+        assert_eq!(rev(2, 10), vec![2u32]);
+        assert!(rev(1, 10).is_empty());
+        assert!(rev(5, 0).is_empty());
+    }
+
+    #[test]
+    fn descending_user_page() {
+        let ledger = mixed_ledger();
+
+        let page = ledger.get_user_page(alice(), 0, 2, TransactionOrder::Descending, None, 100);
+        assert_eq!(page_ids(&page), vec![5u32, 4]);
+        assert_eq!(page.next_cursor, Some(Nat::from(2)));
+
+        let page = ledger.get_user_page(alice(), 2, 10, TransactionOrder::Descending, None, 100);
+        assert_eq!(page_ids(&page), vec![3u32, 1, 0]);
+        assert_eq!(page.next_cursor, None);
+
+        let mint = Some(Operation::Mint);
+        let page = ledger.get_user_page(alice(), 0, 10, TransactionOrder::Descending, mint, 100);
+        assert_eq!(page_ids(&page), vec![5u32, 0]);
+    }
+
+    #[test]
+    fn get_by_u64_id() {
+        let mut ledger = mixed_ledger();
+        ledger.remove_before(2);
+
+        assert!(ledger.get(1).is_none());
+        assert_eq!(ledger.get(2).unwrap().index, Nat::from(2));
+        assert!(ledger.get(6).is_none());
+
+        let too_large = Nat::from(u64::MAX) + Nat::from(3);
+        assert_eq!(to_id(&too_large), u64::MAX);
+        assert!(ledger.get(to_id(&too_large)).is_none());
+        assert_eq!(to_id(&Nat::from(5)), 5);
+    }
+
+    #[test]
+    fn user_index_after_removal() {
+        let mut ledger = mixed_ledger();
+        ledger.remove_before(2);
+        assert_eq!(
+            ids(ledger.get_user_transactions(alice(), 0, 10)),
+            vec![3u32, 4, 5]
+        );
+        assert_eq!(ledger.user_totals(alice()).0, 5);
+    }
+
+    /// Ledger with the transactions made by the senders themselves and by the spenders.
+    fn delegated_ledger() -> Ledger {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        ledger.mint(alice(), alice(), Nat::from(1000), None);
+        ledger.transfer(alice(), bob(), Nat::from(100), Nat::from(0));
+        ledger.transfer_from(bob(), alice(), john(), Nat::from(10), Nat::from(1));
+        ledger.transfer(bob(), john(), Nat::from(10), Nat::from(0));
+        ledger.transfer_from(john(), alice(), bob(), Nat::from(10), Nat::from(1));
+        ledger.transfer_from_include_fee(bob(), alice(), xtc(), Nat::from(10), Nat::from(1));
+        ledger
+    }
+
+    #[test]
+    fn caller_transactions() {
+        let ledger = delegated_ledger();
+
+        let page = ledger.get_caller_page(bob(), None, 10);
+        assert_eq!(page_ids(&page), vec![2u32, 3, 5]);
+        assert_eq!(page.total, Nat::from(3));
+        assert_eq!(page.next_cursor, None);
+
+        let page = ledger.get_caller_page(alice(), None, 10);
+        assert_eq!(page_ids(&page), vec![0u32, 1]);
+        assert_eq!(
+            page_ids(&ledger.get_caller_page(john(), None, 10)),
+            vec![4u32]
+        );
+        assert_eq!(ledger.get_caller_page(xtc(), None, 10).total, Nat::from(0));
+    }
+
+    #[test]
+    fn caller_transactions_paging() {
+        let ledger = delegated_ledger();
+
+        let page = ledger.get_caller_page(bob(), None, 2);
+        assert_eq!(page_ids(&page), vec![2u32, 3]);
+        assert_eq!(page.next_cursor, Some(Nat::from(5)));
+
+        let page = ledger.get_caller_page(bob(), Some(5), 2);
+        assert_eq!(page_ids(&page), vec![5u32]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = ledger.get_caller_page(bob(), Some(4), 10);
+        assert_eq!(page_ids(&page), vec![5u32]);
+    }
+
+    #[test]
+    fn caller_index_backfilled() {
+        let mut ledger = delegated_ledger();
+
+        // Ledger saved before the index was added.
+        ledger.caller_index = None;
+        assert_eq!(
+            page_ids(&ledger.get_caller_page(bob(), None, 10)),
+            vec![2u32, 3, 5]
+        );
+
+        // The history is scanned until the backfill is finished.
+        ledger.transfer_from(bob(), john(), alice(), Nat::from(5), Nat::from(0));
+        assert!(ledger.caller_index.is_none());
+        assert_eq!(
+            page_ids(&ledger.get_caller_page(bob(), None, 10)),
+            vec![2u32, 3, 5, 6]
+        );
+
+        assert!(!ledger.backfill_caller_index(4));
+        assert_eq!(
+            page_ids(&ledger.get_caller_page(bob(), None, 10)),
+            vec![2u32, 3, 5, 6]
+        );
+        ledger.transfer_from(bob(), john(), alice(), Nat::from(5), Nat::from(0));
+        assert!(ledger.backfill_caller_index(4));
+
+        ledger.transfer_from(bob(), john(), alice(), Nat::from(5), Nat::from(0));
+        assert_eq!(
+            ledger.caller_index.as_ref().unwrap()[&bob()],
+            vec![2, 3, 5, 6, 7, 8]
+        );
+        assert_eq!(
+            page_ids(&ledger.get_caller_page(bob(), None, 10)),
+            vec![2u32, 3, 5, 6, 7, 8]
+        );
+        assert_eq!(
+            page_ids(&ledger.get_caller_page(john(), None, 10)),
+            vec![4u32]
+        );
+    }
+
+    #[test]
+    fn past_votes_after_removal() {
+        let mut ledger = delegated_ledger();
+        ledger.delegate(bob(), john());
+        ledger.transfer(alice(), bob(), Nat::from(5), Nat::from(0));
+        let expected = ledger.past_votes(john(), 6);
+        ledger.remove_before(7);
+
+        assert_eq!(
+            ledger.past_votes(john(), 6),
+            Err(TxError::TransactionDoesNotExist)
+        );
+        assert_eq!(
+            ledger.past_votes(john(), 7),
+            expected.map(|votes| votes + Nat::from(5))
+        );
+        assert_eq!(ledger.votes(john()), ledger.past_votes(john(), 7).unwrap());
+    }
+
+    #[test]
+    fn caller_index_after_removal() {
+        let mut ledger = delegated_ledger();
+        ledger.remove_before(3);
+
+        let page = ledger.get_caller_page(bob(), None, 10);
+        assert_eq!(page_ids(&page), vec![3u32, 5]);
+        assert_eq!(page.total, Nat::from(2));
+        assert_eq!(
+            ledger.get_caller_page(alice(), None, 10).total,
+            Nat::from(0)
+        );
+    }
+
+    fn timed_ledger() -> Ledger {
+        let mut ledger = mixed_ledger();
+        for (tx, timestamp) in ledger.history.iter_mut().zip(vec![10, 20, 20, 30, 40, 50]) {
+            tx.timestamp = Int::from(timestamp);
+        }
+        ledger
+    }
+
+    #[test]
+    fn index_by_time() {
+        let ledger = timed_ledger();
+        assert_eq!(ledger.first_index_at_or_after(0), Some(0));
+        assert_eq!(ledger.first_index_at_or_after(10), Some(0));
+        assert_eq!(ledger.first_index_at_or_after(11), Some(1));
+        assert_eq!(ledger.first_index_at_or_after(20), Some(1));
+        assert_eq!(ledger.first_index_at_or_after(21), Some(3));
+        assert_eq!(ledger.first_index_at_or_after(50), Some(5));
+        assert_eq!(ledger.first_index_at_or_after(51), None);
+    }
+
+    #[test]
+    fn index_by_time_empty_ledger() {
+        let ledger = Ledger::default();
+        assert_eq!(ledger.first_index_at_or_after(0), None);
+        assert_eq!(ledger.first_index_at_or_after(u64::MAX), None);
+    }
+
+    #[test]
+    fn index_by_time_after_removal() {
+        let mut ledger = timed_ledger();
+        ledger.remove_before(2);
+        assert_eq!(ledger.first_index_at_or_after(0), Some(2));
+        assert_eq!(ledger.first_index_at_or_after(25), Some(3));
+    }
+
+    #[test]
+    fn ledger_stats_grow_with_records() {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        let stats = ledger.stats();
+        assert_eq!(stats.record_count, Nat::from(0));
+        assert_eq!(stats.heap_bytes_estimate, 0);
+        assert_eq!(stats.oldest_timestamp, 0);
+        assert_eq!(stats.newest_timestamp, 0);
+
+        let mut ledger = timed_ledger();
+        let before = ledger.stats();
+        assert_eq!(before.record_count, Nat::from(6));
+        assert_eq!(before.oldest_timestamp, 10);
+        assert_eq!(before.newest_timestamp, 50);
+
+        ledger.transfer_with_memo(
+            alice(),
+            bob(),
+            Nat::from(1),
+            Nat::from(0),
+            Some(vec![0; 1000]),
+        );
+        let after = ledger.stats();
+        assert_eq!(after.record_count, Nat::from(7));
+        assert_eq!(after.oldest_timestamp, 10);
+        assert!(after.newest_timestamp >= 50);
+        assert!(after.heap_bytes_estimate >= before.heap_bytes_estimate + 1000);
+    }
+
+    #[test]
+    fn by_time_straddling() {
+        let ledger = timed_ledger();
+
+        let page = ledger.get_by_time(15, 45, None, 10);
+        assert_eq!(page_ids(&page), vec![1u32, 2, 3, 4]);
+        assert_eq!(page.total, Nat::from(4));
+        assert_eq!(page.next_cursor, None);
+
+        let page = ledger.get_by_time(0, 25, None, 10);
+        assert_eq!(page_ids(&page), vec![0u32, 1, 2]);
+
+        let page = ledger.get_by_time(45, 100, None, 10);
+        assert_eq!(page_ids(&page), vec![5u32]);
+
+        // The end of the range is not included.
+        let page = ledger.get_by_time(20, 30, None, 10);
+        assert_eq!(page_ids(&page), vec![1u32, 2]);
+    }
+
+    #[test]
+    fn by_time_outside_of_history() {
+        let ledger = timed_ledger();
+
+        let page = ledger.get_by_time(0, 10, None, 10);
+        assert!(page.records.is_empty());
+        assert_eq!(page.total, Nat::from(0));
+        assert_eq!(page.next_cursor, None);
+
+        let page = ledger.get_by_time(51, 100, None, 10);
+        assert!(page.records.is_empty());
+        assert_eq!(page.total, Nat::from(0));
+        assert_eq!(page.next_cursor, None);
+
+        let page = ledger.get_by_time(40, 20, None, 10);
+        assert!(page.records.is_empty());
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn by_time_cursor() {
+        let ledger = timed_ledger();
+
+        let page = ledger.get_by_time(15, 100, None, 2);
+        assert_eq!(page_ids(&page), vec![1u32, 2]);
+        assert_eq!(page.total, Nat::from(5));
+        assert_eq!(page.next_cursor, Some(Nat::from(3)));
+
+        let page = ledger.get_by_time(15, 100, page.next_cursor.as_ref().map(to_id), 2);
+        assert_eq!(page_ids(&page), vec![3u32, 4]);
+        assert_eq!(page.next_cursor, Some(Nat::from(5)));
+
+        let page = ledger.get_by_time(15, 100, page.next_cursor.as_ref().map(to_id), 2);
+        assert_eq!(page_ids(&page), vec![5u32]);
+        assert_eq!(page.next_cursor, None);
+
+        // A cursor before the range starts from the beginning of the range.
+        let page = ledger.get_by_time(15, 100, Some(0), 1);
+        assert_eq!(page_ids(&page), vec![1u32]);
+
+        let page = ledger.get_by_time(15, 100, Some(100), 1);
+        assert!(page.records.is_empty());
+        assert_eq!(page.next_cursor, None);
+    }
+
+    /// Recomputes the hash chain of the history and returns the id of the first record whose
+    /// parent hash doesn't match the hash of the previous record.
+    fn first_broken_link(ledger: &Ledger) -> Option<Nat> {
+        let mut expected = GENESIS_HASH.to_vec();
+        for tx in &ledger.history {
+            if tx.parent_hash.as_ref() != Some(&expected) {
+                return Some(tx.index.clone());
+            }
+
+            let mut hasher = Sha256::new();
+            hasher.update(
+                serde_cbor::to_vec(&(
+                    tx.index.0.to_bytes_be(),
+                    tx.caller.as_ref().map(|caller| caller.as_slice()),
+                    tx.from.as_slice(),
+                    tx.to.as_slice(),
+                    tx.amount.0.to_bytes_be(),
+                    tx.fee.0.to_bytes_be(),
+                    tx.timestamp.0.to_signed_bytes_be(),
+                    format!("{:?}", tx.status),
+                    format!("{:?}", tx.operation),
+                    &tx.reason,
+                    &tx.memo,
+                    &tx.parent_hash,
+                ))
+                .unwrap(),
+            );
+            expected = hasher.finalize().to_vec();
+        }
+
+        if ledger.tip_hash() != expected {
+            return Some(Nat::from(ledger.len()));
+        }
+
+        None
+    }
+
+    #[test]
+    fn hash_chain() {
+        let ledger = mixed_ledger();
+        assert_eq!(ledger.history[0].parent_hash, Some(GENESIS_HASH.to_vec()));
+        assert_eq!(first_broken_link(&ledger), None);
+        assert_eq!(ledger.tip_hash(), tx_hash(&ledger.history[5]));
+        assert_ne!(ledger.tip_hash(), GENESIS_HASH.to_vec());
+    }
+
+    #[test]
+    fn hash_chain_detects_mutation() {
+        let mut ledger = mixed_ledger();
+        ledger.history[2].amount = Nat::from(1_000_000);
+        assert_eq!(first_broken_link(&ledger), Some(Nat::from(3)));
+
+        let mut ledger = mixed_ledger();
+        ledger.history[5].to = john();
+        assert_eq!(first_broken_link(&ledger), Some(Nat::from(6)));
+    }
+
+    #[test]
+    fn hash_ignores_failure_error() {
+        MockContext::new().inject();
+        let mut tx = TxRecord::transfer(Nat::from(0), alice(), bob(), Nat::from(10), Nat::from(0));
+        let succeeded = tx_hash(&tx);
+
+        tx.status = TransactionStatus::Failed {
+            error: TxError::InsufficientBalance,
+        };
+        let failed = tx_hash(&tx);
+        assert_ne!(failed, succeeded);
+
+        tx.status = TransactionStatus::Failed {
+            error: TxError::AmountTooSmall,
+        };
+        assert_eq!(tx_hash(&tx), failed);
+    }
+
+    #[test]
+    fn hash_chain_starts_after_unhashed_records() {
+        let mut ledger = mixed_ledger();
+
+        // Ledger saved before the records were hash-chained.
+        for tx in &mut ledger.history {
+            tx.parent_hash = None;
+        }
+        ledger.last_hash = None;
+        assert_eq!(ledger.tip_hash(), GENESIS_HASH.to_vec());
+
+        ledger.transfer(alice(), bob(), Nat::from(10), Nat::from(0));
+        let tx = ledger.history.last().unwrap();
+        assert_eq!(tx.parent_hash, Some(GENESIS_HASH.to_vec()));
+        assert_eq!(ledger.tip_hash(), tx_hash(tx));
+    }
+
+    /// Appends `count` pseudo-random records of all kinds to the ledger, driven by the `seed`.
+    fn add_random_records(ledger: &mut Ledger, seed: u64, count: usize) {
+        let users = [alice(), bob(), john(), xtc()];
+        let mut state = seed;
+        let mut next = move |n: u64| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) % n
+        };
+
+        for _ in 0..count {
+            let from = users[next(4) as usize];
+            let to = users[next(4) as usize];
+            let caller = users[next(4) as usize];
+            let amount = Nat::from(next(1000));
+            let fee = Nat::from(next(10));
+            match next(11) {
+                0 => ledger.transfer(from, to, amount, fee),
+                1 => ledger.transfer_from(caller, from, to, amount, fee),
+                2 => ledger.transfer_from_include_fee(caller, from, to, amount, fee),
+                3 => ledger.approve(from, to, amount, fee),
+                4 => ledger.approve_unlimited(from, to, fee),
+                5 => ledger.mint(caller, to, amount, None),
+                6 => ledger.burn(from, amount),
+                7 => ledger.burn_from(caller, from, amount),
+                8 => ledger.clawback(caller, from, to, amount),
+                9 => ledger.auction(from, to, amount),
+                _ => ledger.failed(
+                    Operation::Transfer,
+                    caller,
+                    from,
+                    to,
+                    amount,
+                    TxError::InsufficientBalance,
+                ),
+            };
+        }
+    }
+
+    /// Recomputes the stats of the `who` principal from the records kept in the history.
+    fn brute_force_stats(ledger: &Ledger, who: Principal) -> UserStats {
+        let mut stats = UserStats::default();
+        for tx in ledger.iter() {
+            if tx.status.is_failed() || !is_related(tx, who) {
+                continue;
+            }
+
+            let moves_tokens = !matches!(
+                tx.operation,
+                Operation::Approve
+                    | Operation::ApproveUnlimited
+                    | Operation::AdminRevoke
+                    | Operation::AddMinter
+                    | Operation::RemoveMinter
+            );
+            let is_emission = tx.operation == Operation::Mint
+                || (tx.operation == Operation::Auction && tx.from == tx.to);
+            if tx.from == who && moves_tokens && !is_emission {
+                stats.sent += tx.amount.clone();
+            }
+            if tx.to == who && moves_tokens && tx.operation != Operation::Burn {
+                stats.received += tx.amount.clone();
+            }
+            if tx.from == who {
+                stats.fees_paid += tx.fee.clone();
+            }
+
+            stats.tx_count += 1;
+            stats.first_tx.get_or_insert_with(|| tx.index.clone());
+            stats.last_tx = Some(tx.index.clone());
+        }
+
+        stats
+    }
+
+    fn assert_stats_match(ledger: &Ledger) {
+        for user in [alice(), bob(), john(), xtc()] {
+            assert_eq!(ledger.user_stats(user), brute_force_stats(ledger, user));
+        }
+    }
+
+    #[test]
+    fn user_stats_match_brute_force() {
+        for seed in 0..20 {
+            MockContext::new().inject();
+            let mut ledger = Ledger::default();
+            add_random_records(&mut ledger, seed, 100);
+            assert!(ledger.user_stats_complete());
+            assert_stats_match(&ledger);
+        }
+    }
+
+    #[test]
+    fn user_stats_of_transfer() {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        ledger.mint(alice(), alice(), Nat::from(1000), None);
+        ledger.transfer(alice(), bob(), Nat::from(100), Nat::from(5));
+        ledger.burn(bob(), Nat::from(10));
+
+        let stats = ledger.user_stats(alice());
+        assert_eq!(stats.sent, Nat::from(100));
+        assert_eq!(stats.received, Nat::from(1000));
+        assert_eq!(stats.fees_paid, Nat::from(5));
+        assert_eq!(stats.tx_count, Nat::from(2));
+        assert_eq!(stats.first_tx, Some(Nat::from(0)));
+        assert_eq!(stats.last_tx, Some(Nat::from(1)));
+
+        let stats = ledger.user_stats(bob());
+        assert_eq!(stats.sent, Nat::from(10));
+        assert_eq!(stats.received, Nat::from(100));
+        assert_eq!(stats.fees_paid, Nat::from(0));
+        assert_eq!(stats.first_tx, Some(Nat::from(1)));
+        assert_eq!(stats.last_tx, Some(Nat::from(2)));
+
+        assert_eq!(ledger.user_stats(john()), UserStats::default());
+    }
+
+    #[test]
+    fn user_stats_backfilled_in_batches() {
+        for seed in 0..10 {
+            MockContext::new().inject();
+            let mut ledger = Ledger::default();
+            add_random_records(&mut ledger, seed, 50);
+
+            // Ledger saved before the stats were added.
+            ledger.user_stats = None;
+            assert!(!ledger.user_stats_complete());
+            add_random_records(&mut ledger, seed + 100, 10);
+            assert!(!ledger.user_stats_complete());
+
+            // The removed records are skipped by the backfill.
+            ledger.remove_before(5);
+
+            let mut calls = 0;
+            while !ledger.backfill_user_stats(7) {
+                calls += 1;
+            }
+            assert_eq!(calls, 6);
+            assert!(ledger.user_stats_complete());
+            assert!(ledger.backfill_user_stats(7));
+            assert_stats_match(&ledger);
+        }
+    }
+
+    fn brute_force_balance(ledger: &Ledger, who: Principal, id: u64) -> Nat {
+        let records = ledger.get_range(0, id as usize + 1);
+        replay(
+            who,
+            Nat::from(0),
+            records.iter(),
+            &ledger.fee_payments.payments,
+        )
+    }
+
+    #[test]
+    fn indexes_backfilled_in_batches() {
+        for seed in 0..10 {
+            MockContext::new().inject();
+            let mut indexed = Ledger::default();
+            add_random_records(&mut indexed, seed, 50);
+            indexed.delegate(alice(), john());
+            add_random_records(&mut indexed, seed + 100, 10);
+
+            let mut ledger = Ledger::default();
+            add_random_records(&mut ledger, seed, 50);
+
+            // Ledger saved before the indexes were added.
+            ledger.balance_index = None;
+            ledger.vote_index = None;
+            ledger.delegate(alice(), john());
+            add_random_records(&mut ledger, seed + 100, 10);
+            assert!(ledger.balance_index.is_none());
+            assert_eq!(ledger.votes(john()), Nat::from(0));
+            assert_eq!(
+                ledger.balance_at(alice(), 0),
+                Err(TxError::TransactionDoesNotExist)
+            );
+
+            ledger.set_checkpoint_spacing(4, &HashMap::new());
+            let mut calls = 0;
+            while !ledger.backfill_indexes(7, &HashMap::new()) {
+                calls += 1;
+            }
+            assert_eq!(calls, 8);
+            assert_eq!(ledger.checkpoint_spacing(), 4);
+            assert_eq!(ledger.vote_index, indexed.vote_index);
+
+            ledger.transfer(alice(), bob(), Nat::from(1), Nat::from(0));
+            for id in 0..ledger.len() {
+                for who in [alice(), bob(), john(), xtc()] {
+                    assert_eq!(
+                        ledger.balance_at(who, id),
+                        Ok(brute_force_balance(&ledger, who, id))
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn indexes_of_trimmed_history() {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        add_random_records(&mut ledger, 0, 50);
+        let users = [alice(), bob(), john(), xtc()];
+        let balances: HashMap<_, _> = users
+            .iter()
+            .map(|who| (*who, brute_force_balance(&ledger, *who, 49)))
+            .collect();
+
+        // Ledger saved before the indexes were added, with the older records removed.
+        ledger.balance_index = None;
+        ledger.vote_index = None;
+        ledger.remove_before(20);
+        assert!(ledger.backfill_indexes(7, &balances));
+        assert_eq!(
+            ledger.balance_at(alice(), 48),
+            Err(TxError::TransactionDoesNotExist)
+        );
+        assert_eq!(
+            ledger.past_votes(alice(), 48),
+            Err(TxError::TransactionDoesNotExist)
+        );
+
+        ledger.transfer(alice(), bob(), Nat::from(1), Nat::from(0));
+        for who in users {
+            assert_eq!(ledger.balance_at(who, 49).as_ref(), Ok(&balances[&who]));
+            assert_eq!(ledger.past_votes(who, 49).as_ref(), Ok(&balances[&who]));
+            let expected = replay(
+                who,
+                balances[&who].clone(),
+                ledger.get_range(50, 1).iter(),
+                &[],
+            );
+            assert_eq!(ledger.votes(who), expected);
+            assert_eq!(ledger.balance_at(who, 50), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn composed_filters_match_brute_force() {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        add_random_records(&mut ledger, 7, 200);
+        for (i, tx) in ledger.history.iter_mut().enumerate() {
+            tx.timestamp = Int::from(i as u64 * 10);
+        }
+
+        let filters = [
+            TxFilter {
+                operation: Some(Operation::Transfer),
+                from: Some(alice()),
+                min_amount: Some(Nat::from(100)),
+                status: Some(TransactionStatusFilter::Succeeded),
+                ..Default::default()
+            },
+            TxFilter {
+                caller: Some(bob()),
+                to: Some(john()),
+                after_ts: Some(100),
+                before_ts: Some(1_500),
+                ..Default::default()
+            },
+            TxFilter {
+                from: Some(xtc()),
+                to: Some(alice()),
+                max_amount: Some(Nat::from(500)),
+                after_ts: Some(50),
+                ..Default::default()
+            },
+            TxFilter {
+                caller: Some(john()),
+                min_amount: Some(Nat::from(200)),
+                max_amount: Some(Nat::from(800)),
+                status: Some(TransactionStatusFilter::Failed),
+                ..Default::default()
+            },
+            TxFilter::default(),
+        ];
+
+        for filter in &filters {
+            let expected: Vec<_> = ledger
+                .iter()
+                .filter(|tx| filter.matches(tx))
+                .map(|tx| tx.index.clone())
+                .collect();
+            assert!(!expected.is_empty());
+
+            for order in [TransactionOrder::Ascending, TransactionOrder::Descending] {
+                let mut found = vec![];
+                let mut cursor = None;
+                loop {
+                    let page = ledger.query(filter, cursor, 3, order, 5);
+                    assert!(page.records.len() <= 3);
+                    found.extend(page.records.iter().map(|tx| tx.index.clone()));
+                    cursor = match page.next_cursor {
+                        Some(cursor) => Some(to_id(&cursor)),
+                        None => break,
+                    };
+                }
+
+                if order == TransactionOrder::Descending {
+                    found.reverse();
+                }
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn sparse_amount_matches_need_continuation() {
+        MockContext::new().inject();
+        let mut ledger = Ledger::default();
+        for id in 0..50u32 {
+            let amount = if id % 20 == 7 { 5_000 } else { id };
+            ledger.transfer(alice(), bob(), Nat::from(amount), Nat::from(0));
+        }
+
+        let mut cursor = None;
+        let mut found = vec![];
+        let mut calls = 0;
+        loop {
+            let page = ledger.get_by_amount(&Nat::from(1_000), None, cursor, 10, 8);
+            calls += 1;
+            found.extend(page.records.iter().map(|tx| tx.index.clone()));
+            cursor = match page.next_cursor {
+                Some(cursor) => Some(to_id(&cursor)),
+                None => break,
+            };
+        }
+        assert_eq!(found, vec![Nat::from(7), Nat::from(27), Nat::from(47)]);
+        assert_eq!(calls, 7);
+
+        // The page is full before the scan budget is used.
+        let page = ledger.get_by_amount(&Nat::from(10), Some(&Nat::from(12)), None, 2, 100);
+        assert_eq!(page.records.len(), 2);
+        assert_eq!(page.next_cursor, Some(Nat::from(12)));
+
+        let page = ledger.get_by_amount(&Nat::from(10), Some(&Nat::from(12)), Some(12), 2, 100);
+        assert_eq!(page.records.len(), 1);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn operation_counts_match_history() {
+        for seed in 0..10 {
+            MockContext::new().inject();
+            let mut ledger = Ledger::default();
+            add_random_records(&mut ledger, seed, 30);
+
+            // Ledger saved before the counters were added.
+            ledger.operation_counts = None;
+            assert!(!ledger.operation_counts().complete);
+            let brute_force = |ledger: &Ledger| {
+                let mut counts: Vec<(String, Nat)> = vec![];
+                for tx in ledger.iter().filter(|tx| !tx.status.is_failed()) {
+                    let name = format!("{:?}", tx.operation);
+                    match counts.iter_mut().find(|(operation, _)| *operation == name) {
+                        Some((_, count)) => *count += 1u32,
+                        None => counts.push((name, Nat::from(1))),
+                    }
+                }
+                counts.sort_by(|a, b| a.0.cmp(&b.0));
+                counts
+            };
+            // The new records are counted right away, and the older ones by the backfill.
+            add_random_records(&mut ledger, seed + 100, 30);
+            assert!(ledger.operation_counts.is_some());
+            assert!(!ledger.operation_counts().complete);
+            while !ledger.backfill_operation_counts(7) {
+                assert!(!ledger.operation_counts().complete);
+            }
+
+            let counts = ledger.operation_counts();
+            assert!(counts.complete);
+            assert_eq!(counts.counts, brute_force(&ledger));
+
+            // The counters are not decreased when the records are removed from the history.
+            ledger.remove_before(40);
+            assert_eq!(ledger.operation_counts(), counts);
         }
     }
 }
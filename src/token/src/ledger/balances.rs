@@ -0,0 +1,401 @@
+//! Balances of the principals replayed from the ledger records. The balances are saved at
+//! checkpoints, so the balance at any transaction is found by replaying at most the records
+//! between two checkpoints.
+
+use crate::canister::is20_auction::auction_principal;
+use crate::types::{FeePayment, Operation, TxRecord};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use std::collections::{HashMap, HashSet};
+
+use super::to_id;
+
+/// Default number of the records between two balance checkpoints.
+pub const DEFAULT_CHECKPOINT_SPACING: u64 = 1_000;
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct BalanceIndex {
+    /// Number of the records between two checkpoints.
+    spacing: u64,
+
+    /// Id of the first record added to the index. The balances of the records removed from the
+    /// history before the index was built are saved at a checkpoint at this id.
+    start: u64,
+
+    /// Id of the next record to be added to the index.
+    next_id: u64,
+
+    /// Balances after all the records added to the index.
+    current: HashMap<Principal, Nat>,
+
+    /// Principals with the balances changed since the last checkpoint.
+    changed: HashSet<Principal>,
+
+    /// Ids of the checkpoints in ascending order. The checkpoint `id` holds the balances after the
+    /// records before the `id`.
+    checkpoints: Vec<u64>,
+
+    /// Balances of every principal at the checkpoints, in ascending order of the checkpoint ids. A
+    /// balance is saved only at the checkpoints after it changed.
+    saved: HashMap<Principal, Vec<(u64, Nat)>>,
+}
+
+pub enum BalanceChange {
+    Credit(Nat),
+    Debit(Nat),
+}
+
+impl BalanceIndex {
+    /// Creates the index of the `history` starting from the `start` id. The `payments` are the fee
+    /// payments of the history, used to find the `fee_to` principals of the records.
+    pub fn build(start: u64, history: &[TxRecord], payments: &[FeePayment]) -> Self {
+        let mut index = Self {
+            spacing: DEFAULT_CHECKPOINT_SPACING,
+            start,
+            next_id: start,
+            current: HashMap::new(),
+            changed: HashSet::new(),
+            checkpoints: vec![],
+            saved: HashMap::new(),
+        };
+
+        for tx in history {
+            index.add(tx, fee_recipient(payments, to_id(&tx.index)));
+        }
+
+        index
+    }
+
+    /// Creates an empty index of a ledger with the records from `first_id` to `len`, to which the
+    /// records are added with `add`. If the records before `first_id` were removed from the
+    /// history, they can't be replayed, so the index starts after the last record with the current
+    /// `balances` saved at a checkpoint.
+    pub fn new(first_id: u64, len: u64, balances: &HashMap<Principal, Nat>) -> Self {
+        if first_id == 0 {
+            return Self::build(0, &[], &[]);
+        }
+
+        let mut index = Self::build(len, &[], &[]);
+        for (who, balance) in balances.iter().filter(|(_, balance)| **balance > 0u32) {
+            index.current.insert(*who, balance.clone());
+            index.changed.insert(*who);
+        }
+        index.take_checkpoint();
+        index
+    }
+
+    /// Id of the first record added to the index.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Id of the next record to be added to the index.
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// Balance of `who` after all the records added to the index.
+    pub fn balance(&self, who: Principal) -> Nat {
+        self.current.get(&who).cloned().unwrap_or_default()
+    }
+
+    /// Balances of all the principals after all the records added to the index.
+    pub fn balances(&self) -> impl Iterator<Item = (&Principal, &Nat)> {
+        self.current.iter()
+    }
+
+    pub fn spacing(&self) -> u64 {
+        self.spacing
+    }
+
+    /// Sets the number of the records between the checkpoints. The checkpoints taken before are
+    /// kept.
+    pub fn set_spacing(&mut self, spacing: u64) {
+        self.spacing = spacing;
+    }
+
+    /// Applies the `tx` to the balances. The `fee_recipient` is the principal the `fee_to` share of
+    /// the fee was credited to.
+    pub fn add(&mut self, tx: &TxRecord, fee_recipient: Option<Principal>) {
+        for (who, change) in balance_changes(tx, fee_recipient) {
+            apply(self.current.entry(who).or_default(), change);
+            self.changed.insert(who);
+        }
+
+        self.next_id = to_id(&tx.index) + 1;
+        if self.next_id - self.last_checkpoint() >= self.spacing {
+            self.take_checkpoint();
+        }
+    }
+
+    /// Returns the last checkpoint not after the `id` and the balance of `who` at that checkpoint.
+    pub fn checkpoint_before(&self, who: Principal, id: u64) -> (u64, Nat) {
+        let position = self
+            .checkpoints
+            .partition_point(|checkpoint| *checkpoint <= id);
+        let checkpoint = match position {
+            0 => return (self.start, Nat::from(0)),
+            _ => self.checkpoints[position - 1],
+        };
+
+        let balance = self.saved.get(&who).and_then(|saved| {
+            let position = saved.partition_point(|(id, _)| *id <= checkpoint);
+            position
+                .checked_sub(1)
+                .map(|position| saved[position].1.clone())
+        });
+
+        (checkpoint, balance.unwrap_or_default())
+    }
+
+    /// Removes the checkpoints that are not needed to find the balances at the `id` and after it.
+    pub fn remove_before(&mut self, id: u64) {
+        let position = self
+            .checkpoints
+            .partition_point(|checkpoint| *checkpoint <= id);
+        let base = match position {
+            0 => return,
+            _ => self.checkpoints[position - 1],
+        };
+
+        self.checkpoints.drain(..position - 1);
+        for saved in self.saved.values_mut() {
+            let position = saved.partition_point(|(id, _)| *id <= base);
+            saved.drain(..position.saturating_sub(1));
+        }
+    }
+
+    fn last_checkpoint(&self) -> u64 {
+        self.checkpoints.last().copied().unwrap_or(self.start)
+    }
+
+    fn take_checkpoint(&mut self) {
+        for who in self.changed.drain() {
+            let balance = self.current.get(&who).cloned().unwrap_or_default();
+            self.saved
+                .entry(who)
+                .or_default()
+                .push((self.next_id, balance));
+        }
+        self.checkpoints.push(self.next_id);
+    }
+}
+
+/// Returns the balance of `who` after applying the `records` to the `balance`.
+pub fn replay<'a>(
+    who: Principal,
+    mut balance: Nat,
+    records: impl Iterator<Item = &'a TxRecord>,
+    payments: &[FeePayment],
+) -> Nat {
+    for tx in records {
+        for (principal, change) in balance_changes(tx, fee_recipient(payments, to_id(&tx.index))) {
+            if principal == who {
+                apply(&mut balance, change);
+            }
+        }
+    }
+
+    balance
+}
+
+/// Returns the principal credited with the `fee_to` share of the fee of the `id` transaction.
+pub fn fee_recipient(payments: &[FeePayment], id: u64) -> Option<Principal> {
+    let position = payments.partition_point(|payment| to_id(&payment.tx_id) < id);
+    payments
+        .get(position)
+        .filter(|payment| to_id(&payment.tx_id) == id)
+        .map(|payment| payment.recipient)
+}
+
+pub fn apply(balance: &mut Nat, change: BalanceChange) {
+    match change {
+        BalanceChange::Credit(amount) => *balance += amount,
+        BalanceChange::Debit(amount) => *balance = saturating_sub(balance, &amount),
+    }
+}
+
+fn saturating_sub(value: &Nat, amount: &Nat) -> Nat {
+    if value > amount {
+        value.clone() - amount.clone()
+    } else {
+        Nat::from(0)
+    }
+}
+
+/// Returns the changes of the balances made by the `tx`.
+///
+/// The burned share of a fee is recorded as a separate `Burn` record, so it is not debited with the
+/// record that paid the fee. For the records added before the fees were broken down the whole fee is
+/// debited, and the shares credited to the `fee_to` and the auction principals are not known.
+pub fn balance_changes(
+    tx: &TxRecord,
+    fee_recipient: Option<Principal>,
+) -> Vec<(Principal, BalanceChange)> {
+    use BalanceChange::{Credit, Debit};
+
+    if tx.status.is_failed() {
+        return vec![];
+    }
+
+    let fee = match &tx.fee_breakdown {
+        Some(breakdown) => saturating_sub(&tx.fee, &breakdown.burned),
+        None => tx.fee.clone(),
+    };
+
+    let mut changes = match tx.operation {
+        Operation::Transfer | Operation::TransferFrom => vec![
+            (tx.from, Debit(tx.amount.clone() + fee)),
+            (tx.to, Credit(tx.amount.clone())),
+        ],
+        // The amount of the record includes the fee.
+        Operation::TransferFromIncludeFee => {
+            let burned = saturating_sub(&tx.fee, &fee);
+            vec![
+                (tx.from, Debit(saturating_sub(&tx.amount, &burned))),
+                (tx.to, Credit(saturating_sub(&tx.amount, &tx.fee))),
+            ]
+        }
+        Operation::Approve | Operation::ApproveUnlimited => vec![(tx.from, Debit(fee))],
+        Operation::Mint => vec![(tx.to, Credit(tx.amount.clone()))],
+        Operation::Burn => vec![(tx.from, Debit(tx.amount.clone()))],
+        Operation::Clawback => vec![
+            (tx.from, Debit(tx.amount.clone())),
+            (tx.to, Credit(tx.amount.clone())),
+        ],
+        // Auction payouts recorded before they were sent from the auction principal have the
+        // bidder as both `from` and `to`.
+        Operation::Auction if tx.from == tx.to => vec![(tx.to, Credit(tx.amount.clone()))],
+        Operation::Auction => vec![
+            (tx.from, Debit(tx.amount.clone())),
+            (tx.to, Credit(tx.amount.clone())),
+        ],
+        Operation::AdminRevoke
+        | Operation::AddMinter
+        | Operation::RemoveMinter
+        | Operation::Configure
+        | Operation::Delegate => vec![],
+    };
+
+    if let Some(breakdown) = &tx.fee_breakdown {
+        if let Some(recipient) = fee_recipient {
+            if breakdown.fee_to > 0u32 {
+                changes.push((recipient, Credit(breakdown.fee_to.clone())));
+            }
+        }
+        if breakdown.auction > 0u32 {
+            changes.push((auction_principal(), Credit(breakdown.auction.clone())));
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::mock_principals::{alice, bob, john};
+    use ic_kit::MockContext;
+
+    /// Mint of 1000 tokens to alice, followed by transfers of `id` tokens from alice to bob.
+    fn transfers(len: u32) -> Vec<TxRecord> {
+        MockContext::new().inject();
+        let mut history = vec![TxRecord::mint(
+            Nat::from(0),
+            alice(),
+            alice(),
+            Nat::from(1000),
+            None,
+        )];
+        for id in 1..len {
+            history.push(TxRecord::transfer(
+                Nat::from(id),
+                alice(),
+                bob(),
+                Nat::from(id),
+                Nat::from(0),
+            ));
+        }
+        history
+    }
+
+    fn indexed(history: &[TxRecord], spacing: u64) -> BalanceIndex {
+        let mut index = BalanceIndex::build(0, &[], &[]);
+        index.set_spacing(spacing);
+        for tx in history {
+            index.add(tx, None);
+        }
+        index
+    }
+
+    fn balance_at(index: &BalanceIndex, history: &[TxRecord], who: Principal, id: u64) -> Nat {
+        let (checkpoint, balance) = index.checkpoint_before(who, id + 1);
+        assert!(id + 1 - checkpoint < index.spacing());
+        replay(
+            who,
+            balance,
+            history[checkpoint as usize..=id as usize].iter(),
+            &[],
+        )
+    }
+
+    fn brute_force(history: &[TxRecord], who: Principal, id: u64) -> Nat {
+        replay(who, Nat::from(0), history[..=id as usize].iter(), &[])
+    }
+
+    #[test]
+    fn checkpoints_bound_replay() {
+        let history = transfers(20);
+        let index = indexed(&history, 4);
+        assert_eq!(index.checkpoints, vec![4, 8, 12, 16, 20]);
+
+        for id in 0..20 {
+            for who in [alice(), bob(), john()] {
+                assert_eq!(
+                    balance_at(&index, &history, who, id),
+                    brute_force(&history, who, id)
+                );
+            }
+        }
+        assert_eq!(balance_at(&index, &history, bob(), 19), Nat::from(190));
+        assert_eq!(balance_at(&index, &history, alice(), 19), Nat::from(810));
+    }
+
+    #[test]
+    fn started_with_balances() {
+        let history = transfers(20);
+        let balances = [alice(), bob()]
+            .iter()
+            .map(|who| (*who, brute_force(&history, *who, 9)))
+            .collect();
+        let mut index = BalanceIndex::new(5, 10, &balances);
+        index.set_spacing(4);
+        for tx in &history[10..] {
+            index.add(tx, None);
+        }
+
+        for id in 9..20 {
+            for who in [alice(), bob(), john()] {
+                assert_eq!(
+                    balance_at(&index, &history, who, id),
+                    brute_force(&history, who, id)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn removed_checkpoints() {
+        let history = transfers(20);
+        let mut index = indexed(&history, 4);
+        index.remove_before(10);
+        assert_eq!(index.checkpoints, vec![8, 12, 16, 20]);
+        assert!(index.saved.values().all(|saved| saved[0].0 == 8));
+
+        for id in 10..20 {
+            assert_eq!(
+                balance_at(&index, &history, bob(), id),
+                brute_force(&history, bob(), id)
+            );
+        }
+    }
+}
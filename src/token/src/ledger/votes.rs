@@ -0,0 +1,181 @@
+//! Vote weights of the delegates, replayed from the ledger records. Every principal votes for
+//! itself until it delegates its votes with a `Delegate` record. The weight of a delegate is the
+//! sum of the balances of the principals delegating to it.
+
+use crate::types::{Operation, TxRecord};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use std::collections::HashMap;
+
+use super::balances::{apply, balance_changes, BalanceChange, BalanceIndex};
+use super::to_id;
+
+#[derive(Default, CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct VoteIndex {
+    /// Delegates of the principals that don't vote for themselves.
+    delegates: HashMap<Principal, Principal>,
+
+    /// Vote weights of the delegates after the transactions, in ascending order of the
+    /// transaction ids. A weight is saved only after the transactions that changed it.
+    checkpoints: HashMap<Principal, Vec<(u64, Nat)>>,
+}
+
+impl VoteIndex {
+    /// Creates an empty index to which the records are added together with the `balances` index.
+    /// If the `balances` start after the records removed from the history, every principal votes
+    /// for itself with its balance at the start. The ledgers saved before the delegation was added
+    /// have no delegations in the removed records.
+    pub fn new(balances: &BalanceIndex) -> Self {
+        let mut index = Self::default();
+        if let Some(id) = balances.start().checked_sub(1) {
+            for (who, balance) in balances.balances() {
+                index.checkpoints.insert(*who, vec![(id, balance.clone())]);
+            }
+        }
+
+        index
+    }
+
+    /// Returns the principal voting with the balance of `who`.
+    pub fn delegate_of(&self, who: Principal) -> Principal {
+        self.delegates.get(&who).copied().unwrap_or(who)
+    }
+
+    /// Returns the current vote weight of the `delegate`.
+    pub fn votes(&self, delegate: Principal) -> Nat {
+        self.checkpoints
+            .get(&delegate)
+            .and_then(|checkpoints| checkpoints.last())
+            .map(|(_, weight)| weight.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the vote weight of the `delegate` right after the `id` transaction.
+    pub fn past_votes(&self, delegate: Principal, id: u64) -> Nat {
+        self.checkpoints
+            .get(&delegate)
+            .and_then(|checkpoints| {
+                let position = checkpoints.partition_point(|(checkpoint, _)| *checkpoint <= id);
+                position
+                    .checked_sub(1)
+                    .map(|position| checkpoints[position].1.clone())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Applies the `tx` to the vote weights. The `balances` must already include the `tx`.
+    pub fn add(
+        &mut self,
+        tx: &TxRecord,
+        fee_recipient: Option<Principal>,
+        balances: &BalanceIndex,
+    ) {
+        let id = to_id(&tx.index);
+        if tx.operation == Operation::Delegate {
+            if tx.status.is_failed() {
+                return;
+            }
+
+            let (from, to) = (tx.from, tx.to);
+            let previous = self.delegate_of(from);
+            if previous != to {
+                let weight = balances.balance(from);
+                self.change(id, previous, BalanceChange::Debit(weight.clone()));
+                self.change(id, to, BalanceChange::Credit(weight));
+            }
+
+            if to == from {
+                self.delegates.remove(&from);
+            } else {
+                self.delegates.insert(from, to);
+            }
+            return;
+        }
+
+        for (who, change) in balance_changes(tx, fee_recipient) {
+            let delegate = self.delegate_of(who);
+            self.change(id, delegate, change);
+        }
+    }
+
+    /// Removes the weights that are not needed to find the weights right after the `id`
+    /// transaction and after it.
+    pub fn remove_before(&mut self, id: u64) {
+        for checkpoints in self.checkpoints.values_mut() {
+            let position = checkpoints.partition_point(|(checkpoint, _)| *checkpoint <= id);
+            checkpoints.drain(..position.saturating_sub(1));
+        }
+
+        // Delegates without any votes left vote with zero weight anyway.
+        self.checkpoints
+            .retain(|_, checkpoints| checkpoints.iter().any(|(_, weight)| *weight > 0u32));
+    }
+
+    fn change(&mut self, id: u64, delegate: Principal, change: BalanceChange) {
+        let checkpoints = self.checkpoints.entry(delegate).or_default();
+        if checkpoints.last().map_or(true, |(last, _)| *last != id) {
+            let weight = checkpoints
+                .last()
+                .map(|(_, weight)| weight.clone())
+                .unwrap_or_default();
+            checkpoints.push((id, weight));
+        }
+
+        if let Some((_, weight)) = checkpoints.last_mut() {
+            apply(weight, change);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Nat;
+    use ic_kit::mock_principals::{alice, bob, john};
+    use ic_kit::MockContext;
+
+    /// Mint of 1000 tokens to alice, delegation of the alice votes to john, and transfers of `id`
+    /// tokens from alice to bob.
+    fn indexed(len: u32) -> VoteIndex {
+        MockContext::new().inject();
+        let mut history = vec![
+            TxRecord::mint(Nat::from(0), alice(), alice(), Nat::from(1000), None),
+            TxRecord::delegate(Nat::from(1), alice(), john()),
+        ];
+        for id in 2..len {
+            history.push(TxRecord::transfer(
+                Nat::from(id),
+                alice(),
+                bob(),
+                Nat::from(id),
+                Nat::from(0),
+            ));
+        }
+
+        let mut balances = BalanceIndex::build(0, &[], &[]);
+        let mut index = VoteIndex::default();
+        for tx in &history {
+            balances.add(tx, None);
+            index.add(tx, None, &balances);
+        }
+        index
+    }
+
+    #[test]
+    fn removed_weights() {
+        let mut index = indexed(20);
+        let expected: Vec<_> = (10..20)
+            .map(|id| (index.past_votes(john(), id), index.past_votes(bob(), id)))
+            .collect();
+
+        index.remove_before(10);
+        assert_eq!(index.checkpoints[&john()].len(), 10);
+        assert_eq!(index.checkpoints[&john()][0].0, 10);
+        assert!(!index.checkpoints.contains_key(&alice()));
+
+        let removed: Vec<_> = (10..20)
+            .map(|id| (index.past_votes(john(), id), index.past_votes(bob(), id)))
+            .collect();
+        assert_eq!(removed, expected);
+        assert_eq!(index.votes(john()), Nat::from(1000 - (2..20).sum::<u32>()));
+    }
+}
@@ -1,4 +1,7 @@
 pub mod canister;
+pub mod certified;
+pub mod export;
 pub mod ledger;
+pub mod merkle;
 pub mod state;
 pub mod types;
@@ -1,7 +1,10 @@
 #![allow(dead_code)]
 
 mod canister;
+mod certified;
+mod export;
 mod ledger;
+mod merkle;
 mod state;
 mod types;
 
@@ -10,9 +13,14 @@ fn main() {}
 
 #[cfg(not(any(target_arch = "wasm32", test)))]
 fn main() {
-    use canister::is20_auction::{AuctionError, BiddingInfo};
-    use common::types::Metadata;
+    use canister::is20_archive::ArchiveInfo;
+    use canister::is20_auction::{AuctionError, AuctionStats, AutoAuctionRun, BiddingInfo};
+    use canister::is20_emission::EmissionInfo;
+    use canister::is20_events::DeliveryQueueStats;
+    use canister::is20_vesting::VestingInfo;
+    use common::types::{Metadata, SignedTx};
     use ic_cdk::export::candid::{Nat, Principal};
+    use state::{PendingOwnerNotification, Subscriber, VestingSchedule};
     use types::*;
 
     std::print!("{}", ic_canister::generate_idl!());
@@ -0,0 +1,68 @@
+//! Merkle proofs for the claim lists used by `claimMint`.
+//!
+//! A leaf of the tree is `sha256(0x00 || len(principal) || principal || amount)`, where `amount`
+//! is the big-endian representation of the claimed amount. An inner node is
+//! `sha256(0x01 || min(a, b) || max(a, b))` for the child hashes `a` and `b`. As the children are
+//! sorted, a proof is just the list of sibling hashes from the leaf to the root.
+
+use candid::{Nat, Principal};
+use sha2::{Digest, Sha256};
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// Returns the hash of the claim list entry allowing the `account` to claim `amount` of tokens.
+pub fn leaf_hash(account: &Principal, amount: &Nat) -> Vec<u8> {
+    let account = account.as_slice();
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX, account.len() as u8]);
+    hasher.update(account);
+    hasher.update(amount.0.to_bytes_be());
+    hasher.finalize().to_vec()
+}
+
+/// Returns the hash of the inner node with the given child hashes.
+pub fn node_hash(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(first);
+    hasher.update(second);
+    hasher.finalize().to_vec()
+}
+
+/// Returns true if the `proof` connects the `leaf` to the `root` of the tree.
+pub fn verify(root: &[u8], leaf: Vec<u8>, proof: &[Vec<u8>]) -> bool {
+    let computed = proof
+        .iter()
+        .fold(leaf, |hash, sibling| node_hash(&hash, sibling));
+    computed == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::mock_principals::{alice, bob};
+
+    #[test]
+    fn node_hash_is_symmetric() {
+        let a = leaf_hash(&alice(), &Nat::from(100));
+        let b = leaf_hash(&bob(), &Nat::from(200));
+        assert_eq!(node_hash(&a, &b), node_hash(&b, &a));
+    }
+
+    #[test]
+    fn leaf_hash_depends_on_amount() {
+        assert_ne!(
+            leaf_hash(&alice(), &Nat::from(100)),
+            leaf_hash(&alice(), &Nat::from(101))
+        );
+    }
+
+    #[test]
+    fn verify_single_leaf() {
+        let leaf = leaf_hash(&alice(), &Nat::from(100));
+        assert!(verify(&leaf, leaf.clone(), &[]));
+        assert!(!verify(&[0; 32], leaf, &[]));
+    }
+}
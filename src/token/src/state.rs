@@ -1,20 +1,82 @@
-use crate::ledger::Ledger;
-use crate::types::{Allowances, AuctionInfo, PendingNotifications, StatsData, Timestamp};
+use crate::canister::dip20_transactions::_set_allowance;
+use crate::canister::is20_auction::{AutoAuctionRun, MIN_BIDDING_AMOUNT};
+use crate::ledger::{to_id, Ledger};
+use crate::types::{
+    Allowance, Allowances, ApprovalRecord, ApprovalsPage, ArchivedRange, AuctionController,
+    AuctionInfo, AuctionPayoutMode, NotificationFailure, PendingNotifications, StatsData,
+    Timestamp, TransactionsPage, TxError, TxRecord,
+};
 use candid::{CandidType, Deserialize, Nat, Principal};
 use common::types::Metadata;
 use ic_storage::stable::Versioned;
 use ic_storage::IcStorage;
-use std::collections::HashMap;
+use serde::Deserializer;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Default, CandidType, Deserialize, IcStorage)]
 pub struct CanisterState {
+    #[serde(deserialize_with = "deserialize_bidding_state")]
     pub(crate) bidding_state: BiddingState,
     pub(crate) balances: Balances,
     pub(crate) auction_history: AuctionHistory,
     pub(crate) stats: StatsData,
     pub(crate) allowances: Allowances,
+    #[serde(deserialize_with = "deserialize_ledger")]
     pub(crate) ledger: Ledger,
     pub notifications: PendingNotifications,
+
+    /// Failed attempts of the pending notifications. Removed when the notification succeeds.
+    #[serde(default)]
+    pub(crate) notification_failures: HashMap<Nat, NotificationFailure>,
+    #[serde(default)]
+    pub(crate) permit_nonces: HashMap<Principal, u64>,
+    #[serde(default)]
+    pub(crate) spender_whitelist: SpenderWhitelist,
+    #[serde(default)]
+    pub(crate) auction_whitelist: AuctionWhitelist,
+    #[serde(default)]
+    pub(crate) owner_notifications: OwnerNotifications,
+    #[serde(default)]
+    pub(crate) spender_index: SpenderIndex,
+    #[serde(default)]
+    pub(crate) approved_totals: HashMap<Principal, ApprovedTotal>,
+    #[serde(default)]
+    pub(crate) minters: HashSet<Principal>,
+    #[serde(default)]
+    pub(crate) emission: Option<EmissionSchedule>,
+    #[serde(default)]
+    pub(crate) vesting: HashMap<Principal, Vec<VestingSchedule>>,
+    #[serde(default)]
+    pub(crate) claims: ClaimList,
+    #[serde(default)]
+    pub(crate) bridge_exits: HashMap<Nat, BridgeExit>,
+    #[serde(default)]
+    pub(crate) non_circulating: HashSet<Principal>,
+    #[serde(default)]
+    pub(crate) mint_limit: MintLimit,
+    #[serde(default)]
+    pub(crate) faucet: Faucet,
+
+    /// Ids of the transactions with every memo, in ascending order.
+    #[serde(default)]
+    pub(crate) memo_index: HashMap<Vec<u8>, Vec<Nat>>,
+    #[serde(default)]
+    pub(crate) archive: Archive,
+    #[serde(default)]
+    pub(crate) subscriptions: Subscriptions,
+    #[serde(default)]
+    pub(crate) delivery_queue: DeliveryQueue,
+    #[serde(default)]
+    pub(crate) backup: Backup,
+
+    /// Id of the next record expected by `restoreLedger`. `None` if no restore is in progress.
+    #[serde(default)]
+    pub(crate) restore_next: Option<u64>,
+
+    /// Lifetime aggregates of the held auctions. `None` for the states saved before the aggregates
+    /// were added. Such states compute them from the auction history on the next auction.
+    #[serde(default)]
+    pub(crate) auction_stats: Option<AuctionTotals>,
 }
 
 impl CanisterState {
@@ -29,15 +91,159 @@ impl CanisterState {
             fee: self.stats.fee.clone(),
             feeTo: self.stats.fee_to,
             isTestToken: Some(self.stats.is_test_token),
+            minTransferAmount: Some(self.stats.min_transfer_amount.clone()),
+            maxSupply: self.stats.max_supply.clone(),
+        }
+    }
+
+    /// Increases the total supply by `amount`. If the resulting supply would exceed the maximum
+    /// supply, the supply is not changed and `TxError::MaxSupplyExceeded` is returned. If minting
+    /// is renounced, `TxError::MintingDisabled` is returned.
+    pub fn increase_supply(&mut self, amount: &Nat) -> Result<(), TxError> {
+        if self.stats.minting_disabled {
+            return Err(TxError::MintingDisabled);
+        }
+
+        if self.stats.exceeds_max_supply(amount) {
+            return Err(TxError::MaxSupplyExceeded);
+        }
+
+        self.stats.total_minted = Some(self.stats.total_minted() + amount.clone());
+        self.stats.total_supply += amount.clone();
+        Ok(())
+    }
+
+    /// Decreases the total supply by `amount` and counts it as burned. If the supply is less than
+    /// `amount`, it is not changed and `TxError::InsufficientBalance` is returned.
+    pub fn decrease_supply(&mut self, amount: &Nat) -> Result<(), TxError> {
+        if self.stats.total_supply < *amount {
+            return Err(TxError::InsufficientBalance);
+        }
+
+        self.stats.total_supply -= amount.clone();
+        self.stats.total_burned += amount.clone();
+        Ok(())
+    }
+
+    /// Checks if the `caller` can mint `amount` of tokens without exceeding the mint limit.
+    pub fn check_mint_limit(&mut self, caller: Principal, amount: &Nat) -> Result<(), TxError> {
+        if self.is_mint_limit_exempt(caller) {
+            return Ok(());
+        }
+
+        self.mint_limit.check(amount, ic_kit::ic::time())
+    }
+
+    /// Counts the `amount` minted by the `caller` against the mint limit.
+    pub fn record_mint(&mut self, caller: Principal, amount: Nat) {
+        if !self.is_mint_limit_exempt(caller) {
+            self.mint_limit.record(amount, ic_kit::ic::time());
         }
     }
 
+    fn is_mint_limit_exempt(&self, caller: Principal) -> bool {
+        self.mint_limit.owner_exempt && caller == self.stats.owner
+    }
+
+    /// Returns `true` if some of the periodic tasks run by the heartbeat may have work to do. Only
+    /// the flags of the tasks are checked, so the heartbeat can return early at a low cost.
+    pub fn has_periodic_tasks(&self) -> bool {
+        self.bidding_state.auto_auction
+            || !self.bidding_state.pending_refunds.is_empty()
+            || matches!(&self.emission, Some(schedule) if schedule.enabled)
+            || self.archive.canister_id.is_some()
+            || !self.subscriptions.subscribers.is_empty()
+            || !self.delivery_queue.pending.is_empty()
+            || self.ledger.backfill_pending()
+    }
+
+    /// Returns the amount of the `holder` tokens that are still locked by the vesting schedules.
+    pub fn locked_balance(&self, holder: &Principal) -> Nat {
+        let now = ic_kit::ic::time();
+        match self.vesting.get(holder) {
+            Some(schedules) => schedules.iter().fold(Nat::from(0), |locked, schedule| {
+                locked + schedule.locked(now)
+            }),
+            None => Nat::from(0),
+        }
+    }
+
+    /// Returns the part of the `holder` balance that is not locked by the vesting schedules.
+    pub fn unlocked_balance(&self, holder: &Principal) -> Nat {
+        let balance = self.balances.balance_of(holder);
+        let locked = self.locked_balance(holder);
+        if balance > locked {
+            balance - locked
+        } else {
+            Nat::from(0)
+        }
+    }
+
+    /// Returns up to `limit` transactions with the given `memo`, starting from the transaction
+    /// with the `start_cursor` id if it is given. Transactions removed from the ledger history are
+    /// skipped.
+    pub fn find_by_memo(
+        &self,
+        memo: &[u8],
+        start_cursor: Option<Nat>,
+        limit: usize,
+    ) -> TransactionsPage {
+        let ids = self.memo_index.get(memo).map_or(&[][..], |ids| &ids[..]);
+        let start = match start_cursor {
+            Some(cursor) => ids.partition_point(|id| *id < cursor),
+            None => 0,
+        };
+        let end = start.saturating_add(limit).min(ids.len());
+
+        TransactionsPage {
+            records: ids[start..end]
+                .iter()
+                .filter_map(|id| self.ledger.get(to_id(id)).cloned())
+                .collect(),
+            total: Nat::from(ids.len()),
+            next_cursor: ids.get(end).cloned(),
+        }
+    }
+
+    /// Returns the total supply without the balances of the non-circulating accounts and the tokens
+    /// locked by the vesting schedules.
+    pub fn circulating_supply(&self) -> Nat {
+        let excluded = self
+            .non_circulating
+            .iter()
+            .fold(Nat::from(0), |sum, account| {
+                sum + self.balances.balance_of(account)
+            });
+        let locked = self
+            .vesting
+            .keys()
+            .filter(|holder| !self.non_circulating.contains(holder))
+            .fold(Nat::from(0), |sum, holder| {
+                // Vested tokens could have been burned, so the locked amount is limited by the
+                // balance.
+                let balance = self.balances.balance_of(holder);
+                sum + self.locked_balance(holder).min(balance)
+            });
+
+        let excluded = excluded + locked;
+        if self.stats.total_supply > excluded {
+            self.stats.total_supply.clone() - excluded
+        } else {
+            Nat::from(0)
+        }
+    }
+
+    /// Returns the allowance entry if it exists and is not expired yet.
+    pub fn active_allowance(&self, owner: Principal, spender: Principal) -> Option<&Allowance> {
+        self.allowances
+            .get(&owner)?
+            .get(&spender)
+            .filter(|allowance| !allowance.is_expired())
+    }
+
     pub fn allowance(&self, owner: Principal, spender: Principal) -> Nat {
-        match self.allowances.get(&owner) {
-            Some(inner) => match inner.get(&spender) {
-                Some(value) => value.clone(),
-                None => Nat::from(0),
-            },
+        match self.active_allowance(owner, spender) {
+            Some(allowance) => allowance.value.clone(),
             None => Nat::from(0),
         }
     }
@@ -45,19 +251,235 @@ impl CanisterState {
     pub fn allowance_size(&self) -> usize {
         self.allowances
             .iter()
-            .map(|(_, v)| v.len())
+            .map(|(_, v)| v.values().filter(|a| !a.is_expired()).count())
             .reduce(|accum, v| accum + v)
             .unwrap_or(0)
     }
 
-    pub fn user_approvals(&self, who: Principal) -> Vec<(Principal, Nat)> {
+    /// Returns a page of not expired allowances as `(owner, spender, value)` entries, ordered by
+    /// owner and spender principals.
+    pub fn allowances_page(&self, start: usize, limit: usize) -> Vec<(Principal, Principal, Nat)> {
+        self.sorted_allowances()
+            .into_iter()
+            .skip(start)
+            .take(limit)
+            .map(|(owner, spender, allowance)| (owner, spender, allowance.value.clone()))
+            .collect()
+    }
+
+    /// Returns a page of not expired allowances with all their parameters, ordered by owner and
+    /// spender principals.
+    pub fn export_approvals(&self, offset: usize, limit: usize) -> ApprovalsPage {
+        let entries = self.sorted_allowances();
+        let total = entries.len();
+        let records = entries
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(owner, spender, allowance)| {
+                let mut allowance = allowance.clone();
+                allowance.refresh();
+                ApprovalRecord {
+                    owner,
+                    spender,
+                    value: allowance.value,
+                    expires_at: allowance.expires_at,
+                    spending_limit: allowance.spending_limit,
+                    unlimited: allowance.unlimited,
+                    created_at: allowance.created_at,
+                }
+            })
+            .collect();
+
+        ApprovalsPage { records, total }
+    }
+
+    fn sorted_allowances(&self) -> Vec<(Principal, Principal, &Allowance)> {
+        let mut entries = self
+            .allowances
+            .iter()
+            .flat_map(|(&owner, inner)| {
+                inner
+                    .iter()
+                    .filter(|(_, allowance)| !allowance.is_expired())
+                    .map(move |(&spender, allowance)| (owner, spender, allowance))
+            })
+            .collect::<Vec<_>>();
+
+        // Hash map iteration order is not stable between updates, so we sort the entries to keep
+        // the pages consistent.
+        entries.sort_unstable_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        entries
+    }
+
+    /// Removes up to `max_entries` allowances that are expired or have zero value. Returns the
+    /// number of removed entries.
+    pub fn purge_allowances(&mut self, max_entries: usize) -> usize {
+        let stale = self
+            .allowances
+            .iter()
+            .flat_map(|(&owner, inner)| {
+                inner
+                    .iter()
+                    .filter(|(_, allowance)| allowance.value == 0 || allowance.is_expired())
+                    .map(move |(&spender, _)| (owner, spender))
+            })
+            .take(max_entries)
+            .collect::<Vec<_>>();
+
+        for (owner, spender) in &stale {
+            self.spender_index.remove(*spender, *owner);
+            if let Some(inner) = self.allowances.get_mut(owner) {
+                if let Some(removed) = inner.remove(spender) {
+                    let total = self.approved_totals.entry(*owner).or_default();
+                    total.subtract(&removed.value);
+                    total.update_next_expiry(inner);
+                }
+
+                if inner.is_empty() {
+                    self.allowances.remove(owner);
+                    self.approved_totals.remove(owner);
+                }
+            }
+        }
+
+        stale.len()
+    }
+
+    /// Removes all the allowances given by the `owner`, including the expired ones.
+    pub fn remove_owner_allowances(&mut self, owner: Principal) {
+        if let Some(inner) = self.allowances.remove(&owner) {
+            for spender in inner.keys() {
+                self.spender_index.remove(*spender, owner);
+            }
+        }
+
+        self.approved_totals.remove(&owner);
+    }
+
+    /// Returns the sum of not expired allowances given by the `owner`.
+    pub fn approved_total(&self, owner: Principal) -> Nat {
+        match self.approved_totals.get(&owner) {
+            None => Nat::from(0),
+            Some(total) if !total.has_expired() => total.total.clone(),
+            // Some of the allowances expired after the last update of the owner allowances, so
+            // they still are in the aggregate.
+            Some(_) => self
+                .allowances
+                .get(&owner)
+                .into_iter()
+                .flat_map(|inner| inner.values())
+                .filter(|allowance| !allowance.is_expired())
+                .fold(Nat::from(0), |sum, allowance| sum + allowance.value.clone()),
+        }
+    }
+
+    /// Returns a page of not expired allowances given to the `spender`, ordered by the owner
+    /// principal.
+    pub fn spender_approvals(
+        &self,
+        spender: Principal,
+        start: usize,
+        limit: usize,
+    ) -> Vec<(Principal, Nat)> {
+        let mut owners = self.spender_index.owners(spender);
+        owners.sort_unstable();
+
+        owners
+            .into_iter()
+            .filter_map(|owner| {
+                self.active_allowance(owner, spender)
+                    .map(|allowance| (owner, allowance.value.clone()))
+            })
+            .skip(start)
+            .take(limit)
+            .collect()
+    }
+
+    /// Returns a page of not expired allowances given by the user `who`, ordered by the spender
+    /// principal.
+    pub fn user_approvals(
+        &self,
+        who: Principal,
+        start: usize,
+        limit: usize,
+    ) -> Vec<(Principal, Allowance)> {
+        let mut approvals = match self.allowances.get(&who) {
+            Some(allow) => allow
+                .iter()
+                .filter(|(_, allowance)| !allowance.is_expired())
+                .collect::<Vec<_>>(),
+            None => return Vec::new(),
+        };
+
+        approvals.sort_unstable_by_key(|(&spender, _)| spender);
+
+        approvals
+            .into_iter()
+            .skip(start)
+            .take(limit)
+            .map(|(&spender, allowance)| {
+                let mut allowance = allowance.clone();
+                allowance.refresh();
+                (spender, allowance)
+            })
+            .collect()
+    }
+
+    pub fn user_approvals_count(&self, who: Principal) -> usize {
         match self.allowances.get(&who) {
-            Some(allow) => Vec::from_iter(allow.clone().into_iter()),
-            None => Vec::new(),
+            Some(allow) => allow.values().filter(|a| !a.is_expired()).count(),
+            None => 0,
         }
     }
 }
 impl Versioned for CanisterState {
+    type Previous = CanisterStateV1;
+
+    fn upgrade(previous: CanisterStateV1) -> Self {
+        let mut state = Self {
+            bidding_state: previous.bidding_state,
+            balances: previous.balances,
+            auction_history: previous.auction_history,
+            stats: previous.stats,
+            ledger: previous.ledger,
+            notifications: previous.notifications,
+            ..Default::default()
+        };
+
+        // The allowances are set one by one, so that the spender index and the approved totals
+        // are built along.
+        for (owner, allowances) in previous.allowances {
+            for (spender, value) in allowances {
+                let allowance = Allowance {
+                    value,
+                    ..Default::default()
+                };
+                _set_allowance(&mut state, owner, spender, allowance);
+            }
+        }
+
+        state
+    }
+}
+
+/// State saved by the versions of the canister before the allowances had the expiration time and
+/// the other options, when an allowance was a plain amount. The fields added to the other parts of
+/// the state since then are decoded with their defaults.
+#[derive(Default, CandidType, Deserialize)]
+pub struct CanisterStateV1 {
+    #[serde(deserialize_with = "deserialize_bidding_state")]
+    bidding_state: BiddingState,
+    balances: Balances,
+    auction_history: AuctionHistory,
+    stats: StatsData,
+    allowances: HashMap<Principal, HashMap<Principal, Nat>>,
+    #[serde(deserialize_with = "deserialize_ledger")]
+    ledger: Ledger,
+    notifications: PendingNotifications,
+}
+
+impl Versioned for CanisterStateV1 {
     type Previous = ();
 
     fn upgrade((): ()) -> Self {
@@ -73,6 +495,35 @@ impl Balances {
         self.0.get(who).cloned().unwrap_or_else(|| Nat::from(0))
     }
 
+    /// Adds `amount` to the balance of `who`.
+    pub fn credit(&mut self, who: Principal, amount: Nat) -> Result<(), TxError> {
+        if amount > 0u32 {
+            let balance = self.balance_of(&who) + amount;
+            self.0.insert(who, balance);
+        }
+
+        Ok(())
+    }
+
+    /// Subtracts `amount` from the balance of `who`. If the balance is less than `amount`, the
+    /// balance is not changed and `TxError::InsufficientBalance` is returned. Holders with zero
+    /// balance are removed.
+    pub fn debit(&mut self, who: Principal, amount: Nat) -> Result<(), TxError> {
+        let balance = self.balance_of(&who);
+        if balance < amount {
+            return Err(TxError::InsufficientBalance);
+        }
+
+        let balance = balance - amount;
+        if balance != 0u32 {
+            self.0.insert(who, balance);
+        } else {
+            self.0.remove(&who);
+        }
+
+        Ok(())
+    }
+
     pub fn get_holders(&self, start: usize, limit: usize) -> Vec<(Principal, Nat)> {
         let mut balance = self
             .0
@@ -95,9 +546,187 @@ pub struct BiddingState {
     pub auction_period: Timestamp,
     pub cycles_since_auction: u64,
     pub bids: HashMap<Principal, u64>,
+
+    /// Minimum amount of cycles accepted by one bid, set with `setMinBid`. `None` if the default
+    /// minimum is used.
+    #[serde(default)]
+    pub min_bid: Option<u64>,
+
+    /// Minimum and maximum fee ratio set with `setFeeRatioBounds`. `None` if the ratio is not
+    /// bounded.
+    #[serde(default)]
+    pub fee_ratio_bounds: Option<(f64, f64)>,
+
+    /// If set, the auction is run on the heartbeat when it's due.
+    #[serde(default)]
+    pub auto_auction: bool,
+
+    /// Results of the last automatic auctions, oldest first.
+    #[serde(default)]
+    pub auto_auction_log: Vec<AutoAuctionRun>,
+
+    /// Bids since the last auction made by the callers on behalf of other principals.
+    #[serde(default)]
+    pub sponsored_bids: Vec<SponsoredBid>,
+
+    /// Minimum amounts of tokens the bidders expect to receive in the next auction, given to
+    /// `bidCycles`. Bids with a smaller share are refunded instead of paid out.
+    #[serde(default)]
+    pub min_expected_tokens: HashMap<Principal, Nat>,
+
+    /// Cycles of the bids skipped by the auctions that are yet to be sent back to the principals
+    /// that paid them: the bidders or their sponsors.
+    #[serde(default)]
+    pub pending_refunds: Vec<(Principal, u64)>,
+
+    /// Cycles of the skipped bids that could not be sent back, by the principal that paid them.
+    /// They can be claimed with `claimBidRefund`.
+    #[serde(default)]
+    pub refund_credits: HashMap<Principal, u64>,
+
+    /// How the auctions pay the distributed tokens to the bidders, set with
+    /// `setAuctionPayoutMode`.
+    #[serde(default)]
+    pub payout_mode: AuctionPayoutMode,
+
+    /// Rewards of the auctions held in the `Claim` payout mode, by auction id and bidder.
+    #[serde(default)]
+    pub auction_rewards: HashMap<usize, HashMap<Principal, AuctionReward>>,
+
+    /// Set if the `auction_period` is stored in nanoseconds. Before it was added, `setAuctionPeriod`
+    /// and the default period used microseconds, and such periods are converted by
+    /// `migrate_auction_period` when the state is restored after upgrade.
+    #[serde(default)]
+    pub auction_period_in_ns: bool,
+
+    /// Who is allowed to run the auctions, set with `setAuctionController`.
+    #[serde(default)]
+    pub controller: AuctionController,
+
+    /// Share of the auction pool paid to the caller of `runAuction`, in basis points. Set with
+    /// `setAuctionIncentive`.
+    #[serde(default)]
+    pub incentive_rate: u64,
+}
+
+/// Tokens distributed to a bidder by an auction held in the `Claim` payout mode.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct AuctionReward {
+    pub amount: Nat,
+
+    /// Id of the `Auction` transaction paying the reward. `None` if the reward is not claimed yet.
+    pub transaction_id: Option<Nat>,
+}
+
+/// Bid of the `cycles` paid by the `sponsor` on behalf of the `bidder`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct SponsoredBid {
+    pub sponsor: Principal,
+    pub bidder: Principal,
+    pub cycles: u64,
+}
+
+/// Decodes the saved bidding state and converts the auction period of the earlier versions of the
+/// canister with `migrate_auction_period`. The state is only decoded by `post_upgrade`, so the
+/// migration is done there, before any other call can use the period.
+fn deserialize_bidding_state<'de, D>(deserializer: D) -> Result<BiddingState, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut state = BiddingState::deserialize(deserializer)?;
+    state.migrate_auction_period();
+    Ok(state)
+}
+
+/// Decodes the saved ledger and converts the user index of the earlier versions of the canister
+/// with `migrate_user_index`, the same way as `deserialize_bidding_state`.
+fn deserialize_ledger<'de, D>(deserializer: D) -> Result<Ledger, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut ledger = Ledger::deserialize(deserializer)?;
+    ledger.migrate_user_index();
+    Ok(ledger)
 }
 
 impl BiddingState {
+    /// Total amount of cycles the `bidder` bid for the next auction.
+    pub fn bid(&self, bidder: &Principal) -> u64 {
+        self.bids.get(bidder).cloned().unwrap_or(0)
+    }
+
+    /// Amount of cycles bid for the next auction on behalf of the `bidder` by the sponsors.
+    pub fn sponsored_cycles(&self, bidder: &Principal) -> u64 {
+        self.sponsored_bids
+            .iter()
+            .filter(|bid| bid.bidder == *bidder)
+            .map(|bid| bid.cycles)
+            .sum()
+    }
+
+    /// Splits the `cycles` bid by the `bidder` for the next auction between the principals that paid
+    /// them: the sponsors of the bidder and the bidder itself.
+    pub fn bid_payers(&self, bidder: &Principal, cycles: u64) -> Vec<(Principal, u64)> {
+        let mut payers: Vec<(Principal, u64)> = self
+            .sponsored_bids
+            .iter()
+            .filter(|bid| bid.bidder == *bidder)
+            .map(|bid| (bid.sponsor, bid.cycles))
+            .collect();
+
+        let own = cycles.saturating_sub(self.sponsored_cycles(bidder));
+        if own > 0 {
+            payers.push((*bidder, own));
+        }
+
+        payers
+    }
+
+    /// Sets the minimum amount of tokens the `bidder` expects to receive in the next auction. If
+    /// the bidder already has a larger minimum, it is kept.
+    pub fn expect_min_tokens(&mut self, bidder: Principal, min: Nat) {
+        let current = self
+            .min_expected_tokens
+            .entry(bidder)
+            .or_insert_with(|| Nat::from(0));
+        if min > *current {
+            *current = min;
+        }
+    }
+
+    pub fn min_bid(&self) -> u64 {
+        self.min_bid.unwrap_or(MIN_BIDDING_AMOUNT)
+    }
+
+    pub fn fee_ratio_bounds(&self) -> (f64, f64) {
+        self.fee_ratio_bounds.unwrap_or((0.0, 1.0))
+    }
+
+    /// Sets the fee ratio to the `fee_ratio` clamped into the configured bounds.
+    pub fn set_fee_ratio(&mut self, fee_ratio: f64) {
+        let (min, max) = self.fee_ratio_bounds();
+        self.fee_ratio = fee_ratio.clamp(min, max);
+    }
+
+    /// Total amount of the auction rewards that are not claimed yet. These tokens stay in the
+    /// auction balance until they are claimed.
+    pub fn unclaimed_rewards(&self) -> Nat {
+        self.auction_rewards
+            .values()
+            .flat_map(HashMap::values)
+            .filter(|reward| reward.transaction_id.is_none())
+            .fold(Nat::from(0), |total, reward| total + reward.amount.clone())
+    }
+
+    /// Converts the `auction_period` stored in microseconds by the earlier versions of the canister
+    /// into nanoseconds. Does nothing if the period is already converted.
+    pub fn migrate_auction_period(&mut self) {
+        if !self.auction_period_in_ns {
+            self.auction_period = self.auction_period.saturating_mul(1_000);
+            self.auction_period_in_ns = true;
+        }
+    }
+
     pub fn is_auction_due(&self) -> bool {
         let curr_time = ic_kit::ic::time();
         let next_auction = self.last_auction + self.auction_period;
@@ -105,5 +734,590 @@ impl BiddingState {
     }
 }
 
+/// Limit of the total amount of tokens that can be minted in a sliding window of time, set with
+/// `setMintLimit`. The limit is shared by all the minters.
+#[derive(Default, CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct MintLimit {
+    pub max_amount: Nat,
+
+    /// Length of the window in nanoseconds. Zero means that minting is not limited.
+    pub window: Timestamp,
+
+    /// If set, mints made by the owner are not limited and don't count against the limit.
+    pub owner_exempt: bool,
+
+    /// IC times and amounts of the mints made in the current window, in the order of the mints.
+    pub recent: Vec<(Timestamp, Nat)>,
+}
+
+impl MintLimit {
+    /// Returns `TxError::MintLimitExceeded` if minting `amount` at the `now` IC time would exceed
+    /// the limit. `resets_at` in the error is the time when enough of the earlier mints leave the
+    /// window to mint the `amount`.
+    pub fn check(&mut self, amount: &Nat, now: Timestamp) -> Result<(), TxError> {
+        if self.window == 0 {
+            return Ok(());
+        }
+
+        self.prune(now);
+        let mut minted = self
+            .recent
+            .iter()
+            .fold(Nat::from(0), |sum, (_, amount)| sum + amount.clone());
+        if minted.clone() + amount.clone() <= self.max_amount {
+            return Ok(());
+        }
+
+        // If the amount is larger than the limit itself, it cannot be minted at any time, so the
+        // end of the current window is reported.
+        let mut resets_at = now.saturating_add(self.window);
+        for (time, recent_amount) in &self.recent {
+            minted -= recent_amount.clone();
+            if minted.clone() + amount.clone() <= self.max_amount {
+                resets_at = time.saturating_add(self.window);
+                break;
+            }
+        }
+
+        Err(TxError::MintLimitExceeded { resets_at })
+    }
+
+    pub fn record(&mut self, amount: Nat, now: Timestamp) {
+        if self.window > 0 {
+            self.prune(now);
+            self.recent.push((now, amount));
+        }
+    }
+
+    fn prune(&mut self, now: Timestamp) {
+        let window = self.window;
+        self.recent
+            .retain(|(time, _)| time.saturating_add(window) > now);
+    }
+}
+
+/// Length of the window the `faucet` limit applies to, in nanoseconds.
+pub const FAUCET_WINDOW: Timestamp = 24 * 60 * 60 * 1_000_000_000;
+
+/// Test tokens received by the principals with `faucet`.
+#[derive(Default, CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct Faucet {
+    /// Amount of tokens a principal can receive in a `FAUCET_WINDOW`.
+    pub limit: Nat,
+
+    /// IC time of the start of the current window and the amount received in it, by principal.
+    pub received: HashMap<Principal, (Timestamp, Nat)>,
+}
+
+impl Faucet {
+    /// Returns `TxError::FaucetLimitReached` if giving `amount` to `to` at the `now` IC time would
+    /// exceed the limit.
+    pub fn check(&self, to: Principal, amount: &Nat, now: Timestamp) -> Result<(), TxError> {
+        let (retry_after, received) = match self.received.get(&to) {
+            Some((start, received)) if start.saturating_add(FAUCET_WINDOW) > now => {
+                (start.saturating_add(FAUCET_WINDOW), received.clone())
+            }
+            _ => (now.saturating_add(FAUCET_WINDOW), Nat::from(0)),
+        };
+
+        if received + amount.clone() > self.limit {
+            Err(TxError::FaucetLimitReached { retry_after })
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn record(&mut self, to: Principal, amount: Nat, now: Timestamp) {
+        let entry = self
+            .received
+            .entry(to)
+            .or_insert_with(|| (now, Nat::from(0)));
+        if entry.0.saturating_add(FAUCET_WINDOW) <= now {
+            *entry = (now, Nat::from(0));
+        }
+
+        entry.1 += amount;
+    }
+
+    /// Removes the entries of the principals whose windows are over.
+    pub fn prune(&mut self, now: Timestamp) {
+        self.received
+            .retain(|_, (start, _)| start.saturating_add(FAUCET_WINDOW) > now);
+    }
+}
+
+/// Schedule of the tokens minted periodically to the `to` principal, set with `setEmission`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct EmissionSchedule {
+    pub to: Principal,
+    pub amount_per_period: Nat,
+
+    /// Length of the period in nanoseconds.
+    pub period: Timestamp,
+    pub enabled: bool,
+
+    /// IC time of the last emission, or of the time the schedule was set if there were no emissions
+    /// yet.
+    pub last_run: Timestamp,
+}
+
+impl EmissionSchedule {
+    pub fn next_run(&self) -> Timestamp {
+        self.last_run.saturating_add(self.period)
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.enabled && ic_kit::ic::time() >= self.next_run()
+    }
+}
+
+/// Tokens minted with `mintVested`. The tokens are locked until the cliff, and then unlocked
+/// linearly until the end of the vesting duration.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct VestingSchedule {
+    pub amount: Nat,
+
+    /// IC time the tokens were minted.
+    pub start: Timestamp,
+
+    /// Time in nanoseconds since `start` before which all the tokens are locked.
+    pub cliff: Timestamp,
+
+    /// Time in nanoseconds since `start` after which all the tokens are unlocked.
+    pub duration: Timestamp,
+}
+
+impl VestingSchedule {
+    /// Returns the amount of tokens still locked at the `now` IC time.
+    pub fn locked(&self, now: Timestamp) -> Nat {
+        let elapsed = now.saturating_sub(self.start);
+        if elapsed < self.cliff {
+            self.amount.clone()
+        } else if elapsed >= self.duration {
+            Nat::from(0)
+        } else {
+            self.amount.clone() - self.amount.clone() * elapsed / self.duration
+        }
+    }
+}
+
+/// Allocation list committed with `setClaimRoot`, from which the users claim their tokens with
+/// `claimMint`.
+#[derive(Default, CandidType, Deserialize)]
+pub struct ClaimList {
+    /// Root hash of the merkle tree of the list entries.
+    pub root: Option<Vec<u8>>,
+
+    /// Principals that have already claimed their tokens.
+    pub claimed: HashSet<Principal>,
+}
+
+/// List of spenders the users are allowed to give allowances to.
+#[derive(Default, CandidType, Deserialize)]
+pub struct SpenderWhitelist {
+    pub enabled: bool,
+    pub spenders: HashSet<Principal>,
+}
+
+impl SpenderWhitelist {
+    /// Returns true if the whitelist is disabled or the `spender` is in the list.
+    pub fn is_allowed(&self, spender: &Principal) -> bool {
+        !self.enabled || self.spenders.contains(spender)
+    }
+}
+
+/// List of bidders allowed to bid in the cycle auctions.
+#[derive(Default, CandidType, Deserialize)]
+pub struct AuctionWhitelist {
+    pub enabled: bool,
+    pub bidders: HashSet<Principal>,
+}
+
+impl AuctionWhitelist {
+    /// Returns true if the whitelist is disabled or the `bidder` is in the list.
+    pub fn is_allowed(&self, bidder: &Principal) -> bool {
+        !self.enabled || self.bidders.contains(bidder)
+    }
+}
+
+/// Maximum number of allowance notifications waiting to be delivered to the owners. If the queue is
+/// full, the oldest notification is dropped.
+pub const MAX_PENDING_OWNER_NOTIFICATIONS: usize = 100;
+
+/// Target of the notification about a `burnAndNotify` transaction, stored until the notification
+/// is delivered.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct BridgeExit {
+    pub bridge: Principal,
+    pub payload: Vec<u8>,
+}
+
+/// Maximum number of delivery attempts for an allowance notification.
+pub const MAX_OWNER_NOTIFICATION_ATTEMPTS: u8 = 3;
+
+/// Allowance notification waiting to be delivered to the owner of the tokens.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct PendingOwnerNotification {
+    pub owner: Principal,
+    pub tx_id: Nat,
+    pub spender: Principal,
+    pub amount: Nat,
+    pub attempts: u8,
+}
+
+/// Owners that opted in to be notified when their allowances are used, and the queue of
+/// notifications not delivered yet.
+#[derive(Default, CandidType, Deserialize)]
+pub struct OwnerNotifications {
+    pub enabled: HashSet<Principal>,
+    pub pending: Vec<PendingOwnerNotification>,
+}
+
+impl OwnerNotifications {
+    /// Adds the notification to the queue if the owner opted in to receive them.
+    pub fn push(&mut self, notification: PendingOwnerNotification) {
+        if !self.enabled.contains(&notification.owner) {
+            return;
+        }
+
+        if self.pending.len() >= MAX_PENDING_OWNER_NOTIFICATIONS {
+            self.pending.remove(0);
+        }
+
+        self.pending.push(notification);
+    }
+}
+
+/// Sum of the allowances given by an owner, including the expired ones that are not removed from
+/// the state yet.
+#[derive(Default, CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApprovedTotal {
+    pub total: Nat,
+
+    /// The earliest expiration time of the allowances in the aggregate.
+    pub next_expiry: Option<Timestamp>,
+}
+
+impl ApprovedTotal {
+    /// Subtracts the `amount` from the total. The total is clamped at zero, so an aggregate out of
+    /// sync with the allowances can't trap the update.
+    pub fn subtract(&mut self, amount: &Nat) {
+        if self.total > *amount {
+            self.total -= amount.clone();
+        } else {
+            self.total = Nat::from(0);
+        }
+    }
+
+    pub fn update_next_expiry(&mut self, allowances: &HashMap<Principal, Allowance>) {
+        self.next_expiry = allowances
+            .values()
+            .filter_map(|allowance| allowance.expires_at)
+            .min();
+    }
+
+    /// Returns true if some of the allowances in the aggregate are expired.
+    pub fn has_expired(&self) -> bool {
+        matches!(self.next_expiry, Some(expires_at) if expires_at <= ic_kit::ic::time())
+    }
+}
+
+/// Reverse index of the allowances: the owners that gave an allowance to the spender.
+#[derive(Default, CandidType, Deserialize)]
+pub struct SpenderIndex(pub HashMap<Principal, HashSet<Principal>>);
+
+impl SpenderIndex {
+    pub fn insert(&mut self, spender: Principal, owner: Principal) {
+        self.0.entry(spender).or_default().insert(owner);
+    }
+
+    pub fn remove(&mut self, spender: Principal, owner: Principal) {
+        if let Some(owners) = self.0.get_mut(&spender) {
+            owners.remove(&owner);
+            if owners.is_empty() {
+                self.0.remove(&spender);
+            }
+        }
+    }
+
+    pub fn owners(&self, spender: Principal) -> Vec<Principal> {
+        self.0
+            .get(&spender)
+            .map(|owners| owners.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
 #[derive(Default, CandidType, Deserialize)]
 pub struct AuctionHistory(pub Vec<AuctionInfo>);
+
+/// Aggregates of the held auctions.
+#[derive(Default, CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct AuctionTotals {
+    pub auction_count: u64,
+    pub cycles_collected: Nat,
+    pub tokens_distributed: Nat,
+
+    /// Sum of the numbers of the bidders in every auction.
+    pub participants: u64,
+
+    /// IC time of the last auction. `None` if no auctions were held.
+    pub last_auction_time: Option<Timestamp>,
+}
+
+impl AuctionTotals {
+    pub fn from_history(history: &[AuctionInfo]) -> Self {
+        let mut totals = Self::default();
+        for info in history {
+            totals.add(info);
+        }
+
+        totals
+    }
+
+    pub fn add(&mut self, info: &AuctionInfo) {
+        // The auctions held before the number of the bidders was recorded paid every bidder with
+        // a separate transaction.
+        let participants = match info.participants {
+            0 => (info.transaction_ids.len() + info.refunded_bids.len()) as u64,
+            participants => participants,
+        };
+
+        self.auction_count += 1;
+        self.cycles_collected += info.cycles_collected;
+        self.tokens_distributed += info.tokens_distributed.clone();
+        self.participants += participants;
+        self.last_auction_time = Some(info.auction_time);
+    }
+}
+
+/// Maximum number of the subscribers to the ledger events, including the ones not approved yet.
+pub const MAX_SUBSCRIBERS: usize = 16;
+
+/// Canister receiving the `on_is20_event` calls for the new transactions.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct Subscriber {
+    /// Canister the events are sent to.
+    pub callback: Principal,
+
+    /// Events are sent only after the owner approved the subscription.
+    pub approved: bool,
+
+    /// Id of the next transaction to send to the subscriber.
+    pub next_id: u64,
+
+    /// Number of the events accepted by the subscriber.
+    pub delivered: u64,
+
+    /// Number of the failed delivery attempts, including the retries from the delivery queue.
+    pub failed: u64,
+}
+
+/// Subscribers to the ledger events by the principals that subscribed.
+#[derive(Default, CandidType, Deserialize)]
+pub struct Subscriptions {
+    pub subscribers: HashMap<Principal, Subscriber>,
+
+    /// Set while the events are being sent, so that the same events are not sent concurrently.
+    pub in_progress: bool,
+}
+
+impl Subscriptions {
+    /// Returns true if some of the approved subscribers didn't receive the transactions before
+    /// the `ledger_len` id.
+    pub fn is_due(&self, ledger_len: u64) -> bool {
+        !self.in_progress
+            && self
+                .subscribers
+                .values()
+                .any(|subscriber| subscriber.approved && subscriber.next_id < ledger_len)
+    }
+}
+
+/// Backup of the ledger sent to another canister.
+#[derive(Default, CandidType, Deserialize)]
+pub struct Backup {
+    pub target: Option<Principal>,
+
+    /// Id of the first record of the backup.
+    pub start: u64,
+
+    /// Id of the next record to send.
+    pub next: u64,
+
+    /// Id after the last record of the backup. The records added after the backup was started are
+    /// not included.
+    pub end: u64,
+
+    /// Set while a chunk is being sent, so that no other chunk is sent concurrently.
+    pub in_progress: bool,
+
+    pub completed: bool,
+}
+
+/// Maximum number of the events waiting in the delivery queue. When the queue is full, the oldest
+/// event is dropped.
+pub const MAX_QUEUED_DELIVERIES: usize = 1_000;
+
+/// Maximum number of the delivery attempts of an event, including the first one.
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry of a failed delivery. The delay is doubled after every failed
+/// attempt.
+pub const DELIVERY_RETRY_DELAY: u64 = 60 * 1_000_000_000;
+
+/// Maximum delay between two delivery attempts.
+pub const MAX_DELIVERY_RETRY_DELAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Event that failed to be delivered to a subscriber, waiting for the next attempt.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct PendingDelivery {
+    pub subscriber: Principal,
+    pub callback: Principal,
+    pub record: TxRecord,
+    pub attempts: u32,
+    pub next_attempt: Timestamp,
+
+    /// Set for the notifications of the auction winners, which are sent with the `auction_reward`
+    /// call instead of `on_is20_event`. The `record` is the `Auction` transaction paying the
+    /// reward.
+    #[serde(default)]
+    pub auction_id: Option<usize>,
+}
+
+/// Events that failed to be delivered to the subscribers, retried with an exponential backoff.
+#[derive(Default, CandidType, Deserialize)]
+pub struct DeliveryQueue {
+    pub pending: Vec<PendingDelivery>,
+
+    /// Number of the events dropped after running out of attempts or from a full queue.
+    pub dropped: u64,
+
+    /// Set while the due events are being sent, so that the same events are not sent
+    /// concurrently.
+    pub in_progress: bool,
+}
+
+impl DeliveryQueue {
+    /// Schedules the next attempt of the `delivery` that failed at the `now` time, or drops it if it
+    /// ran out of attempts.
+    pub fn retry(&mut self, mut delivery: PendingDelivery, now: Timestamp) {
+        delivery.attempts += 1;
+        if delivery.attempts >= MAX_DELIVERY_ATTEMPTS {
+            self.dropped += 1;
+            return;
+        }
+
+        let delay = DELIVERY_RETRY_DELAY
+            .saturating_mul(1 << (delivery.attempts - 1))
+            .min(MAX_DELIVERY_RETRY_DELAY);
+        delivery.next_attempt = now.saturating_add(delay);
+        self.push(delivery);
+    }
+
+    /// Adds the `delivery` to the queue, dropping the oldest one if the queue is full.
+    pub fn push(&mut self, delivery: PendingDelivery) {
+        if self.pending.len() >= MAX_QUEUED_DELIVERIES {
+            self.pending.remove(0);
+            self.dropped += 1;
+        }
+
+        self.pending.push(delivery);
+    }
+
+    /// Removes the events due at the `now` time from the queue and returns them.
+    pub fn take_due(&mut self, now: Timestamp) -> Vec<PendingDelivery> {
+        let (due, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|delivery| delivery.next_attempt <= now);
+        self.pending = pending;
+        due
+    }
+
+    /// Returns true if some of the events are due at the `now` time.
+    pub fn is_due(&self, now: Timestamp) -> bool {
+        !self.in_progress
+            && self
+                .pending
+                .iter()
+                .any(|delivery| delivery.next_attempt <= now)
+    }
+}
+
+/// Configuration of moving the old transactions from the ledger to an archive canister, and the
+/// ranges of the transactions already moved.
+#[derive(Default, CandidType, Deserialize)]
+pub struct Archive {
+    /// Canister the transactions are moved to. `None` if archiving is not configured.
+    pub canister_id: Option<Principal>,
+
+    /// Number of the transactions kept in the ledger, above which the oldest transactions are
+    /// moved to the archive. Zero disables automatic archiving.
+    pub threshold: u64,
+
+    /// Maximum number of the transactions moved to the archive in one call.
+    pub batch_size: u64,
+
+    /// Archived ranges in ascending order.
+    pub ranges: Vec<ArchivedRange>,
+
+    /// Set while a batch of the transactions is being sent to the archive, so that no other batch
+    /// is sent concurrently.
+    pub in_progress: bool,
+}
+
+impl Archive {
+    /// Returns true if the ledger with `history_len` transactions should be archived.
+    pub fn is_due(&self, history_len: usize) -> bool {
+        self.canister_id.is_some()
+            && !self.in_progress
+            && self.threshold > 0
+            && history_len as u64 > self.threshold
+    }
+
+    /// Returns the archived range that contains the transaction with the given `id`.
+    pub fn find(&self, id: &Nat) -> Option<&ArchivedRange> {
+        self.ranges
+            .iter()
+            .find(|range| range.start <= *id && *id < range.start.clone() + range.length.clone())
+    }
+
+    /// Records that the transactions [start, start + length) were moved to the `canister_id`
+    /// archive. Adjacent ranges in the same archive are merged.
+    pub fn add_range(&mut self, canister_id: Principal, start: Nat, length: Nat) {
+        if let Some(last) = self.ranges.last_mut() {
+            if last.canister_id == canister_id && last.start.clone() + last.length.clone() == start
+            {
+                last.length += length;
+                return;
+            }
+        }
+
+        self.ranges.push(ArchivedRange {
+            canister_id,
+            start,
+            length,
+        });
+    }
+
+    /// Returns the parts of the archived ranges within [start, end).
+    pub fn ranges_in(&self, start: &Nat, end: &Nat) -> Vec<ArchivedRange> {
+        self.ranges
+            .iter()
+            .filter_map(|range| {
+                let range_end = range.start.clone() + range.length.clone();
+                let from = range.start.clone().max(start.clone());
+                let to = range_end.min(end.clone());
+                if from < to {
+                    Some(ArchivedRange {
+                        canister_id: range.canister_id,
+                        start: from.clone(),
+                        length: to - from,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
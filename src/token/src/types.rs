@@ -1,6 +1,6 @@
-use candid::{CandidType, Deserialize, Nat, Principal};
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
 use common::types::Metadata;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap};
 
 mod tx_record;
 pub use tx_record::*;
@@ -20,14 +20,91 @@ pub struct StatsData {
     pub deploy_time: u64,
     pub min_cycles: u64,
     pub is_test_token: bool,
+
+    /// Minimum amount of a transfer, set with `setMinTransferAmount`.
+    #[serde(default)]
+    pub min_transfer_amount: Nat,
+
+    /// If set, the approvals are charged with the transfer fee. The states saved before the flag
+    /// was added charged the approvals, so it's set for them.
+    #[serde(default = "approve_fee_enabled_default")]
+    pub approve_fee_enabled: bool,
+
+    /// Maximum total supply of the token. Set at initialization and cannot be changed afterwards.
+    /// `None` means that the supply is not limited.
+    pub max_supply: Option<Nat>,
+
+    /// Share of every fee that is burned instead of being sent to `fee_to` and the cycle auction,
+    /// in basis points.
+    #[serde(default)]
+    pub fee_burn_rate: u64,
+
+    /// Total amount of tokens burned over the lifetime of the token.
+    #[serde(default)]
+    pub total_burned: Nat,
+
+    /// Total amount of tokens minted over the lifetime of the token, including the initial supply.
+    /// `None` in the states saved before the counter was added, use `total_minted()` to get the
+    /// value.
+    #[serde(default)]
+    pub total_minted: Option<Nat>,
+
+    /// If set, no new tokens can be minted. Once set with `renounceMinting`, the flag cannot be
+    /// cleared.
+    #[serde(default)]
+    pub minting_disabled: bool,
+
+    /// If set, transfers rejected by the canister are recorded in the ledger with the
+    /// `TransactionStatus::Failed` status.
+    #[serde(default)]
+    pub record_failed_transactions: bool,
+
+    /// Maximum number of the transactions returned by one transaction query, set with
+    /// `setMaxQueryLimit`. `None` if the default limit is used.
+    #[serde(default)]
+    pub max_query_limit: Option<u64>,
+}
+
+fn approve_fee_enabled_default() -> bool {
+    true
 }
 
 impl StatsData {
     pub fn fee_info(&self) -> (Nat, Principal) {
         (self.fee.clone(), self.fee_to)
     }
+
+    /// Returns the total amount of tokens minted over the lifetime of the token. For the tokens
+    /// created before the counter was added, it is computed from the current supply and the burned
+    /// amount.
+    pub fn total_minted(&self) -> Nat {
+        self.total_minted
+            .clone()
+            .unwrap_or_else(|| self.total_supply.clone() + self.total_burned.clone())
+    }
+
+    /// Returns true if minting `amount` of tokens would make the total supply exceed the maximum.
+    pub fn exceeds_max_supply(&self, amount: &Nat) -> bool {
+        match &self.max_supply {
+            Some(max_supply) => self.total_supply.clone() + amount.clone() > *max_supply,
+            None => false,
+        }
+    }
+
+    /// Fee charged for changing an allowance. It is equal to the transfer fee, unless charging
+    /// fees on approvals is disabled.
+    pub fn approve_fee(&self) -> Nat {
+        if self.approve_fee_enabled {
+            self.fee.clone()
+        } else {
+            Nat::from(0)
+        }
+    }
 }
 
+/// Maximum fee burn rate in basis points, which means burning the whole fee.
+pub const MAX_FEE_BURN_RATE: u64 = 10_000;
+
 // 10T cycles is an equivalent of approximately $10. This should be enough to last the canister
 // for the default auction cycle, which is 1 day.
 const DEFAULT_MIN_CYCLES: u64 = 10_000_000_000_000;
@@ -39,6 +116,7 @@ impl From<Metadata> for StatsData {
             name: md.name,
             symbol: md.symbol,
             decimals: md.decimals,
+            total_minted: Some(md.totalSupply.clone()),
             total_supply: md.totalSupply,
             owner: md.owner,
             fee: md.fee,
@@ -46,6 +124,14 @@ impl From<Metadata> for StatsData {
             deploy_time: ic_kit::ic::time(),
             min_cycles: DEFAULT_MIN_CYCLES,
             is_test_token: md.isTestToken.unwrap_or(false),
+            min_transfer_amount: md.minTransferAmount.unwrap_or_else(|| Nat::from(0)),
+            approve_fee_enabled: true,
+            max_supply: md.maxSupply,
+            fee_burn_rate: 0,
+            total_burned: Nat::from(0),
+            minting_disabled: false,
+            record_failed_transactions: false,
+            max_query_limit: None,
         }
     }
 }
@@ -59,6 +145,8 @@ pub struct TokenInfo {
     pub deployTime: Timestamp,
     pub holderNumber: usize,
     pub cycles: u64,
+    pub totalBurned: Nat,
+    pub mintingDisabled: bool,
 }
 
 impl Default for StatsData {
@@ -75,13 +163,146 @@ impl Default for StatsData {
             deploy_time: 0,
             min_cycles: 0,
             is_test_token: false,
+            min_transfer_amount: Nat::from(0),
+            approve_fee_enabled: true,
+            max_supply: None,
+            fee_burn_rate: 0,
+            total_burned: Nat::from(0),
+            total_minted: None,
+            minting_disabled: false,
+            record_failed_transactions: false,
+            max_query_limit: None,
+        }
+    }
+}
+
+/// Value reported for unlimited allowances, given with `approveUnlimited`.
+pub const UNLIMITED_ALLOWANCE: u128 = u128::MAX;
+
+/// Amount of tokens a spender is allowed to transfer from the owner's account.
+#[derive(CandidType, Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct Allowance {
+    pub value: Nat,
+
+    /// IC time after which the allowance is treated as zero. `None` means that the allowance never
+    /// expires.
+    #[serde(default)]
+    pub expires_at: Option<Timestamp>,
+
+    /// If set, the allowance is revoked after the first `transferFrom` that uses it.
+    #[serde(default)]
+    pub one_time: bool,
+
+    /// Optional limit of the amount the spender can transfer in a period of time.
+    #[serde(default)]
+    pub spending_limit: Option<SpendingLimit>,
+
+    /// If set, `transferFrom` calls don't decrease the allowance. The `value` of such allowances is
+    /// `UNLIMITED_ALLOWANCE`.
+    #[serde(default)]
+    pub unlimited: bool,
+
+    /// IC time the allowance was given. Changing the allowance with `transferFrom`,
+    /// `increaseAllowance` or `decreaseAllowance` doesn't change this value.
+    #[serde(default)]
+    pub created_at: Timestamp,
+
+    /// Number of successful `transferFrom` calls that used the allowance.
+    #[serde(default)]
+    pub times_used: u64,
+
+    /// IC time of the last successful `transferFrom` call that used the allowance.
+    #[serde(default)]
+    pub last_used: Option<Timestamp>,
+}
+
+impl Allowance {
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= ic_kit::ic::time())
+    }
+
+    /// Starts a new spending limit period, if the current one is over.
+    pub fn refresh(&mut self) {
+        if let Some(limit) = &mut self.spending_limit {
+            limit.refresh(ic_kit::ic::time());
+        }
+    }
+}
+
+/// Allowance with its usage statistics returned by `getUserApprovalsDetailed`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct ApprovalDetails {
+    pub spender: Principal,
+    pub value: Nat,
+    pub expires_at: Option<Timestamp>,
+    pub created_at: Timestamp,
+    pub times_used: u64,
+    pub last_used: Option<Timestamp>,
+}
+
+/// Allowance entry returned by `exportApprovals`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct ApprovalRecord {
+    pub owner: Principal,
+    pub spender: Principal,
+    pub value: Nat,
+    pub expires_at: Option<Timestamp>,
+    pub spending_limit: Option<SpendingLimit>,
+    pub unlimited: bool,
+    pub created_at: Timestamp,
+}
+
+/// A page of the allowances returned by `exportApprovals`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct ApprovalsPage {
+    pub records: Vec<ApprovalRecord>,
+
+    /// Total number of not expired allowances at the time of the call.
+    pub total: usize,
+}
+
+/// Limit of the amount of tokens that can be transferred using an allowance in every period of
+/// time. The transfer fees are counted against the limit.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct SpendingLimit {
+    pub limit_per_period: Nat,
+
+    /// Length of the period in nanoseconds.
+    pub period: Timestamp,
+
+    /// Amount that can still be transferred in the current period.
+    pub remaining: Nat,
+
+    /// IC time when the current period ends.
+    pub resets_at: Timestamp,
+}
+
+impl SpendingLimit {
+    pub fn new(limit_per_period: Nat, period: Timestamp) -> Self {
+        Self {
+            remaining: limit_per_period.clone(),
+            limit_per_period,
+            period,
+            resets_at: ic_kit::ic::time().saturating_add(period),
+        }
+    }
+
+    fn refresh(&mut self, now: Timestamp) {
+        if now >= self.resets_at {
+            // The periods are counted from the time the limit was set, so several periods could
+            // have passed since the last access.
+            let periods_passed = (now - self.resets_at) / self.period + 1;
+            self.resets_at = self
+                .resets_at
+                .saturating_add(periods_passed.saturating_mul(self.period));
+            self.remaining = self.limit_per_period.clone();
         }
     }
 }
 
-pub type Allowances = HashMap<Principal, HashMap<Principal, Nat>>;
+pub type Allowances = HashMap<Principal, HashMap<Principal, Allowance>>;
 
-#[derive(CandidType, Debug, PartialEq, Deserialize)]
+#[derive(CandidType, Debug, Clone, PartialEq, Deserialize)]
 pub enum TxError {
     InsufficientBalance,
     InsufficientAllowance,
@@ -92,17 +313,500 @@ pub enum TxError {
     NotificationFailed { cdk_msg: String },
     AlreadyNotified,
     TransactionDoesNotExist,
+    SelfApproval,
+    AnonymousPrincipal,
+    InvalidSignature,
+    InvalidNonce { expected: u64 },
+    SpenderNotAllowed,
+    AllowanceChanged { current: Nat },
+    SpendingLimitExceeded { resets_at: Timestamp },
+    InvalidSpendingLimit,
+    MaxSupplyExceeded,
+    BatchTooLarge { max: u64 },
+    InvalidFeeBurnRate,
+    InvalidEmissionPeriod,
+    InsufficientUnlockedBalance,
+    InvalidVestingSchedule,
+    AlreadyClaimed,
+    InvalidProof,
+    MintLimitExceeded { resets_at: Timestamp },
+    MintingDisabled,
+    ReasonTooLong { max: u64 },
+    FaucetLimitReached { retry_after: Timestamp },
+    QueryLimitExceeded { max: u64 },
+    MemoTooLong { max: u64 },
+    InvalidQueryLimit { max: u64 },
+    TransactionArchived { canister_id: Principal },
+    ArchiveFailed { cdk_msg: String },
+    InvalidArchiveConfig,
+    InvalidCheckpointSpacing,
+    TooManySubscribers { max: u64 },
+    SubscriberNotFound,
+    BackupInProgress,
+    BackupFailed { cdk_msg: String },
+    BackupVerificationFailed,
+    RestoreNotAllowed,
+    RestoreOutOfOrder { expected: Nat },
+    InvalidFeeRatioBounds,
+    NoAuctionReward,
+    AuctionPeriodTooShort { min: u64 },
+    InvalidAuctionIncentive,
 }
 
 pub type TxReceipt = Result<Nat, TxError>;
-pub type PendingNotifications = HashSet<Nat>;
 
+/// Result of a successful `transferFromV2` call.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct TransferFromReceipt {
+    /// Id of the transaction.
+    pub tx_id: Nat,
+
+    /// Allowance of the caller left after the transfer. The fee charged for the transfer is also
+    /// deducted from the allowance.
+    pub remaining_allowance: Nat,
+}
+/// Ids of the transactions the receivers can be notified about with `notify`. The ids are ordered
+/// to list them in pages with `getUnnotifiedTransactions`.
+pub type PendingNotifications = BTreeSet<Nat>;
+
+/// Notification state of a transaction returned by `getNotificationStatus`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub enum NotificationStatus {
+    /// The receiver of the transaction can be notified with `notify`.
+    Pending,
+
+    /// The notification was sent `attempts` times without success, the last attempt failed with
+    /// the `cdk_msg` error. The notification can be retried with `notify`.
+    Failed { attempts: u32, cdk_msg: String },
+
+    /// The receiver was notified, or the transaction doesn't need a notification.
+    NotPending,
+}
+
+/// Failed attempts to notify the receiver of a transaction.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct NotificationFailure {
+    pub attempts: u32,
+
+    /// Error of the last attempt.
+    pub cdk_msg: String,
+}
+
+/// A page of the ids returned by `getUnnotifiedTransactions`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct UnnotifiedPage {
+    pub ids: Vec<Nat>,
+
+    /// Number of the transactions waiting for a notification, for all the receivers.
+    pub total: Nat,
+
+    /// Id to request the next page from. `None` if there were no more pending notifications at the
+    /// time of the call.
+    pub next_cursor: Option<Nat>,
+}
+
+/// Chunk of the transaction history returned by `exportTransactions`. See the `export` module for
+/// the format of the `data`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct ExportChunk {
+    pub data: Vec<u8>,
+
+    /// Id of the transaction to continue the export from, or `None` if there are no more
+    /// transactions.
+    pub next: Option<Nat>,
+}
+
+/// Chunk of the ledger sent to the backup canister with the `receive_backup` call, and restored
+/// with `restoreLedger`.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct BackupChunk {
+    /// Id of the first record of the chunk.
+    pub start: Nat,
+    pub records: Vec<TxRecord>,
+
+    /// Fee shares credited to the `fee_to` principals by the records of the chunk.
+    pub fee_payments: Vec<FeePayment>,
+
+    /// Set in the last chunk of the backup.
+    pub end: Option<BackupEnd>,
+}
+
+/// Size and hash of a complete backup, sent with its last chunk.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct BackupEnd {
+    /// Number of the records in the backup.
+    pub record_count: u64,
+
+    /// Hash of the last record of the backup.
+    pub tip_hash: Vec<u8>,
+}
+
+/// Reply of the backup canister to the `receive_backup` call: the number of the records of the
+/// backup it stored so far and the hash of the last of them.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct BackupAck {
+    pub record_count: u64,
+    pub tip_hash: Option<Vec<u8>>,
+}
+
+/// Progress of the backup returned by `backupLedger` and `getBackupProgress`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct BackupProgress {
+    /// Canister the last backup is sent to. `None` if no backup was started.
+    pub target: Option<Principal>,
+
+    /// Id of the first record of the backup.
+    pub start: Nat,
+
+    /// Id of the next record to send.
+    pub next: Nat,
+
+    /// Id after the last record of the backup.
+    pub end: Nat,
+
+    /// `true` if all the records were sent and the backup canister confirmed them.
+    pub completed: bool,
+
+    /// Id of the next record expected by `restoreLedger`, or `None` if no restore is in progress.
+    pub restore_next: Option<Nat>,
+}
+
+/// Transactions returned by `getBlocksSince`.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct BlocksPage {
+    pub records: Vec<TxRecord>,
+
+    /// Id of the newest transaction in the ledger at the time of the call.
+    pub last_id: Nat,
+
+    /// Number of the transactions after the returned ones.
+    pub remaining: Nat,
+}
+
+/// Size of the ledger returned by `getLedgerStats`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct LedgerStats {
+    /// Number of the transaction records stored by the token.
+    pub record_count: Nat,
+
+    /// Approximate number of bytes taken in the heap by the records and the ledger indexes.
+    pub heap_bytes_estimate: u64,
+
+    /// Size of the stable memory of the canister. The ledger is written to the stable memory on
+    /// upgrades, so this is the size of the state saved by the last upgrade.
+    pub stable_bytes: u64,
+
+    /// Timestamp of the oldest stored record, or 0 if there are no records.
+    pub oldest_timestamp: Timestamp,
+
+    /// Timestamp of the newest stored record, or 0 if there are no records.
+    pub newest_timestamp: Timestamp,
+}
+
+/// Number of the successful transactions of every operation, returned by `getOperationCounts`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct OperationCounts {
+    /// Counts by the operation names, sorted by the names.
+    pub counts: Vec<(String, Nat)>,
+
+    /// `false` while the transactions stored before the upgrade that added the counters are
+    /// being counted. Until then the counts are partial.
+    pub complete: bool,
+}
+
+/// Share of a transaction fee credited to the `fee_to` principal.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct FeePayment {
+    pub tx_id: Nat,
+
+    /// The `fee_to` principal at the time of the transaction.
+    pub recipient: Principal,
+
+    pub amount: Nat,
+}
+
+/// A page of the fee payments returned by `getFeeTransactions`.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct FeePaymentsPage {
+    pub payments: Vec<FeePayment>,
+
+    /// Total number of the fee payments stored by the token.
+    pub total: Nat,
+
+    /// Cursor to request the next page with. `None` if there were no more payments at the time of
+    /// the call.
+    pub next_cursor: Option<Nat>,
+}
+
+/// Result of checking a range of the ledger with `verifyLedger`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub enum LedgerCheckResult {
+    /// All the `checked` records are consistent. `minted` and `burned` are the amounts minted and
+    /// burned by these records. `next` is the id to continue the check from, or `None` if the end
+    /// of the history was reached.
+    Consistent {
+        checked: u64,
+        minted: Nat,
+        burned: Nat,
+        next: Option<Nat>,
+    },
+
+    /// The record with the `id` is inconsistent with the rest of the ledger. The check stops at the
+    /// first inconsistency.
+    Inconsistent { id: Nat, issue: LedgerIssue },
+}
+
+/// Inconsistency found by `verifyLedger`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub enum LedgerIssue {
+    /// The record is stored under a different id.
+    WrongIndex { stored: Nat },
+
+    /// The parent hash of the record doesn't match the hash of the previous record.
+    ParentHashMismatch,
+
+    /// The hash of the last record doesn't match the ledger tip.
+    TipHashMismatch,
+
+    /// The timestamp of the record is less than the timestamp of the previous record.
+    TimestampDecreased,
+
+    /// The fee breakdown of the record doesn't add up to the fee.
+    FeeBreakdownMismatch,
+}
+
+/// Aggregated transactions of a principal, returned by `getUserStats`. Failed transactions are not
+/// counted.
+#[derive(CandidType, Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct UserStats {
+    /// Sum of the amounts of the transfers, burns and clawbacks from the principal's account.
+    pub sent: Nat,
+
+    /// Sum of the amounts of the transfers, mints, clawbacks and auction payouts to the
+    /// principal's account.
+    pub received: Nat,
+
+    /// Sum of the fees paid by the principal.
+    pub fees_paid: Nat,
+
+    /// Number of the transactions the principal is the sender, the receiver or the caller of.
+    pub tx_count: Nat,
+
+    /// Id of the first transaction of the principal.
+    pub first_tx: Option<Nat>,
+
+    /// Id of the last transaction of the principal.
+    pub last_tx: Option<Nat>,
+}
+
+impl UserStats {
+    /// Adds the successful transaction `tx` related to the `who` principal to the aggregates.
+    pub fn add(&mut self, who: Principal, tx: &TxRecord) {
+        if tx.from == who {
+            if matches!(
+                tx.operation,
+                Operation::Transfer
+                    | Operation::TransferFrom
+                    | Operation::TransferFromIncludeFee
+                    | Operation::Burn
+                    | Operation::Clawback
+            ) {
+                self.sent += tx.amount.clone();
+            }
+            // Auction payouts recorded before they were sent from the auction principal have the
+            // bidder as both `from` and `to`.
+            if tx.operation == Operation::Auction && tx.from != tx.to {
+                self.sent += tx.amount.clone();
+            }
+            self.fees_paid += tx.fee.clone();
+        }
+
+        if tx.to == who
+            && matches!(
+                tx.operation,
+                Operation::Transfer
+                    | Operation::TransferFrom
+                    | Operation::TransferFromIncludeFee
+                    | Operation::Mint
+                    | Operation::Clawback
+                    | Operation::Auction
+            )
+        {
+            self.received += tx.amount.clone();
+        }
+
+        self.tx_count += 1;
+        if self
+            .first_tx
+            .as_ref()
+            .map_or(true, |first| tx.index < *first)
+        {
+            self.first_tx = Some(tx.index.clone());
+        }
+        if self.last_tx.as_ref().map_or(true, |last| tx.index > *last) {
+            self.last_tx = Some(tx.index.clone());
+        }
+    }
+}
+
+/// Last record of the hash-chained ledger, returned by `getLedgerTip`.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct LedgerTip {
+    pub last_id: Nat,
+
+    /// Hash of the last record. If no records were added since the ledger was hash-chained, this
+    /// is the genesis hash (32 zero bytes).
+    pub last_hash: Vec<u8>,
+}
+
+/// Ledger tip returned by `getCertifiedTip`, with the data needed to verify it.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct CertifiedTip {
+    pub last_id: Nat,
+    pub last_hash: Vec<u8>,
+
+    /// Certificate of the canister's certified data. Only available in query calls.
+    pub certificate: Option<Vec<u8>>,
+
+    /// CBOR-encoded hash tree whose root hash is the certified data.
+    pub witness: Vec<u8>,
+}
+
+/// Range of the transactions moved from the ledger to an archive canister.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct ArchivedRange {
+    pub canister_id: Principal,
+
+    /// Id of the first transaction in the range.
+    pub start: Nat,
+
+    /// Number of the transactions in the range.
+    pub length: Nat,
+}
+
+/// Result of the `queryTransactions` call.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct TransactionsQueryResult {
+    /// Records of the requested range kept in the token canister.
+    pub records: Vec<TxRecord>,
+
+    /// Parts of the requested range that were moved to the archive canisters. These records
+    /// should be requested from the archives.
+    pub archived: Vec<ArchivedRange>,
+}
+
+/// Order of the records returned by `getTransactionsPage`.
 #[derive(CandidType, Debug, Clone, Copy, Deserialize, PartialEq)]
+pub enum TransactionOrder {
+    /// From the oldest to the newest.
+    Ascending,
+
+    /// From the newest to the oldest.
+    Descending,
+}
+
+/// A page of the transactions returned by `getTransactionsPage` and `getUserTransactionsPage`.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct TransactionsPage {
+    pub records: Vec<TxRecord>,
+
+    /// Total number of the transactions the page was taken from at the time of the call.
+    pub total: Nat,
+
+    /// Cursor to request the next page with. `None` if there were no more records at the time of
+    /// the call.
+    pub next_cursor: Option<Nat>,
+}
+
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
 pub enum TransactionStatus {
     Succeeded,
+
+    /// The transaction was rejected with the `error` and had no effect on the balances. Failed
+    /// transactions are only recorded if the owner enabled it with `setRecordFailedTransactions`.
+    Failed {
+        error: TxError,
+    },
+}
+
+impl Default for TransactionStatus {
+    fn default() -> Self {
+        TransactionStatus::Succeeded
+    }
+}
+
+impl TransactionStatus {
+    pub fn is_failed(&self) -> bool {
+        matches!(self, TransactionStatus::Failed { .. })
+    }
+}
+
+/// Status of the transactions returned by the filtered transaction queries.
+#[derive(CandidType, Debug, Clone, Copy, Deserialize, PartialEq)]
+pub enum TransactionStatusFilter {
+    Succeeded,
     Failed,
 }
 
+impl TransactionStatusFilter {
+    pub fn matches(&self, status: &TransactionStatus) -> bool {
+        match self {
+            TransactionStatusFilter::Succeeded => !status.is_failed(),
+            TransactionStatusFilter::Failed => status.is_failed(),
+        }
+    }
+}
+
+/// Criteria of the transactions returned by `queryTransactionsByFilter`. A transaction matches the
+/// filter if it matches all the given criteria.
+#[derive(CandidType, Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct TxFilter {
+    pub operation: Option<Operation>,
+    pub from: Option<Principal>,
+    pub to: Option<Principal>,
+    pub caller: Option<Principal>,
+
+    /// Minimum amount of the transaction, inclusive.
+    pub min_amount: Option<Nat>,
+
+    /// Maximum amount of the transaction, inclusive.
+    pub max_amount: Option<Nat>,
+
+    /// The transaction was made at or after this time.
+    pub after_ts: Option<Timestamp>,
+
+    /// The transaction was made before this time.
+    pub before_ts: Option<Timestamp>,
+    pub status: Option<TransactionStatusFilter>,
+}
+
+impl TxFilter {
+    pub fn matches(&self, tx: &TxRecord) -> bool {
+        self.operation
+            .map_or(true, |operation| tx.operation == operation)
+            && self.from.map_or(true, |from| tx.from == from)
+            && self.to.map_or(true, |to| tx.to == to)
+            && self.caller.map_or(true, |caller| tx.caller == Some(caller))
+            && self
+                .min_amount
+                .as_ref()
+                .map_or(true, |min| tx.amount >= *min)
+            && self
+                .max_amount
+                .as_ref()
+                .map_or(true, |max| tx.amount <= *max)
+            && self
+                .after_ts
+                .map_or(true, |after| tx.timestamp >= Int::from(after))
+            && self
+                .before_ts
+                .map_or(true, |before| tx.timestamp < Int::from(before))
+            && self
+                .status
+                .map_or(true, |status| status.matches(&tx.status))
+    }
+}
+
 #[derive(CandidType, Debug, Clone, Copy, Deserialize, PartialEq)]
 pub enum Operation {
     Approve,
@@ -111,6 +815,57 @@ pub enum Operation {
     TransferFrom,
     Burn,
     Auction,
+    Clawback,
+    TransferFromIncludeFee,
+    ApproveUnlimited,
+    AdminRevoke,
+    AddMinter,
+    RemoveMinter,
+    Configure,
+    Delegate,
+}
+
+/// Who is allowed to run the auctions with `runAuction`.
+#[derive(CandidType, Debug, Clone, Copy, Deserialize, PartialEq)]
+pub enum AuctionController {
+    Anyone,
+    OwnerOnly,
+}
+
+impl Default for AuctionController {
+    fn default() -> Self {
+        Self::Anyone
+    }
+}
+
+/// Maximum share of the auction pool paid to the caller of `runAuction`, in basis points.
+pub const MAX_AUCTION_INCENTIVE_RATE: u64 = 1_000;
+
+/// Tokens paid from the auction pool to the caller that ran the auction.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct AuctionIncentive {
+    pub caller: Principal,
+    pub amount: Nat,
+
+    /// Id of the `Auction` transaction paying the incentive.
+    pub transaction_id: Nat,
+}
+
+/// How the auctions pay the distributed tokens to the bidders.
+#[derive(CandidType, Debug, Clone, Copy, Deserialize, PartialEq)]
+pub enum AuctionPayoutMode {
+    /// The tokens are transferred to the bidders by the auction.
+    Push,
+
+    /// The auction only records the rewards of the bidders, and the bidders claim them with
+    /// `claimAuctionReward`.
+    Claim,
+}
+
+impl Default for AuctionPayoutMode {
+    fn default() -> Self {
+        Self::Push
+    }
 }
 
 #[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
@@ -122,4 +877,31 @@ pub struct AuctionInfo {
     pub fee_ratio: f64,
     pub first_transaction_id: Nat,
     pub last_transaction_id: Nat,
+    /// Ids of the `Auction` transactions paying the distributed tokens to the bidders.
+    #[serde(default)]
+    pub transaction_ids: Vec<Nat>,
+
+    /// `true` if the `auction_reward` notifications were queued for the winning bidder canisters.
+    #[serde(default)]
+    pub notifications_dispatched: bool,
+
+    /// Bids skipped because their share was below the `min_expected_tokens` of the bidder, with
+    /// the amounts of cycles refunded to the bidders.
+    #[serde(default)]
+    pub refunded_bids: Vec<(Principal, u64)>,
+
+    /// If `Claim`, the distributed tokens are paid when the bidders claim them with
+    /// `claimAuctionReward`, and `transaction_ids` is empty.
+    #[serde(default)]
+    pub payout_mode: AuctionPayoutMode,
+
+    /// Incentive paid to the caller of `runAuction`. Not included in `tokens_distributed`. `None`
+    /// if the auction was run on the heartbeat or the incentive is not set.
+    #[serde(default)]
+    pub caller_incentive: Option<AuctionIncentive>,
+
+    /// Number of the bidders in the auction, including the skipped ones. `0` for the auctions held
+    /// before it was recorded.
+    #[serde(default)]
+    pub participants: u64,
 }
@@ -1,4 +1,4 @@
-use crate::types::{Operation, TransactionStatus};
+use crate::types::{Operation, TransactionStatus, TxError, UNLIMITED_ALLOWANCE};
 use candid::{CandidType, Deserialize, Int, Nat, Principal};
 use ic_kit::ic;
 
@@ -11,11 +11,90 @@ pub struct TxRecord {
     pub amount: Nat,
     pub fee: Nat,
     pub timestamp: Int,
+    #[serde(default)]
     pub status: TransactionStatus,
     pub operation: Operation,
+
+    /// Reason given for a mint. Not set for other operations.
+    #[serde(default)]
+    pub reason: Option<String>,
+
+    /// Memo given by the sender of a transfer.
+    #[serde(default)]
+    pub memo: Option<Vec<u8>>,
+
+    /// Hash of the previous record in the ledger. Not set for the records added before the
+    /// ledger was hash-chained.
+    #[serde(default)]
+    pub parent_hash: Option<Vec<u8>>,
+
+    /// Setting changed by a `Configure` record. Not set for other operations.
+    #[serde(default)]
+    pub config: Option<ConfigChange>,
+
+    /// Split of the `fee` between its recipients. Not set for the records added before the fees
+    /// were broken down.
+    #[serde(default)]
+    pub fee_breakdown: Option<FeeBreakdown>,
+}
+
+/// Change of a canister setting made by the owner.
+#[derive(Deserialize, CandidType, Debug, Clone, PartialEq)]
+pub struct ConfigChange {
+    /// Name of the changed setting.
+    pub setting: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+/// Split of a transaction fee. The amounts add up to the `fee` of the record.
+#[derive(Deserialize, CandidType, Debug, Clone, Default, PartialEq)]
+pub struct FeeBreakdown {
+    /// Amount credited to the `fee_to` principal.
+    pub fee_to: Nat,
+
+    /// Amount retained for the cycle auction.
+    pub auction: Nat,
+
+    /// Amount burned according to the fee burn rate.
+    pub burned: Nat,
+}
+
+impl FeeBreakdown {
+    pub fn total(&self) -> Nat {
+        self.fee_to.clone() + self.auction.clone() + self.burned.clone()
+    }
 }
 
 impl TxRecord {
+    /// Record of the `operation` rejected with the `error`.
+    pub fn failed(
+        index: Nat,
+        operation: Operation,
+        caller: Principal,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+        error: TxError,
+    ) -> Self {
+        Self {
+            caller: Some(caller),
+            index,
+            from,
+            to,
+            amount,
+            fee: Nat::from(0),
+            timestamp: ic::time().into(),
+            status: TransactionStatus::Failed { error },
+            operation,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
+        }
+    }
+
     pub fn transfer(index: Nat, from: Principal, to: Principal, amount: Nat, fee: Nat) -> Self {
         Self {
             caller: Some(from),
@@ -27,6 +106,11 @@ impl TxRecord {
             timestamp: ic::time().into(),
             status: TransactionStatus::Succeeded,
             operation: Operation::Transfer,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
         }
     }
 
@@ -48,6 +132,39 @@ impl TxRecord {
             timestamp: ic::time().into(),
             status: TransactionStatus::Succeeded,
             operation: Operation::TransferFrom,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
+        }
+    }
+
+    /// Record of a `transferFromIncludeFee` call. The `amount` is the value deducted from the `from`
+    /// account, the fee included.
+    pub fn transfer_from_include_fee(
+        index: Nat,
+        caller: Principal,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+        fee: Nat,
+    ) -> Self {
+        Self {
+            caller: Some(caller),
+            index,
+            from,
+            to,
+            amount,
+            fee,
+            timestamp: ic::time().into(),
+            status: TransactionStatus::Succeeded,
+            operation: Operation::TransferFromIncludeFee,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
         }
     }
 
@@ -62,10 +179,94 @@ impl TxRecord {
             timestamp: ic::time().into(),
             status: TransactionStatus::Succeeded,
             operation: Operation::Approve,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
         }
     }
 
-    pub fn mint(index: Nat, from: Principal, to: Principal, amount: Nat) -> Self {
+    pub fn approve_unlimited(index: Nat, from: Principal, to: Principal, fee: Nat) -> Self {
+        Self {
+            caller: Some(from),
+            index,
+            from,
+            to,
+            amount: Nat::from(UNLIMITED_ALLOWANCE),
+            fee,
+            timestamp: ic::time().into(),
+            status: TransactionStatus::Succeeded,
+            operation: Operation::ApproveUnlimited,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
+        }
+    }
+
+    /// Record of an allowance given by `from` to `to` principal, revoked by the canister owner.
+    pub fn admin_revoke(index: Nat, caller: Principal, from: Principal, to: Principal) -> Self {
+        Self {
+            caller: Some(caller),
+            index,
+            from,
+            to,
+            amount: Nat::from(0),
+            fee: Nat::from(0),
+            timestamp: ic::time().into(),
+            status: TransactionStatus::Succeeded,
+            operation: Operation::AdminRevoke,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
+        }
+    }
+
+    /// Record of the `minter` principal added to the list of minters by the canister owner.
+    pub fn add_minter(index: Nat, caller: Principal, minter: Principal) -> Self {
+        Self::minter_change(index, caller, minter, Operation::AddMinter)
+    }
+
+    /// Record of the `minter` principal removed from the list of minters by the canister owner.
+    pub fn remove_minter(index: Nat, caller: Principal, minter: Principal) -> Self {
+        Self::minter_change(index, caller, minter, Operation::RemoveMinter)
+    }
+
+    fn minter_change(
+        index: Nat,
+        caller: Principal,
+        minter: Principal,
+        operation: Operation,
+    ) -> Self {
+        Self {
+            caller: Some(caller),
+            index,
+            from: caller,
+            to: minter,
+            amount: Nat::from(0),
+            fee: Nat::from(0),
+            timestamp: ic::time().into(),
+            status: TransactionStatus::Succeeded,
+            operation,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
+        }
+    }
+
+    pub fn mint(
+        index: Nat,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+        reason: Option<String>,
+    ) -> Self {
         Self {
             caller: Some(from),
             index,
@@ -76,6 +277,11 @@ impl TxRecord {
             timestamp: ic::time().into(),
             status: TransactionStatus::Succeeded,
             operation: Operation::Mint,
+            reason,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
         }
     }
 
@@ -90,20 +296,124 @@ impl TxRecord {
             timestamp: ic::time().into(),
             status: TransactionStatus::Succeeded,
             operation: Operation::Burn,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
+        }
+    }
+
+    pub fn burn_from(index: Nat, caller: Principal, from: Principal, amount: Nat) -> Self {
+        Self {
+            caller: Some(caller),
+            index,
+            from,
+            to: from,
+            amount,
+            fee: Nat::from(0),
+            timestamp: ic::time().into(),
+            status: TransactionStatus::Succeeded,
+            operation: Operation::Burn,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
+        }
+    }
+
+    pub fn clawback(
+        index: Nat,
+        caller: Principal,
+        from: Principal,
+        to: Principal,
+        amount: Nat,
+    ) -> Self {
+        Self {
+            caller: Some(caller),
+            index,
+            from,
+            to,
+            amount,
+            fee: Nat::from(0),
+            timestamp: ic::time().into(),
+            status: TransactionStatus::Succeeded,
+            operation: Operation::Clawback,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
         }
     }
 
-    pub fn auction(index: Nat, to: Principal, amount: Nat) -> Self {
+    /// Record of the `setting` changed by the canister owner from `old_value` to `new_value`.
+    pub fn configure(
+        index: Nat,
+        caller: Principal,
+        setting: String,
+        old_value: String,
+        new_value: String,
+    ) -> Self {
         Self {
-            caller: Some(to),
+            caller: Some(caller),
             index,
-            from: to,
+            from: caller,
+            to: caller,
+            amount: Nat::from(0),
+            fee: Nat::from(0),
+            timestamp: ic::time().into(),
+            status: TransactionStatus::Succeeded,
+            operation: Operation::Configure,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: Some(ConfigChange {
+                setting,
+                old_value,
+                new_value,
+            }),
+            fee_breakdown: None,
+        }
+    }
+
+    /// Record of the `from` principal delegating its votes to the `delegate` principal.
+    pub fn delegate(index: Nat, from: Principal, delegate: Principal) -> Self {
+        Self {
+            caller: Some(from),
+            index,
+            from,
+            to: delegate,
+            amount: Nat::from(0),
+            fee: Nat::from(0),
+            timestamp: ic::time().into(),
+            status: TransactionStatus::Succeeded,
+            operation: Operation::Delegate,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
+        }
+    }
+
+    pub fn auction(index: Nat, from: Principal, to: Principal, amount: Nat) -> Self {
+        Self {
+            caller: None,
+            index,
+            from,
             to,
             amount,
             fee: Nat::from(0),
             timestamp: ic::time().into(),
             status: TransactionStatus::Succeeded,
             operation: Operation::Auction,
+            reason: None,
+            memo: None,
+            parent_hash: None,
+            config: None,
+            fee_breakdown: None,
         }
     }
 }